@@ -0,0 +1,719 @@
+//! The command line arguments. The command and its options used to be passed through environment
+//! variables (`LEDGER_COMMAND`, `LEDGER_TESTNET`, `LEDGER_PATH`, ...). This is still supported
+//! when no argument is given, but deprecated.
+
+use std::{env, ffi::OsString, fmt, path::PathBuf, process, str::FromStr};
+
+use clap::{builder::FalseyValueParser, CommandFactory, Parser, Subcommand};
+use ledger_manager::{
+    bitcoin_app::{AppVersion, DerivationPath},
+    DeviceModel, Proxy, SpkiPin,
+};
+
+use crate::report::ErrorCode;
+
+// The commands which predate the arguments, by their value of LEDGER_COMMAND.
+const LEGACY_COMMANDS: &[(&str, &str)] = &[
+    ("getinfo", "info"),
+    ("genuinecheck", "genuine-check"),
+    ("installapp", "install"),
+    ("updateapp", "update"),
+    ("uninstallapp", "uninstall"),
+    ("openapp", "open"),
+    ("updatefirm", "upgrade"),
+    ("getfingerprint", "fingerprint"),
+    ("getxpub", "xpub"),
+    ("registerpolicy", "register-policy"),
+    ("listpolicies", "list-policies"),
+    ("exportpolicies", "export-policies"),
+    ("displayaddress", "display-address"),
+    ("signpsbt", "sign-psbt"),
+    ("signmessage", "sign-message"),
+    ("verifymessage", "verify-message"),
+    ("checkpolicy", "check-policy"),
+    ("getappversion", "app-version"),
+];
+
+// The environment variables the options of these commands were read from.
+const LEGACY_OPTS: &[(&str, &str)] = &[
+    ("path", "LEDGER_PATH"),
+    ("display", "LEDGER_DISPLAY"),
+    ("name", "LEDGER_POLICY_NAME"),
+    ("template", "LEDGER_POLICY_TEMPLATE"),
+    ("keys", "LEDGER_POLICY_KEYS"),
+    ("policy", "LEDGER_POLICY_NAME"),
+    ("change", "LEDGER_CHANGE"),
+    ("index", "LEDGER_INDEX"),
+    ("psbt", "LEDGER_PSBT"),
+    ("output", "LEDGER_OUTPUT"),
+    ("message", "LEDGER_MESSAGE"),
+    ("bip322", "LEDGER_BIP322"),
+    ("address", "LEDGER_ADDRESS"),
+    ("signature", "LEDGER_SIGNATURE"),
+    ("descriptor", "LEDGER_DESCRIPTOR"),
+    ("app-version", "LEDGER_APP_VERSION"),
+];
+
+// Parse a value with its FromStr implementation, for the types whose error can't be sent across
+// threads as clap requires.
+fn parse<T: FromStr>(s: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    s.parse().map_err(|e: T::Err| e.to_string())
+}
+
+fn parse_tls_pin(s: &str) -> Result<SpkiPin, String> {
+    parse(s.trim())
+}
+
+fn parse_key(s: &str) -> Result<String, String> {
+    Ok(s.trim().to_string())
+}
+
+// A target id, in hex if prefixed with 0x and in decimal otherwise.
+fn parse_target_id(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// The options common to all the commands.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct GlobalOpts {
+    /// Use the Bitcoin testnet app
+    #[arg(long, global = true)]
+    pub testnet: bool,
+    /// The device to use, by HID path or serial number, if several are connected
+    #[arg(long, global = true, value_name = "PATH|SERIAL")]
+    pub device: Option<String>,
+    /// Run the Bitcoin app commands against the Speculos emulator at this APDU address
+    #[arg(long, global = true, value_name = "ADDR", env = "LEDGER_SPECULOS")]
+    pub speculos: Option<String>,
+    /// Print the outcome of the command as a single JSON document
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Print debug logs on stderr
+    #[arg(long, global = true)]
+    pub verbose: bool,
+    /// Where to store the registered policies and the catalog cache [default: ~/.bacca]
+    #[arg(long, global = true, value_name = "DIR", env = "LEDGER_DATADIR")]
+    pub datadir: Option<PathBuf>,
+    /// Never send the hashes of the installed apps to Ledger
+    #[arg(long, global = true, env = "LEDGER_PRIVACY", value_parser = FalseyValueParser::new())]
+    pub privacy: bool,
+    /// Connect to the Ledger API through this HTTP or SOCKS5 proxy
+    #[arg(
+        long,
+        global = true,
+        value_name = "URL",
+        env = "LEDGER_PROXY",
+        value_parser = parse::<Proxy>
+    )]
+    pub proxy: Option<Proxy>,
+    /// Timeout for connecting to and reading from the Ledger API
+    #[arg(long, global = true, value_name = "SECS", env = "LEDGER_TIMEOUT")]
+    pub timeout: Option<u64>,
+    /// How many times to retry the catalog lookups on transient failures
+    #[arg(long, global = true, value_name = "N", env = "LEDGER_RETRIES")]
+    pub retries: Option<u32>,
+    /// A public key the Ledger API must present, as sha256/<base64>. May be repeated
+    #[arg(
+        long = "tls-pin",
+        global = true,
+        value_name = "PIN",
+        env = "LEDGER_TLS_PINS",
+        value_delimiter = ',',
+        value_parser = parse_tls_pin
+    )]
+    pub tls_pins: Vec<SpkiPin>,
+    /// The base URL of the Manager API [default: https://manager.api.live.ledger.com/api]
+    #[arg(long, global = true, value_name = "URL")]
+    pub api_url: Option<String>,
+    /// The base URL of the scriptrunner websockets [default:
+    /// wss://scriptrunner.api.live.ledger.com/update]
+    #[arg(long, global = true, value_name = "URL")]
+    pub socket_url: Option<String>,
+}
+
+/// The device the apps of the catalog are listed for.
+#[derive(Debug, Clone, clap::Args)]
+#[group(required = true, multiple = false)]
+pub struct CatalogTarget {
+    /// The device model: nanos, nanox, nanosp, stax or flex
+    #[arg(long, value_parser = parse::<DeviceModel>)]
+    pub model: Option<DeviceModel>,
+    /// The target id of the device, instead of its model (for instance 0x33100004)
+    #[arg(long, value_name = "ID", value_parser = parse_target_id)]
+    pub target_id: Option<u32>,
+}
+
+impl CatalogTarget {
+    pub fn target_id(&self) -> u32 {
+        match (self.model, self.target_id) {
+            (Some(model), _) => model.target_id(),
+            (None, Some(id)) => id,
+            (None, None) => unreachable!("One of them is required"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Get information about the device and the installed apps
+    Info,
+    /// Check the device is genuine
+    GenuineCheck,
+    /// Install the Bitcoin app
+    Install,
+    /// Update the Bitcoin app
+    Update,
+    /// Uninstall the Bitcoin app
+    Uninstall,
+    /// Open the Bitcoin app
+    Open,
+    /// Bring the device up to date: update its firmware if a newer one is available, reinstall
+    /// the apps it removes and update the Bitcoin apps
+    #[command(visible_alias = "update-firmware")]
+    Upgrade {
+        /// Only print the steps of the upgrade, without carrying them out
+        #[arg(long = "plan")]
+        plan_only: bool,
+    },
+    /// Get the master key fingerprint from the Bitcoin app
+    Fingerprint,
+    /// Get the extended public key at a derivation path, along with its key origin
+    Xpub {
+        /// The derivation path [default: m/84'/0'/0', or m/84'/1'/0' on testnet]
+        #[arg(long, value_parser = parse::<DerivationPath>)]
+        path: Option<DerivationPath>,
+        /// Display it on the device for you to check it
+        #[arg(long)]
+        display: bool,
+    },
+    /// Register a wallet policy on the device, and store it in the data directory
+    RegisterPolicy {
+        /// The name of the policy
+        #[arg(long)]
+        name: String,
+        /// The policy template, such as wsh(sortedmulti(2,@0/**,@1/**))
+        #[arg(long)]
+        template: String,
+        /// The comma-separated keys of the policy, with their origin
+        #[arg(long, required = true, value_delimiter = ',', value_parser = parse_key)]
+        keys: Vec<String>,
+    },
+    /// List the policies registered on this device
+    ListPolicies,
+    /// Export the registry of the policies registered on this device
+    ExportPolicies,
+    /// Display an address on the device for you to check it
+    DisplayAddress {
+        /// Use the registered policy with this name instead of the standard account at --path
+        #[arg(long, value_name = "NAME")]
+        policy: Option<String>,
+        /// The derivation path [default: m/84'/0'/0', or m/84'/1'/0' on testnet]
+        #[arg(long, value_parser = parse::<DerivationPath>)]
+        path: Option<DerivationPath>,
+        /// Display a change address
+        #[arg(long)]
+        change: bool,
+        /// The index of the address
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        index: u32,
+    },
+    /// Sign a PSBT
+    SignPsbt {
+        /// The PSBT to sign, in binary or base64
+        #[arg(long, value_name = "FILE")]
+        psbt: PathBuf,
+        /// Use the registered policy with this name instead of the standard account at --path
+        #[arg(long, value_name = "NAME")]
+        policy: Option<String>,
+        /// The derivation path [default: m/84'/0'/0', or m/84'/1'/0' on testnet]
+        #[arg(long, value_parser = parse::<DerivationPath>)]
+        path: Option<DerivationPath>,
+        /// Also write the signed PSBT in base64 to this file
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Sign a message with the key of an address (BIP-137)
+    SignMessage {
+        /// The full derivation path of the address
+        #[arg(long, value_parser = parse::<DerivationPath>)]
+        path: DerivationPath,
+        /// The message to sign
+        #[arg(long)]
+        message: String,
+        /// Get a BIP-322 simple signature instead (BIP84 paths only)
+        #[arg(long)]
+        bip322: bool,
+    },
+    /// Verify a BIP-137 or BIP-322 simple signature. Doesn't need a device
+    VerifyMessage {
+        /// The address which signed the message
+        #[arg(long)]
+        address: String,
+        /// The message
+        #[arg(long)]
+        message: String,
+        /// The base64 signature
+        #[arg(long)]
+        signature: String,
+    },
+    /// Check whether the Bitcoin app supports a descriptor or a policy template
+    CheckPolicy {
+        /// The descriptor to check, or a policy template with --template
+        #[arg(long, visible_alias = "template")]
+        descriptor: String,
+        /// Check against this version instead of querying the app
+        #[arg(long, value_name = "VERSION", value_parser = parse::<AppVersion>)]
+        app_version: Option<AppVersion>,
+    },
+    /// Get the version of the Bitcoin app and the protocol it speaks
+    AppVersion,
+    /// Manage the device from an interactive terminal user interface
+    Tui,
+    /// Send raw APDU commands to the device and print the responses
+    Apdu {
+        /// A command in hex, for instance e001000000. Can be repeated
+        #[arg(long = "hex", value_name = "HEX", required_unless_present = "file")]
+        commands: Vec<String>,
+        /// A script of commands in hex, one per line. Empty lines and # comments are skipped
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+        /// Stop at the first response with a status other than 0x9000
+        #[arg(long)]
+        stop_on_error: bool,
+    },
+    /// List the Bitcoin apps available for a device model and firmware. Doesn't need a device
+    Catalog {
+        #[command(flatten)]
+        target: CatalogTarget,
+        /// The version of the firmware of the device
+        #[arg(long, value_name = "VERSION")]
+        firmware: String,
+    },
+    /// Set up the devices plugged in one after the other: genuine check, install or update the
+    /// Bitcoin app and check it, and write a line per device to a report
+    Provision {
+        /// The report to append to. CSV, or JSON lines if it ends with .json or .jsonl
+        #[arg(long, value_name = "PATH")]
+        report: PathBuf,
+        /// Also install or update the Bitcoin Test app
+        #[arg(long = "with-test-app")]
+        test_app: bool,
+        /// Stop after this many devices [default: until interrupted]
+        #[arg(long, value_name = "N")]
+        count: Option<u32>,
+    },
+    /// Install, update or uninstall the Bitcoin apps to match the profile describing the desired
+    /// state of the device
+    Apply {
+        /// The profile, in TOML or in JSON if it ends with .json
+        #[arg(long, value_name = "PATH")]
+        profile: PathBuf,
+        /// Only report how the device differs from the profile, without changing it
+        #[arg(long)]
+        check: bool,
+    },
+    /// Save the list of the installed apps, to restore them after a firmware update
+    Snapshot {
+        /// Where to save the snapshot [default: apps_snapshot.json in the data directory]
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Reinstall the apps of a snapshot missing from the device, at their latest version for its
+    /// firmware
+    Restore {
+        /// The snapshot to restore [default: apps_snapshot.json in the data directory]
+        #[arg(long, value_name = "PATH")]
+        snapshot: Option<PathBuf>,
+    },
+}
+
+impl Command {
+    /// The name of the command on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::GenuineCheck => "genuine-check",
+            Self::Install => "install",
+            Self::Update => "update",
+            Self::Uninstall => "uninstall",
+            Self::Open => "open",
+            Self::Upgrade { .. } => "upgrade",
+            Self::Fingerprint => "fingerprint",
+            Self::Xpub { .. } => "xpub",
+            Self::RegisterPolicy { .. } => "register-policy",
+            Self::ListPolicies => "list-policies",
+            Self::ExportPolicies => "export-policies",
+            Self::DisplayAddress { .. } => "display-address",
+            Self::SignPsbt { .. } => "sign-psbt",
+            Self::SignMessage { .. } => "sign-message",
+            Self::VerifyMessage { .. } => "verify-message",
+            Self::CheckPolicy { .. } => "check-policy",
+            Self::AppVersion => "app-version",
+            Self::Tui => "tui",
+            Self::Apdu { .. } => "apdu",
+            Self::Catalog { .. } => "catalog",
+            Self::Provision { .. } => "provision",
+            Self::Apply { .. } => "apply",
+            Self::Snapshot { .. } => "snapshot",
+            Self::Restore { .. } => "restore",
+        }
+    }
+}
+
+/// Install and use the Bitcoin app on a Ledger device.
+#[derive(Debug, Clone, Parser)]
+#[command(name = "ledger_manager_cli", arg_required_else_help = true)]
+pub struct Args {
+    #[command(flatten)]
+    pub global: GlobalOpts,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+impl Args {
+    /// Parse the command line arguments. Falls back to the deprecated environment variables if
+    /// there is none.
+    pub fn get() -> Self {
+        let mut args: Vec<OsString> = env::args_os().collect();
+        let mut legacy = false;
+        if args.len() == 1 {
+            match legacy_args(|name| env::var(name).ok()) {
+                Some(Ok(legacy_args)) => {
+                    eprintln!("Warning: passing the command through LEDGER_COMMAND is deprecated. Run with --help for the command line arguments.");
+                    args.extend(legacy_args.into_iter().map(OsString::from));
+                    legacy = true;
+                }
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    process::exit(ErrorCode::InvalidArgument.exit_code());
+                }
+                None => {}
+            }
+        }
+        match Self::try_parse_from(args) {
+            Ok(args) => args,
+            Err(e) => {
+                // The help is printed as an error when no argument is given at all.
+                let _ = e.print();
+                if legacy {
+                    eprintln!("The options were read from their environment variable, such as LEDGER_PATH for --path.");
+                }
+                process::exit(if e.use_stderr() {
+                    ErrorCode::InvalidArgument.exit_code()
+                } else {
+                    0
+                });
+            }
+        }
+    }
+}
+
+/// The command line arguments equivalent to the deprecated environment variables, if
+/// `LEDGER_COMMAND` is set. `var` gets the value of an environment variable.
+fn legacy_args(var: impl Fn(&str) -> Option<String>) -> Option<Result<Vec<String>, String>> {
+    let legacy_name = var("LEDGER_COMMAND")?;
+    let Some((_, name)) = LEGACY_COMMANDS.iter().find(|(l, _)| *l == legacy_name) else {
+        return Some(Err(format!("Unknown LEDGER_COMMAND '{}'.", legacy_name)));
+    };
+
+    let mut args = Vec::new();
+    if var("LEDGER_TESTNET").is_some() {
+        args.push("--testnet".to_string());
+    }
+    // The output of getinfo was JSON.
+    if *name == "info" {
+        args.push("--json".to_string());
+    }
+    args.push(name.to_string());
+
+    let command = Args::command();
+    let subcommand = command
+        .find_subcommand(name)
+        .expect("Legacy commands are defined");
+    for arg in subcommand.get_arguments() {
+        let Some(long) = arg.get_long() else { continue };
+        let Some((_, env)) = LEGACY_OPTS.iter().find(|(opt, _)| *opt == long) else {
+            continue;
+        };
+        // The flags were set by setting their variable to any value.
+        match var(env) {
+            Some(_) if !arg.get_action().takes_values() => args.push(format!("--{}", long)),
+            Some(value) => args.push(format!("--{}={}", long, value)),
+            None => {}
+        }
+    }
+    Some(Ok(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("ledger_manager_cli").chain(args.iter().copied()))
+    }
+
+    fn legacy(vars: &[(&str, &str)]) -> Option<Result<Vec<String>, String>> {
+        legacy_args(|name| {
+            vars.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.to_string())
+        })
+    }
+
+    #[test]
+    fn definition() {
+        Args::command().debug_assert();
+        for (_, name) in LEGACY_COMMANDS {
+            assert!(Args::command().find_subcommand(name).is_some(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn options() {
+        // Global options go before or after the command.
+        let args = parse_args(&[
+            "--testnet",
+            "xpub",
+            "--json",
+            "--path",
+            "m/48'/0'/0'/2'",
+            "--display",
+            "--tls-pin=sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+            "--proxy",
+            "socks5://127.0.0.1:9050",
+            "--timeout",
+            "5",
+        ])
+        .unwrap();
+        assert!(args.global.testnet && args.global.json && !args.global.verbose);
+        assert_eq!(args.global.tls_pins.len(), 1);
+        assert!(matches!(args.global.proxy, Some(Proxy::Socks5 { .. })));
+        assert_eq!(args.global.timeout, Some(5));
+        match args.command {
+            Command::Xpub { path, display } => {
+                assert_eq!(path, Some("m/48'/0'/0'/2'".parse().unwrap()));
+                assert!(display);
+            }
+            c => panic!("Unexpected command {:?}", c),
+        }
+
+        match parse_args(&[
+            "register-policy",
+            "--name=Multi",
+            "--template=wsh(multi(1,@0/**,@1/**))",
+            "--keys",
+            "[f5acc2fd/48'/1'/0'/2']tpubA, [aabbccdd/48'/1'/0'/2']tpubB",
+        ])
+        .unwrap()
+        .command
+        {
+            Command::RegisterPolicy { name, keys, .. } => {
+                assert_eq!(name, "Multi");
+                assert_eq!(
+                    keys,
+                    [
+                        "[f5acc2fd/48'/1'/0'/2']tpubA",
+                        "[aabbccdd/48'/1'/0'/2']tpubB"
+                    ]
+                );
+            }
+            c => panic!("Unexpected command {:?}", c),
+        }
+
+        match parse_args(&["display-address", "--change"])
+            .unwrap()
+            .command
+        {
+            Command::DisplayAddress {
+                policy,
+                path,
+                change,
+                index,
+            } => {
+                assert!(policy.is_none() && path.is_none() && change);
+                assert_eq!(index, 0);
+            }
+            c => panic!("Unexpected command {:?}", c),
+        }
+        match parse_args(&["check-policy", "--template", "wpkh(@0/**)"])
+            .unwrap()
+            .command
+        {
+            Command::CheckPolicy { descriptor, .. } => assert_eq!(descriptor, "wpkh(@0/**)"),
+            c => panic!("Unexpected command {:?}", c),
+        }
+        match parse_args(&["apdu", "--hex", "e001000000", "--hex", "b001000000"])
+            .unwrap()
+            .command
+        {
+            Command::Apdu { commands, file, .. } => {
+                assert_eq!(commands, ["e001000000", "b001000000"]);
+                assert!(file.is_none());
+            }
+            c => panic!("Unexpected command {:?}", c),
+        }
+        match parse_args(&[
+            "catalog",
+            "--target-id",
+            "0x33100004",
+            "--firmware",
+            "2.2.3",
+        ])
+        .unwrap()
+        .command
+        {
+            Command::Catalog { target, firmware } => {
+                assert_eq!(target.target_id(), 0x33100004);
+                assert_eq!(firmware, "2.2.3");
+            }
+            c => panic!("Unexpected command {:?}", c),
+        }
+        match parse_args(&["catalog", "--model", "nanox", "--firmware", "2.2.3"])
+            .unwrap()
+            .command
+        {
+            Command::Catalog { target, .. } => {
+                assert_eq!(target.target_id(), DeviceModel::NanoX.target_id())
+            }
+            c => panic!("Unexpected command {:?}", c),
+        }
+
+        // Invalid or missing values.
+        for args in [
+            &["xpub", "--path", "m/84'/x"][..],
+            &["--proxy", "ftp://proxy", "info"],
+            &["--tls-pin", "sha256/", "info"],
+            &["sign-message", "--message", "Hello"],
+            &["register-policy", "--name", "Multi", "--template"],
+            &["apdu"],
+            &["catalog", "--firmware", "2.2.3"],
+            &[
+                "catalog",
+                "--model",
+                "nanos",
+                "--target-id",
+                "1",
+                "--firmware",
+                "2.2.3",
+            ],
+            &["check-policy", "--app-version", "two"],
+            &["info", "--plan"],
+            &["unknown"],
+            &[],
+        ] {
+            assert!(parse_args(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        for name in ["upgrade", "update-firmware"] {
+            let args = parse_args(&[name, "--plan"]).unwrap();
+            assert_eq!(args.command.name(), "upgrade");
+            assert!(matches!(args.command, Command::Upgrade { plan_only: true }));
+        }
+    }
+
+    #[test]
+    fn names() {
+        // The name reported is the one of the command line.
+        let commands: &[&[&str]] = &[
+            &["info"],
+            &["genuine-check"],
+            &["install"],
+            &["update"],
+            &["uninstall"],
+            &["open"],
+            &["upgrade"],
+            &["fingerprint"],
+            &["xpub"],
+            &["register-policy", "--name=a", "--template=b", "--keys=c"],
+            &["list-policies"],
+            &["export-policies"],
+            &["display-address"],
+            &["sign-psbt", "--psbt=a.psbt"],
+            &["sign-message", "--path=m/84'/0'/0'/0/0", "--message=a"],
+            &[
+                "verify-message",
+                "--address=a",
+                "--message=b",
+                "--signature=c",
+            ],
+            &["check-policy", "--descriptor=a"],
+            &["app-version"],
+            &["tui"],
+            &["apdu", "--file=a.apdu"],
+            &["catalog", "--model=nanos", "--firmware=2.1.0"],
+            &["provision", "--report=a.csv"],
+            &["apply", "--profile=a.toml"],
+            &["snapshot"],
+            &["restore"],
+        ];
+        assert_eq!(commands.len(), Args::command().get_subcommands().count());
+        for args in commands {
+            assert_eq!(parse_args(args).unwrap().command.name(), args[0]);
+        }
+    }
+
+    #[test]
+    fn legacy_env() {
+        assert!(legacy(&[("LEDGER_PATH", "m/84'/0'/0'")]).is_none());
+        assert!(legacy(&[("LEDGER_COMMAND", "getxpubs")]).unwrap().is_err());
+
+        let args = legacy(&[
+            ("LEDGER_COMMAND", "getxpub"),
+            ("LEDGER_TESTNET", "1"),
+            ("LEDGER_PATH", "m/84'/1'/0'"),
+            ("LEDGER_DISPLAY", ""),
+            ("LEDGER_MESSAGE", "Not an option of getxpub"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            args,
+            ["--testnet", "xpub", "--path=m/84'/1'/0'", "--display"]
+        );
+        let args = parse_args(&args.iter().map(String::as_str).collect::<Vec<_>>()).unwrap();
+        assert!(args.global.testnet && !args.global.json);
+        assert!(matches!(args.command, Command::Xpub { display: true, .. }));
+
+        // The output of getinfo was JSON.
+        let args = legacy(&[("LEDGER_COMMAND", "getinfo")]).unwrap().unwrap();
+        assert_eq!(args, ["--json", "info"]);
+
+        let args = legacy(&[
+            ("LEDGER_COMMAND", "registerpolicy"),
+            ("LEDGER_POLICY_NAME", "Multi"),
+            ("LEDGER_POLICY_TEMPLATE", "wsh(multi(1,@0/**,@1/**))"),
+            ("LEDGER_POLICY_KEYS", "a,b"),
+        ])
+        .unwrap()
+        .unwrap();
+        let args = parse_args(&args.iter().map(String::as_str).collect::<Vec<_>>()).unwrap();
+        match args.command {
+            Command::RegisterPolicy {
+                name,
+                template,
+                keys,
+            } => {
+                assert_eq!(name, "Multi");
+                assert_eq!(template, "wsh(multi(1,@0/**,@1/**))");
+                assert_eq!(keys, ["a", "b"]);
+            }
+            c => panic!("Unexpected command {:?}", c),
+        }
+
+        // A missing option is still an error.
+        let args = legacy(&[("LEDGER_COMMAND", "signmessage")])
+            .unwrap()
+            .unwrap();
+        assert!(parse_args(&args.iter().map(String::as_str).collect::<Vec<_>>()).is_err());
+    }
+}
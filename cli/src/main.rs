@@ -1,176 +1,962 @@
-use std::{env, process};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time,
+};
 
 use ledger_manager::{
-    genuine_check, install_bitcoin_app,
+    bitcoin_app::{
+        self, AddressType, AppVersion, DerivationPath, PolicyRegistry, Psbt, RegisteredPolicy,
+        WalletPolicy, HARDENED_INDEX,
+    },
+    bitcoin_app_version, deser_apdu_command, genuine_check, get_running_app, identify_apps,
+    install_bitcoin_app, latest_bitcoin_apps_for_target,
     ledger_transport_hidapi::{hidapi::HidApi, TransportNativeHID},
-    list_installed_apps, open_bitcoin_app, update_bitcoin_app, DeviceInfo, InstallErr, UpdateErr,
+    list_installed_apps_raw, open_bitcoin_app, running_bitcoin_app, ser_apdu_command,
+    transport::{SpeculosTransport, Transport},
+    uninstall_bitcoin_app, update_bitcoin_app, ApiConfig, DeviceInfo, DeviceModel, EnsureErr,
+    GenuineCheckErr, InstallErr, RunningApp, StatusCode, UninstallErr, UpdateErr, OPEN_APP_TIMEOUT,
 };
 
-// Print on stderr and exit with 1.
-macro_rules! error {
-    ($($arg:tt)*) => {{
-        eprintln!($($arg)*);
-        process::exit(1);
-    }};
-}
-
-#[derive(Debug, Clone, Copy)]
-enum Command {
-    GetInfo,
-    GenuineCheck,
-    InstallMainApp,
-    UpdateMainApp,
-    OpenMainApp,
-    InstallTestApp,
-    UpdateTestApp,
-    OpenTestApp,
-    UpdateFirmware,
-}
-
-impl Command {
-    /// Read command from environment variables.
-    pub fn get() -> Option<Self> {
-        let is_testnet = env::var("LEDGER_TESTNET").is_ok();
-        let cmd_str = env::var("LEDGER_COMMAND").ok()?;
-
-        if cmd_str == "getinfo" {
-            Some(Self::GetInfo)
-        } else if cmd_str == "genuinecheck" {
-            Some(Self::GenuineCheck)
-        } else if cmd_str == "installapp" {
-            Some(if is_testnet {
-                Self::InstallTestApp
-            } else {
-                Self::InstallMainApp
-            })
-        } else if cmd_str == "updateapp" {
-            Some(if is_testnet {
-                Self::UpdateTestApp
-            } else {
-                Self::UpdateMainApp
-            })
-        } else if cmd_str == "openapp" {
-            Some(if is_testnet {
-                Self::OpenTestApp
-            } else {
-                Self::OpenMainApp
-            })
-        } else if cmd_str == "updatefirm" {
-            Some(Self::UpdateFirmware)
-        } else {
-            None
+mod args;
+mod profile;
+mod provision;
+mod report;
+mod snapshot;
+#[cfg(unix)]
+mod tui;
+mod upgrade;
+
+use args::{Args, Command, GlobalOpts};
+use report::{CliError, ErrorCode, Report};
+
+// Print the logs on stderr. Only the warnings and errors unless --verbose is set.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
         }
     }
+
+    fn flush(&self) {}
+}
+
+fn set_logger(verbose: bool) {
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(if verbose {
+            log::LevelFilter::Debug
+        } else {
+            log::LevelFilter::Warn
+        });
+    }
 }
 
-fn ledger_api() -> TransportNativeHID {
-    let hid_api = match HidApi::new() {
-        Ok(a) => a,
-        Err(e) => error!("Error initializing HDI api: {}.", e),
+// Connect to the device set with --device, by HID path or serial number, or to the first one.
+fn ledger_api(opts: &GlobalOpts) -> Result<TransportNativeHID, CliError> {
+    let hid_api =
+        HidApi::new().map_err(|e| CliError::from_err("Error initializing HDI api", &e))?;
+    let res = if let Some(device) = &opts.device {
+        let info = TransportNativeHID::list_ledgers(&hid_api).find(|info| {
+            info.path().to_string_lossy() == device.as_str()
+                || info.serial_number() == Some(device.as_str())
+        });
+        match info {
+            Some(info) => TransportNativeHID::open_device(&hid_api, info),
+            None => {
+                return Err(CliError::new(
+                    ErrorCode::Device,
+                    format!(
+                        "No Ledger device found with HID path or serial number '{}'.",
+                        device
+                    ),
+                ))
+            }
+        }
+    } else {
+        TransportNativeHID::new(&hid_api)
     };
-    match TransportNativeHID::new(&hid_api) {
-        Ok(a) => a,
-        Err(e) => error!("Error connecting to Ledger device: {}.", e),
+    res.map_err(|e| CliError::from_err("Error connecting to Ledger device", &e))
+}
+
+// The Bitcoin app commands may also be run against the Speculos emulator, by setting --speculos
+// to the address of its APDU port.
+fn bitcoin_app_transport(opts: &GlobalOpts) -> Result<Box<dyn Transport>, CliError> {
+    if let Some(addr) = &opts.speculos {
+        match SpeculosTransport::connect(addr.as_str()) {
+            Ok(t) => Ok(Box::new(t)),
+            Err(e) => Err(CliError::new(
+                ErrorCode::Device,
+                format!("Error connecting to Speculos at {}: {}.", addr, e),
+            )),
+        }
+    } else {
+        Ok(Box::new(ledger_api(opts)?))
     }
 }
 
-fn device_info(ledger_api: &TransportNativeHID) -> DeviceInfo {
+fn device_info(ledger_api: &TransportNativeHID) -> Result<DeviceInfo, CliError> {
+    DeviceInfo::new(ledger_api)
+        .map_err(|e| CliError::from_err("Error fetching device info", e.as_ref()))
+}
+
+// Report the device information, if the device is on its dashboard. It's only informative so
+// don't fail the command if it can't be queried.
+fn report_device(ledger_api: &TransportNativeHID, report: &mut Report) {
     match DeviceInfo::new(ledger_api) {
-        Ok(i) => i,
-        Err(e) => error!("Error fetching device info: {}", e),
+        Ok(info) => report.set("device", serde_json::json!(info)),
+        Err(e) => log::debug!("Could not get the device info to report it: {}", e),
     }
 }
 
-fn print_ledger_info(ledger_api: &TransportNativeHID) {
-    let device_info = device_info(ledger_api);
-    println!("Information about the device: {:#?}", device_info);
-
-    println!("Querying installed applications from your Ledger. You might have to confirm on your device.");
-    let apps = match list_installed_apps(ledger_api) {
-        Ok(a) => a,
-        Err(e) => error!("Error listing installed applications: {}.", e),
+// Report the version of the installed Bitcoin app. Like the device information it's only
+// informative.
+fn report_app_version(ledger_api: &TransportNativeHID, opts: &GlobalOpts, report: &mut Report) {
+    let version = match bitcoin_app_version(ledger_api, opts.testnet, &api_config(opts)) {
+        Ok(v) => v.map(|v| v.to_string()),
+        Err(e) => {
+            log::warn!("Could not get the version of the Bitcoin app: {}", e);
+            None
+        }
     };
-    println!("Installed applications:");
+    report.set(
+        "app",
+        serde_json::json!({
+            "name": if opts.testnet { "Bitcoin Test" } else { "Bitcoin" },
+            "version": version,
+        }),
+    );
+}
+
+fn print_ledger_info(
+    ledger_api: &TransportNativeHID,
+    opts: &GlobalOpts,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let device_info = device_info(ledger_api)?;
+    report.set("device", serde_json::json!(device_info));
+    report.say(format!("Target id: {:#x}", device_info.target_id));
+    report.say(format!("Firmware version: {}", device_info.version));
+    if let Some(mcu_version) = &device_info.mcu_version {
+        report.say(format!("MCU version: {}", mcu_version));
+    }
+    report.say(format!("Bootloader: {}", device_info.is_bootloader));
+    report.say(format!("PIN validated: {}", device_info.pin_validated()));
+    report.say(format!(
+        "Manager allowed: {}",
+        device_info.manager_allowed()
+    ));
+
+    eprintln!("Querying installed applications from your Ledger. You might have to confirm on your device.");
+    let installed = list_installed_apps_raw(ledger_api)
+        .map_err(|e| CliError::from_err("Error listing installed applications", e.as_ref()))?;
+    report.set("device_apps", serde_json::json!(installed));
+    let apps = identify_apps(ledger_api, &installed, &api_config(opts))
+        .map_err(|e| CliError::from_err("Error identifying installed applications", e.as_ref()))?;
+    report.set("installed_apps", serde_json::json!(apps));
+    report.say("Installed applications:");
     for app in apps {
-        println!("  - {:?}", app);
+        match app {
+            Some(app) => report.say(format!("  - {} ({})", app.version_name, app.version)),
+            None => report.say("  - Unknown app"),
+        }
     }
+    Ok(())
 }
 
-fn perform_genuine_check(ledger_api: &TransportNativeHID) {
-    println!("Querying Ledger's remote HSM to perform the genuine check. You might have to confirm the operation on your device.");
-    if let Err(e) = genuine_check(ledger_api) {
-        error!("Error when performing genuine check: {}", e);
+fn perform_genuine_check(
+    ledger_api: &TransportNativeHID,
+    opts: &GlobalOpts,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    eprintln!("Querying Ledger's remote HSM to perform the genuine check. You might have to confirm the operation on your device.");
+    let res = genuine_check(ledger_api, &api_config(opts));
+    report_device(ledger_api, report);
+    match res {
+        Ok(()) => {
+            report.set("genuine", serde_json::json!(true));
+            report.say("Success. Your Ledger is genuine.");
+            Ok(())
+        }
+        Err(e @ GenuineCheckErr::NotGenuine(_)) => {
+            report.set("genuine", serde_json::json!(false));
+            Err(CliError::new(ErrorCode::NotGenuine, e.to_string()))
+        }
+        Err(e @ GenuineCheckErr::NoResult) => Err(CliError::new(ErrorCode::Network, e.to_string())),
+        Err(GenuineCheckErr::Any(e)) => Err(CliError::from_err(
+            "Error when performing genuine check",
+            e.as_ref(),
+        )),
     }
-    println!("Success. Your Ledger is genuine.");
 }
 
 // Install the Bitcoin app on the device.
-fn install_app(ledger_api: &TransportNativeHID, is_testnet: bool) {
-    println!("You may have to allow on your device 1) listing installed apps 2) the Ledger manager to install the app.");
-    match install_bitcoin_app(ledger_api, is_testnet) {
-        Ok(()) => println!("Successfully installed the app."),
+fn install_app(
+    ledger_api: &TransportNativeHID,
+    opts: &GlobalOpts,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    eprintln!("You may have to allow on your device 1) listing installed apps 2) the Ledger manager to install the app.");
+    let res = install_bitcoin_app(ledger_api, opts.testnet, &api_config(opts));
+    report_device(ledger_api, report);
+    match res {
+        Ok(install) => {
+            report.set("install", serde_json::json!(install));
+            report_app_version(ledger_api, opts, report);
+            report.say(format!(
+                "Successfully installed the app (hash {}).",
+                install.hash
+            ));
+            Ok(())
+        }
         Err(InstallErr::AlreadyInstalled) => {
-            error!("Bitcoin app already installed. Use the update command to update it.")
+            report_app_version(ledger_api, opts, report);
+            Err(CliError::new(
+                ErrorCode::AlreadyInstalled,
+                "Bitcoin app already installed. Use the update command to update it.",
+            ))
         }
-        Err(InstallErr::AppNotFound) => error!("Could not get info about Bitcoin app."),
-        Err(InstallErr::Any(e)) => error!("Error installing Bitcoin app: {}.", e),
+        Err(InstallErr::AppNotFound) => Err(CliError::new(
+            ErrorCode::AppNotFound,
+            "Could not get info about Bitcoin app.",
+        )),
+        Err(InstallErr::Verification(e)) => {
+            Err(CliError::from_err("Error checking the installed app", &e))
+        }
+        Err(InstallErr::Any(e)) => Err(CliError::from_err(
+            "Error installing Bitcoin app",
+            e.as_ref(),
+        )),
     }
 }
 
-fn update_app(ledger_api: &TransportNativeHID, is_testnet: bool) {
-    println!("You may have to allow on your device 1) listing installed apps 2) the Ledger manager to install the app.");
-    match update_bitcoin_app(ledger_api, is_testnet) {
-        Ok(()) => println!("Successfully updated the app."),
-        Err(UpdateErr::NotInstalled) => {
-            error!("Bitcoin app isn't installed. Use the install command instead.")
+fn update_app(
+    ledger_api: &TransportNativeHID,
+    opts: &GlobalOpts,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    eprintln!("You may have to allow on your device 1) listing installed apps 2) the Ledger manager to install the app.");
+    let res = update_bitcoin_app(ledger_api, opts.testnet, &api_config(opts));
+    report_device(ledger_api, report);
+    match res {
+        Ok(update) => {
+            report.set("update", serde_json::json!(update));
+            report_app_version(ledger_api, opts, report);
+            if update.attempts.len() > 1 {
+                report.say(format!("{}.", update));
+            }
+            report.say("Successfully updated the app.");
+            Ok(())
+        }
+        Err(UpdateErr::NotInstalled) => Err(CliError::new(
+            ErrorCode::NotInstalled,
+            "Bitcoin app isn't installed. Use the install command instead.",
+        )),
+        Err(UpdateErr::AppNotFound) => Err(CliError::new(
+            ErrorCode::AppNotFound,
+            "Could not get info about Bitcoin app.",
+        )),
+        Err(UpdateErr::AlreadyLatest) => {
+            report_app_version(ledger_api, opts, report);
+            Err(CliError::new(
+                ErrorCode::AlreadyLatest,
+                "Bitcoin app is already at the latest version.",
+            ))
         }
-        Err(UpdateErr::AppNotFound) => error!("Could not get info about Bitcoin app."),
-        Err(UpdateErr::AlreadyLatest) => error!("Bitcoin app is already at the latest version."),
-        Err(UpdateErr::Any(e)) => error!("Error installing Bitcoin app: {}.", e),
+        Err(UpdateErr::Verification(e)) => {
+            Err(CliError::from_err("Error checking the updated app", &e))
+        }
+        Err(UpdateErr::Failed(update)) => {
+            report.set("update", serde_json::json!(update));
+            report_app_version(ledger_api, opts, report);
+            Err(CliError::new(
+                ErrorCode::Other,
+                format!("Error updating Bitcoin app: {}.", update),
+            ))
+        }
+        Err(UpdateErr::Any(e)) => Err(CliError::from_err(
+            "Error installing Bitcoin app",
+            e.as_ref(),
+        )),
     }
 }
 
-fn open_app(ledger_api: &TransportNativeHID, is_testnet: bool) {
-    if let Err(e) = open_bitcoin_app(ledger_api, is_testnet) {
-        error!("Error opening Bitcoin app: {}", e);
+fn uninstall_app(
+    ledger_api: &TransportNativeHID,
+    opts: &GlobalOpts,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    eprintln!("You may have to allow on your device 1) listing installed apps 2) the Ledger manager to uninstall the app.");
+    let res = uninstall_bitcoin_app(ledger_api, opts.testnet, &api_config(opts));
+    report_device(ledger_api, report);
+    match res {
+        Ok(()) => {
+            report.say("Successfully uninstalled the app.");
+            Ok(())
+        }
+        Err(UninstallErr::NotInstalled) => Err(CliError::new(
+            ErrorCode::NotInstalled,
+            "Bitcoin app isn't installed.",
+        )),
+        Err(UninstallErr::AppNotFound) => Err(CliError::new(
+            ErrorCode::AppNotFound,
+            "Could not get info about the installed Bitcoin app.",
+        )),
+        Err(UninstallErr::Any(e)) => Err(CliError::from_err(
+            "Error uninstalling Bitcoin app",
+            e.as_ref(),
+        )),
     }
 }
 
-fn main() {
-    let command = if let Some(cmd) = Command::get() {
-        cmd
+// The error of making sure this app is installed.
+fn ensure_error(name: &str, e: EnsureErr) -> CliError {
+    match e {
+        EnsureErr::AppNotFound => CliError::new(
+            ErrorCode::AppNotFound,
+            format!("Could not get info about the {} app.", name),
+        ),
+        EnsureErr::Unsatisfiable { .. } => CliError::new(ErrorCode::Other, format!("{}.", e)),
+        EnsureErr::Failed(report) => CliError::new(
+            ErrorCode::Other,
+            format!("Error updating the {} app: {}.", name, report),
+        ),
+        e => CliError::from_err(&format!("Error installing the {} app", name), &e),
+    }
+}
+
+fn open_app(
+    ledger_api: &TransportNativeHID,
+    opts: &GlobalOpts,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    report_device(ledger_api, report);
+    open_bitcoin_app(ledger_api, opts.testnet)
+        .map_err(|e| CliError::from_err("Error opening Bitcoin app", e.as_ref()))
+}
+
+fn print_fingerprint(transport: &dyn Transport, report: &mut Report) -> Result<(), CliError> {
+    let fingerprint = bitcoin_app::get_master_fingerprint(transport)
+        .map_err(|e| CliError::from_err("Error getting the master key fingerprint", e.as_ref()))?;
+    report.set("fingerprint", serde_json::json!(hex::encode(fingerprint)));
+    Ok(())
+}
+
+// Default to the BIP84 first account.
+fn derivation_path(path: &Option<DerivationPath>, is_testnet: bool) -> DerivationPath {
+    path.clone().unwrap_or_else(|| {
+        let coin_type = if is_testnet { 1 } else { 0 };
+        DerivationPath(vec![
+            84 + HARDENED_INDEX,
+            coin_type + HARDENED_INDEX,
+            HARDENED_INDEX,
+        ])
+    })
+}
+
+fn print_xpub(
+    transport: &dyn Transport,
+    path: &Option<DerivationPath>,
+    display: bool,
+    is_testnet: bool,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let path = derivation_path(path, is_testnet);
+
+    let key = bitcoin_app::get_key_origin(transport, &path, display)
+        .map_err(|e| CliError::from_err("Error getting the extended public key", e.as_ref()))?;
+    report.set(
+        "fingerprint",
+        serde_json::json!(hex::encode(key.fingerprint)),
+    );
+    report.set("path", serde_json::json!(key.path.to_string()));
+    report.set("xpub", serde_json::json!(key.xpub));
+    report.set("key", serde_json::json!(key.to_string()));
+    Ok(())
+}
+
+// The directory where to store our data. Set by --datadir, default to ~/.bacca.
+fn datadir(opts: &GlobalOpts) -> Option<PathBuf> {
+    opts.datadir.clone().or_else(|| {
+        env::var("HOME")
+            .ok()
+            .map(|home| PathBuf::from(home).join(".bacca"))
+    })
+}
+
+// The directory where to store the registered policies.
+fn policies_dir(opts: &GlobalOpts) -> Result<PathBuf, CliError> {
+    match datadir(opts) {
+        Some(datadir) => Ok(datadir.join("policies")),
+        None => Err(CliError::new(
+            ErrorCode::InvalidArgument,
+            "Could not determine the data directory. Set --datadir.",
+        )),
+    }
+}
+
+// The apps seen in the catalog are cached in the data directory.
+fn api_config(opts: &GlobalOpts) -> ApiConfig {
+    let mut config = ApiConfig {
+        privacy: opts.privacy,
+        catalog_cache: datadir(opts).map(|d| d.join("catalog_cache.json")),
+        proxy: opts.proxy.clone(),
+        tls_pins: opts.tls_pins.clone(),
+        ..ApiConfig::default()
+    };
+    if let Some(secs) = opts.timeout {
+        config.connect_timeout = time::Duration::from_secs(secs);
+        config.read_timeout = time::Duration::from_secs(secs);
+    }
+    if let Some(retries) = opts.retries {
+        config.retries = retries;
+    }
+    if let Some(url) = &opts.api_url {
+        config.api_url = url.trim_end_matches('/').to_string();
+    }
+    if let Some(url) = &opts.socket_url {
+        config.socket_url = url.trim_end_matches('/').to_string();
+    }
+    config
+}
+
+fn policy_registry(
+    transport: &dyn Transport,
+    opts: &GlobalOpts,
+) -> Result<PolicyRegistry, CliError> {
+    let fingerprint = bitcoin_app::get_master_fingerprint(transport)
+        .map_err(|e| CliError::from_err("Error getting the master key fingerprint", e.as_ref()))?;
+    PolicyRegistry::load(&policies_dir(opts)?, fingerprint)
+        .map_err(|e| CliError::from_err("Error loading the policy registry", e.as_ref()))
+}
+
+// The registered policy with this name.
+fn registered_policy<'a>(
+    registry: &'a PolicyRegistry,
+    name: &str,
+) -> Result<&'a RegisteredPolicy, CliError> {
+    registry.get(name).ok_or_else(|| {
+        CliError::new(
+            ErrorCode::InvalidArgument,
+            format!("No policy named '{}' registered for this device.", name),
+        )
+    })
+}
+
+fn register_policy(
+    transport: &dyn Transport,
+    opts: &GlobalOpts,
+    name: String,
+    template: String,
+    keys: Vec<String>,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let policy = WalletPolicy::new(name, template, keys).map_err(|e| {
+        CliError::new(
+            ErrorCode::InvalidArgument,
+            format!("Invalid wallet policy: {}", e),
+        )
+    })?;
+
+    let mut registry = policy_registry(transport, opts)?;
+    eprintln!("Please review and approve the policy on your device.");
+    let registered = bitcoin_app::register_wallet(transport, &policy)
+        .map_err(|e| CliError::from_err("Error registering the policy", e.as_ref()))?;
+    registry.insert(registered.clone());
+    registry
+        .save()
+        .map_err(|e| CliError::from_err("Error saving the policy registry", e.as_ref()))?;
+    report.set("name", serde_json::json!(registered.policy.name));
+    report.set("policy_id", serde_json::json!(hex::encode(registered.id)));
+    report.set("hmac", serde_json::json!(hex::encode(registered.hmac)));
+    Ok(())
+}
+
+fn list_policies(
+    transport: &dyn Transport,
+    opts: &GlobalOpts,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let registry = policy_registry(transport, opts)?;
+    let policies: Vec<_> = registry
+        .policies()
+        .iter()
+        .map(|p| {
+            serde_json::json!({
+                "name": p.policy.name,
+                "descriptor": p.policy.descriptor().ok(),
+                "policy_id": hex::encode(p.id),
+            })
+        })
+        .collect();
+    let policies = serde_json::Value::Array(policies);
+    report.say(policies.to_string());
+    report.set("policies", policies);
+    Ok(())
+}
+
+fn export_policies(
+    transport: &dyn Transport,
+    opts: &GlobalOpts,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let json = policy_registry(transport, opts)?
+        .export()
+        .map_err(|e| CliError::from_err("Error exporting the policy registry", e.as_ref()))?;
+    report.set(
+        "registry",
+        serde_json::from_str(&json).unwrap_or(serde_json::Value::Null),
+    );
+    report.say(json);
+    Ok(())
+}
+
+// Display the address at this index of the registered policy with this name if set, or of the
+// standard account at this path otherwise.
+fn display_address(
+    transport: &dyn Transport,
+    opts: &GlobalOpts,
+    policy: &Option<String>,
+    path: &Option<DerivationPath>,
+    change: bool,
+    index: u32,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    eprintln!("Please check the address displayed on your device.");
+    let res = if let Some(name) = policy {
+        let registry = policy_registry(transport, opts)?;
+        let policy = registered_policy(&registry, name)?;
+        bitcoin_app::display_registered_address(transport, policy, change, index)
     } else {
-        error!("Invalid or no command specified. The command must be passed through the LEDGER_COMMAND env var. Set LEDGER_TESTNET to use the Bitcoin testnet app instead where applicable.");
+        let path = derivation_path(path, opts.testnet);
+        bitcoin_app::display_single_sig_address(transport, &path, change, index)
     };
+    let address =
+        res.map_err(|e| CliError::from_err("Error displaying the address", e.as_ref()))?;
+    report.set("address", serde_json::json!(address));
+    report.set("change", serde_json::json!(change));
+    report.set("index", serde_json::json!(index));
+    Ok(())
+}
 
-    let ledger_api = ledger_api();
-    match command {
-        Command::GetInfo => {
-            print_ledger_info(&ledger_api);
-        }
-        Command::GenuineCheck => {
-            perform_genuine_check(&ledger_api);
-        }
-        Command::InstallMainApp => {
-            install_app(&ledger_api, false);
-        }
-        Command::InstallTestApp => {
-            install_app(&ledger_api, true);
-        }
-        Command::OpenMainApp => {
-            open_app(&ledger_api, false);
-        }
-        Command::OpenTestApp => {
-            open_app(&ledger_api, true);
-        }
-        Command::UpdateMainApp => {
-            update_app(&ledger_api, false);
+// Sign the PSBT in this file, either in binary or base64. It is signed with the registered policy
+// with this name if set, with the standard account at this path otherwise. The signed PSBT is
+// printed, and written in base64 to the output file if set.
+fn sign_psbt(
+    transport: &dyn Transport,
+    opts: &GlobalOpts,
+    psbt_path: &Path,
+    policy: &Option<String>,
+    path: &Option<DerivationPath>,
+    output: &Option<PathBuf>,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let psbt_data = fs::read(psbt_path).map_err(|e| {
+        CliError::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "Error reading PSBT file at '{}': {}.",
+                psbt_path.display(),
+                e
+            ),
+        )
+    })?;
+    let psbt = if psbt_data.starts_with(b"psbt\xff") {
+        Psbt::deserialize(&psbt_data)
+    } else {
+        Psbt::from_base64(&String::from_utf8_lossy(&psbt_data))
+    };
+    let psbt = psbt
+        .map_err(|e| CliError::new(ErrorCode::InvalidArgument, format!("Invalid PSBT: {}", e)))?;
+
+    eprintln!("Please review and approve the transaction on your device.");
+    let res = if let Some(name) = policy {
+        let registry = policy_registry(transport, opts)?;
+        let policy = registered_policy(&registry, name)?;
+        bitcoin_app::sign_psbt_registered(transport, &psbt, policy)
+    } else {
+        let path = derivation_path(path, opts.testnet);
+        bitcoin_app::sign_psbt_single_sig(transport, &psbt, &path)
+    };
+    let signed_psbt = res
+        .map_err(|e| CliError::from_err("Error signing the PSBT", e.as_ref()))?
+        .to_base64();
+
+    if let Some(output) = output {
+        fs::write(output, &signed_psbt).map_err(|e| {
+            CliError::new(
+                ErrorCode::Other,
+                format!(
+                    "Error writing the signed PSBT to '{}': {}.",
+                    output.display(),
+                    e
+                ),
+            )
+        })?;
+    }
+    report.set("psbt", serde_json::json!(signed_psbt));
+    Ok(())
+}
+
+// Sign the message with the key at this path, which must be the full derivation path of an
+// address. Get a BIP-322 signature instead if set (for BIP84 paths only).
+fn sign_message(
+    transport: &dyn Transport,
+    path: &DerivationPath,
+    message: &str,
+    bip322: bool,
+    is_testnet: bool,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let pubkey = bitcoin_app::get_extended_pubkey(transport, path, false)
+        .and_then(|xpub| bitcoin_app::xpub_public_key(&xpub))
+        .map_err(|e| CliError::from_err("Error getting the public key", e.as_ref()))?;
+    let address_type = AddressType::from_path(path).unwrap_or(AddressType::Legacy);
+    let address = bitcoin_app::address(&pubkey, address_type, is_testnet);
+
+    eprintln!("Please review and approve the message on your device.");
+    let res = if bip322 {
+        bitcoin_app::sign_message_bip322(transport, message.as_bytes(), path)
+    } else {
+        bitcoin_app::sign_message(transport, message.as_bytes(), path)
+    };
+    let signature = res.map_err(|e| CliError::from_err("Error signing the message", e.as_ref()))?;
+    report.set("address", serde_json::json!(address));
+    report.set("path", serde_json::json!(path.to_string()));
+    report.set("signature", serde_json::json!(signature));
+    report.set(
+        "format",
+        serde_json::json!(if bip322 { "bip322" } else { "bip137" }),
+    );
+    Ok(())
+}
+
+// Verify the signature of the message for this address. Doesn't need a device.
+fn verify_message(
+    address: &str,
+    message: &str,
+    signature: &str,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let valid =
+        bitcoin_app::verify_message(address, message.as_bytes(), signature).map_err(|e| {
+            CliError::new(
+                ErrorCode::InvalidArgument,
+                format!("Error verifying the signature: {}", e),
+            )
+        })?;
+    report.set("valid", serde_json::json!(valid));
+    Ok(())
+}
+
+// Get the Bitcoin app running on the device, opening it if necessary. On Speculos the app is
+// always running.
+fn running_app(opts: &GlobalOpts) -> Result<RunningApp, CliError> {
+    let res = if opts.speculos.is_some() {
+        get_running_app(bitcoin_app_transport(opts)?.as_ref())
+    } else {
+        running_bitcoin_app(&ledger_api(opts)?, opts.testnet, OPEN_APP_TIMEOUT)
+    };
+    res.map_err(|e| CliError::from_err("Error getting the Bitcoin app version", e.as_ref()))
+}
+
+fn print_app_version(opts: &GlobalOpts, report: &mut Report) -> Result<(), CliError> {
+    let app = running_app(opts)?;
+    let protocol = app
+        .protocol()
+        .map_err(|e| CliError::from_err("Error parsing the app version", e.as_ref()))?;
+    report.set("name", serde_json::json!(app.name));
+    report.set("version", serde_json::json!(app.version));
+    report.set("flags", serde_json::json!(hex::encode(&app.flags)));
+    report.set("protocol", serde_json::json!(protocol.to_string()));
+    Ok(())
+}
+
+// Check whether the installed Bitcoin app can be used with the descriptor, or wallet policy
+// template. The version of the app is queried from the app itself unless given.
+fn check_policy(
+    opts: &GlobalOpts,
+    descriptor: &str,
+    app_version: &Option<AppVersion>,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let app_version = match app_version {
+        Some(v) => v.clone(),
+        None => running_app(opts)?
+            .app_version()
+            .map_err(|e| CliError::from_err("Error parsing the app version", e.as_ref()))?,
+    };
+
+    let support = bitcoin_app::check_policy_support(descriptor, &app_version).map_err(|e| {
+        CliError::new(
+            ErrorCode::InvalidArgument,
+            format!("Error checking the descriptor: {}", e),
+        )
+    })?;
+    if support.update_required() {
+        eprintln!(
+            "Update required: {} needs version {} of the Bitcoin app, version {} is installed.",
+            support.feature, support.required_version, support.app_version
+        );
+    }
+    report.set("supported", serde_json::json!(support.is_supported()));
+    report.set(
+        "update_required",
+        serde_json::json!(support.update_required()),
+    );
+    report.set("feature", serde_json::json!(support.feature.to_string()));
+    report.set(
+        "required_version",
+        serde_json::json!(support.required_version.to_string()),
+    );
+    report.set(
+        "app_version",
+        serde_json::json!(support.app_version.to_string()),
+    );
+    Ok(())
+}
+
+// List the latest Bitcoin apps available in the catalog for this target id and firmware version.
+fn print_catalog(
+    opts: &GlobalOpts,
+    model: &Option<DeviceModel>,
+    target_id: u32,
+    firmware: &str,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let apps = latest_bitcoin_apps_for_target(target_id, firmware, &api_config(opts))
+        .map_err(|e| CliError::from_err("Error querying the catalog", e.as_ref()))?;
+    report.set("model", serde_json::json!(model.map(|m| m.to_string())));
+    report.set("target_id", serde_json::json!(target_id));
+    report.set("firmware_version", serde_json::json!(firmware));
+    report.set("apps", serde_json::json!(apps));
+
+    match model {
+        Some(model) => report.say(format!(
+            "{} ({:#x}), firmware {}:",
+            model, target_id, firmware
+        )),
+        None => report.say(format!(
+            "Target id {:#x}, firmware {}:",
+            target_id, firmware
+        )),
+    }
+    if apps.is_empty() {
+        report.say("  No Bitcoin app available.");
+    }
+    for app in apps {
+        let size = app
+            .bytes
+            .map(|b| format!("{} bytes", b))
+            .unwrap_or_else(|| "unknown size".to_string());
+        report.say(format!(
+            "  - {} {} ({})",
+            app.version_name, app.version, size
+        ));
+        report.say(format!("    hash: {}", app.hash));
+        report.say(format!("    firmware: {}", app.firmware));
+    }
+    Ok(())
+}
+
+// Send the commands given in hex, then those in the script file, and print the responses. Stop at
+// the first error status if set.
+fn send_apdus(
+    opts: &GlobalOpts,
+    commands: &[String],
+    file: &Option<PathBuf>,
+    stop_on_error: bool,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let mut commands = commands.to_vec();
+    if let Some(file) = file {
+        let script = fs::read_to_string(file).map_err(|e| {
+            CliError::new(
+                ErrorCode::InvalidArgument,
+                format!("Error reading the script at '{}': {}.", file.display(), e),
+            )
+        })?;
+        commands.extend(
+            script
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+    }
+    // Check the whole script before sending anything.
+    let commands = commands
+        .iter()
+        .map(|c| deser_apdu_command(c))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| CliError::new(ErrorCode::InvalidArgument, e.to_string()))?;
+
+    let transport = bitcoin_app_transport(opts)?;
+    let mut responses = Vec::new();
+    let mut res = Ok(());
+    for command in &commands {
+        let answer = match transport.exchange(command) {
+            Ok(a) => a,
+            Err(e) => {
+                res = Err(CliError::from_err("Error sending the command", e.as_ref()));
+                break;
+            }
+        };
+        let status = answer.retcode();
+        let status_name = StatusCode::from_u16(status).map(|s| format!("{:?}", s));
+        report.say(format!("=> {}", hex::encode(ser_apdu_command(command))));
+        report.say(format!(
+            "<= {} {:04x} ({})",
+            hex::encode(answer.data()),
+            status,
+            status_name.as_deref().unwrap_or("unknown status")
+        ));
+        responses.push(serde_json::json!({
+            "command": hex::encode(ser_apdu_command(command)),
+            "data": hex::encode(answer.data()),
+            "status": format!("{:04x}", status),
+            "status_name": status_name,
+        }));
+        if stop_on_error && status != StatusCode::OK as u16 {
+            res = Err(CliError::new(
+                ErrorCode::Device,
+                format!(
+                    "Stopping at command {}: status {:#06x} ({}).",
+                    responses.len(),
+                    status,
+                    status_name.as_deref().unwrap_or("unknown status")
+                ),
+            ));
+            break;
         }
-        Command::UpdateTestApp => {
-            update_app(&ledger_api, true);
+    }
+    report.set("responses", serde_json::Value::Array(responses));
+    res
+}
+
+// Run the command, reporting its outcome in the report.
+fn run(opts: &GlobalOpts, command: Command, report: &mut Report) -> Result<(), CliError> {
+    match command {
+        Command::Info => print_ledger_info(&ledger_api(opts)?, opts, report),
+        Command::GenuineCheck => perform_genuine_check(&ledger_api(opts)?, opts, report),
+        Command::Install => install_app(&ledger_api(opts)?, opts, report),
+        Command::Update => update_app(&ledger_api(opts)?, opts, report),
+        Command::Uninstall => uninstall_app(&ledger_api(opts)?, opts, report),
+        Command::Open => open_app(&ledger_api(opts)?, opts, report),
+        Command::Upgrade { plan_only } => upgrade::run(opts, plan_only, report),
+        Command::Fingerprint => print_fingerprint(bitcoin_app_transport(opts)?.as_ref(), report),
+        Command::Xpub { path, display } => print_xpub(
+            bitcoin_app_transport(opts)?.as_ref(),
+            &path,
+            display,
+            opts.testnet,
+            report,
+        ),
+        Command::RegisterPolicy {
+            name,
+            template,
+            keys,
+        } => register_policy(
+            bitcoin_app_transport(opts)?.as_ref(),
+            opts,
+            name,
+            template,
+            keys,
+            report,
+        ),
+        Command::ListPolicies => list_policies(bitcoin_app_transport(opts)?.as_ref(), opts, report),
+        Command::ExportPolicies => {
+            export_policies(bitcoin_app_transport(opts)?.as_ref(), opts, report)
         }
-        Command::UpdateFirmware => {
-            unimplemented!()
+        Command::DisplayAddress {
+            policy,
+            path,
+            change,
+            index,
+        } => display_address(
+            bitcoin_app_transport(opts)?.as_ref(),
+            opts,
+            &policy,
+            &path,
+            change,
+            index,
+            report,
+        ),
+        Command::SignPsbt {
+            psbt,
+            policy,
+            path,
+            output,
+        } => sign_psbt(
+            bitcoin_app_transport(opts)?.as_ref(),
+            opts,
+            &psbt,
+            &policy,
+            &path,
+            &output,
+            report,
+        ),
+        Command::SignMessage {
+            path,
+            message,
+            bip322,
+        } => sign_message(
+            bitcoin_app_transport(opts)?.as_ref(),
+            &path,
+            &message,
+            bip322,
+            opts.testnet,
+            report,
+        ),
+        Command::VerifyMessage {
+            address,
+            message,
+            signature,
+        } => verify_message(&address, &message, &signature, report),
+        Command::CheckPolicy {
+            descriptor,
+            app_version,
+        } => check_policy(opts, &descriptor, &app_version, report),
+        Command::AppVersion => print_app_version(opts, report),
+        #[cfg(unix)]
+        Command::Tui => tui::run(opts),
+        #[cfg(not(unix))]
+        Command::Tui => Err(CliError::new(
+            ErrorCode::Other,
+            "The terminal UI is only available on Unix systems.",
+        )),
+        Command::Apdu {
+            commands,
+            file,
+            stop_on_error,
+        } => send_apdus(opts, &commands, &file, stop_on_error, report),
+        Command::Catalog { target, firmware } => {
+            print_catalog(opts, &target.model, target.target_id(), &firmware, report)
         }
+        Command::Provision {
+            report: report_path,
+            test_app,
+            count,
+        } => provision::run(opts, test_app, &report_path, count, report),
+        Command::Apply { profile, check } => profile::run(opts, &profile, check, report),
+        Command::Snapshot { output } => snapshot::run_snapshot(opts, output, report),
+        Command::Restore { snapshot } => snapshot::run_restore(opts, snapshot, report),
     }
 }
+
+fn main() {
+    let Args {
+        global: opts,
+        command,
+    } = Args::get();
+    set_logger(opts.verbose);
+
+    let mut report = Report::new(command.name(), opts.json);
+    let res = run(&opts, command, &mut report);
+    report.finish(res);
+}
@@ -0,0 +1,790 @@
+//! Profiles describing the desired state of a device: the firmware version, which Bitcoin apps
+//! must be installed at which version and which must be absent, and whether it must pass the
+//! genuine check. A profile is compared to the connected device to plan the installs, updates
+//! and uninstalls needed, which `apply` then carries out.
+//!
+//! A profile is a TOML file, or JSON if its name ends with `.json`. For instance:
+//!
+//! ```toml
+//! genuine_check = true
+//! firmware = ">=2.2.0"
+//!
+//! [apps]
+//! bitcoin = "latest"
+//! bitcoin_test = "absent"
+//! ```
+//!
+//! The apps are `bitcoin` and `bitcoin_test`. Each is required to be the `latest` version from the
+//! catalog, `installed` at any version, at an exact version (`2.1.3`), at least at a version
+//! (`>=2.1.0`), or `absent`. The apps not listed are left alone.
+
+use std::{fs, path::Path, str::FromStr};
+
+use ledger_manager::{
+    bitcoin_app::AppVersion, bitcoin_app_installed, bitcoin_app_version, ensure_bitcoin_app,
+    genuine_check, get_latest_apps, ledger_transport_hidapi::TransportNativeHID,
+    uninstall_bitcoin_app, ApiConfig, BitcoinAppInfo, DeviceInfo, GenuineCheckErr, Network,
+    UninstallErr, VersionRequirement,
+};
+use serde_derive::Deserialize;
+
+use crate::{
+    api_config,
+    args::GlobalOpts,
+    report::{CliError, ErrorCode, Report},
+};
+
+// Parse an exact version such as `2.1.3`, or a minimum version such as `>=2.1.0`.
+fn parse_version_requirement(s: &str) -> Result<VersionRequirement, String> {
+    let s = s.trim();
+    let (min, version) = match s.strip_prefix(">=") {
+        Some(v) => (true, v),
+        None => (false, s.strip_prefix('=').unwrap_or(s)),
+    };
+    let version = version
+        .parse()
+        .map_err(|_| format!("Invalid version requirement '{}'.", s))?;
+    Ok(if min {
+        VersionRequirement::AtLeast(version)
+    } else {
+        VersionRequirement::Exact(version)
+    })
+}
+
+/// What is required of an app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppReq {
+    Installed(VersionRequirement),
+    Absent,
+}
+
+impl FromStr for AppReq {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "latest" => Ok(Self::Installed(VersionRequirement::Latest)),
+            "installed" => Ok(Self::Installed(VersionRequirement::Any)),
+            "absent" => Ok(Self::Absent),
+            s => parse_version_requirement(s)
+                .map(Self::Installed)
+                .map_err(|_| {
+                    format!(
+                        "Invalid app requirement '{}'. Expected latest, installed, absent, a \
+                         version such as 2.1.3 or a minimum version such as >=2.1.0.",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+// A profile as written in the file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    #[serde(default)]
+    genuine_check: bool,
+    firmware: Option<String>,
+    #[serde(default)]
+    apps: AppsFile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AppsFile {
+    bitcoin: Option<String>,
+    bitcoin_test: Option<String>,
+}
+
+/// The desired state of a device.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub genuine_check: bool,
+    /// An exact or minimum version.
+    pub firmware: Option<VersionRequirement>,
+    /// The requirements on the Bitcoin app and on the Test app.
+    pub bitcoin: Option<AppReq>,
+    pub bitcoin_test: Option<AppReq>,
+}
+
+impl Profile {
+    pub fn load(path: &Path) -> Result<Self, CliError> {
+        let invalid = |e: String| {
+            CliError::new(
+                ErrorCode::InvalidArgument,
+                format!("Invalid profile '{}': {}", path.display(), e),
+            )
+        };
+        let content = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        Self::parse(&content, path.extension().is_some_and(|e| e == "json")).map_err(invalid)
+    }
+
+    // Parse a profile in JSON if `json` is set, in TOML otherwise.
+    fn parse(content: &str, json: bool) -> Result<Self, String> {
+        let file: ProfileFile = if json {
+            serde_json::from_str(content).map_err(|e| e.to_string())?
+        } else {
+            toml::from_str(content).map_err(|e| e.to_string().trim_end().to_string())?
+        };
+        let app_req = |field: &str, req: Option<String>| {
+            req.map(|r| r.parse().map_err(|e| format!("{}: {}", field, e)))
+                .transpose()
+        };
+        Ok(Self {
+            genuine_check: file.genuine_check,
+            firmware: file
+                .firmware
+                .map(|r| parse_version_requirement(&r).map_err(|e| format!("firmware: {}", e)))
+                .transpose()?,
+            bitcoin: app_req("apps.bitcoin", file.apps.bitcoin)?,
+            bitcoin_test: app_req("apps.bitcoin_test", file.apps.bitcoin_test)?,
+        })
+    }
+}
+
+// A change to make to an app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Install,
+    Update,
+    Uninstall,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Install => "install",
+            Self::Update => "update",
+            Self::Uninstall => "uninstall",
+        }
+    }
+}
+
+// A difference between the device and the profile. Without an action it can't be fixed by
+// applying the profile.
+#[derive(Debug, Clone)]
+struct Drift {
+    description: String,
+    testnet: bool,
+    action: Option<Action>,
+    // The requirement the install or update must satisfy.
+    requirement: Option<VersionRequirement>,
+    // The version installed by the action.
+    version: Option<String>,
+}
+
+impl Drift {
+    fn unfixable(description: String) -> Self {
+        Self {
+            description,
+            testnet: false,
+            action: None,
+            requirement: None,
+            version: None,
+        }
+    }
+
+    fn to_json(&self, done: bool) -> serde_json::Value {
+        serde_json::json!({
+            "description": self.description,
+            "action": self.action.as_ref().map(|a| a.as_str()),
+            "app": self.action.as_ref().map(|_| app_name(self.testnet)),
+            "version": self.version,
+            "done": done,
+        })
+    }
+}
+
+fn app_name(testnet: bool) -> &'static str {
+    if testnet {
+        "Bitcoin Test"
+    } else {
+        "Bitcoin"
+    }
+}
+
+// What is known of an app on the device.
+#[derive(Debug, Clone, Default)]
+struct AppState {
+    installed: bool,
+    // Whether it's the latest version from the catalog, by its hash.
+    is_latest: bool,
+    // The version of the installed app, if it's known to the catalog.
+    version: Option<AppVersion>,
+}
+
+// Query the state of an app on the device.
+fn app_state(
+    ledger_api: &TransportNativeHID,
+    testnet: bool,
+    latest: Option<&BitcoinAppInfo>,
+    config: &ApiConfig,
+) -> Result<AppState, CliError> {
+    let installed = bitcoin_app_installed(ledger_api, testnet)
+        .map_err(|e| CliError::from_err("Error listing installed applications", e.as_ref()))?;
+    let latest_hash = latest.map(|l| l.hash.as_str());
+    let is_latest = installed
+        .as_ref()
+        .is_some_and(|app| Some(hex::encode(&app.hash).as_str()) == latest_hash);
+    let version = match &installed {
+        Some(_) if is_latest => latest.and_then(|l| l.version.parse::<AppVersion>().ok()),
+        Some(_) => bitcoin_app_version(ledger_api, testnet, config).unwrap_or_else(|e| {
+            log::warn!(
+                "Could not get the version of the {} app: {}",
+                app_name(testnet),
+                e
+            );
+            None
+        }),
+        None => None,
+    };
+    Ok(AppState {
+        installed: installed.is_some(),
+        is_latest,
+        version,
+    })
+}
+
+// Compare an app on the device to the requirement of the profile.
+fn app_drift(
+    testnet: bool,
+    req: AppReq,
+    state: &AppState,
+    latest: Option<&BitcoinAppInfo>,
+) -> Option<Drift> {
+    let name = app_name(testnet);
+    let describe_installed = || match (state.installed, &state.version) {
+        (false, _) => "not installed".to_string(),
+        (true, Some(v)) => format!("version {} is installed", v),
+        (true, None) => "an unknown version is installed".to_string(),
+    };
+
+    let (satisfied, wanted) = match req {
+        AppReq::Absent => {
+            return state.installed.then(|| Drift {
+                description: format!("The {} app must be absent.", name),
+                testnet,
+                action: Some(Action::Uninstall),
+                requirement: None,
+                version: None,
+            })
+        }
+        AppReq::Installed(VersionRequirement::Any) => (state.installed, "installed".to_string()),
+        // By its hash, or by its version for a later one.
+        AppReq::Installed(VersionRequirement::Latest) => {
+            let latest = latest.and_then(|l| l.version.parse::<AppVersion>().ok());
+            let is_later = match (&state.version, &latest) {
+                (Some(version), Some(latest)) => {
+                    VersionRequirement::Latest.matches(version, latest)
+                }
+                _ => false,
+            };
+            (
+                state.is_latest || is_later,
+                "the latest version".to_string(),
+            )
+        }
+        // The latest version only matters to the requirement of the latest version.
+        AppReq::Installed(ref r) => (
+            state
+                .version
+                .as_ref()
+                .is_some_and(|version| r.matches(version, version)),
+            r.to_string(),
+        ),
+    };
+    if satisfied {
+        return None;
+    }
+    let description = format!(
+        "The {} app must be {} but {}.",
+        name,
+        wanted,
+        describe_installed()
+    );
+
+    // The catalog only offers the latest version for the firmware of the device.
+    let Some(latest) = latest else {
+        return Some(Drift::unfixable(format!(
+            "{} The catalog has no {} app for the firmware of the device.",
+            description, name
+        )));
+    };
+    let AppReq::Installed(requirement) = req else {
+        unreachable!("Handled above")
+    };
+    if !latest
+        .version
+        .parse::<AppVersion>()
+        .is_ok_and(|latest| requirement.matches(&latest, &latest))
+    {
+        return Some(Drift::unfixable(format!(
+            "{} The catalog only offers version {} for the firmware of the device.",
+            description, latest.version
+        )));
+    }
+    Some(Drift {
+        description,
+        testnet,
+        action: Some(if state.installed {
+            Action::Update
+        } else {
+            Action::Install
+        }),
+        requirement: Some(requirement),
+        version: Some(latest.version.clone()),
+    })
+}
+
+// Compare the firmware version of the device to the requirement of the profile.
+fn firmware_drift(req: &VersionRequirement, version: &str) -> Option<Drift> {
+    let matches = version
+        .parse::<AppVersion>()
+        .is_ok_and(|v| req.matches(&v, &v));
+    (!matches).then(|| {
+        Drift::unfixable(format!(
+            "The firmware must be {} but version {} is installed. Update it with the upgrade \
+             command.",
+            req, version
+        ))
+    })
+}
+
+// Carry out the action fixing this drift.
+fn fix(ledger_api: &TransportNativeHID, drift: &Drift, config: &ApiConfig) -> Result<(), CliError> {
+    let name = app_name(drift.testnet);
+    match (&drift.action, &drift.requirement) {
+        (Some(Action::Install | Action::Update), Some(requirement)) => {
+            let network = if drift.testnet {
+                Network::Testnet
+            } else {
+                Network::Mainnet
+            };
+            ensure_bitcoin_app(ledger_api, network, requirement.clone(), config)
+                .map(|_| ())
+                .map_err(|e| crate::ensure_error(name, e))
+        }
+        (Some(Action::Uninstall), _) => uninstall_bitcoin_app(ledger_api, drift.testnet, config)
+            .map_err(|e| match e {
+                UninstallErr::NotInstalled => CliError::new(
+                    ErrorCode::NotInstalled,
+                    format!("The {} app was uninstalled in the meantime.", name),
+                ),
+                UninstallErr::AppNotFound => CliError::new(
+                    ErrorCode::AppNotFound,
+                    format!("Could not get info about the {} app.", name),
+                ),
+                UninstallErr::Any(e) => {
+                    CliError::from_err(&format!("Error uninstalling the {} app", name), e.as_ref())
+                }
+            }),
+        _ => Ok(()),
+    }
+}
+
+/// Compare the connected device to the profile at this path and report the differences. Unless
+/// `check` is set, then make the changes to match the profile.
+pub fn run(
+    opts: &GlobalOpts,
+    path: &Path,
+    check: bool,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let profile = Profile::load(path)?;
+    let ledger_api = crate::ledger_api(opts)?;
+    let config = api_config(opts);
+    let device_info = DeviceInfo::new(&ledger_api)
+        .map_err(|e| CliError::from_err("Error fetching device info", e.as_ref()))?;
+    report.set("device", serde_json::json!(device_info));
+
+    if profile.genuine_check {
+        eprintln!("Running the genuine check. You might have to confirm on the device.");
+        match genuine_check(&ledger_api, &config) {
+            Ok(()) => report.set("genuine", serde_json::json!(true)),
+            Err(e @ GenuineCheckErr::NotGenuine(_)) => {
+                report.set("genuine", serde_json::json!(false));
+                return Err(CliError::new(ErrorCode::NotGenuine, e.to_string()));
+            }
+            Err(e @ GenuineCheckErr::NoResult) => {
+                return Err(CliError::new(ErrorCode::Network, e.to_string()))
+            }
+            Err(GenuineCheckErr::Any(e)) => {
+                return Err(CliError::from_err(
+                    "Error when performing genuine check",
+                    e.as_ref(),
+                ))
+            }
+        }
+    }
+
+    let mut drifts = Vec::new();
+    if let Some(req) = &profile.firmware {
+        drifts.extend(firmware_drift(req, &device_info.version));
+    }
+    let apps = [(false, profile.bitcoin), (true, profile.bitcoin_test)];
+    let (latest, latest_test) = if apps
+        .iter()
+        .any(|(_, req)| req.as_ref().is_some_and(|r| *r != AppReq::Absent))
+    {
+        get_latest_apps(&device_info, &config)
+            .map_err(|e| CliError::from_err("Error querying the catalog", e.as_ref()))?
+    } else {
+        (None, None)
+    };
+    eprintln!("Querying installed applications from your Ledger. You might have to confirm on your device.");
+    for (testnet, req) in apps {
+        if let Some(req) = req {
+            let latest = if testnet { &latest_test } else { &latest };
+            let state = app_state(&ledger_api, testnet, latest.as_ref(), &config)?;
+            drifts.extend(app_drift(testnet, req, &state, latest.as_ref()));
+        }
+    }
+    // Uninstall first to make room for the installs.
+    drifts.sort_by_key(|d| d.action != Some(Action::Uninstall));
+
+    let to_json = |drifts: &[Drift], done: usize| {
+        serde_json::Value::Array(
+            drifts
+                .iter()
+                .enumerate()
+                .map(|(i, d)| d.to_json(i < done))
+                .collect(),
+        )
+    };
+    report.set("drift", to_json(&drifts, 0));
+    if drifts.is_empty() {
+        report.say("The device matches the profile.");
+        return Ok(());
+    }
+    let plan_line = |d: &Drift| match (&d.action, &d.version) {
+        (Some(action), Some(version)) => format!(
+            "{} ({} {} {})",
+            d.description,
+            action.as_str(),
+            app_name(d.testnet),
+            version
+        ),
+        (Some(action), None) => {
+            format!(
+                "{} ({} {})",
+                d.description,
+                action.as_str(),
+                app_name(d.testnet)
+            )
+        }
+        (None, _) => format!("{} (can't be fixed here)", d.description),
+    };
+    report.say(if check {
+        "The device doesn't match the profile:"
+    } else {
+        "Plan:"
+    });
+    for drift in &drifts {
+        report.say(format!("  - {}", plan_line(drift)));
+    }
+    if check {
+        return Err(CliError::new(
+            ErrorCode::Drift,
+            format!(
+                "The device doesn't match the profile: {} difference(s).",
+                drifts.len()
+            ),
+        ));
+    }
+    if drifts.iter().any(|d| d.action.is_none()) {
+        return Err(CliError::new(
+            ErrorCode::Drift,
+            "The profile can't be applied to this device, nothing was changed.",
+        ));
+    }
+
+    eprintln!("You may have to allow on your device 1) listing installed apps 2) the Ledger manager to install the app.");
+    for (i, drift) in drifts.iter().enumerate() {
+        if let Err(e) = fix(&ledger_api, drift, &config) {
+            report.set("drift", to_json(&drifts, i));
+            return Err(e);
+        }
+        report.say(format!(
+            "Done: {} {}.",
+            drift
+                .action
+                .as_ref()
+                .map(|a| a.as_str())
+                .unwrap_or_default(),
+            app_name(drift.testnet)
+        ));
+    }
+    report.set("drift", to_json(&drifts, drifts.len()));
+    report.say("Applied the profile.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> AppVersion {
+        s.parse().unwrap()
+    }
+
+    fn latest(version: &str) -> BitcoinAppInfo {
+        BitcoinAppInfo {
+            version_name: "bitcoin".to_string(),
+            version_id: 1,
+            version: version.to_string(),
+            perso: "perso_11".to_string(),
+            delete: "nanos/2.1.0/bitcoin/app_2.2.0_del".to_string(),
+            delete_key: "nanos/2.1.0/bitcoin/app_2.2.0_del_key".to_string(),
+            firmware: "nanos/2.1.0/bitcoin/app_2.2.0".to_string(),
+            firmware_key: "nanos/2.1.0/bitcoin/app_2.2.0_key".to_string(),
+            hash: "ab".repeat(32),
+            bytes: None,
+            parent_name: None,
+        }
+    }
+
+    fn installed(v: Option<&str>, is_latest: bool) -> AppState {
+        AppState {
+            installed: true,
+            is_latest,
+            version: v.map(version),
+        }
+    }
+
+    #[test]
+    fn requirements() {
+        assert_eq!(
+            "latest".parse(),
+            Ok(AppReq::Installed(VersionRequirement::Latest))
+        );
+        assert_eq!(
+            "installed".parse(),
+            Ok(AppReq::Installed(VersionRequirement::Any))
+        );
+        assert_eq!(" absent ".parse(), Ok(AppReq::Absent));
+        assert_eq!(
+            "2.1.3".parse(),
+            Ok(AppReq::Installed(VersionRequirement::Exact(version(
+                "2.1.3"
+            ))))
+        );
+        assert_eq!(
+            "=2.1.3".parse(),
+            Ok(AppReq::Installed(VersionRequirement::Exact(version(
+                "2.1.3"
+            ))))
+        );
+        assert_eq!(
+            ">=2.1.0".parse(),
+            Ok(AppReq::Installed(VersionRequirement::AtLeast(version(
+                "2.1.0"
+            ))))
+        );
+        for invalid in ["", "newest", "2.x", ">2.1.0", "<=2.1.0", ">=latest"] {
+            assert!(invalid.parse::<AppReq>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn load() {
+        let profile = Profile::parse(
+            r#"
+            genuine_check = true
+            firmware = ">=2.2.0"
+
+            [apps]
+            bitcoin = "latest"
+            bitcoin_test = "absent"
+            "#,
+            false,
+        )
+        .unwrap();
+        assert!(profile.genuine_check);
+        assert_eq!(
+            profile.firmware,
+            Some(VersionRequirement::AtLeast(version("2.2.0")))
+        );
+        assert_eq!(
+            profile.bitcoin,
+            Some(AppReq::Installed(VersionRequirement::Latest))
+        );
+        assert_eq!(profile.bitcoin_test, Some(AppReq::Absent));
+
+        let profile = Profile::parse(r#"{ "apps": { "bitcoin": "2.1.3" } }"#, true).unwrap();
+        assert!(!profile.genuine_check);
+        assert_eq!(profile.firmware, None);
+        assert_eq!(
+            profile.bitcoin,
+            Some(AppReq::Installed(VersionRequirement::Exact(version(
+                "2.1.3"
+            ))))
+        );
+        assert_eq!(profile.bitcoin_test, None);
+
+        // The invalid field is named.
+        let err = Profile::parse("[apps]\nbitcoin = \"newest\"", false).unwrap_err();
+        assert!(err.starts_with("apps.bitcoin: "), "{}", err);
+        let err = Profile::parse("firmware = \"latest\"", false).unwrap_err();
+        assert!(err.starts_with("firmware: "), "{}", err);
+        assert!(Profile::parse("[apps]\nethereum = \"latest\"", false).is_err());
+        assert!(Profile::parse("{ \"genuine\": true }", true).is_err());
+        assert!(Profile::load(Path::new("/nonexistent/profile.toml")).is_err());
+    }
+
+    #[test]
+    fn firmware() {
+        let req = VersionRequirement::AtLeast(version("2.2.0"));
+        assert!(firmware_drift(&req, "2.2.0").is_none());
+        assert!(firmware_drift(&req, "2.3.1").is_none());
+        let drift = firmware_drift(&req, "2.1.0").unwrap();
+        assert!(drift.action.is_none());
+        assert_eq!(
+            drift.description,
+            "The firmware must be version 2.2.0 or later but version 2.1.0 is installed. Update \
+             it with the upgrade command."
+        );
+        let req = VersionRequirement::Exact(version("2.1.0"));
+        assert!(firmware_drift(&req, "2.1.0").is_none());
+        assert!(firmware_drift(&req, "2.2.0").is_some());
+        // A version which can't be parsed doesn't match.
+        assert!(firmware_drift(&req, "unknown").is_some());
+    }
+
+    #[test]
+    fn apps() {
+        let latest = latest("2.2.0");
+        let absent = AppState::default();
+        let req = |s: &str| s.parse::<AppReq>().unwrap();
+        let action = |drift: Option<Drift>| drift.map(|d| (d.action, d.version));
+
+        // Latest: by the hash of the installed app, or a later version.
+        let latest_req = req("latest");
+        assert!(app_drift(
+            false,
+            latest_req.clone(),
+            &installed(None, true),
+            Some(&latest)
+        )
+        .is_none());
+        assert!(app_drift(
+            false,
+            latest_req.clone(),
+            &installed(Some("2.3.0"), false),
+            Some(&latest)
+        )
+        .is_none());
+        assert_eq!(
+            action(app_drift(
+                false,
+                latest_req.clone(),
+                &installed(Some("2.1.3"), false),
+                Some(&latest)
+            )),
+            Some((Some(Action::Update), Some("2.2.0".to_string())))
+        );
+        assert_eq!(
+            action(app_drift(false, latest_req.clone(), &absent, Some(&latest))),
+            Some((Some(Action::Install), Some("2.2.0".to_string())))
+        );
+        // Nothing to install for this firmware.
+        let drift = app_drift(true, latest_req, &absent, None).unwrap();
+        assert!(drift.action.is_none());
+        assert_eq!(
+            drift.description,
+            "The Bitcoin Test app must be the latest version but not installed. The catalog has \
+             no Bitcoin Test app for the firmware of the device."
+        );
+
+        // Installed: at any version, even unknown.
+        assert!(app_drift(
+            false,
+            req("installed"),
+            &installed(None, false),
+            Some(&latest)
+        )
+        .is_none());
+        assert_eq!(
+            action(app_drift(false, req("installed"), &absent, Some(&latest))),
+            Some((Some(Action::Install), Some("2.2.0".to_string())))
+        );
+
+        // Absent.
+        assert!(app_drift(false, req("absent"), &absent, None).is_none());
+        assert_eq!(
+            action(app_drift(
+                false,
+                req("absent"),
+                &installed(None, false),
+                None
+            )),
+            Some((Some(Action::Uninstall), None))
+        );
+
+        // An exact version.
+        assert!(app_drift(
+            false,
+            req("2.1.3"),
+            &installed(Some("2.1.3"), false),
+            Some(&latest)
+        )
+        .is_none());
+        assert_eq!(
+            action(app_drift(
+                false,
+                req("2.2.0"),
+                &installed(Some("2.1.3"), false),
+                Some(&latest)
+            )),
+            Some((Some(Action::Update), Some("2.2.0".to_string())))
+        );
+        // The catalog only has the latest version.
+        let drift = app_drift(
+            false,
+            req("2.1.3"),
+            &installed(Some("2.1.0"), false),
+            Some(&latest),
+        )
+        .unwrap();
+        assert!(drift.action.is_none());
+        assert_eq!(
+            drift.description,
+            "The Bitcoin app must be version 2.1.3 but version 2.1.0 is installed. The catalog \
+             only offers version 2.2.0 for the firmware of the device."
+        );
+
+        // A minimum version.
+        assert!(app_drift(
+            false,
+            req(">=2.1.0"),
+            &installed(Some("2.1.3"), false),
+            Some(&latest)
+        )
+        .is_none());
+        assert_eq!(
+            action(app_drift(
+                false,
+                req(">=2.1.0"),
+                &installed(Some("2.0.6"), false),
+                Some(&latest)
+            )),
+            Some((Some(Action::Update), Some("2.2.0".to_string())))
+        );
+        // An unknown version doesn't satisfy it.
+        let drift = app_drift(
+            false,
+            req(">=2.1.0"),
+            &installed(None, false),
+            Some(&latest),
+        )
+        .unwrap();
+        assert_eq!(drift.action, Some(Action::Update));
+        assert!(drift
+            .description
+            .contains("an unknown version is installed"));
+        assert!(app_drift(false, req(">=2.3.0"), &absent, Some(&latest))
+            .unwrap()
+            .action
+            .is_none());
+    }
+}
@@ -0,0 +1,441 @@
+//! Provisioning many devices in a row. For each device plugged in, run the genuine check, install
+//! or update the Bitcoin app (and optionally the Test app), check the installed app is the one
+//! from the catalog and append a line to the report. A device failing doesn't stop the batch.
+//!
+//! The report is CSV, or JSON lines if the file name ends with `.json` or `.jsonl`. It is written
+//! as the devices are provisioned so nothing is lost if the batch is interrupted.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    thread, time,
+};
+
+use ledger_manager::{
+    ensure_bitcoin_app, genuine_check, is_dashboard_open,
+    ledger_transport_hidapi::{hidapi::HidApi, TransportNativeHID},
+    quit_app, ApiConfig, DeviceError, DeviceInfo, DeviceModel, EnsureAction, Event, EventHandler,
+    GenuineCheckErr, Network, VersionRequirement, QUIT_APP_TIMEOUT,
+};
+
+use crate::{
+    api_config,
+    args::GlobalOpts,
+    report::{CliError, ErrorCode, Report},
+};
+
+// How often to look for a device being plugged in, removed or unlocked.
+const DEVICE_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
+
+const CSV_HEADER: &[&str] = &[
+    "started_at",
+    "finished_at",
+    "serial",
+    "model",
+    "target_id",
+    "firmware",
+    "genuine",
+    "app_action",
+    "app_version",
+    "app_hash",
+    "app_verified",
+    "test_app_action",
+    "test_app_version",
+    "test_app_hash",
+    "test_app_verified",
+    "error",
+];
+
+// What was done with an app on a device.
+#[derive(Debug, Clone, Default)]
+struct AppRecord {
+    // installed, updated, up_to_date or failed.
+    action: String,
+    version: String,
+    hash: String,
+    // Whether the installed app is the latest one from the catalog.
+    verified: bool,
+}
+
+// A line of the report.
+#[derive(Debug, Clone, Default)]
+struct Record {
+    started_at: String,
+    finished_at: String,
+    serial: String,
+    model: String,
+    target_id: Option<u32>,
+    firmware: String,
+    genuine: Option<bool>,
+    app: Option<AppRecord>,
+    test_app: Option<AppRecord>,
+    errors: Vec<String>,
+}
+
+impl Record {
+    fn to_json(&self) -> serde_json::Value {
+        let app = |name: &str, app: &AppRecord| {
+            serde_json::json!({
+                "name": name,
+                "action": app.action,
+                "version": app.version,
+                "hash": app.hash,
+                "verified": app.verified,
+            })
+        };
+        let apps: Vec<_> = [("Bitcoin", &self.app), ("Bitcoin Test", &self.test_app)]
+            .into_iter()
+            .filter_map(|(name, a)| a.as_ref().map(|a| app(name, a)))
+            .collect();
+        serde_json::json!({
+            "started_at": self.started_at,
+            "finished_at": self.finished_at,
+            "serial": self.serial,
+            "model": self.model,
+            "target_id": self.target_id,
+            "firmware": self.firmware,
+            "genuine": self.genuine,
+            "apps": apps,
+            "error": if self.errors.is_empty() { None } else { Some(self.errors.join(" ")) },
+        })
+    }
+
+    fn to_csv(&self) -> String {
+        let app_fields = |app: &Option<AppRecord>| match app {
+            Some(app) => vec![
+                app.action.clone(),
+                app.version.clone(),
+                app.hash.clone(),
+                app.verified.to_string(),
+            ],
+            None => vec![String::new(); 4],
+        };
+        let mut fields = vec![
+            self.started_at.clone(),
+            self.finished_at.clone(),
+            self.serial.clone(),
+            self.model.clone(),
+            self.target_id
+                .map(|id| format!("{:#x}", id))
+                .unwrap_or_default(),
+            self.firmware.clone(),
+            self.genuine.map(|g| g.to_string()).unwrap_or_default(),
+        ];
+        fields.extend(app_fields(&self.app));
+        fields.extend(app_fields(&self.test_app));
+        fields.push(self.errors.join(" "));
+        csv_line(&fields)
+    }
+}
+
+fn csv_line(fields: &[impl AsRef<str>]) -> String {
+    fields
+        .iter()
+        .map(|f| {
+            let f = f.as_ref();
+            if f.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", f.replace('"', "\"\""))
+            } else {
+                f.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// The time as RFC 3339, in UTC.
+fn timestamp(time: time::SystemTime) -> String {
+    let secs = time
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // Convert the days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+// Appends the records to the report file, as CSV or JSON lines.
+struct ReportFile {
+    file: fs::File,
+    json: bool,
+}
+
+impl ReportFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let json = matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("json") | Some("jsonl")
+        );
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if !json && file.metadata()?.len() == 0 {
+            writeln!(file, "{}", csv_line(CSV_HEADER))?;
+        }
+        Ok(Self { file, json })
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.json {
+            writeln!(self.file, "{}", record.to_json())?;
+        } else {
+            writeln!(self.file, "{}", record.to_csv())?;
+        }
+        self.file.flush()
+    }
+}
+
+fn hid_error(e: impl std::error::Error + 'static) -> CliError {
+    CliError::from_err("Error listing the connected devices", &e)
+}
+
+// Wait for a device to be plugged in and connect to it. Returns its HID path and serial number
+// along with the connection, so a device failing to connect is reported like any other failure.
+type PluggedDevice = (Result<TransportNativeHID, CliError>, String, String);
+
+fn wait_for_device(hid_api: &mut HidApi) -> Result<PluggedDevice, CliError> {
+    loop {
+        hid_api.refresh_devices().map_err(hid_error)?;
+        if let Some(info) = TransportNativeHID::list_ledgers(hid_api).next() {
+            let path = info.path().to_string_lossy().into_owned();
+            let serial = info.serial_number().unwrap_or_default().to_string();
+            let ledger_api = TransportNativeHID::open_device(hid_api, info)
+                .map_err(|e| CliError::from_err("Error connecting to Ledger device", &e));
+            return Ok((ledger_api, path, serial));
+        }
+        thread::sleep(DEVICE_POLL_INTERVAL);
+    }
+}
+
+// Wait for the device at this HID path to be removed.
+fn wait_for_removal(hid_api: &mut HidApi, path: &str) -> Result<(), CliError> {
+    loop {
+        hid_api.refresh_devices().map_err(hid_error)?;
+        if !TransportNativeHID::list_ledgers(hid_api)
+            .any(|info| info.path().to_string_lossy() == path)
+        {
+            return Ok(());
+        }
+        thread::sleep(DEVICE_POLL_INTERVAL);
+    }
+}
+
+// Wait for the device to be unlocked and on its dashboard, and get its information.
+fn wait_for_dashboard(ledger_api: &TransportNativeHID) -> Result<DeviceInfo, CliError> {
+    let mut prompted = false;
+    loop {
+        match is_dashboard_open(ledger_api) {
+            Ok(true) => break,
+            Ok(false) => {
+                quit_app(ledger_api, QUIT_APP_TIMEOUT)
+                    .map_err(|e| CliError::from_err("Error quitting the running app", &e))?;
+                break;
+            }
+            Err(e)
+                if e.downcast_ref::<DeviceError>()
+                    .is_some_and(|e| e.is_locked()) =>
+            {
+                if !prompted {
+                    eprintln!("  Unlock the device with its PIN.");
+                    prompted = true;
+                }
+                thread::sleep(DEVICE_POLL_INTERVAL);
+            }
+            Err(e) => return Err(CliError::from_err("Error querying the device", e.as_ref())),
+        }
+    }
+    DeviceInfo::new(ledger_api)
+        .map_err(|e| CliError::from_err("Error fetching device info", e.as_ref()))
+}
+
+// Install the app, or update it if it's not the latest version. Then check the installed app is the
+// latest one from the catalog.
+fn provision_app(
+    ledger_api: &TransportNativeHID,
+    testnet: bool,
+    config: &ApiConfig,
+    app: &mut AppRecord,
+) -> Result<(), CliError> {
+    let name = if testnet { "Bitcoin Test" } else { "Bitcoin" };
+    app.action = "failed".to_string();
+    let network = if testnet {
+        Network::Testnet
+    } else {
+        Network::Mainnet
+    };
+    let action = ensure_bitcoin_app(ledger_api, network, VersionRequirement::Latest, config)
+        .map_err(|e| crate::ensure_error(name, e))?;
+    app.action = match action {
+        EnsureAction::Installed(_) => "installed",
+        EnsureAction::Updated(_) => "updated",
+        EnsureAction::Nothing { .. } => "up_to_date",
+    }
+    .to_string();
+    // The app installed or left alone is the latest from the catalog, as checked when ensuring it.
+    app.version = action.version().unwrap_or_default().to_string();
+    app.hash = action.hash().unwrap_or_default().to_string();
+    app.verified = true;
+    Ok(())
+}
+
+// Provision the device, recording what was done. The apps are only installed on a genuine device.
+fn provision_device(
+    ledger_api: &TransportNativeHID,
+    test_app: bool,
+    config: &ApiConfig,
+    record: &mut Record,
+) -> Result<(), CliError> {
+    let device_info = wait_for_dashboard(ledger_api)?;
+    record.target_id = Some(device_info.target_id);
+    record.model = DeviceModel::from_target_id(device_info.target_id)
+        .map(|m| m.to_string())
+        .unwrap_or_default();
+    record.firmware = device_info.version.clone();
+
+    eprintln!("  Running the genuine check. You might have to confirm on the device.");
+    match genuine_check(ledger_api, config) {
+        Ok(()) => record.genuine = Some(true),
+        Err(e @ GenuineCheckErr::NotGenuine(_)) => {
+            record.genuine = Some(false);
+            return Err(CliError::new(ErrorCode::NotGenuine, e.to_string()));
+        }
+        Err(e @ GenuineCheckErr::NoResult) => {
+            return Err(CliError::new(ErrorCode::Network, e.to_string()))
+        }
+        Err(GenuineCheckErr::Any(e)) => {
+            return Err(CliError::from_err(
+                "Error when performing genuine check",
+                e.as_ref(),
+            ))
+        }
+    }
+
+    // Provision the Test app even if the Bitcoin app failed, and report both.
+    let mut res = Ok(());
+    for testnet in [false, true] {
+        if testnet && !test_app {
+            continue;
+        }
+        let mut app = AppRecord::default();
+        if let Err(e) = provision_app(ledger_api, testnet, config, &mut app) {
+            record.errors.push(e.message.clone());
+            res = res.and(Err(e));
+        }
+        if testnet {
+            record.test_app = Some(app);
+        } else {
+            record.app = Some(app);
+        }
+    }
+    res
+}
+
+/// Provision the devices plugged in one after the other, until `count` devices were provisioned if
+/// set, or until interrupted otherwise. A line is written to the report file for each device.
+pub fn run(
+    opts: &GlobalOpts,
+    test_app: bool,
+    report_path: &Path,
+    count: Option<u32>,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let mut report_file = ReportFile::open(report_path).map_err(|e| {
+        CliError::new(
+            ErrorCode::InvalidArgument,
+            format!(
+                "Error opening the report at '{}': {}.",
+                report_path.display(),
+                e
+            ),
+        )
+    })?;
+    let mut config = api_config(opts);
+    config.on_event = Some(EventHandler::new(|event| match event {
+        Event::Status(status) => eprintln!("  {}", status),
+        Event::Progress { done, total } => {
+            eprint!("\r  {}/{}", done, total);
+            if done == total {
+                eprintln!();
+            }
+        }
+    }));
+    let mut hid_api = HidApi::new().map_err(hid_error)?;
+
+    let mut records = Vec::new();
+    let mut failed = 0;
+    while count.is_none_or(|count| records.len() < count as usize) {
+        eprintln!(
+            "Plug in device #{} and unlock it, or press Ctrl-C to stop.",
+            records.len() + 1
+        );
+        let (ledger_api, path, serial) = wait_for_device(&mut hid_api)?;
+        let mut record = Record {
+            started_at: timestamp(time::SystemTime::now()),
+            serial,
+            ..Record::default()
+        };
+        let res = ledger_api
+            .and_then(|ledger_api| provision_device(&ledger_api, test_app, &config, &mut record));
+        record.finished_at = timestamp(time::SystemTime::now());
+        match res {
+            Ok(()) => eprintln!("Device #{} provisioned.", records.len() + 1),
+            Err(e) => {
+                if record.errors.is_empty() {
+                    record.errors.push(e.message.clone());
+                }
+                failed += 1;
+                eprintln!("Device #{} failed: {}", records.len() + 1, e.message);
+            }
+        }
+        report_file.write(&record).map_err(|e| {
+            CliError::new(
+                ErrorCode::Other,
+                format!(
+                    "Error writing to the report at '{}': {}.",
+                    report_path.display(),
+                    e
+                ),
+            )
+        })?;
+        records.push(record.to_json());
+
+        eprintln!("Unplug the device.");
+        wait_for_removal(&mut hid_api, &path)?;
+    }
+
+    let total = records.len();
+    report.set("devices", serde_json::Value::Array(records));
+    report.say(format!(
+        "Provisioned {} devices, {} failed. See the report at '{}'.",
+        total - failed,
+        failed,
+        report_path.display()
+    ));
+    if failed > 0 {
+        return Err(CliError::new(
+            ErrorCode::Other,
+            format!("{} of {} devices failed.", failed, total),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! The outcome of a command. It is printed either for a human, or with `--json` as a single JSON
+//! document on stdout. The exit code tells apart the categories of errors in both cases.
+
+use std::{error, process};
+
+use ledger_manager::{
+    bitcoin_app::StatusError, json::SCHEMA_VERSION, ledger_transport_hidapi::LedgerHIDError,
+    DeviceError, NetError, QuitAppErr, VerificationErr,
+};
+
+/// The category of an error, reported as the `code` of the error in the JSON output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Other,
+    /// The arguments were invalid, or so was the data they point to (PSBT, policy, ...).
+    InvalidArgument,
+    /// The device couldn't be reached or returned an error.
+    Device,
+    DeviceLocked,
+    /// The user rejected the operation on the device.
+    UserRefused,
+    AlreadyInstalled,
+    AlreadyLatest,
+    NotInstalled,
+    /// The Ledger API doesn't know about the app.
+    AppNotFound,
+    /// The Ledger API couldn't be reached or returned an error.
+    Network,
+    NotGenuine,
+    /// The device doesn't match the profile.
+    Drift,
+    /// The app found on the device after installing it isn't the one from the catalog.
+    Verification,
+}
+
+impl ErrorCode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Other => "other",
+            Self::InvalidArgument => "invalid_argument",
+            Self::Device => "device",
+            Self::DeviceLocked => "device_locked",
+            Self::UserRefused => "user_refused",
+            Self::AlreadyInstalled => "already_installed",
+            Self::AlreadyLatest => "already_latest",
+            Self::NotInstalled => "not_installed",
+            Self::AppNotFound => "app_not_found",
+            Self::Network => "network",
+            Self::NotGenuine => "not_genuine",
+            Self::Drift => "drift",
+            Self::Verification => "verification",
+        }
+    }
+
+    /// The exit code of the process for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Other => 1,
+            Self::InvalidArgument => 2,
+            Self::Device => 3,
+            Self::DeviceLocked => 4,
+            Self::UserRefused => 5,
+            Self::AlreadyInstalled => 6,
+            Self::AlreadyLatest => 7,
+            Self::NotInstalled => 8,
+            Self::AppNotFound => 9,
+            Self::Network => 10,
+            Self::NotGenuine => 11,
+            Self::Drift => 12,
+            Self::Verification => 13,
+        }
+    }
+
+    // The category of an error returned by the library, looking through its sources.
+    fn of(e: &(dyn error::Error + 'static)) -> Self {
+        if let Some(e) = e.downcast_ref::<DeviceError>() {
+            if e.is_locked() {
+                Self::DeviceLocked
+            } else if e.is_user_refusal() {
+                Self::UserRefused
+            } else {
+                Self::Device
+            }
+        } else if let Some(e) = e.downcast_ref::<StatusError>() {
+            Self::of(&DeviceError(e.0))
+        } else if let Some(e) = e.downcast_ref::<QuitAppErr>() {
+            match e {
+                QuitAppErr::Timeout => Self::Device,
+                QuitAppErr::Any(e) => Self::of(e.as_ref()),
+            }
+        } else if e.is::<LedgerHIDError>() {
+            Self::Device
+        } else if e.is::<NetError>() {
+            Self::Network
+        } else if e.is::<VerificationErr>() {
+            Self::Verification
+        } else {
+            e.source().map(Self::of).unwrap_or(Self::Other)
+        }
+    }
+}
+
+/// An error making a command fail.
+#[derive(Debug)]
+pub struct CliError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CliError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// An error returned by the library, categorized after its type. The message is prefixed with
+    /// what we were doing.
+    pub fn from_err(context: &str, e: &(dyn error::Error + 'static)) -> Self {
+        Self::new(ErrorCode::of(e), format!("{}: {}", context, e))
+    }
+}
+
+/// What a command reports. The fields are gathered as the command goes, so they are also reported
+/// along with an error (for instance the device information when the installation failed).
+pub struct Report {
+    command: &'static str,
+    json: bool,
+    fields: serde_json::Map<String, serde_json::Value>,
+    text: Vec<String>,
+}
+
+impl Report {
+    pub fn new(command: &'static str, json: bool) -> Self {
+        Self {
+            command,
+            json,
+            fields: serde_json::Map::new(),
+            text: Vec::new(),
+        }
+    }
+
+    /// Set a field of the JSON output.
+    pub fn set(&mut self, key: &str, value: serde_json::Value) {
+        self.fields.insert(key.to_string(), value);
+    }
+
+    /// Add a line to the output for a human. If none is set, the fields are printed as JSON
+    /// instead. The lines are also printed if the command fails, before the error.
+    pub fn say(&mut self, line: impl Into<String>) {
+        self.text.push(line.into());
+    }
+
+    /// Print the outcome of the command and exit with the code for its error, if any.
+    pub fn finish(self, res: Result<(), CliError>) -> ! {
+        let code = res.as_ref().err().map(|e| e.code.exit_code()).unwrap_or(0);
+        if self.json {
+            let mut doc = serde_json::Map::new();
+            doc.insert("schema_version".into(), SCHEMA_VERSION.into());
+            doc.insert("command".into(), self.command.into());
+            doc.insert("success".into(), res.is_ok().into());
+            doc.extend(self.fields);
+            if let Err(e) = &res {
+                doc.insert(
+                    "error".into(),
+                    serde_json::json!({ "code": e.code.as_str(), "message": e.message }),
+                );
+            }
+            println!("{}", serde_json::Value::Object(doc));
+        } else {
+            match res {
+                Ok(()) if self.text.is_empty() && !self.fields.is_empty() => {
+                    println!("{}", serde_json::Value::Object(self.fields))
+                }
+                Ok(()) => {
+                    for line in self.text {
+                        println!("{}", line);
+                    }
+                }
+                Err(e) => {
+                    for line in self.text {
+                        println!("{}", line);
+                    }
+                    eprintln!("{}", e.message);
+                }
+            }
+        }
+        process::exit(code);
+    }
+}
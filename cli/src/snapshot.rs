@@ -0,0 +1,113 @@
+//! Saving the set of apps installed on the device to disk, and reinstalling it after a firmware
+//! update removed the apps.
+
+use std::path::{Path, PathBuf};
+
+use ledger_manager::{
+    ledger_transport_hidapi::TransportNativeHID,
+    snapshot::{self, RestoreOutcome, Snapshot},
+    ApiConfig, Event, EventHandler,
+};
+
+use crate::{
+    api_config,
+    args::GlobalOpts,
+    datadir,
+    report::{CliError, ErrorCode, Report},
+};
+
+/// The snapshot at `path`, or by default the one in the data directory.
+pub fn snapshot_path(opts: &GlobalOpts, path: Option<PathBuf>) -> Result<PathBuf, CliError> {
+    match path.or_else(|| datadir(opts).map(|d| d.join("apps_snapshot.json"))) {
+        Some(path) => Ok(path),
+        None => Err(CliError::new(
+            ErrorCode::InvalidArgument,
+            "Could not determine the data directory. Set --datadir, or the path of the snapshot.",
+        )),
+    }
+}
+
+/// Take a snapshot of the apps installed on the device and save it at this path.
+pub fn save(
+    ledger_api: &TransportNativeHID,
+    path: &Path,
+    config: &ApiConfig,
+) -> Result<Snapshot, CliError> {
+    let snapshot = snapshot::take(ledger_api, config).map_err(|e| {
+        CliError::from_err("Error taking a snapshot of the installed apps", e.as_ref())
+    })?;
+    snapshot::save(path, &snapshot)
+        .map_err(|e| CliError::from_err("Error saving the snapshot", e.as_ref()))?;
+    Ok(snapshot)
+}
+
+/// Save the snapshot of the apps installed on the device.
+pub fn run_snapshot(
+    opts: &GlobalOpts,
+    path: Option<PathBuf>,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let path = snapshot_path(opts, path)?;
+    let snapshot = save(&crate::ledger_api(opts)?, &path, &api_config(opts))?;
+    report.set("path", serde_json::json!(path));
+    report.set("snapshot", serde_json::json!(snapshot));
+
+    report.say(format!(
+        "Saved the {} installed apps to '{}':",
+        snapshot.apps.len(),
+        path.display()
+    ));
+    for app in &snapshot.apps {
+        match &app.catalog {
+            Some(info) => report.say(format!("  - {} {}", app.name, info.version)),
+            None => report.say(format!("  - {} (unknown version)", app.name)),
+        }
+    }
+    Ok(())
+}
+
+/// Reinstall the apps of the snapshot missing from the device. Fails if any app couldn't be
+/// restored, after trying all of them.
+pub fn run_restore(
+    opts: &GlobalOpts,
+    path: Option<PathBuf>,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let path = snapshot_path(opts, path)?;
+    let snapshot = snapshot::load(&path)
+        .map_err(|e| CliError::new(ErrorCode::InvalidArgument, e.to_string()))?;
+    let ledger_api = crate::ledger_api(opts)?;
+    let mut config = api_config(opts);
+    config.on_event = Some(EventHandler::new(|event| {
+        if let Event::Status(status) = event {
+            eprintln!("{}", status);
+        }
+    }));
+    let restored = snapshot::restore(&ledger_api, &snapshot, &config)
+        .map_err(|e| CliError::from_err("Error restoring the snapshot", e.as_ref()))?;
+    report.set("restore", serde_json::json!(restored));
+
+    for app in &restored.apps {
+        let previous = match &app.previous_version {
+            Some(version) => format!(" (was {})", version),
+            None => String::new(),
+        };
+        report.say(format!("  - {}{}: {}", app.name, previous, app.outcome));
+    }
+    if restored.is_complete() {
+        report.say("All the apps of the snapshot are installed.");
+        return Ok(());
+    }
+    let missing: Vec<_> = restored.not_restored().map(|a| a.name.as_str()).collect();
+    let all_unavailable = restored
+        .not_restored()
+        .all(|a| matches!(a.outcome, RestoreOutcome::NotAvailable));
+    Err(CliError::new(
+        if all_unavailable {
+            ErrorCode::AppNotFound
+        } else {
+            ErrorCode::Other
+        },
+        format!("Could not restore: {}.", missing.join(", ")),
+    ))
+}
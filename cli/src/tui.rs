@@ -0,0 +1,515 @@
+//! An interactive terminal user interface, for operators working over SSH without a desktop. Like
+//! the GUI it shows the device and the versions of the Bitcoin apps, installed and latest, along
+//! with keys to manage them. The operations run in a separate thread, which reports their steps
+//! and progress through the `on_event` handler of the API configuration.
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+    sync::mpsc,
+    thread,
+};
+
+use ledger_manager::{
+    ensure_bitcoin_app, genuine_check, get_latest_apps, is_dashboard_open, list_installed_apps,
+    open_bitcoin_app, quit_app, uninstall_bitcoin_app, ApiConfig, DeviceInfo, DeviceModel,
+    EnsureAction, Event, EventHandler, GenuineCheckErr, Network, UninstallErr, VersionRequirement,
+    QUIT_APP_TIMEOUT,
+};
+
+use crate::{
+    api_config,
+    args::GlobalOpts,
+    ledger_api,
+    report::{CliError, ErrorCode},
+};
+
+const CTRL_C: u8 = 0x03;
+const TAB: u8 = b'\t';
+const ESC: u8 = 0x1b;
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+// Put the terminal in raw mode on the alternate screen, and restore it when dropped.
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    fn enable() -> io::Result<Self> {
+        // SAFETY: the termios struct is only read by tcsetattr after being filled by tcgetattr.
+        let original = unsafe {
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+            raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            original
+        };
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b[?1049h\x1b[?25l")?;
+        stdout.flush()?;
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+        // SAFETY: restoring the attributes we read before.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.original);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(u8),
+    Up,
+    Down,
+}
+
+// What an operation run in the background thread reports.
+enum Msg {
+    Key(Key),
+    Event(Event),
+    Refreshed(Result<Snapshot, CliError>),
+    Genuine(Result<bool, CliError>),
+    // The outcome of an install, update, uninstall or open. Refresh after it if set.
+    Done(Result<String, CliError>, bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Refresh,
+    GenuineCheck,
+    Install,
+    Update,
+    Uninstall,
+    Open,
+}
+
+#[derive(Debug, Clone, Default)]
+enum Installed {
+    #[default]
+    Unknown,
+    No,
+    Yes(String),
+}
+
+#[derive(Debug, Clone, Default)]
+struct AppVersions {
+    installed: Installed,
+    latest: Option<String>,
+}
+
+// What was read from the device and the Ledger API.
+#[derive(Debug, Clone)]
+struct Snapshot {
+    device: DeviceInfo,
+    mainnet: AppVersions,
+    testnet: AppVersions,
+}
+
+#[derive(Default)]
+struct State {
+    snapshot: Option<Snapshot>,
+    genuine: Option<bool>,
+    // Whether the actions apply to the testnet app.
+    testnet: bool,
+    busy: bool,
+    // An action waiting for the user to confirm it.
+    confirm: Option<Action>,
+    status: String,
+    error: bool,
+    progress: Option<(usize, usize)>,
+    // The outcome of the last operation, to show again once the refresh following it is done.
+    outcome: Option<String>,
+}
+
+impl State {
+    fn set_status(&mut self, status: impl Into<String>, error: bool) {
+        self.status = status.into();
+        self.error = error;
+    }
+}
+
+// Read the device and the versions of its Bitcoin apps, installed and latest.
+fn refresh(opts: &GlobalOpts, config: &ApiConfig) -> Result<Snapshot, CliError> {
+    let ledger_api = ledger_api(opts)?;
+    let on_dashboard = is_dashboard_open(&ledger_api)
+        .map_err(|e| CliError::from_err("Error querying the device", e.as_ref()))?;
+    if !on_dashboard {
+        quit_app(&ledger_api, QUIT_APP_TIMEOUT)
+            .map_err(|e| CliError::from_err("Error quitting the running app", &e))?;
+    }
+    let device = DeviceInfo::new(&ledger_api)
+        .map_err(|e| CliError::from_err("Error fetching device info", e.as_ref()))?;
+
+    let installed = list_installed_apps(&ledger_api, config)
+        .map_err(|e| CliError::from_err("Error listing installed applications", e.as_ref()))?;
+    let installed_version = |name: &str| {
+        installed
+            .iter()
+            .flatten()
+            .find(|app| app.version_name == name)
+            .map(|app| Installed::Yes(app.version.clone()))
+            .unwrap_or(Installed::No)
+    };
+    let (mainnet, testnet) = get_latest_apps(&device, config)
+        .map_err(|e| CliError::from_err("Error querying the latest apps", e.as_ref()))?;
+
+    Ok(Snapshot {
+        mainnet: AppVersions {
+            installed: installed_version("Bitcoin"),
+            latest: mainnet.map(|app| app.version),
+        },
+        testnet: AppVersions {
+            installed: installed_version("Bitcoin Test"),
+            latest: testnet.map(|app| app.version),
+        },
+        device,
+    })
+}
+
+// Run the action on the device, and report its outcome.
+fn run_action(action: Action, testnet: bool, opts: &GlobalOpts, config: &ApiConfig) -> Msg {
+    if action == Action::Refresh {
+        return Msg::Refreshed(refresh(opts, config));
+    }
+    let ledger_api = match ledger_api(opts) {
+        Ok(l) => l,
+        Err(e) => return Msg::Done(Err(e), false),
+    };
+    match action {
+        Action::Refresh => unreachable!("Handled above"),
+        Action::GenuineCheck => Msg::Genuine(match genuine_check(&ledger_api, config) {
+            Ok(()) => Ok(true),
+            Err(GenuineCheckErr::NotGenuine(_)) => Ok(false),
+            Err(e @ GenuineCheckErr::NoResult) => {
+                Err(CliError::new(ErrorCode::Network, e.to_string()))
+            }
+            Err(GenuineCheckErr::Any(e)) => Err(CliError::from_err(
+                "Error when performing genuine check",
+                e.as_ref(),
+            )),
+        }),
+        Action::Install | Action::Update => {
+            let network = if testnet {
+                Network::Testnet
+            } else {
+                Network::Mainnet
+            };
+            // Installing leaves an installed app alone, updating brings it to the latest version.
+            let requirement = if action == Action::Install {
+                VersionRequirement::Any
+            } else {
+                VersionRequirement::Latest
+            };
+            Msg::Done(
+                match ensure_bitcoin_app(&ledger_api, network, requirement, config) {
+                    Ok(EnsureAction::Nothing { .. }) if action == Action::Install => {
+                        Ok("The app is already installed.".to_string())
+                    }
+                    Ok(EnsureAction::Nothing { .. }) => {
+                        Ok("The app is already at the latest version.".to_string())
+                    }
+                    Ok(EnsureAction::Installed(_)) => {
+                        Ok("Successfully installed the app.".to_string())
+                    }
+                    Ok(EnsureAction::Updated(report)) if report.attempts.len() > 1 => {
+                        Ok(format!("{}.", report))
+                    }
+                    Ok(EnsureAction::Updated(_)) => Ok("Successfully updated the app.".to_string()),
+                    Err(e) => Err(crate::ensure_error(
+                        if testnet { "Bitcoin Test" } else { "Bitcoin" },
+                        e,
+                    )),
+                },
+                true,
+            )
+        }
+        Action::Uninstall => Msg::Done(
+            match uninstall_bitcoin_app(&ledger_api, testnet, config) {
+                Ok(()) => Ok("Successfully uninstalled the app.".to_string()),
+                Err(UninstallErr::NotInstalled) => Err(CliError::new(
+                    ErrorCode::NotInstalled,
+                    "Bitcoin app isn't installed.",
+                )),
+                Err(UninstallErr::AppNotFound) => Err(CliError::new(
+                    ErrorCode::AppNotFound,
+                    "Could not get info about the installed Bitcoin app.",
+                )),
+                Err(UninstallErr::Any(e)) => Err(CliError::from_err(
+                    "Error uninstalling Bitcoin app",
+                    e.as_ref(),
+                )),
+            },
+            true,
+        ),
+        Action::Open => Msg::Done(
+            open_bitcoin_app(&ledger_api, testnet)
+                .map(|()| "Opened the app. Quit it on the device to manage the apps.".to_string())
+                .map_err(|e| CliError::from_err("Error opening Bitcoin app", e.as_ref())),
+            false,
+        ),
+    }
+}
+
+// Read the keys pressed, forever. Arrows are sent by the terminal as escape sequences.
+fn read_keys(sender: mpsc::Sender<Msg>) {
+    let mut bytes = io::stdin().lock().bytes().map_while(Result::ok);
+    while let Some(byte) = bytes.next() {
+        let key = if byte == ESC {
+            match (bytes.next(), bytes.next()) {
+                (Some(b'['), Some(b'A')) => Key::Up,
+                (Some(b'['), Some(b'B')) => Key::Down,
+                _ => continue,
+            }
+        } else {
+            Key::Char(byte)
+        };
+        if sender.send(Msg::Key(key)).is_err() {
+            return;
+        }
+    }
+}
+
+fn installed_str(installed: &Installed) -> String {
+    match installed {
+        Installed::Unknown => "-".to_string(),
+        Installed::No => "Not installed".to_string(),
+        Installed::Yes(version) => version.clone(),
+    }
+}
+
+fn render(state: &State) -> io::Result<()> {
+    let mut lines = vec![
+        "\x1b[1m Bacca - your Ledger companion\x1b[0m".to_string(),
+        String::new(),
+        "\x1b[1m Device\x1b[0m".to_string(),
+    ];
+    let snapshot = state.snapshot.as_ref();
+    let model = snapshot
+        .map(|s| match DeviceModel::from_target_id(s.device.target_id) {
+            Some(model) => model.to_string(),
+            None => format!("Unknown ({:#x})", s.device.target_id),
+        })
+        .unwrap_or_else(|| "-".to_string());
+    lines.push(format!("   {:<16}{}", "Model", model));
+    lines.push(format!(
+        "   {:<16}{}",
+        "Firmware",
+        snapshot.map(|s| s.device.version.as_str()).unwrap_or("-")
+    ));
+    lines.push(format!(
+        "   {:<16}{}",
+        "Genuine",
+        match state.genuine {
+            None => "-",
+            Some(true) => "\x1b[32mYes\x1b[0m",
+            Some(false) => "\x1b[31;1mNo!\x1b[0m",
+        }
+    ));
+    lines.push(String::new());
+    lines.push(format!(
+        "\x1b[1m {:<18}{:<18}{}\x1b[0m",
+        "Apps", "Installed", "Latest"
+    ));
+    let default_versions = AppVersions::default();
+    for (name, testnet) in [("Bitcoin", false), ("Bitcoin Test", true)] {
+        let versions = match snapshot {
+            Some(s) if testnet => &s.testnet,
+            Some(s) => &s.mainnet,
+            None => &default_versions,
+        };
+        lines.push(format!(
+            " {} {:<16}{:<18}{}",
+            if state.testnet == testnet { ">" } else { " " },
+            name,
+            installed_str(&versions.installed),
+            versions.latest.as_deref().unwrap_or("-"),
+        ));
+    }
+    lines.push(String::new());
+    if state.error {
+        lines.push(format!(" \x1b[31m{}\x1b[0m", state.status));
+    } else {
+        lines.push(format!(" {}", state.status));
+    }
+    if let Some((done, total)) = state.progress {
+        let filled = (done * PROGRESS_BAR_WIDTH).checked_div(total).unwrap_or(0);
+        lines.push(format!(
+            " [{}{}] {}/{}",
+            "#".repeat(filled),
+            ".".repeat(PROGRESS_BAR_WIDTH - filled),
+            done,
+            total
+        ));
+    } else {
+        lines.push(String::new());
+    }
+    lines.push(String::new());
+    lines.push(
+        " [r] refresh  [g] genuine check  [tab/arrows] select app  [i] install  [u] update"
+            .to_string(),
+    );
+    lines.push(" [x] uninstall  [o] open  [q] quit".to_string());
+
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "\x1b[2J\x1b[H")?;
+    for line in lines {
+        write!(stdout, "{}\x1b[K\r\n", line)?;
+    }
+    stdout.flush()
+}
+
+// Start the action in the background, unless another one is running.
+fn start(
+    action: Action,
+    state: &mut State,
+    opts: &GlobalOpts,
+    config: &ApiConfig,
+    sender: &mpsc::Sender<Msg>,
+) {
+    if state.busy {
+        state.set_status("Wait for the current operation to finish.", true);
+        return;
+    }
+    state.busy = true;
+    state.progress = None;
+    state.set_status("Connecting to the device...", false);
+    let (opts, config, sender) = (opts.clone(), config.clone(), sender.clone());
+    let testnet = state.testnet;
+    thread::spawn(move || {
+        let _ = sender.send(run_action(action, testnet, &opts, &config));
+    });
+}
+
+/// Run the terminal UI until the user quits it.
+pub fn run(opts: &GlobalOpts) -> Result<(), CliError> {
+    // SAFETY: isatty only reads the file descriptor.
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+        return Err(CliError::new(
+            ErrorCode::InvalidArgument,
+            "The terminal UI must be run in a terminal.",
+        ));
+    }
+    // The logs would garble the screen.
+    log::set_max_level(log::LevelFilter::Off);
+
+    let (sender, receiver) = mpsc::channel();
+    let mut config = api_config(opts);
+    let event_sender = sender.clone();
+    config.on_event = Some(EventHandler::new(move |event| {
+        let _ = event_sender.send(Msg::Event(event));
+    }));
+    let key_sender = sender.clone();
+    thread::spawn(move || read_keys(key_sender));
+
+    let _terminal = RawTerminal::enable().map_err(|e| {
+        CliError::new(
+            ErrorCode::Other,
+            format!("Error setting up the terminal: {}", e),
+        )
+    })?;
+    let mut state = State::default();
+    start(Action::Refresh, &mut state, opts, &config, &sender);
+    render(&state).map_err(|e| CliError::new(ErrorCode::Other, e.to_string()))?;
+
+    while let Ok(msg) = receiver.recv() {
+        match msg {
+            Msg::Key(Key::Char(CTRL_C)) => break,
+            Msg::Key(Key::Char(b'q')) if !state.busy => break,
+            Msg::Key(Key::Char(b'q')) => state.set_status(
+                "An operation is running, quitting now could leave the app half installed. Press Ctrl-C to quit anyway.",
+                true,
+            ),
+            Msg::Key(key) => {
+                let action = match key {
+                    Key::Char(b'r') => Some(Action::Refresh),
+                    Key::Char(b'g') => Some(Action::GenuineCheck),
+                    Key::Char(b'i') => Some(Action::Install),
+                    Key::Char(b'u') => Some(Action::Update),
+                    Key::Char(b'x') => Some(Action::Uninstall),
+                    Key::Char(b'o') => Some(Action::Open),
+                    Key::Char(b'y') => state.confirm.take(),
+                    Key::Char(TAB) | Key::Up | Key::Down => {
+                        state.testnet = !state.testnet;
+                        None
+                    }
+                    _ => None,
+                };
+                match action {
+                    Some(Action::Uninstall) if state.confirm.is_none() && key != Key::Char(b'y') => {
+                        state.confirm = Some(Action::Uninstall);
+                        state.set_status(
+                            format!(
+                                "Press [y] to confirm uninstalling the {} app.",
+                                if state.testnet { "Bitcoin Test" } else { "Bitcoin" }
+                            ),
+                            false,
+                        );
+                    }
+                    Some(action) => {
+                        state.confirm = None;
+                        start(action, &mut state, opts, &config, &sender);
+                    }
+                    None => state.confirm = None,
+                }
+            }
+            Msg::Event(Event::Status(status)) => state.set_status(status, false),
+            Msg::Event(Event::Progress { done, total }) => state.progress = Some((done, total)),
+            Msg::Refreshed(res) => {
+                state.busy = false;
+                state.progress = None;
+                match res {
+                    Ok(snapshot) => {
+                        state.snapshot = Some(snapshot);
+                        let outcome = state.outcome.take().unwrap_or_default();
+                        state.set_status(outcome, false);
+                    }
+                    Err(e) => state.set_status(e.message, true),
+                }
+            }
+            Msg::Genuine(res) => {
+                state.busy = false;
+                match res {
+                    Ok(true) => {
+                        state.genuine = Some(true);
+                        state.set_status("Success. Your Ledger is genuine.", false);
+                    }
+                    Ok(false) => {
+                        state.genuine = Some(false);
+                        state.set_status("The device is not genuine!", true);
+                    }
+                    Err(e) => state.set_status(e.message, true),
+                }
+            }
+            Msg::Done(res, then_refresh) => {
+                state.busy = false;
+                let succeeded = res.is_ok();
+                match res {
+                    Ok(msg) => state.set_status(msg, false),
+                    Err(e) => state.set_status(e.message, true),
+                }
+                if succeeded && then_refresh {
+                    state.outcome = Some(state.status.clone());
+                    start(Action::Refresh, &mut state, opts, &config, &sender);
+                }
+            }
+        }
+        render(&state).map_err(|e| CliError::new(ErrorCode::Other, e.to_string()))?;
+    }
+
+    Ok(())
+}
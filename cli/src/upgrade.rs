@@ -0,0 +1,163 @@
+//! Bringing the device up to date: print the upgrade plan, then carry it out step by step,
+//! reconnecting to the device when it restarts.
+
+use std::{thread, time};
+
+use ledger_manager::{
+    ledger_transport_hidapi::TransportNativeHID, list_installed_apps_raw, plan_upgrade,
+    run_upgrade_step, DeviceInfo, Event, EventHandler, UpgradePlan, UpgradeStep,
+};
+
+use crate::{
+    api_config,
+    args::GlobalOpts,
+    report::{CliError, ErrorCode, Report},
+    snapshot,
+};
+
+// How long to wait for the device to come back after it restarted, including the confirmations
+// on the device.
+const RESTART_TIMEOUT: time::Duration = time::Duration::from_secs(10 * 60);
+const RECONNECT_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+fn format_bytes(bytes: Option<u64>) -> String {
+    match bytes {
+        Some(b) if b >= 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+        Some(b) => format!("{} kB", b.div_ceil(1024)),
+        None => "unknown size".to_string(),
+    }
+}
+
+fn format_duration(duration: time::Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{} s", secs)
+    } else {
+        format!("{} min", secs.div_ceil(60))
+    }
+}
+
+fn plan_json(plan: &UpgradePlan, done: usize) -> serde_json::Value {
+    let steps: Vec<_> = plan
+        .steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let mut json = serde_json::json!(step);
+            json["description"] = step.to_string().into();
+            json["bytes"] = serde_json::json!(step.bytes());
+            json["estimate_secs"] = step.estimate().as_secs().into();
+            json["done"] = (i < done).into();
+            json
+        })
+        .collect();
+    serde_json::json!({
+        "firmware_version": plan.firmware_version,
+        "lost_apps": plan.lost_apps,
+        "estimate_secs": plan.estimate().as_secs(),
+        "steps": steps,
+    })
+}
+
+// Wait for the device to restart and be ready for this step, and connect to it again.
+fn reconnect(opts: &GlobalOpts, step: &UpgradeStep) -> Result<TransportNativeHID, CliError> {
+    eprintln!("Waiting for the device to restart. Follow the instructions on the device.");
+    let start = time::Instant::now();
+    while start.elapsed() < RESTART_TIMEOUT {
+        thread::sleep(RECONNECT_INTERVAL);
+        let Ok(ledger_api) = crate::ledger_api(opts) else {
+            continue;
+        };
+        match DeviceInfo::new(&ledger_api) {
+            Ok(info) if step.is_ready(&info) => return Ok(ledger_api),
+            Ok(_) => {}
+            Err(e) => log::debug!("Device not ready yet: {}", e),
+        }
+    }
+    Err(CliError::new(
+        ErrorCode::Device,
+        "Timed out waiting for the device to restart.",
+    ))
+}
+
+/// Plan the upgrade of the device and print it. Unless `plan_only` is set, then carry it out.
+pub fn run(opts: &GlobalOpts, plan_only: bool, report: &mut Report) -> Result<(), CliError> {
+    let mut ledger_api = crate::ledger_api(opts)?;
+    let mut config = api_config(opts);
+    config.on_event = Some(EventHandler::new(|event| match event {
+        Event::Status(status) => eprintln!("{}", status),
+        Event::Progress { done, total } => {
+            eprint!("\r{}/{}", done, total);
+            if done == total {
+                eprintln!();
+            }
+        }
+    }));
+
+    let device_info = DeviceInfo::new(&ledger_api)
+        .map_err(|e| CliError::from_err("Error fetching device info", e.as_ref()))?;
+    report.set("device", serde_json::json!(device_info));
+    eprintln!("Querying installed applications from your Ledger. You might have to confirm on your device.");
+    let installed = list_installed_apps_raw(&ledger_api)
+        .map_err(|e| CliError::from_err("Error listing installed applications", e.as_ref()))?;
+    let plan = plan_upgrade(&device_info, &installed, &config)
+        .map_err(|e| CliError::from_err("Error planning the upgrade", e.as_ref()))?;
+    report.set("plan", plan_json(&plan, 0));
+
+    if plan.is_empty() {
+        report.say("The device is up to date.");
+        return Ok(());
+    }
+    report.say(format!(
+        "Upgrade plan, about {}:",
+        format_duration(plan.estimate())
+    ));
+    for (i, step) in plan.steps.iter().enumerate() {
+        report.say(format!(
+            "  {}. {} ({}, about {})",
+            i + 1,
+            step,
+            format_bytes(step.bytes()),
+            format_duration(step.estimate())
+        ));
+    }
+    if !plan.lost_apps.is_empty() {
+        report.say(format!(
+            "Not available for the new firmware, and removed by the update: {}",
+            plan.lost_apps.join(", ")
+        ));
+    }
+    if plan_only {
+        return Ok(());
+    }
+
+    // The firmware update removes the apps, save them to restore them if the upgrade fails midway.
+    if plan.firmware_version.is_some() {
+        let path = snapshot::snapshot_path(opts, None)?;
+        snapshot::save(&ledger_api, &path, &config)?;
+        report.set("snapshot", serde_json::json!(path));
+        eprintln!(
+            "Saved the list of the installed apps to '{}'. If the upgrade fails, reinstall them \
+             with the restore command.",
+            path.display()
+        );
+    }
+
+    for (i, step) in plan.steps.iter().enumerate() {
+        if i > 0 && plan.steps[i - 1].restarts_device() {
+            drop(ledger_api);
+            ledger_api = reconnect(opts, step)?;
+        }
+        eprintln!("Step {}/{}: {}.", i + 1, plan.steps.len(), step);
+        if let Err(e) = run_upgrade_step(&ledger_api, step, &config) {
+            report.set("plan", plan_json(&plan, i));
+            return Err(CliError::from_err(
+                &format!("Error at step {} ({})", i + 1, step),
+                e.as_ref(),
+            ));
+        }
+    }
+    report.set("plan", plan_json(&plan, plan.steps.len()));
+    report.say("The device is up to date.");
+    Ok(())
+}
@@ -1,14 +1,15 @@
 use crate::{
-    ledger_service::{LedgerListener, LedgerMessage, Version},
+    ledger_service::{privacy_from_env, LedgerListener, LedgerMessage, Version},
     theme::{self, Theme},
 };
 use async_channel::{Receiver, Sender};
 use iced::{
     alignment, executor,
-    widget::{Button, Column, Container, Row, Rule, Space, Text},
+    widget::{Button, Checkbox, Column, Container, Row, Rule, Space, Text},
     Alignment, Application, Element, Font, Length, Renderer,
 };
 use iced_runtime::{futures::Subscription, Command};
+use ledger_manager::bitcoin_app::{unsupported_features, AppVersion};
 
 const ICONEX_ICONS_BYTES: &[u8] = include_bytes!("iconex-icons.ttf");
 
@@ -29,6 +30,9 @@ pub enum Message {
     #[allow(unused)]
     Connect,
     GenuineCheck,
+    TogglePrivacy(bool),
+    PlanUpgrade,
+    RunUpgrade,
 
     ResetAlarm,
     Result,
@@ -54,6 +58,8 @@ pub struct LedgerInstaller {
     device_is_genuine: Option<bool>,
     device_busy: bool,
     alarm: bool,
+    privacy: bool,
+    upgrade_plan: Option<Vec<String>>,
 }
 
 impl LedgerInstaller {
@@ -84,6 +90,8 @@ impl Application for LedgerInstaller {
             device_is_genuine: None,
             device_busy: false,
             alarm: false,
+            privacy: privacy_from_env(),
+            upgrade_plan: None,
         };
 
         let cmd = iced::font::load(ICONEX_ICONS_BYTES).map(Message::from);
@@ -101,6 +109,7 @@ impl Application for LedgerInstaller {
                 LedgerMessage::Connected(model, version) => {
                     self.device_busy = false;
                     if model.is_none() {
+                        self.upgrade_plan = None;
                         self.main_app_version = Version::None;
                         self.main_latest_version = Version::None;
                         self.test_app_version = Version::None;
@@ -137,6 +146,13 @@ impl Application for LedgerInstaller {
                     self.main_latest_version = bitcoin;
                     self.test_latest_version = test;
                 }
+                LedgerMessage::UpgradePlan(plan) => {
+                    // The device stays busy while the plan is carried out.
+                    if plan.is_some() {
+                        self.device_busy = false;
+                    }
+                    self.upgrade_plan = plan;
+                }
                 _ => {
                     log::debug!(
                         "LedgerInstaller.update() => Unhandled message from ledger: {:?}!",
@@ -172,6 +188,20 @@ impl Application for LedgerInstaller {
                 self.device_busy = true;
                 self.send_ledger_msg(LedgerMessage::GenuineCheck)
             }
+            Message::PlanUpgrade => {
+                self.device_busy = true;
+                self.send_ledger_msg(LedgerMessage::PlanUpgrade)
+            }
+            Message::RunUpgrade => {
+                self.main_app_version = Version::None;
+                self.test_app_version = Version::None;
+                self.device_busy = true;
+                self.send_ledger_msg(LedgerMessage::RunUpgrade)
+            }
+            Message::TogglePrivacy(privacy) => {
+                self.privacy = privacy;
+                self.send_ledger_msg(LedgerMessage::SetPrivacy(privacy))
+            }
             Message::Result => {}
             _ => {
                 log::debug!("LedgerInstaller.update() => Unhandled message {:?}", event)
@@ -217,7 +247,22 @@ impl Application for LedgerInstaller {
                             .push(Space::with_width(Length::Fill)),
                     )
                     .push(Space::with_height(5))
-                    .push(apps),
+                    .push(apps)
+                    .push(Space::with_height(5))
+                    .push(upgrade_container(&self.upgrade_plan, self.device_busy))
+                    .push(Space::with_height(5))
+                    .push(
+                        Row::new()
+                            .push(Space::with_width(Length::Fill))
+                            .push(
+                                Checkbox::new(
+                                    "Privacy mode: never send my installed apps to Ledger",
+                                    self.privacy,
+                                )
+                                .on_toggle(Message::TogglePrivacy),
+                            )
+                            .push(Space::with_width(Length::Fill)),
+                    ),
             )
         } else {
             None
@@ -363,6 +408,44 @@ fn device_container<'a>(
     .padding(10)
 }
 
+fn upgrade_container<'a>(
+    plan: &Option<Vec<String>>,
+    device_busy: bool,
+) -> Container<'a, Message, Theme, Renderer> {
+    // We do not allow user to click the buttons if service still processing a task w/ device
+    let enabled = |msg: Message| if !device_busy { Some(msg) } else { None };
+
+    let content = match plan {
+        None => Column::new().push(
+            Row::new()
+                .push(Text::new("Firmware and apps up to date?"))
+                .push(Space::with_width(Length::Fill))
+                .push(Button::new("Check").on_press_maybe(enabled(Message::PlanUpgrade)))
+                .align_items(Alignment::Center),
+        ),
+        Some(steps) if steps.is_empty() => {
+            Column::new().push(Text::new("The firmware and the apps are up to date."))
+        }
+        Some(steps) => steps
+            .iter()
+            .fold(Column::new(), |col, step| {
+                col.push(Text::new(step.clone()).size(14))
+            })
+            .push(Space::with_height(5))
+            .push(
+                Row::new()
+                    .push(Space::with_width(Length::Fill))
+                    .push(Button::new("Upgrade").on_press_maybe(enabled(Message::RunUpgrade)))
+                    .push(Space::with_width(Length::Fill)),
+            ),
+    };
+
+    Container::new(content)
+        .style(theme::Container::Frame)
+        .padding(10)
+        .width(Length::Fill)
+}
+
 fn apps_container<'a>(
     bitcoin_version: Version,
     bitcoin_latest: Version,
@@ -377,7 +460,7 @@ fn apps_container<'a>(
     // It looks weird that we load iconex-icons.ttf by its name: Untitled1
     const ICONEX_ICONS: Font = Font::with_name("Untitled1");
 
-    fn raw_btn(txt: &str, msg: Option<Message>) -> Button<Message, Theme> {
+    fn raw_btn(txt: &str, msg: Option<Message>) -> Button<'_, Message, Theme> {
         Button::new(
             Row::new()
                 .push(
@@ -421,6 +504,23 @@ fn apps_container<'a>(
         }
     }
 
+    // Installed apps too old to be used with some descriptors (for instance taproot miniscript)
+    // must be updated.
+    fn update_required(version: &Version) -> Option<Text<'static, Theme>> {
+        let Version::Installed(version) = version else {
+            return None;
+        };
+        let features = unsupported_features(&version.parse::<AppVersion>().ok()?);
+        if features.is_empty() {
+            return None;
+        }
+        let features: Vec<_> = features.iter().map(|f| f.to_string()).collect();
+        Some(
+            Text::new(format!("Update required for {}", features.join(", ")))
+                .style(theme::Text::Color(theme::color::ORANGE)),
+        )
+    }
+
     // We do not allow user to click buttons if service still processing a task w/ device
     let install_bitcoin_msg = if !device_busy {
         Some(Message::InstallMain)
@@ -457,8 +557,10 @@ fn apps_container<'a>(
         update_test_msg,
     );
 
+    let bitcoin_update_required = update_required(&bitcoin_version);
     let bitcoin_version = version(bitcoin_version);
 
+    let test_update_required = update_required(&test_version);
     let test_version = version(test_version);
 
     Container::new(
@@ -472,6 +574,7 @@ fn apps_container<'a>(
                             .push(
                                 Text::new(bitcoin_version).style(theme::Text::Color(version_color)),
                             )
+                            .push_maybe(bitcoin_update_required)
                             .push(Space::with_height(Length::Fill))
                             .width(vertical_rule_position)
                             .align_items(Alignment::Center),
@@ -504,6 +607,7 @@ fn apps_container<'a>(
                             .push(Space::with_height(Length::Fill))
                             .push(Text::new("Bitcoin Test").size(network_size))
                             .push(Text::new(test_version).style(theme::Text::Color(version_color)))
+                            .push_maybe(test_update_required)
                             .push(Space::with_height(Length::Fill))
                             .width(vertical_rule_position)
                             .align_items(Alignment::Center),
@@ -2,21 +2,84 @@ use crate::listener;
 use crate::{gui::Message, gui::Message::LedgerServiceMsg, service::ServiceFn};
 use std::error::Error;
 
-use form_urlencoded::Serializer as UrlSerializer;
 use ledger_manager::{
-    bitcoin_latest_app, genuine_check, get_latest_apps,
+    ensure_bitcoin_app, genuine_check, get_latest_apps,
     ledger_transport_hidapi::{hidapi::HidApi, TransportNativeHID},
-    list_installed_apps, query_via_websocket, DeviceInfo, BASE_SOCKET_URL,
+    list_installed_apps, list_installed_apps_raw, plan_upgrade, run_upgrade_step, snapshot,
+    ApiConfig, DeviceInfo, EnsureAction, GenuineCheckErr, Network, UpgradePlan, UpgradeStep,
+    VersionRequirement,
 };
+use std::env;
 use std::fmt::{Display, Formatter};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long to wait for the device to come back after it restarted during an upgrade, including
+// the confirmations on the device.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
 listener!(LedgerListener, LedgerMessage, Message, LedgerServiceMsg);
 
 // TODO: those helpers, used by both the CLI and the GUI, should live in the lib somehow.
 
+/// Whether to start in privacy mode. Like for the CLI, it's enabled by setting LEDGER_PRIVACY.
+pub fn privacy_from_env() -> bool {
+    env::var("LEDGER_PRIVACY").is_ok()
+}
+
+// The same data directory as the CLI's.
+fn datadir() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("LEDGER_DATADIR") {
+        Some(PathBuf::from(dir))
+    } else {
+        env::var("HOME")
+            .ok()
+            .map(|home| PathBuf::from(home).join(".bacca"))
+    }
+}
+
+// The apps seen in the catalog are cached in the data directory. Like for the CLI, a proxy can be
+// set through LEDGER_PROXY.
+fn api_config(privacy: bool) -> ApiConfig {
+    let datadir = datadir();
+    let proxy = env::var("LEDGER_PROXY")
+        .ok()
+        .and_then(|proxy| match proxy.parse() {
+            Ok(p) => Some(p),
+            Err(e) => {
+                log::error!("Ignoring invalid LEDGER_PROXY: {}", e);
+                None
+            }
+        });
+    // Don't start at all rather than ignoring an invalid pin, as it could disable the pinning.
+    let tls_pins = env::var("LEDGER_TLS_PINS")
+        .map(|pins| {
+            pins.split(',')
+                .filter(|p| !p.trim().is_empty())
+                .map(|pin| match pin.parse() {
+                    Ok(pin) => pin,
+                    Err(e) => {
+                        log::error!("Invalid LEDGER_TLS_PINS: {}", e);
+                        process::exit(1);
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ApiConfig {
+        privacy,
+        catalog_cache: datadir.map(|d| d.join("catalog_cache.json")),
+        proxy,
+        tls_pins,
+        ..ApiConfig::default()
+    }
+}
+
 fn check_apps_installed<M>(
     transport: &TransportNativeHID,
+    config: &ApiConfig,
     msg_callback: M,
 ) -> Result<(Model, Version, Version), Box<dyn Error>>
 where
@@ -27,7 +90,7 @@ where
     let mut mainnet = Version::NotInstalled;
     let mut testnet = Version::NotInstalled;
     let mut model = Model::Unknown;
-    match list_installed_apps(transport) {
+    match list_installed_apps(transport, config) {
         Ok(apps) => {
             log::debug!("List installed apps:ok");
             msg_callback("List installed apps...", false);
@@ -58,6 +121,7 @@ where
 
 fn check_latest_apps<M>(
     transport: &TransportNativeHID,
+    config: &ApiConfig,
     msg_callback: M,
 ) -> Result<(Version, Version), Box<dyn Error>>
 where
@@ -67,7 +131,7 @@ where
     msg_callback("Querying latest apps on Ledger API...", false);
 
     let device_info = DeviceInfo::new(transport)?;
-    let (bitcoin, test) = get_latest_apps(&device_info)?;
+    let (bitcoin, test) = get_latest_apps(&device_info, config)?;
 
     let bitcoin = if let Some(app) = bitcoin {
         Version::Latest(app.version)
@@ -84,57 +148,57 @@ where
     Ok((bitcoin, test))
 }
 
-fn install_app<M>(transport: &TransportNativeHID, msg_callback: M, testnet: bool)
+// Install the Bitcoin app, or update it if it's not the latest version.
+fn ensure_app<M>(transport: &TransportNativeHID, config: &ApiConfig, msg_callback: M, testnet: bool)
 where
     M: Fn(&str, bool),
 {
-    log::debug!("ledger::install_app(testnet={})", testnet);
-
-    msg_callback("Get device info from API...", false);
-    if let Ok(device_info) = device_info(transport) {
-        let bitcoin_app = match bitcoin_latest_app(&device_info, testnet) {
-            Ok(Some(a)) => a,
-            Ok(None) => {
-                msg_callback("Could not get info about Bitcoin app.", true);
-                return;
-            }
-            Err(e) => {
-                msg_callback(
-                    &format!("Error querying info about Bitcoin app: {}.", e),
-                    true,
-                );
-                return;
-            }
-        };
-        msg_callback(
-            "Installing, please allow Ledger manager on device...",
-            false,
-        );
-        // Now install the app by connecting through their websocket thing to their HSM. Make sure to
-        // properly escape the parameters in the request's parameter.
-        let install_ws_url = UrlSerializer::new(format!("{}/install?", BASE_SOCKET_URL))
-            .append_pair("targetId", &device_info.target_id.to_string())
-            .append_pair("perso", &bitcoin_app.perso)
-            .append_pair("deleteKey", &bitcoin_app.delete_key)
-            .append_pair("firmware", &bitcoin_app.firmware)
-            .append_pair("firmwareKey", &bitcoin_app.firmware_key)
-            .append_pair("hash", &bitcoin_app.hash)
-            .finish();
-        msg_callback("Install app...", false);
-        if let Err(e) = query_via_websocket(transport, &install_ws_url) {
-            msg_callback(
-                &format!(
-                    "Got an error when installing Bitcoin app from Ledger's remote HSM: {}.",
-                    e
-                ),
-                false,
-            );
-            return;
-        }
-        msg_callback("Successfully installed the app.", false);
+    log::debug!("ledger::ensure_app(testnet={})", testnet);
+
+    msg_callback(
+        "Installing, please allow Ledger manager on device...",
+        false,
+    );
+    let network = if testnet {
+        Network::Testnet
     } else {
-        msg_callback("Fail to fetch device info!", true);
+        Network::Mainnet
+    };
+    match ensure_bitcoin_app(transport, network, VersionRequirement::Latest, config) {
+        Ok(EnsureAction::Nothing { .. }) => {
+            msg_callback("The app is already at the latest version.", false)
+        }
+        Ok(EnsureAction::Installed(_)) => msg_callback("Successfully installed the app.", false),
+        Ok(EnsureAction::Updated(report)) => msg_callback(&format!("{}.", report), false),
+        Err(e) => msg_callback(
+            &format!("Got an error when installing Bitcoin app: {}.", e),
+            true,
+        ),
+    }
+}
+
+// The lines describing the upgrade plan to the user.
+fn upgrade_plan_lines(plan: &UpgradePlan) -> Vec<String> {
+    let mut lines: Vec<_> = plan
+        .steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            format!(
+                "{}. {} (about {} min)",
+                i + 1,
+                step,
+                step.estimate().as_secs().div_ceil(60)
+            )
+        })
+        .collect();
+    if !plan.lost_apps.is_empty() {
+        lines.push(format!(
+            "Removed by the update: {}",
+            plan.lost_apps.join(", ")
+        ));
     }
+    lines
 }
 
 fn ledger_api() -> Result<HidApi, String> {
@@ -157,6 +221,7 @@ struct VersionInfo {
 #[allow(clippy::result_unit_err)]
 fn get_version_info<V, M>(
     transport: TransportNativeHID,
+    config: &ApiConfig,
     actual_device_version: &Option<String>,
     version_callback: V,
     msg_callback: M,
@@ -192,7 +257,7 @@ where
         // if it's our first connection, we check the if apps are installed & version
         msg_callback("Querying installed apps. Please confirm on device.", false);
         if actual_device_version.is_none() && device_version.is_some() {
-            match check_apps_installed(&transport, &msg_callback) {
+            match check_apps_installed(&transport, config, &msg_callback) {
                 Ok((model, mainnet, testnet)) => {
                     msg_callback("", false);
                     return Ok(VersionInfo {
@@ -320,6 +385,9 @@ pub enum LedgerMessage {
     InstallTest,
     TryConnect,
     GenuineCheck,
+    SetPrivacy(bool),
+    PlanUpgrade,
+    RunUpgrade,
 
     Connected(Option<String>, Option<String>),
     MainAppVersion(Version),
@@ -327,6 +395,8 @@ pub enum LedgerMessage {
     DisplayMessage(String, bool),
     DeviceIsGenuine(Option<bool>),
     LatestApps(Version, Version),
+    /// The steps of the upgrade of the device, empty if it's up to date. None once carried out.
+    UpgradePlan(Option<Vec<String>>),
 }
 
 pub struct LedgerService {
@@ -338,6 +408,8 @@ pub struct LedgerService {
     testnet_version: Version,
     last_mainnet: Version,
     last_testnet: Version,
+    api_config: ApiConfig,
+    upgrade_plan: Option<UpgradePlan>,
 }
 
 impl LedgerService {
@@ -371,6 +443,9 @@ impl LedgerService {
             LedgerMessage::UpdateTest => self.update_test(),
             LedgerMessage::InstallTest => self.install_test(),
             LedgerMessage::GenuineCheck => self.genuine_check(),
+            LedgerMessage::SetPrivacy(privacy) => self.api_config.privacy = *privacy,
+            LedgerMessage::PlanUpgrade => self.plan_upgrade(),
+            LedgerMessage::RunUpgrade => self.run_upgrade(),
             _ => {
                 log::debug!("LedgerService.handle_message({:?}) -> unhandled!", msg)
             }
@@ -397,9 +472,11 @@ impl LedgerService {
                 // check for latest apps on ledger catalog
                 if self.last_mainnet.is_none() || self.last_testnet.is_none() {
                     log::info!("Query Ledger catalog...");
-                    if let Ok((bitcoin, test)) = check_latest_apps(&transport, |msg, alarm| {
-                        Self::display_message(&sender, msg, alarm)
-                    }) {
+                    if let Ok((bitcoin, test)) =
+                        check_latest_apps(&transport, &self.api_config, |msg, alarm| {
+                            Self::display_message(&sender, msg, alarm)
+                        })
+                    {
                         self.last_mainnet = bitcoin.clone();
                         self.last_testnet = test.clone();
                         self.send_to_gui(LedgerMessage::LatestApps(bitcoin, test))
@@ -416,6 +493,7 @@ impl LedgerService {
                 // get versions of device & apps
                 if let Ok(info) = get_version_info(
                     transport,
+                    &self.api_config,
                     &self.device_version,
                     |model, version| {
                         self.send_to_gui(LedgerMessage::Connected(model, version));
@@ -494,8 +572,9 @@ impl LedgerService {
     fn install_app(&mut self, testnet: bool) {
         let sender = self.sender.clone();
         if let Some(transport) = self.connect() {
-            install_app(
+            ensure_app(
                 &transport,
+                &self.api_config,
                 |msg, alarm| Self::display_message(&sender, msg, alarm),
                 testnet,
             )
@@ -526,12 +605,19 @@ impl LedgerService {
                 false,
             ));
             log::info!("Check if device genuine...");
-            if let Err(e) = genuine_check(&transport) {
-                self.send_to_gui(LedgerMessage::DisplayMessage(e.to_string(), true));
-                self.send_to_gui(LedgerMessage::DeviceIsGenuine(None));
-            } else {
-                self.send_to_gui(LedgerMessage::DisplayMessage("".to_string(), false));
-                self.send_to_gui(LedgerMessage::DeviceIsGenuine(Some(true)));
+            match genuine_check(&transport, &self.api_config) {
+                Ok(()) => {
+                    self.send_to_gui(LedgerMessage::DisplayMessage("".to_string(), false));
+                    self.send_to_gui(LedgerMessage::DeviceIsGenuine(Some(true)));
+                }
+                Err(e @ GenuineCheckErr::NotGenuine(_)) => {
+                    self.send_to_gui(LedgerMessage::DisplayMessage(e.to_string(), true));
+                    self.send_to_gui(LedgerMessage::DeviceIsGenuine(Some(false)));
+                }
+                Err(e) => {
+                    self.send_to_gui(LedgerMessage::DisplayMessage(e.to_string(), true));
+                    self.send_to_gui(LedgerMessage::DeviceIsGenuine(None));
+                }
             }
         } else {
             log::info!("Cannot connect to device!");
@@ -544,6 +630,145 @@ impl LedgerService {
         log::info!("LedgerService::genuine_check() ended!");
     }
 
+    fn plan_upgrade(&mut self) {
+        log::info!("LedgerService::plan_upgrade()");
+        let sender = self.sender.clone();
+        let Some(transport) = self.connect() else {
+            Self::display_message(&sender, "Cannot connect to device!", true);
+            return;
+        };
+        Self::display_message(
+            &sender,
+            "Planning the upgrade. Please confirm listing the apps on device.",
+            false,
+        );
+        let plan = DeviceInfo::new(&transport).and_then(|info| {
+            let installed = list_installed_apps_raw(&transport)?;
+            plan_upgrade(&info, &installed, &self.api_config)
+        });
+        match plan {
+            Ok(plan) => {
+                self.send_to_gui(LedgerMessage::UpgradePlan(Some(upgrade_plan_lines(&plan))));
+                self.upgrade_plan = Some(plan);
+                Self::display_message(&sender, "", false);
+            }
+            Err(e) => Self::display_message(
+                &sender,
+                &format!("Error planning the upgrade: {}.", e),
+                true,
+            ),
+        }
+    }
+
+    // Wait for the device to restart and be ready for this step of the upgrade.
+    fn reconnect(&self, step: &UpgradeStep) -> Option<TransportNativeHID> {
+        let start = Instant::now();
+        while start.elapsed() < RESTART_TIMEOUT {
+            thread::sleep(Duration::from_secs(1));
+            if let Some(transport) = self.connect() {
+                if DeviceInfo::new(&transport).is_ok_and(|info| step.is_ready(&info)) {
+                    return Some(transport);
+                }
+            }
+        }
+        None
+    }
+
+    fn run_upgrade(&mut self) {
+        log::info!("LedgerService::run_upgrade()");
+        let sender = self.sender.clone();
+        let Some(plan) = self.upgrade_plan.take() else {
+            return;
+        };
+        self.send_to_gui(LedgerMessage::UpgradePlan(None));
+        let mut transport = self.connect();
+
+        // The firmware update removes the apps: save them first, to reinstall any the upgrade
+        // didn't. The snapshot is saved at the same place as the CLI's, for its restore command.
+        let snapshot = if plan.firmware_version.is_some() {
+            let Some(transport) = &transport else {
+                Self::display_message(&sender, "Cannot connect to device!", true);
+                return;
+            };
+            let snapshot = match snapshot::take(transport, &self.api_config) {
+                Ok(s) => s,
+                Err(e) => {
+                    Self::display_message(
+                        &sender,
+                        &format!("Error listing the installed apps, not upgrading: {}.", e),
+                        true,
+                    );
+                    return;
+                }
+            };
+            if let Some(path) = datadir().map(|d| d.join("apps_snapshot.json")) {
+                if let Err(e) = snapshot::save(&path, &snapshot) {
+                    log::error!("Error saving the snapshot of the installed apps: {}", e);
+                }
+            }
+            Some(snapshot)
+        } else {
+            None
+        };
+
+        for (i, step) in plan.steps.iter().enumerate() {
+            if i > 0 && plan.steps[i - 1].restarts_device() {
+                drop(transport);
+                Self::display_message(
+                    &sender,
+                    "Waiting for the device to restart. Follow the instructions on device.",
+                    false,
+                );
+                transport = self.reconnect(step);
+            }
+            let Some(transport) = &transport else {
+                Self::display_message(&sender, "Cannot connect to device!", true);
+                return;
+            };
+            Self::display_message(
+                &sender,
+                &format!("Step {}/{}: {}...", i + 1, plan.steps.len(), step),
+                false,
+            );
+            if let Err(e) = run_upgrade_step(transport, step, &self.api_config) {
+                let hint = if snapshot.is_some() {
+                    " The installed apps were saved, reinstall them with the restore command."
+                } else {
+                    ""
+                };
+                Self::display_message(
+                    &sender,
+                    &format!("Error at step {} ({}): {}.{}", i + 1, step, e, hint),
+                    true,
+                );
+                return;
+            }
+        }
+        if let (Some(snapshot), Some(transport)) = (&snapshot, &transport) {
+            Self::display_message(&sender, "Reinstalling the missing apps...", false);
+            match snapshot::restore(transport, snapshot, &self.api_config) {
+                Ok(report) => {
+                    for app in report.not_restored() {
+                        Self::display_message(
+                            &sender,
+                            &format!("{}: {}.", app.name, app.outcome),
+                            true,
+                        );
+                    }
+                }
+                Err(e) => Self::display_message(
+                    &sender,
+                    &format!("Error reinstalling the apps: {}.", e),
+                    true,
+                ),
+            }
+        }
+        drop(transport);
+        self.device_version = None;
+        self.poll();
+        Self::display_message(&sender, "The device is up to date.", false);
+    }
+
     fn display_message(sender: &Sender<LedgerMessage>, msg: &str, alarm: bool) {
         let sender = sender.clone();
         let msg = LedgerMessage::DisplayMessage(msg.to_string(), alarm);
@@ -570,6 +795,8 @@ impl ServiceFn<LedgerMessage, Sender<LedgerMessage>> for LedgerService {
             testnet_version: Version::None,
             last_mainnet: Version::None,
             last_testnet: Version::None,
+            api_config: api_config(privacy_from_env()),
+            upgrade_plan: None,
         }
     }
 
@@ -40,3 +40,8 @@ pub const GREEN: Color = Color::from_rgb(
     0xFF as f32 / 255.0,
     0x66 as f32 / 255.0,
 );
+pub const ORANGE: Color = Color::from_rgb(
+    0xFF as f32 / 255.0,
+    0xA7 as f32 / 255.0,
+    0x00 as f32 / 255.0,
+);
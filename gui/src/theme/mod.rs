@@ -3,7 +3,7 @@ pub mod color;
 use iced::{
     application,
     widget::{
-        self, button, container, progress_bar,
+        self, button, checkbox, container, progress_bar,
         rule::{Appearance, FillMode},
         slider, text, text_input,
     },
@@ -279,3 +279,47 @@ impl widget::rule::StyleSheet for Theme {
         }
     }
 }
+
+#[derive(Debug, Copy, Clone, Default)]
+pub enum Checkbox {
+    #[default]
+    Simple,
+}
+
+impl checkbox::StyleSheet for Theme {
+    type Style = Checkbox;
+
+    fn active(&self, _style: &Self::Style, is_checked: bool) -> checkbox::Appearance {
+        checkbox::Appearance {
+            background: if is_checked {
+                color::GREEN.into()
+            } else {
+                iced::Color::TRANSPARENT.into()
+            },
+            icon_color: color::LIGHT_BLACK,
+            border: iced::Border {
+                color: color::GREY_3,
+                width: 1.0,
+                radius: 4.0.into(),
+            },
+            text_color: Some(color::GREY_3),
+        }
+    }
+
+    fn hovered(&self, _style: &Self::Style, is_checked: bool) -> checkbox::Appearance {
+        checkbox::Appearance {
+            background: if is_checked {
+                color::GREEN.into()
+            } else {
+                iced::Color::TRANSPARENT.into()
+            },
+            icon_color: color::LIGHT_BLACK,
+            border: iced::Border {
+                color: color::GREEN,
+                width: 1.0,
+                radius: 4.0.into(),
+            },
+            text_color: Some(color::WHITE),
+        }
+    }
+}
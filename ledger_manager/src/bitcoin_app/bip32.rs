@@ -0,0 +1,211 @@
+//! BIP32 derivation paths and key origins.
+
+use bitcoin_hashes::{sha512, Hash, HashEngine, Hmac, HmacEngine};
+use secp256k1::{PublicKey, Scalar, Secp256k1};
+
+use std::{error, fmt, str};
+
+/// Derivation steps at or above this index are hardened.
+pub const HARDENED_INDEX: u32 = 0x8000_0000;
+
+/// A BIP32 derivation path, such as `m/84'/0'/0'`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl DerivationPath {
+    /// The steps of this path.
+    pub fn steps(&self) -> &[u32] {
+        &self.0
+    }
+
+    /// Serialize this path the way the Bitcoin app expects it: the number of steps on one byte,
+    /// followed by each step as a big-endian u32.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ser = Vec::with_capacity(1 + self.0.len() * 4);
+        ser.push(self.0.len() as u8);
+        for step in &self.0 {
+            ser.extend_from_slice(&step.to_be_bytes());
+        }
+        ser
+    }
+}
+
+impl str::FromStr for DerivationPath {
+    type Err = Box<dyn error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s
+            .strip_prefix("m/")
+            .or_else(|| if s == "m" { Some("") } else { None })
+            .unwrap_or(s);
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let mut steps = Vec::new();
+        for step in s.split('/') {
+            let (index, hardened) = match step
+                .strip_suffix('\'')
+                .or_else(|| step.strip_suffix('h'))
+                .or_else(|| step.strip_suffix('H'))
+            {
+                Some(index) => (index, true),
+                None => (step, false),
+            };
+            let index: u32 = index
+                .parse()
+                .map_err(|_| format!("Invalid derivation step '{}'.", step))?;
+            if index >= HARDENED_INDEX {
+                return Err(format!("Derivation step '{}' is out of range.", step).into());
+            }
+            steps.push(if hardened {
+                index + HARDENED_INDEX
+            } else {
+                index
+            });
+        }
+
+        if steps.len() > 8 {
+            return Err("The Bitcoin app doesn't support derivation paths over 8 steps.".into());
+        }
+        Ok(Self(steps))
+    }
+}
+
+// Written without the leading "m/", as it appears in a key origin.
+fn fmt_steps(steps: &[u32], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (i, step) in steps.iter().enumerate() {
+        if i > 0 {
+            write!(f, "/")?;
+        }
+        if *step >= HARDENED_INDEX {
+            write!(f, "{}'", step - HARDENED_INDEX)?;
+        } else {
+            write!(f, "{}", step)?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        if !self.0.is_empty() {
+            write!(f, "/")?;
+        }
+        fmt_steps(&self.0, f)
+    }
+}
+
+/// An extended public key along with its origin: the fingerprint of the master key and the path
+/// it was derived at. Displayed as a descriptor key expression, such as
+/// `[f5acc2fd/84'/0'/0']xpub...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyOrigin {
+    pub fingerprint: [u8; 4],
+    pub path: DerivationPath,
+    pub xpub: String,
+}
+
+impl fmt::Display for KeyOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}", hex::encode(self.fingerprint))?;
+        if !self.path.0.is_empty() {
+            write!(f, "/")?;
+            fmt_steps(&self.path.0, f)?;
+        }
+        write!(f, "]{}", self.xpub)
+    }
+}
+
+// The version bytes of the extended public keys for the test networks.
+const TESTNET_XPUB_VERSION: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+
+// Decode a BIP32-serialized extended public key.
+fn decode_xpub(xpub: &str) -> Result<[u8; 78], Box<dyn error::Error>> {
+    let data = super::encoding::base58check_decode(xpub)?;
+    data.try_into()
+        .map_err(|_| "Invalid extended public key length.".into())
+}
+
+/// The compressed public key encoded in this (BIP32-serialized) extended public key.
+pub fn xpub_public_key(xpub: &str) -> Result<[u8; 33], Box<dyn error::Error>> {
+    Ok(decode_xpub(xpub)?[45..].try_into()?)
+}
+
+/// Whether this extended public key is for the test networks (a `tpub`).
+pub fn xpub_is_testnet(xpub: &str) -> Result<bool, Box<dyn error::Error>> {
+    Ok(decode_xpub(xpub)?[..4] == TESTNET_XPUB_VERSION)
+}
+
+/// The compressed public key derived from this extended public key along these unhardened steps.
+pub fn derive_public_key(xpub: &str, steps: &[u32]) -> Result<[u8; 33], Box<dyn error::Error>> {
+    let data = decode_xpub(xpub)?;
+    let secp = Secp256k1::verification_only();
+    let mut chain_code: [u8; 32] = data[13..45].try_into()?;
+    let mut key = PublicKey::from_slice(&data[45..])?;
+    for step in steps {
+        if *step >= HARDENED_INDEX {
+            return Err("Can't derive a hardened step from an extended public key.".into());
+        }
+        let mut engine = HmacEngine::<sha512::Hash>::new(&chain_code);
+        engine.input(&key.serialize());
+        engine.input(&step.to_be_bytes());
+        let hmac = Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+        let tweak = Scalar::from_be_bytes(hmac[..32].try_into()?)?;
+        key = key.add_exp_tweak(&secp, &tweak)?;
+        chain_code = hmac[32..].try_into()?;
+    }
+    Ok(key.serialize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The BIP84 and BIP86 accounts of the test mnemonic "abandon abandon ... about".
+    const BIP84_XPUB: &str = "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V";
+    const BIP86_XPUB: &str = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+
+    #[test]
+    fn paths() {
+        let path: DerivationPath = "m/48'/1h/0'/2'".parse().unwrap();
+        assert_eq!(
+            path.steps(),
+            [
+                48 + HARDENED_INDEX,
+                1 + HARDENED_INDEX,
+                HARDENED_INDEX,
+                2 + HARDENED_INDEX
+            ]
+        );
+        assert_eq!(path.to_string(), "m/48'/1'/0'/2'");
+        assert!("m/84'/x".parse::<DerivationPath>().is_err());
+    }
+
+    #[test]
+    fn derivation() {
+        assert_eq!(
+            hex::encode(derive_public_key(BIP84_XPUB, &[0, 0]).unwrap()),
+            "0330d54fd0dd420a6e5f8d3624f5f3482cae350f79d5f0753bf5beef9c2d91af3c"
+        );
+        assert_eq!(
+            hex::encode(derive_public_key(BIP84_XPUB, &[1, 0]).unwrap()),
+            "03025324888e429ab8e3dbaf1f7802648b9cd01e9b418485c5fa4c1b9b5700e1a6"
+        );
+        assert_eq!(
+            hex::encode(&derive_public_key(BIP86_XPUB, &[0, 0]).unwrap()[1..]),
+            "cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115"
+        );
+        assert_eq!(
+            derive_public_key(BIP84_XPUB, &[]).unwrap(),
+            xpub_public_key(BIP84_XPUB).unwrap()
+        );
+        assert!(derive_public_key(BIP84_XPUB, &[HARDENED_INDEX]).is_err());
+        assert!(derive_public_key(&BIP84_XPUB[..100], &[0]).is_err());
+
+        assert!(!xpub_is_testnet(BIP84_XPUB).unwrap());
+        assert!(xpub_is_testnet("tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M").unwrap());
+    }
+}
@@ -0,0 +1,344 @@
+//! The client side of the interactive protocol of the Bitcoin app.
+//!
+//! When the app needs data it can't fit in an APDU (a descriptor template, the keys of a policy,
+//! the fields of a PSBT...), it interrupts its execution and sends a request to the client. The
+//! client answers from the data it was told about beforehand. See
+//! https://github.com/LedgerHQ/app-bitcoin-new/blob/develop/doc/bitcoin.md#client-commands.
+
+use super::{
+    encoding::{read_varint, sha256, write_varint},
+    merkle::MerkleTree,
+};
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    error,
+};
+
+// https://github.com/LedgerHQ/app-bitcoin-new/blob/develop/doc/bitcoin.md#client-commands
+const YIELD: u8 = 0x10;
+const GET_PREIMAGE: u8 = 0x40;
+const GET_MERKLE_LEAF_PROOF: u8 = 0x41;
+const GET_MERKLE_LEAF_INDEX: u8 = 0x42;
+const GET_MORE_ELEMENTS: u8 = 0xa0;
+
+/// Answers the requests sent by the app while it executes a command.
+#[derive(Debug, Default)]
+pub struct ClientCommandInterpreter {
+    known_preimages: HashMap<[u8; 32], Vec<u8>>,
+    known_trees: HashMap<[u8; 32], MerkleTree>,
+    // Elements that didn't fit in a response, for the app to query with GET_MORE_ELEMENTS.
+    queue: VecDeque<Vec<u8>>,
+    yielded: Vec<Vec<u8>>,
+}
+
+impl ClientCommandInterpreter {
+    /// Make this preimage available to the app, which will query it by its SHA256.
+    pub fn add_known_preimage(&mut self, preimage: Vec<u8>) {
+        self.known_preimages.insert(sha256(&preimage), preimage);
+    }
+
+    /// Make this list of elements available to the app, which will query them by the root of the
+    /// Merkle tree committing to them.
+    pub fn add_known_list<E: AsRef<[u8]>>(&mut self, elements: &[E]) {
+        for element in elements {
+            let mut preimage = vec![0x00];
+            preimage.extend_from_slice(element.as_ref());
+            self.add_known_preimage(preimage);
+        }
+        let tree = MerkleTree::from_elements(elements);
+        self.known_trees.insert(tree.root(), tree);
+    }
+
+    /// Make this key-value mapping available to the app, as the lists of its sorted keys and
+    /// values.
+    pub fn add_known_mapping(&mut self, mapping: &BTreeMap<Vec<u8>, Vec<u8>>) {
+        let keys: Vec<_> = mapping.keys().collect();
+        let values: Vec<_> = mapping.values().collect();
+        self.add_known_list(&keys);
+        self.add_known_list(&values);
+    }
+
+    /// The data yielded by the app during the execution of the command, in order.
+    pub fn yielded(&self) -> &[Vec<u8>] {
+        &self.yielded
+    }
+
+    /// Compute the response to a request from the app.
+    pub fn execute(&mut self, request: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        let (code, req) = request
+            .split_first()
+            .ok_or("Empty client command from the Bitcoin app.")?;
+        match *code {
+            YIELD => {
+                self.yielded.push(req.to_vec());
+                Ok(Vec::new())
+            }
+            GET_PREIMAGE => self.get_preimage(req),
+            GET_MERKLE_LEAF_PROOF => self.get_merkle_leaf_proof(req),
+            GET_MERKLE_LEAF_INDEX => self.get_merkle_leaf_index(req),
+            GET_MORE_ELEMENTS => self.get_more_elements(req),
+            c => Err(format!("Unknown client command {:#04x} from the Bitcoin app.", c).into()),
+        }
+    }
+
+    fn get_preimage(&mut self, req: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        if req.len() != 33 || req[0] != 0 {
+            return Err("Invalid GET_PREIMAGE request.".into());
+        }
+        let hash: [u8; 32] = req[1..].try_into()?;
+        let preimage = self
+            .known_preimages
+            .get(&hash)
+            .ok_or("The Bitcoin app requested an unknown preimage.")?;
+
+        // What doesn't fit in the response is queued, byte by byte, for GET_MORE_ELEMENTS.
+        let mut resp = write_varint(preimage.len() as u64);
+        let payload_size = (255 - resp.len() - 1).min(preimage.len());
+        resp.push(payload_size as u8);
+        resp.extend_from_slice(&preimage[..payload_size]);
+        self.queue
+            .extend(preimage[payload_size..].iter().map(|b| vec![*b]));
+        Ok(resp)
+    }
+
+    fn get_merkle_leaf_proof(&mut self, req: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        if req.len() < 32 {
+            return Err("Invalid GET_MERKLE_LEAF_PROOF request.".into());
+        }
+        let root: [u8; 32] = req[..32].try_into()?;
+        let (tree_size, read) =
+            read_varint(&req[32..]).ok_or("Invalid GET_MERKLE_LEAF_PROOF request.")?;
+        let (leaf_index, _) =
+            read_varint(&req[32 + read..]).ok_or("Invalid GET_MERKLE_LEAF_PROOF request.")?;
+        let tree = self
+            .known_trees
+            .get(&root)
+            .ok_or("The Bitcoin app requested a proof for an unknown Merkle tree.")?;
+        if tree.len() as u64 != tree_size {
+            return Err("The Bitcoin app requested a proof with an invalid tree size.".into());
+        }
+        let leaf = tree
+            .leaf(leaf_index as usize)
+            .ok_or("The Bitcoin app requested a proof for an out of range leaf.")?;
+
+        let proof = tree.prove_leaf(leaf_index as usize);
+        let n_response_elements = ((255 - 32 - 1 - 1) / 32).min(proof.len());
+        let mut resp = leaf.to_vec();
+        resp.push(proof.len() as u8);
+        resp.push(n_response_elements as u8);
+        for hash in &proof[..n_response_elements] {
+            resp.extend_from_slice(hash);
+        }
+        self.queue
+            .extend(proof[n_response_elements..].iter().map(|h| h.to_vec()));
+        Ok(resp)
+    }
+
+    fn get_merkle_leaf_index(&mut self, req: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        if req.len() != 64 {
+            return Err("Invalid GET_MERKLE_LEAF_INDEX request.".into());
+        }
+        let root: [u8; 32] = req[..32].try_into()?;
+        let leaf: [u8; 32] = req[32..].try_into()?;
+        let tree = self
+            .known_trees
+            .get(&root)
+            .ok_or("The Bitcoin app requested a leaf index for an unknown Merkle tree.")?;
+
+        Ok(match tree.leaf_index(&leaf) {
+            Some(i) => {
+                let mut resp = vec![1];
+                resp.extend(write_varint(i as u64));
+                resp
+            }
+            None => vec![0, 0],
+        })
+    }
+
+    fn get_more_elements(&mut self, req: &[u8]) -> Result<Vec<u8>, Box<dyn error::Error>> {
+        if !req.is_empty() {
+            return Err("Invalid GET_MORE_ELEMENTS request.".into());
+        }
+        let element_len = self
+            .queue
+            .front()
+            .ok_or("The Bitcoin app requested more elements but none are queued.")?
+            .len();
+        if self.queue.iter().any(|e| e.len() != element_len) {
+            return Err("Queued elements for the Bitcoin app have different lengths.".into());
+        }
+
+        let mut elements = Vec::new();
+        while 2 + (elements.len() + 1) * element_len <= 255 {
+            match self.queue.pop_front() {
+                Some(e) => elements.push(e),
+                None => break,
+            }
+        }
+        let mut resp = vec![elements.len() as u8, element_len as u8];
+        resp.extend(elements.into_iter().flatten());
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bitcoin_app::merkle::element_hash;
+
+    fn request(code: u8, data: &[&[u8]]) -> Vec<u8> {
+        let mut req = vec![code];
+        for d in data {
+            req.extend_from_slice(d);
+        }
+        req
+    }
+
+    #[test]
+    fn yield_data() {
+        let mut client = ClientCommandInterpreter::default();
+        assert_eq!(client.execute(&[YIELD, 1, 2, 3]).unwrap(), b"");
+        assert_eq!(client.execute(&[YIELD]).unwrap(), b"");
+        assert_eq!(client.yielded(), [vec![1, 2, 3], vec![]]);
+    }
+
+    #[test]
+    fn preimages() {
+        let mut client = ClientCommandInterpreter::default();
+        client.add_known_preimage(b"abc".to_vec());
+        let resp = client
+            .execute(&request(GET_PREIMAGE, &[&[0], &sha256(b"abc")]))
+            .unwrap();
+        assert_eq!(resp, [3, 3, b'a', b'b', b'c']);
+
+        // Too long to fit in a response: the rest is queued byte by byte.
+        let preimage: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        client.add_known_preimage(preimage.clone());
+        let resp = client
+            .execute(&request(GET_PREIMAGE, &[&[0], &sha256(&preimage)]))
+            .unwrap();
+        assert_eq!(resp[..4], [0xfd, 0x2c, 0x01, 251]);
+        assert_eq!(resp[4..], preimage[..251]);
+        let resp = client.execute(&[GET_MORE_ELEMENTS]).unwrap();
+        assert_eq!(resp[..2], [49, 1]);
+        assert_eq!(resp[2..], preimage[251..]);
+        assert!(client.execute(&[GET_MORE_ELEMENTS]).is_err());
+
+        for invalid in [
+            request(GET_PREIMAGE, &[&[0], &sha256(b"abcd")]),
+            request(GET_PREIMAGE, &[&[1], &sha256(b"abc")]),
+            request(GET_PREIMAGE, &[&[0], &sha256(b"abc")[..31]]),
+            request(GET_PREIMAGE, &[]),
+        ] {
+            assert!(client.execute(&invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn merkle_proofs() {
+        let elements: Vec<[u8; 2]> = (0..100u16).map(|i| i.to_be_bytes()).collect();
+        let mut client = ClientCommandInterpreter::default();
+        client.add_known_list(&elements);
+        let root = hex::decode("4f5f5110917344b5b89ac45988a09c4cd13d0683a84850f18e06d2991de8850a")
+            .unwrap();
+        let proof = [
+            "ef98e3d170fd59ca02abe60634ff1ef474af3b22c5209483b23d919c15d02b82",
+            "71454beee7f1effb57650a86dc427ba1b1daa31dcac4f4bcf9d20f1ea8cddca5",
+            "26e76a6f9de6ec475e4208feb94b20aa6f0395b1efef3ac17c6add059bd63c19",
+            "00e009b876f76516ee8170f7a76218325754740d4acd020305c152fae8721292",
+            "cc184f7ed7d5e24270099665629f88b3e2b4d8535ee517fe98812b22c0c96178",
+            "20088c7aff1ab0cd474b2c71e6ef48e90a645c3eecf46ec5b8bd2548d758e414",
+            "a231db619fce5a2bd5efa31b4c3b2300e8a3f22cd7fa396eaf0dafaa6fb052ee",
+        ]
+        .map(|h| hex::decode(h).unwrap());
+
+        // Only 6 hashes of the proof fit in the response.
+        let leaf = element_hash(&elements[37]);
+        let resp = client
+            .execute(&request(GET_MERKLE_LEAF_PROOF, &[&root, &[100], &[37]]))
+            .unwrap();
+        assert_eq!(resp[..32], leaf);
+        assert_eq!(resp[32..34], [7, 6]);
+        assert_eq!(resp[34..], proof[..6].concat());
+        assert_eq!(
+            client.execute(&[GET_MORE_ELEMENTS]).unwrap(),
+            [&[1, 32][..], &proof[6]].concat()
+        );
+
+        // The leaves' preimages are known too.
+        assert_eq!(
+            client
+                .execute(&request(GET_PREIMAGE, &[&[0], &leaf]))
+                .unwrap(),
+            [3, 3, 0x00, 0x00, 37]
+        );
+
+        assert_eq!(
+            client
+                .execute(&request(GET_MERKLE_LEAF_INDEX, &[&root, &leaf]))
+                .unwrap(),
+            [1, 37]
+        );
+        assert_eq!(
+            client
+                .execute(&request(GET_MERKLE_LEAF_INDEX, &[&root, &[0; 32]]))
+                .unwrap(),
+            [0, 0]
+        );
+
+        for invalid in [
+            // Wrong tree size.
+            request(GET_MERKLE_LEAF_PROOF, &[&root, &[99], &[37]]),
+            // Leaf out of range.
+            request(GET_MERKLE_LEAF_PROOF, &[&root, &[100], &[100]]),
+            // Unknown tree.
+            request(GET_MERKLE_LEAF_PROOF, &[&[0; 32], &[100], &[37]]),
+            request(GET_MERKLE_LEAF_PROOF, &[&root, &[100]]),
+            request(GET_MERKLE_LEAF_PROOF, &[&root[..31]]),
+            request(GET_MERKLE_LEAF_INDEX, &[&[0; 32], &leaf]),
+            request(GET_MERKLE_LEAF_INDEX, &[&root, &leaf[..31]]),
+        ] {
+            assert!(client.execute(&invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn mappings() {
+        let map = BTreeMap::from([
+            (vec![0x01], b"a".to_vec()),
+            (vec![0x00, 0x02], b"bc".to_vec()),
+        ]);
+        let mut client = ClientCommandInterpreter::default();
+        client.add_known_mapping(&map);
+        // The roots of the keys and values sorted by key, as committed to.
+        let commitment = crate::bitcoin_app::merkle::merkleized_map_commitment(&map);
+        let (keys_root, values_root) = commitment[1..].split_at(32);
+        let leaf = element_hash(b"bc");
+        assert_eq!(
+            client
+                .execute(&request(GET_MERKLE_LEAF_INDEX, &[values_root, &leaf]))
+                .unwrap(),
+            [1, 0]
+        );
+        assert_eq!(
+            client
+                .execute(&request(
+                    GET_MERKLE_LEAF_INDEX,
+                    &[keys_root, &element_hash(&[0x01])]
+                ))
+                .unwrap(),
+            [1, 1]
+        );
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let mut client = ClientCommandInterpreter::default();
+        assert!(client.execute(&[]).is_err());
+        assert!(client.execute(&[0x99]).is_err());
+        assert!(client.execute(&[GET_MORE_ELEMENTS]).is_err());
+        assert!(client.execute(&[GET_MORE_ELEMENTS, 0]).is_err());
+    }
+}
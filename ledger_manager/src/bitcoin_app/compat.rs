@@ -0,0 +1,445 @@
+//! Which descriptors the installed Bitcoin app can register and sign for.
+//!
+//! See https://github.com/LedgerHQ/app-bitcoin-new/blob/develop/CHANGELOG.md for the features
+//! introduced by each release.
+
+use std::{cmp, error, fmt, str};
+
+/// The version of a Bitcoin app, as reported by the Ledger API or by the app itself.
+///
+/// Versions are ordered as in semantic versioning: a pre-release (as in `2.2.0-rc1`) comes before
+/// the release, and is never equal to it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AppVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// The pre-release identifiers, if any, without the leading dash.
+    pub pre: Option<String>,
+}
+
+impl AppVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            pre: None,
+        }
+    }
+
+    /// Whether this is a pre-release, such as a release candidate.
+    pub fn is_pre_release(&self) -> bool {
+        self.pre.is_some()
+    }
+}
+
+// Compare two pre-release identifiers: numeric ones numerically, and before alphanumeric ones.
+fn cmp_pre_identifier(a: &str, b: &str) -> cmp::Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => cmp::Ordering::Less,
+        (Err(_), Ok(_)) => cmp::Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+impl Ord for AppVersion {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (&self.pre, &other.pre) {
+                (None, None) => cmp::Ordering::Equal,
+                (None, Some(_)) => cmp::Ordering::Greater,
+                (Some(_), None) => cmp::Ordering::Less,
+                (Some(a), Some(b)) => {
+                    let (mut a, mut b) = (a.split('.'), b.split('.'));
+                    loop {
+                        match (a.next(), b.next()) {
+                            (None, None) => return cmp::Ordering::Equal,
+                            (None, Some(_)) => return cmp::Ordering::Less,
+                            (Some(_), None) => return cmp::Ordering::Greater,
+                            (Some(a), Some(b)) => match cmp_pre_identifier(a, b) {
+                                cmp::Ordering::Equal => {}
+                                ord => return ord,
+                            },
+                        }
+                    }
+                }
+            })
+    }
+}
+
+impl PartialOrd for AppVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl str::FromStr for AppVersion {
+    type Err = Box<dyn error::Error>;
+
+    /// Parse a version such as `2.1.3` or `2.2.0-rc1`. Build metadata (after a `+`) is ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("Invalid app version '{}'.", s);
+        let without_build = s.split('+').next().unwrap_or(s);
+        let (version, pre) = match without_build.split_once('-') {
+            Some((_, pre)) if pre.is_empty() || pre.split('.').any(|id| id.is_empty()) => {
+                return Err(invalid().into())
+            }
+            Some((version, pre)) => (version, Some(pre.to_string())),
+            None => (without_build, None),
+        };
+        let mut parts = version.split('.').map(|p| p.parse::<u32>());
+        let (major, minor, patch) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => (major, minor, patch),
+            (Some(Ok(major)), Some(Ok(minor)), None, None) => (major, minor, 0),
+            _ => return Err(invalid().into()),
+        };
+        Ok(Self {
+            major,
+            minor,
+            patch,
+            pre,
+        })
+    }
+}
+
+impl fmt::Display for AppVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if let Some(pre) = &self.pre {
+            write!(f, "-{}", pre)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first version of the app speaking the client protocol implemented in this module, with
+/// version 2 wallet policies. Older versions (including the "Bitcoin Legacy" app) can't be used
+/// at all.
+pub const MIN_CLIENT_VERSION: AppVersion = AppVersion::new(2, 1, 0);
+
+/// The protocol spoken by a Bitcoin app.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientProtocol {
+    /// The protocol of the apps before version 2.0.0, still spoken by the "Bitcoin Legacy" app. It
+    /// isn't supported by this module.
+    Legacy,
+    /// The protocol introduced with version 2.0.0, implemented by this module.
+    V2,
+}
+
+impl ClientProtocol {
+    /// The protocol spoken by the app with this name and version.
+    pub fn of(app_name: &str, app_version: &AppVersion) -> Self {
+        if app_name.to_lowercase().contains("legacy") || app_version.major < 2 {
+            Self::Legacy
+        } else {
+            Self::V2
+        }
+    }
+}
+
+impl fmt::Display for ClientProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Legacy => write!(f, "legacy"),
+            Self::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+/// A descriptor feature which isn't supported by every version of the app.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PolicyFeature {
+    /// A single key `pkh()`, `wpkh()` or `sh(wpkh())` descriptor.
+    SingleSig,
+    /// A `multi()` or `sortedmulti()` under `sh()`, `wsh()` or `sh(wsh())`.
+    Multisig,
+    /// A `tr()` descriptor with only a key path.
+    Taproot,
+    /// Any other miniscript under `wsh()` or `sh(wsh())`.
+    Miniscript,
+    /// A `tr()` descriptor with a tree of scripts.
+    TaprootMiniscript,
+}
+
+impl PolicyFeature {
+    const ALL: [Self; 5] = [
+        Self::SingleSig,
+        Self::Multisig,
+        Self::Taproot,
+        Self::Miniscript,
+        Self::TaprootMiniscript,
+    ];
+
+    /// The first version of the app supporting this feature.
+    pub fn min_version(&self) -> AppVersion {
+        match self {
+            Self::SingleSig | Self::Multisig | Self::Taproot => AppVersion::new(2, 0, 0),
+            Self::Miniscript => AppVersion::new(2, 1, 0),
+            Self::TaprootMiniscript => AppVersion::new(2, 2, 0),
+        }
+    }
+}
+
+impl fmt::Display for PolicyFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SingleSig => write!(f, "single-sig"),
+            Self::Multisig => write!(f, "multisig"),
+            Self::Taproot => write!(f, "taproot"),
+            Self::Miniscript => write!(f, "miniscript"),
+            Self::TaprootMiniscript => write!(f, "taproot miniscript"),
+        }
+    }
+}
+
+// Strip "name(" and the matching closing parenthesis.
+fn strip_fragment<'a>(s: &'a str, name: &str) -> Option<&'a str> {
+    s.strip_prefix(name)?.strip_prefix('(')?.strip_suffix(')')
+}
+
+// Whether there is a comma outside of any parenthesis or brace in this expression.
+fn has_top_level_comma(s: &str) -> bool {
+    let mut depth = 0i32;
+    for c in s.chars() {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            ',' if depth == 0 => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn is_multisig(s: &str) -> bool {
+    s.starts_with("multi(") || s.starts_with("sortedmulti(")
+}
+
+/// The feature used by this descriptor, or descriptor template of a wallet policy.
+pub fn policy_feature(descriptor: &str) -> Result<PolicyFeature, Box<dyn error::Error>> {
+    let desc: String = descriptor
+        .split('#')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+
+    let feature = if let Some(inner) = strip_fragment(&desc, "tr") {
+        if has_top_level_comma(inner) {
+            PolicyFeature::TaprootMiniscript
+        } else {
+            PolicyFeature::Taproot
+        }
+    } else if let Some(inner) = strip_fragment(&desc, "wsh")
+        .or_else(|| strip_fragment(&desc, "sh").and_then(|s| strip_fragment(s, "wsh")))
+    {
+        if is_multisig(inner) {
+            PolicyFeature::Multisig
+        } else {
+            PolicyFeature::Miniscript
+        }
+    } else if strip_fragment(&desc, "pkh").is_some()
+        || strip_fragment(&desc, "wpkh").is_some()
+        || strip_fragment(&desc, "sh")
+            .and_then(|s| strip_fragment(s, "wpkh"))
+            .is_some()
+    {
+        PolicyFeature::SingleSig
+    } else if strip_fragment(&desc, "sh").is_some_and(is_multisig) {
+        PolicyFeature::Multisig
+    } else {
+        return Err(format!("Unsupported descriptor '{}'.", descriptor).into());
+    };
+    Ok(feature)
+}
+
+/// Whether an app can register and sign for a descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicySupport {
+    /// The feature used by the descriptor.
+    pub feature: PolicyFeature,
+    /// The first version of the app that can be used with this descriptor.
+    pub required_version: AppVersion,
+    /// The version of the app that was checked.
+    pub app_version: AppVersion,
+}
+
+impl PolicySupport {
+    /// Whether the app can register and sign for the descriptor.
+    pub fn is_supported(&self) -> bool {
+        self.app_version >= self.required_version
+    }
+
+    /// Whether the app must be updated to be used with the descriptor.
+    pub fn update_required(&self) -> bool {
+        !self.is_supported()
+    }
+}
+
+/// Check whether this version of the app can register and sign for this descriptor, or
+/// descriptor template of a wallet policy.
+pub fn check_policy_support(
+    descriptor: &str,
+    app_version: &AppVersion,
+) -> Result<PolicySupport, Box<dyn error::Error>> {
+    let feature = policy_feature(descriptor)?;
+    Ok(PolicySupport {
+        feature,
+        required_version: cmp::max(feature.min_version(), MIN_CLIENT_VERSION),
+        app_version: app_version.clone(),
+    })
+}
+
+/// The features this version of the app doesn't support. Empty if it is recent enough for any
+/// descriptor.
+pub fn unsupported_features(app_version: &AppVersion) -> Vec<PolicyFeature> {
+    PolicyFeature::ALL
+        .into_iter()
+        .filter(|f| cmp::max(f.min_version(), MIN_CLIENT_VERSION) > *app_version)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn app_versions() {
+        assert_eq!(
+            "2.1.3".parse::<AppVersion>().unwrap(),
+            AppVersion::new(2, 1, 3)
+        );
+        assert_eq!(
+            " 2.2".parse::<AppVersion>().unwrap(),
+            AppVersion::new(2, 2, 0)
+        );
+        assert_eq!(
+            "2.1.3+build.5".parse::<AppVersion>().unwrap(),
+            AppVersion::new(2, 1, 3)
+        );
+        for invalid in [
+            "",
+            "2",
+            "2.x.0",
+            "2.1.3.4",
+            "v2.1.3",
+            "2.2.0-",
+            "2.2.0-rc..1",
+        ] {
+            assert!(invalid.parse::<AppVersion>().is_err(), "{}", invalid);
+        }
+        assert!(AppVersion::new(2, 10, 0) > AppVersion::new(2, 9, 9));
+    }
+
+    #[test]
+    fn pre_releases() {
+        let rc1 = "2.2.0-rc1".parse::<AppVersion>().unwrap();
+        assert_eq!(rc1.pre.as_deref(), Some("rc1"));
+        assert!(rc1.is_pre_release());
+        assert_eq!(rc1.to_string(), "2.2.0-rc1");
+        assert_ne!(rc1, AppVersion::new(2, 2, 0));
+
+        // As in the example of the semantic versioning specification.
+        let ordered = [
+            "2.1.9",
+            "2.2.0-alpha",
+            "2.2.0-alpha.1",
+            "2.2.0-alpha.beta",
+            "2.2.0-beta",
+            "2.2.0-beta.2",
+            "2.2.0-beta.11",
+            "2.2.0-rc.1",
+            "2.2.0",
+            "2.2.1-rc1",
+        ]
+        .map(|v| v.parse::<AppVersion>().unwrap());
+        for pair in ordered.windows(2) {
+            assert!(pair[0] < pair[1], "{} < {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn features() {
+        for (descriptor, feature) in [
+            ("pkh(@0/**)", PolicyFeature::SingleSig),
+            ("wpkh(@0/**)", PolicyFeature::SingleSig),
+            ("sh(wpkh(@0/**))", PolicyFeature::SingleSig),
+            ("sh(multi(2,@0/**,@1/**))", PolicyFeature::Multisig),
+            ("wsh(sortedmulti(2,@0/**,@1/**))", PolicyFeature::Multisig),
+            ("sh(wsh(multi(2,@0/**,@1/**)))", PolicyFeature::Multisig),
+            ("tr(@0/**)", PolicyFeature::Taproot),
+            (
+                "wsh(or_d(pk(@0/**),and_v(v:pk(@1/**),older(65535))))",
+                PolicyFeature::Miniscript,
+            ),
+            (
+                "sh(wsh(and_v(v:pk(@0/**),pk(@1/**))))",
+                PolicyFeature::Miniscript,
+            ),
+            ("tr(@0/**,pk(@1/**))", PolicyFeature::TaprootMiniscript),
+            (
+                "tr(@0/**,{pk(@1/**),and_v(v:pk(@2/**),older(144))})",
+                PolicyFeature::TaprootMiniscript,
+            ),
+            // Whitespace and checksums are ignored.
+            (
+                "wpkh( [d34db33f/84h/0h/0h]xpub/0/*)#qwlqgth7",
+                PolicyFeature::SingleSig,
+            ),
+        ] {
+            assert_eq!(
+                policy_feature(descriptor).unwrap(),
+                feature,
+                "{}",
+                descriptor
+            );
+        }
+        for invalid in ["", "pk(@0/**)", "wsh(@0/**", "sh(pk(@0/**))", "raw(00)"] {
+            assert!(policy_feature(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn support() {
+        for (feature, version) in [
+            (PolicyFeature::SingleSig, AppVersion::new(2, 0, 0)),
+            (PolicyFeature::Multisig, AppVersion::new(2, 0, 0)),
+            (PolicyFeature::Taproot, AppVersion::new(2, 0, 0)),
+            (PolicyFeature::Miniscript, AppVersion::new(2, 1, 0)),
+            (PolicyFeature::TaprootMiniscript, AppVersion::new(2, 2, 0)),
+        ] {
+            assert_eq!(feature.min_version(), version, "{}", feature);
+        }
+
+        let support =
+            check_policy_support("tr(@0/**,pk(@1/**))", &AppVersion::new(2, 1, 3)).unwrap();
+        assert_eq!(support.feature, PolicyFeature::TaprootMiniscript);
+        assert_eq!(support.required_version, AppVersion::new(2, 2, 0));
+        assert!(support.update_required());
+        // The client protocol requires 2.1.0 even for the features of 2.0.0.
+        let support = check_policy_support("wpkh(@0/**)", &AppVersion::new(2, 0, 6)).unwrap();
+        assert_eq!(support.required_version, MIN_CLIENT_VERSION);
+        assert!(!support.is_supported());
+        assert!(
+            check_policy_support("wpkh(@0/**)", &AppVersion::new(2, 1, 0))
+                .unwrap()
+                .is_supported()
+        );
+
+        assert_eq!(
+            unsupported_features(&AppVersion::new(2, 1, 3)),
+            [PolicyFeature::TaprootMiniscript]
+        );
+        assert!(unsupported_features(&AppVersion::new(2, 2, 0)).is_empty());
+        assert_eq!(
+            unsupported_features(&AppVersion::new(2, 0, 6)).len(),
+            PolicyFeature::ALL.len()
+        );
+    }
+}
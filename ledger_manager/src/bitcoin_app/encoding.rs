@@ -0,0 +1,235 @@
+//! Bitcoin serialization primitives used by the Bitcoin app protocol.
+
+use bech32::{segwit, Fe32, Hrp};
+use bitcoin_hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+
+/// SHA256 of the given data.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    sha256::Hash::hash(data).to_byte_array()
+}
+
+/// Serialize an integer as a Bitcoin "CompactSize".
+pub fn write_varint(n: u64) -> Vec<u8> {
+    if n < 0xfd {
+        vec![n as u8]
+    } else if n <= 0xffff {
+        let mut v = vec![0xfd];
+        v.extend_from_slice(&(n as u16).to_le_bytes());
+        v
+    } else if n <= 0xffff_ffff {
+        let mut v = vec![0xfe];
+        v.extend_from_slice(&(n as u32).to_le_bytes());
+        v
+    } else {
+        let mut v = vec![0xff];
+        v.extend_from_slice(&n.to_le_bytes());
+        v
+    }
+}
+
+/// Read a Bitcoin "CompactSize" from the beginning of `data`. Returns the integer and the number of
+/// bytes it was encoded on.
+pub fn read_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let prefix = *data.first()?;
+    let len = match prefix {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        n => return Some((n as u64, 1)),
+    };
+    let bytes = data.get(1..1 + len)?;
+    let mut buf = [0; 8];
+    buf[..len].copy_from_slice(bytes);
+    Some((u64::from_le_bytes(buf), 1 + len))
+}
+
+/// Double SHA256 of the given data, as used for transaction ids and checksums.
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    sha256d::Hash::hash(data).to_byte_array()
+}
+
+/// RIPEMD160 of the given data.
+pub fn ripemd160(data: &[u8]) -> [u8; 20] {
+    ripemd160::Hash::hash(data).to_byte_array()
+}
+
+/// RIPEMD160 of the SHA256 of the given data, as used in addresses.
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    hash160::Hash::hash(data).to_byte_array()
+}
+
+/// Encode this payload in base58, with a 4-bytes checksum.
+pub fn base58check_encode(payload: &[u8]) -> String {
+    base58ck::encode_check(payload)
+}
+
+/// Decode a base58 string with a 4-bytes checksum, and return the payload.
+pub fn base58check_decode(s: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Ok(base58ck::decode_check(s)?)
+}
+
+/// Encode a segwit address (BIP-173 and BIP-350).
+///
+/// # Panics
+/// If the human readable part or the witness version is invalid, or the program is of an invalid
+/// length for this witness version.
+pub fn segwit_address_encode(hrp: &str, witness_version: u8, program: &[u8]) -> String {
+    let hrp = Hrp::parse(hrp).expect("Valid human readable part");
+    let witness_version = Fe32::try_from(witness_version).expect("Valid witness version");
+    segwit::encode(hrp, witness_version, program).expect("Valid witness program")
+}
+
+/// Decode a segwit address (BIP-173 and BIP-350). Returns the human readable part, the witness
+/// version and the witness program.
+pub fn segwit_address_decode(address: &str) -> Option<(String, u8, Vec<u8>)> {
+    let (hrp, witness_version, program) = segwit::decode(address).ok()?;
+    Some((hrp.to_lowercase(), witness_version.to_u8(), program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_roundtrip() {
+        for (n, len) in [
+            (0, 1),
+            (0xfc, 1),
+            (0xfd, 3),
+            (0xffff, 3),
+            (0x1_0000, 5),
+            (u64::MAX, 9),
+        ] {
+            let data = write_varint(n);
+            assert_eq!(data.len(), len);
+            assert_eq!(read_varint(&data), Some((n, len)));
+        }
+        assert_eq!(read_varint(&[]), None);
+        assert_eq!(read_varint(&[0xfe, 0x01, 0x02]), None);
+    }
+
+    #[test]
+    fn sha256_vectors() {
+        // From FIPS 180-2.
+        assert_eq!(
+            hex::encode(sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex::encode(sha256d(b"abc")),
+            "4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358"
+        );
+    }
+
+    #[test]
+    fn ripemd160_vectors() {
+        // From https://homes.esat.kuleuven.be/~bosselae/ripemd160.html
+        for (data, hash) in [
+            ("", "9c1185a5c5e9fc54612808977ee8f548b2258d31"),
+            ("abc", "8eb208f7e05d987a9b044a8e98c6b087f15a0bfc"),
+            (
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "12a053384a9c0c88e405a06c27dcf49ada62eb2b",
+            ),
+        ] {
+            assert_eq!(hex::encode(ripemd160(data.as_bytes())), hash);
+        }
+    }
+
+    #[test]
+    fn base58check() {
+        // The hash160 of the compressed generator point, as a P2PKH address.
+        let payload = hex::decode("00751e76e8199196d454941c45d1b3a323f1433bd6").unwrap();
+        assert_eq!(
+            base58check_encode(&payload),
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH"
+        );
+        assert_eq!(
+            base58check_decode("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH").unwrap(),
+            payload
+        );
+        assert!(base58check_decode("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMJ").is_err());
+        assert!(base58check_decode("1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAM0").is_err());
+        assert!(base58check_decode("").is_err());
+    }
+
+    #[test]
+    fn segwit_address_valid() {
+        // From BIP-173 and BIP-350.
+        for (address, hrp, version, program) in [
+            (
+                "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+                "bc",
+                0,
+                "751e76e8199196d454941c45d1b3a323f1433bd6",
+            ),
+            (
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                "tb",
+                0,
+                "1863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+            ),
+            (
+                "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+                "bc",
+                1,
+                "751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6",
+            ),
+            ("BC1SW50QGDZ25J", "bc", 16, "751e"),
+            (
+                "bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs",
+                "bc",
+                2,
+                "751e76e8199196d454941c45d1b3a323",
+            ),
+            (
+                "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+                "tb",
+                1,
+                "000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+            ),
+        ] {
+            let program = hex::decode(program).unwrap();
+            assert_eq!(
+                segwit_address_decode(address),
+                Some((hrp.to_string(), version, program.clone())),
+                "{}",
+                address
+            );
+            assert_eq!(
+                segwit_address_encode(hrp, version, &program),
+                address.to_lowercase()
+            );
+        }
+    }
+
+    #[test]
+    fn segwit_address_invalid() {
+        // From BIP-173 and BIP-350.
+        for address in [
+            // Bech32 checksum for a version 1 or later program.
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+            "BC1S0XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ54WELL",
+            // Bech32m checksum for a version 0 program.
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+            "tb1q0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq24jc47",
+            // Invalid character.
+            "bc1p38j9r5y49hruaue7wxjce0updqjuyyx0kh56v8s25huc6995vvpql3jow4",
+            // Invalid witness version.
+            "BC130XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ7ZWS8R",
+            // Invalid program lengths.
+            "bc1pw5dgrnzv",
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v8n0nx0muaewav253zgeav",
+            "BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P",
+            // Mixed case.
+            "tb1z0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq47Zagq",
+            // Invalid padding.
+            "bc1zw508d6qejxtdg4y5r3zarvaryvqyzf3du",
+            "tb1q0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vpggkg4j",
+            // Empty data.
+            "bc1gmk9yu",
+        ] {
+            assert_eq!(segwit_address_decode(address), None, "{}", address);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! The Merkle trees used by the Bitcoin app to commit to lists of elements it doesn't have the
+//! memory to store. They follow the construction from RFC 6962, with the left subtree of each node
+//! containing the largest power of 2 number of leaves strictly smaller than the total.
+
+use super::encoding::{sha256, write_varint};
+
+use std::collections::BTreeMap;
+
+/// The root of an empty tree.
+const NIL: [u8; 32] = [0; 32];
+
+/// The hash of an element, that is a leaf of a Merkle tree.
+pub fn element_hash(element: &[u8]) -> [u8; 32] {
+    let mut preimage = Vec::with_capacity(1 + element.len());
+    preimage.push(0x00);
+    preimage.extend_from_slice(element);
+    sha256(&preimage)
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut preimage = [0; 65];
+    preimage[0] = 0x01;
+    preimage[1..33].copy_from_slice(left);
+    preimage[33..].copy_from_slice(right);
+    sha256(&preimage)
+}
+
+// The number of leaves in the left subtree of a tree with n > 1 leaves.
+fn left_size(n: usize) -> usize {
+    debug_assert!(n > 1);
+    let mut size = 1;
+    while size * 2 < n {
+        size *= 2;
+    }
+    size
+}
+
+fn root_of(leaves: &[[u8; 32]]) -> [u8; 32] {
+    match leaves.len() {
+        0 => NIL,
+        1 => leaves[0],
+        n => {
+            let (left, right) = leaves.split_at(left_size(n));
+            node_hash(&root_of(left), &root_of(right))
+        }
+    }
+}
+
+fn proof_of(leaves: &[[u8; 32]], index: usize) -> Vec<[u8; 32]> {
+    if leaves.len() <= 1 {
+        return Vec::new();
+    }
+    let (left, right) = leaves.split_at(left_size(leaves.len()));
+    let (mut proof, sibling) = if index < left.len() {
+        (proof_of(left, index), root_of(right))
+    } else {
+        (proof_of(right, index - left.len()), root_of(left))
+    };
+    proof.push(sibling);
+    proof
+}
+
+/// A Merkle tree, built over the hashes of its leaves.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    leaves: Vec<[u8; 32]>,
+}
+
+impl MerkleTree {
+    /// Build a tree from the hashes of its leaves, in order.
+    pub fn new(leaves: Vec<[u8; 32]>) -> Self {
+        Self { leaves }
+    }
+
+    /// Build a tree committing to these elements, in order.
+    pub fn from_elements<E: AsRef<[u8]>>(elements: &[E]) -> Self {
+        Self::new(elements.iter().map(|e| element_hash(e.as_ref())).collect())
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        root_of(&self.leaves)
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn leaf(&self, index: usize) -> Option<&[u8; 32]> {
+        self.leaves.get(index)
+    }
+
+    pub fn leaf_index(&self, leaf: &[u8; 32]) -> Option<usize> {
+        self.leaves.iter().position(|l| l == leaf)
+    }
+
+    /// The inclusion proof for the leaf at this index, from the bottom of the tree up.
+    pub fn prove_leaf(&self, index: usize) -> Vec<[u8; 32]> {
+        proof_of(&self.leaves, index)
+    }
+}
+
+/// The commitment to a key-value mapping, such as a PSBT map: the number of entries followed by
+/// the roots of the Merkle trees of its sorted keys and of the corresponding values.
+pub fn merkleized_map_commitment(map: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+    let keys: Vec<_> = map.keys().collect();
+    let values: Vec<_> = map.values().collect();
+    let mut commitment = write_varint(map.len() as u64);
+    commitment.extend_from_slice(&MerkleTree::from_elements(&keys).root());
+    commitment.extend_from_slice(&MerkleTree::from_elements(&values).root());
+    commitment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The expected values were computed with an independent implementation of the construction.
+
+    fn leaves(n: u8) -> Vec<[u8; 32]> {
+        (0..n).map(|i| element_hash(&[i])).collect()
+    }
+
+    fn hex32(s: &str) -> [u8; 32] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    // The root of the tree with this leaf at this index, from its inclusion proof.
+    fn root_from_proof(
+        leaf: [u8; 32],
+        mut index: usize,
+        mut size: usize,
+        proof: &[[u8; 32]],
+    ) -> [u8; 32] {
+        // Walk down the tree to know on which side the sibling is at each level.
+        let mut sides = Vec::new();
+        while size > 1 {
+            let left = left_size(size);
+            if index < left {
+                sides.push(false);
+                size = left;
+            } else {
+                sides.push(true);
+                index -= left;
+                size -= left;
+            }
+        }
+        assert_eq!(sides.len(), proof.len());
+        sides
+            .iter()
+            .rev()
+            .zip(proof)
+            .fold(leaf, |hash, (is_right, sibling)| {
+                if *is_right {
+                    node_hash(sibling, &hash)
+                } else {
+                    node_hash(&hash, sibling)
+                }
+            })
+    }
+
+    #[test]
+    fn roots() {
+        assert_eq!(MerkleTree::new(Vec::new()).root(), NIL);
+        for (n, root) in [
+            (
+                1,
+                "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+            ),
+            (
+                2,
+                "a20bf9a7cc2dc8a08f5f415a71b19f6ac427bab54d24eec868b5d3103449953a",
+            ),
+            (
+                3,
+                "3b6cccd7e3e023ff393006f030315ee7ad9eb111b022b41fba7e5b7a3973f688",
+            ),
+            (
+                5,
+                "b855b42d6c30f5b087e05266783fbd6e394f7b926013ccaa67700a8b0c5a596f",
+            ),
+        ] {
+            assert_eq!(MerkleTree::new(leaves(n)).root(), hex32(root), "{}", n);
+        }
+        let elements: Vec<[u8; 1]> = (0..5).map(|i| [i]).collect();
+        assert_eq!(
+            MerkleTree::from_elements(&elements).root(),
+            MerkleTree::new(leaves(5)).root()
+        );
+    }
+
+    #[test]
+    fn proofs() {
+        let tree = MerkleTree::new(leaves(5));
+        assert_eq!(
+            tree.prove_leaf(3),
+            [
+                hex32("fcf0a6c700dd13e274b6fba8deea8dd9b26e4eedde3495717cac8408c9c5177f"),
+                hex32("a20bf9a7cc2dc8a08f5f415a71b19f6ac427bab54d24eec868b5d3103449953a"),
+                hex32("4f35212d12f9ad2036492c95f1fe79baf4ec7bd9bef3dffa7579f2293ff546a4"),
+            ]
+        );
+        assert_eq!(
+            tree.prove_leaf(4),
+            [hex32(
+                "9bcd51240af4005168f033121ba85be5a6ed4f0e6a5fac262066729b8fbfdecb"
+            )]
+        );
+
+        for n in 1..=33 {
+            let tree = MerkleTree::new(leaves(n));
+            for i in 0..n as usize {
+                let leaf = *tree.leaf(i).unwrap();
+                assert_eq!(tree.leaf_index(&leaf), Some(i));
+                let proof = tree.prove_leaf(i);
+                assert_eq!(root_from_proof(leaf, i, n as usize, &proof), tree.root());
+            }
+        }
+    }
+
+    #[test]
+    fn map_commitment() {
+        let map = BTreeMap::from([
+            (vec![0x01], b"a".to_vec()),
+            (vec![0x00, 0x02], b"bc".to_vec()),
+        ]);
+        assert_eq!(
+            hex::encode(merkleized_map_commitment(&map)),
+            "02d04a71aee9829b0c8cd6968daca3fe77c1ae16d5a016fae6e2db4c7d646290f4\
+             43f8566b8aa23a88a0286b12253152e7bba5d8b2746bfc318557d79919096ed4"
+        );
+    }
+}
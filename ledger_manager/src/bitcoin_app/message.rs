@@ -0,0 +1,470 @@
+//! Signed messages: the legacy format from BIP-137 returned by the device, and the BIP-322
+//! "simple" signatures for segwit addresses.
+
+use super::{
+    encoding::{
+        base58check_encode, hash160, read_varint, segwit_address_decode, segwit_address_encode,
+        sha256, sha256d, write_varint,
+    },
+    psbt::{Psbt, PsbtMap},
+    DerivationPath,
+};
+
+use base64::Engine;
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId, Signature},
+    Message, PublicKey, Scalar, Secp256k1,
+};
+
+use std::error;
+
+const MESSAGE_MAGIC: &[u8] = b"\x18Bitcoin Signed Message:\n";
+
+/// The message is sent to the device in chunks of this size.
+pub const MESSAGE_CHUNK_SIZE: usize = 64;
+
+const P2PKH_VERSION_MAINNET: u8 = 0x00;
+const P2PKH_VERSION_TESTNET: u8 = 0x6f;
+const P2SH_VERSION_MAINNET: u8 = 0x05;
+const P2SH_VERSION_TESTNET: u8 = 0xc4;
+
+// BIP-137 signature headers: 27 + recovery id, plus 4 for compressed keys, plus 8 for P2SH-P2WPKH
+// or 12 for P2WPKH.
+const HEADER_BASE: u8 = 27;
+const HEADER_MAX: u8 = 42;
+
+/// The kind of single-key address a message is signed for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressType {
+    /// P2PKH, for BIP44 accounts.
+    Legacy,
+    /// P2SH-P2WPKH, for BIP49 accounts.
+    NestedSegwit,
+    /// P2WPKH, for BIP84 accounts.
+    NativeSegwit,
+}
+
+impl AddressType {
+    /// The address type corresponding to the purpose of this derivation path.
+    pub fn from_path(path: &DerivationPath) -> Option<Self> {
+        match path.steps().first()?.checked_sub(super::HARDENED_INDEX)? {
+            44 => Some(Self::Legacy),
+            49 => Some(Self::NestedSegwit),
+            84 => Some(Self::NativeSegwit),
+            _ => None,
+        }
+    }
+}
+
+fn p2wpkh_script(pubkey: &[u8]) -> Vec<u8> {
+    let mut script = vec![0x00, 0x14];
+    script.extend_from_slice(&hash160(pubkey));
+    script
+}
+
+/// The address of this type for this public key.
+pub fn address(pubkey: &[u8], address_type: AddressType, is_testnet: bool) -> String {
+    match address_type {
+        AddressType::Legacy => {
+            let mut payload = vec![if is_testnet {
+                P2PKH_VERSION_TESTNET
+            } else {
+                P2PKH_VERSION_MAINNET
+            }];
+            payload.extend_from_slice(&hash160(pubkey));
+            base58check_encode(&payload)
+        }
+        AddressType::NestedSegwit => {
+            let mut payload = vec![if is_testnet {
+                P2SH_VERSION_TESTNET
+            } else {
+                P2SH_VERSION_MAINNET
+            }];
+            payload.extend_from_slice(&hash160(&p2wpkh_script(pubkey)));
+            base58check_encode(&payload)
+        }
+        AddressType::NativeSegwit => {
+            segwit_address_encode(if is_testnet { "tb" } else { "bc" }, 0, &hash160(pubkey))
+        }
+    }
+}
+
+/// The BIP86 taproot address of this public key, used as internal key without a script tree.
+pub fn taproot_address(pubkey: &[u8], is_testnet: bool) -> Result<String, Box<dyn error::Error>> {
+    let secp = Secp256k1::verification_only();
+    let (internal_key, _) = PublicKey::from_slice(pubkey)?.x_only_public_key();
+    let tweak = Scalar::from_be_bytes(tagged_hash("TapTweak", &internal_key.serialize()))?;
+    let (output_key, _) = internal_key.add_tweak(&secp, &tweak)?;
+    Ok(segwit_address_encode(
+        if is_testnet { "tb" } else { "bc" },
+        1,
+        &output_key.serialize(),
+    ))
+}
+
+/// The hash signed by the device: the double SHA256 of the message prefixed by the magic string.
+pub fn message_hash(message: &[u8]) -> [u8; 32] {
+    let mut data = MESSAGE_MAGIC.to_vec();
+    data.extend(write_varint(message.len() as u64));
+    data.extend_from_slice(message);
+    sha256d(&data)
+}
+
+fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = sha256(tag.as_bytes());
+    let mut preimage = tag_hash.to_vec();
+    preimage.extend_from_slice(&tag_hash);
+    preimage.extend_from_slice(data);
+    sha256(&preimage)
+}
+
+// A version 0 transaction with a single input and a single output of 0 value, without witness.
+fn bip322_transaction(
+    prevout: ([u8; 32], u32),
+    script_sig: &[u8],
+    script_pubkey: &[u8],
+) -> Vec<u8> {
+    let mut tx = 0u32.to_le_bytes().to_vec();
+    tx.push(1);
+    tx.extend_from_slice(&prevout.0);
+    tx.extend_from_slice(&prevout.1.to_le_bytes());
+    tx.extend(write_varint(script_sig.len() as u64));
+    tx.extend_from_slice(script_sig);
+    tx.extend_from_slice(&0u32.to_le_bytes());
+    tx.push(1);
+    tx.extend_from_slice(&0u64.to_le_bytes());
+    tx.extend(write_varint(script_pubkey.len() as u64));
+    tx.extend_from_slice(script_pubkey);
+    tx.extend_from_slice(&0u32.to_le_bytes());
+    tx
+}
+
+// The BIP-322 "to_spend" transaction, committing to the message and the challenge.
+fn bip322_to_spend(message: &[u8], script_pubkey: &[u8]) -> Vec<u8> {
+    let mut script_sig = vec![0x00, 0x20];
+    script_sig.extend_from_slice(&tagged_hash("BIP0322-signed-message", message));
+    bip322_transaction(([0; 32], 0xffff_ffff), &script_sig, script_pubkey)
+}
+
+// The BIP-322 "to_sign" transaction, spending "to_spend" to an OP_RETURN.
+fn bip322_to_sign(to_spend: &[u8]) -> Vec<u8> {
+    bip322_transaction((sha256d(to_spend), 0), &[], &[0x6a])
+}
+
+/// The PSBT to have the device sign to produce a BIP-322 simple signature of this message for
+/// the P2WPKH address of this public key, derived at `path` from the master key with this
+/// fingerprint.
+pub fn bip322_psbt(
+    message: &[u8],
+    pubkey: &[u8; 33],
+    fingerprint: [u8; 4],
+    path: &DerivationPath,
+) -> Psbt {
+    let script_pubkey = p2wpkh_script(pubkey);
+    let to_spend = bip322_to_spend(message, &script_pubkey);
+
+    let mut global = PsbtMap::new();
+    global.insert(vec![0x00], bip322_to_sign(&to_spend));
+
+    let mut input = PsbtMap::new();
+    let mut witness_utxo = 0u64.to_le_bytes().to_vec();
+    witness_utxo.extend(write_varint(script_pubkey.len() as u64));
+    witness_utxo.extend_from_slice(&script_pubkey);
+    input.insert(vec![0x00], to_spend);
+    input.insert(vec![0x01], witness_utxo);
+    let mut bip32_key = vec![0x06];
+    bip32_key.extend_from_slice(pubkey);
+    let mut bip32_value = fingerprint.to_vec();
+    for step in path.steps() {
+        bip32_value.extend_from_slice(&step.to_le_bytes());
+    }
+    input.insert(bip32_key, bip32_value);
+
+    Psbt {
+        global,
+        inputs: vec![input],
+        outputs: vec![PsbtMap::new()],
+    }
+}
+
+/// Encode a BIP-322 simple signature from the signature of the "to_sign" transaction: the
+/// witness stack spending the P2WPKH output.
+pub fn bip322_simple_signature(signature: &[u8], pubkey: &[u8; 33]) -> String {
+    let mut witness = write_varint(2);
+    witness.extend(write_varint(signature.len() as u64));
+    witness.extend_from_slice(signature);
+    witness.extend(write_varint(pubkey.len() as u64));
+    witness.extend_from_slice(pubkey);
+    base64::engine::general_purpose::STANDARD.encode(witness)
+}
+
+// The BIP-143 signature hash for the single P2WPKH input of the "to_sign" transaction, with
+// SIGHASH_ALL.
+fn bip322_sighash(to_spend: &[u8], pubkey_hash: &[u8]) -> [u8; 32] {
+    let mut prevout = sha256d(to_spend).to_vec();
+    prevout.extend_from_slice(&0u32.to_le_bytes());
+    let mut script_code = vec![0x19, 0x76, 0xa9, 0x14];
+    script_code.extend_from_slice(pubkey_hash);
+    script_code.extend_from_slice(&[0x88, 0xac]);
+    let mut outputs = 0u64.to_le_bytes().to_vec();
+    outputs.extend_from_slice(&[0x01, 0x6a]);
+
+    let mut preimage = 0u32.to_le_bytes().to_vec();
+    preimage.extend_from_slice(&sha256d(&prevout));
+    preimage.extend_from_slice(&sha256d(&0u32.to_le_bytes()));
+    preimage.extend_from_slice(&prevout);
+    preimage.extend_from_slice(&script_code);
+    preimage.extend_from_slice(&0u64.to_le_bytes());
+    preimage.extend_from_slice(&0u32.to_le_bytes());
+    preimage.extend_from_slice(&sha256d(&outputs));
+    preimage.extend_from_slice(&0u32.to_le_bytes());
+    preimage.extend_from_slice(&1u32.to_le_bytes());
+    sha256d(&preimage)
+}
+
+fn verify_bip137(address: &str, message: &[u8], signature: &[u8; 65]) -> bool {
+    let header = signature[0] - HEADER_BASE;
+    let recovery_id = header & 3;
+    let compressed = header >= 4;
+    let Ok(recovery_id) = RecoveryId::from_i32(recovery_id.into()) else {
+        return false;
+    };
+    let Ok(signature) = RecoverableSignature::from_compact(&signature[1..], recovery_id) else {
+        return false;
+    };
+    let Ok(pubkey) = Secp256k1::verification_only()
+        .recover_ecdsa(&Message::from_digest(message_hash(message)), &signature)
+    else {
+        return false;
+    };
+    let pubkey = if compressed {
+        pubkey.serialize().to_vec()
+    } else {
+        pubkey.serialize_uncompressed().to_vec()
+    };
+
+    // Don't trust the header for the address type, as wallets historically disagree about it.
+    // Segwit addresses are only defined for compressed keys.
+    let address_types: &[AddressType] = if compressed {
+        &[
+            AddressType::Legacy,
+            AddressType::NestedSegwit,
+            AddressType::NativeSegwit,
+        ]
+    } else {
+        &[AddressType::Legacy]
+    };
+    address_types.iter().any(|address_type| {
+        [false, true]
+            .iter()
+            .any(|is_testnet| self::address(&pubkey, *address_type, *is_testnet) == address)
+    })
+}
+
+fn verify_bip322_simple(
+    address: &str,
+    message: &[u8],
+    witness: &[u8],
+) -> Result<bool, Box<dyn error::Error>> {
+    let program = match segwit_address_decode(address) {
+        Some((_, 0, program)) if program.len() == 20 => program,
+        _ => {
+            return Err(
+                "BIP-322 signatures can only be verified for P2WPKH addresses for now.".into(),
+            )
+        }
+    };
+
+    // The witness stack must be a signature and a public key.
+    let mut items = Vec::new();
+    let (n_items, mut pos) = read_varint(witness).ok_or("Invalid BIP-322 signature.")?;
+    for _ in 0..n_items {
+        let (len, read) = witness
+            .get(pos..)
+            .and_then(read_varint)
+            .ok_or("Invalid BIP-322 signature.")?;
+        pos += read;
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| pos.checked_add(len))
+            .ok_or("Invalid BIP-322 signature.")?;
+        items.push(witness.get(pos..end).ok_or("Invalid BIP-322 signature.")?);
+        pos = end;
+    }
+    if pos != witness.len() || items.len() != 2 {
+        return Err("Invalid BIP-322 signature.".into());
+    }
+    let (signature, pubkey) = (items[0], items[1]);
+    if pubkey.len() != 33 || hash160(pubkey)[..] != program[..] {
+        return Ok(false);
+    }
+    let Some((der, sighash_type)) = signature.split_last().map(|(t, der)| (der, *t)) else {
+        return Ok(false);
+    };
+    if sighash_type != 0x01 {
+        return Ok(false);
+    }
+    let (Ok(mut signature), Ok(pubkey)) =
+        (Signature::from_der_lax(der), PublicKey::from_slice(pubkey))
+    else {
+        return Ok(false);
+    };
+    // The message isn't a transaction, so non-standard high-S signatures are accepted too.
+    signature.normalize_s();
+
+    let to_spend = bip322_to_spend(message, &p2wpkh_script(&pubkey.serialize()));
+    let sighash = bip322_sighash(&to_spend, &program);
+    Ok(Secp256k1::verification_only()
+        .verify_ecdsa(&Message::from_digest(sighash), &signature, &pubkey)
+        .is_ok())
+}
+
+/// Verify a base64 signature of this message for this address. Both BIP-137 signatures (as
+/// returned by the device) for any single-key address type and BIP-322 simple signatures for
+/// P2WPKH addresses are supported.
+pub fn verify_message(
+    address: &str,
+    message: &[u8],
+    signature: &str,
+) -> Result<bool, Box<dyn error::Error>> {
+    let signature = base64::engine::general_purpose::STANDARD.decode(signature.trim())?;
+    match signature.as_slice().try_into() {
+        Ok(sig) if (HEADER_BASE..=HEADER_MAX).contains(&signature[0]) => {
+            Ok(verify_bip137(address, message, sig))
+        }
+        _ => verify_bip322_simple(address, message, &signature),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use secp256k1::SecretKey;
+
+    // The generator point, i.e. the public key for the private key 1.
+    const G_COMPRESSED: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const G_UNCOMPRESSED: &str = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+    // The address, and the simple signatures of the empty message and of "Hello World", from
+    // BIP-322.
+    const BIP322_ADDRESS: &str = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+    const BIP322_SIG_EMPTY: &str = "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+    const BIP322_SIG_HELLO: &str = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+
+    #[test]
+    fn addresses() {
+        let g = hex::decode(G_COMPRESSED).unwrap();
+        let g_uncompressed = hex::decode(G_UNCOMPRESSED).unwrap();
+        assert_eq!(
+            address(&g, AddressType::Legacy, false),
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH"
+        );
+        assert_eq!(
+            address(&g_uncompressed, AddressType::Legacy, false),
+            "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm"
+        );
+        assert_eq!(
+            address(&g, AddressType::NativeSegwit, false),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+
+        // The first receive addresses from the BIP-49 and BIP-84 test vectors.
+        let bip49 =
+            hex::decode("03a1af804ac108a8a51782198c2d034b28bf90c8803f5a53f76276fa69a4eae77f")
+                .unwrap();
+        assert_eq!(
+            address(&bip49, AddressType::NestedSegwit, true),
+            "2Mww8dCYPUpKHofjgcXcBCEGmniw9CoaiD2"
+        );
+        let bip84 =
+            hex::decode("0330d54fd0dd420a6e5f8d3624f5f3482cae350f79d5f0753bf5beef9c2d91af3c")
+                .unwrap();
+        assert_eq!(
+            address(&bip84, AddressType::NativeSegwit, false),
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+    }
+
+    #[test]
+    fn bip322_vectors() {
+        assert!(verify_message(BIP322_ADDRESS, b"", BIP322_SIG_EMPTY).unwrap());
+        assert!(verify_message(BIP322_ADDRESS, b"Hello World", BIP322_SIG_HELLO).unwrap());
+        // Swapped signatures.
+        assert!(!verify_message(BIP322_ADDRESS, b"", BIP322_SIG_HELLO).unwrap());
+        assert!(!verify_message(BIP322_ADDRESS, b"Hello World", BIP322_SIG_EMPTY).unwrap());
+        // Another address.
+        assert!(!verify_message(
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            b"",
+            BIP322_SIG_EMPTY
+        )
+        .unwrap());
+    }
+
+    #[test]
+    fn bip322_psbt_is_valid() {
+        let pubkey: [u8; 33] = hex::decode(G_COMPRESSED).unwrap().try_into().unwrap();
+        let path: DerivationPath = "m/84'/0'/0'/0/0".parse().unwrap();
+        let psbt = bip322_psbt(b"Hello World", &pubkey, [0xf5, 0xac, 0xc2, 0xfd], &path);
+        assert_eq!(Psbt::deserialize(&psbt.serialize()).unwrap(), psbt);
+        let v2 = psbt.to_v2().unwrap();
+        assert_eq!(Psbt::deserialize(&v2.serialize()).unwrap(), v2);
+    }
+
+    #[test]
+    fn bip322_malformed_witness() {
+        let engine = base64::engine::general_purpose::STANDARD;
+        for witness in [
+            // An item length overflowing the position in the witness.
+            &[0x02, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff][..],
+            // An item longer than the witness.
+            &[0x02, 0x05, 0x00],
+            // More items than in the witness.
+            &[0x03, 0x00, 0x00],
+            &[0x01],
+            &[],
+        ] {
+            assert!(verify_message(BIP322_ADDRESS, b"", &engine.encode(witness)).is_err());
+        }
+    }
+
+    #[test]
+    fn bip137_signatures() {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secp, &key);
+        let message = b"This is an example of a signed message.";
+        let (recovery_id, sig) = secp
+            .sign_ecdsa_recoverable(&Message::from_digest(message_hash(message)), &key)
+            .serialize_compact();
+        let signature = |header: u8| {
+            let mut data = vec![header + recovery_id.to_i32() as u8];
+            data.extend_from_slice(&sig);
+            base64::engine::general_purpose::STANDARD.encode(data)
+        };
+
+        let uncompressed = address(&pubkey.serialize_uncompressed(), AddressType::Legacy, false);
+        assert!(verify_message(&uncompressed, message, &signature(HEADER_BASE)).unwrap());
+        for address_type in [
+            AddressType::Legacy,
+            AddressType::NestedSegwit,
+            AddressType::NativeSegwit,
+        ] {
+            let address = address(&pubkey.serialize(), address_type, false);
+            // Whatever the address type in the header.
+            for header in [HEADER_BASE + 4, HEADER_BASE + 8, HEADER_BASE + 12] {
+                assert!(verify_message(&address, message, &signature(header)).unwrap());
+            }
+            // A signature for the uncompressed key doesn't match.
+            assert!(!verify_message(&address, message, &signature(HEADER_BASE)).unwrap());
+            assert!(!verify_message(&address, b"Another message", &signature(31)).unwrap());
+        }
+        assert!(!verify_message(
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH",
+            message,
+            &signature(HEADER_BASE + 4)
+        )
+        .unwrap());
+    }
+}
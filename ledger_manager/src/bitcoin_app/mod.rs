@@ -0,0 +1,571 @@
+//! Talk to the Bitcoin app running on the device.
+//!
+//! This implements the client side of the protocol spoken by the Bitcoin app (both the main and the
+//! Test one) starting with version 2.1.0. See
+//! https://github.com/LedgerHQ/app-bitcoin-new/blob/develop/doc/bitcoin.md for the specifications.
+
+mod bip32;
+mod client;
+mod compat;
+pub mod encoding;
+mod merkle;
+mod message;
+mod policy;
+mod psbt;
+mod registry;
+
+pub use bip32::{
+    derive_public_key, xpub_is_testnet, xpub_public_key, DerivationPath, KeyOrigin, HARDENED_INDEX,
+};
+pub use compat::{
+    check_policy_support, policy_feature, unsupported_features, AppVersion, ClientProtocol,
+    PolicyFeature, PolicySupport, MIN_CLIENT_VERSION,
+};
+pub use message::{address, message_hash, taproot_address, verify_message, AddressType};
+pub use policy::{WalletPolicy, MAX_POLICY_NAME_LEN};
+pub use psbt::{PartialSignature, Psbt, PsbtMap};
+pub use registry::{PolicyRegistry, RegisteredPolicy};
+
+use crate::{transport::Transport, StatusCode};
+use client::ClientCommandInterpreter;
+use encoding::{read_varint, write_varint};
+use ledger_apdu::APDUCommand;
+use merkle::{merkleized_map_commitment, MerkleTree};
+
+use base64::Engine;
+
+use std::{error, fmt, str};
+
+const CLA_BTC: u8 = 0xe1;
+const CLA_FRAMEWORK: u8 = 0xf8;
+
+// Sent to answer a client command from the app.
+const INS_CONTINUE_INTERRUPTED: u8 = 0x01;
+
+// The status word with which the app interrupts its execution to send us a client command.
+const SW_INTERRUPTED_EXECUTION: u16 = 0xe000;
+
+// The version of the client protocol we speak. Passed as P2 of every command.
+const CURRENT_PROTOCOL_VERSION: u8 = 1;
+
+// https://github.com/LedgerHQ/app-bitcoin-new/blob/develop/doc/bitcoin.md#command-codes
+#[derive(Debug, Clone, Copy)]
+enum BitcoinIns {
+    GetExtendedPubkey = 0x00,
+    RegisterWallet = 0x02,
+    GetWalletAddress = 0x03,
+    SignPsbt = 0x04,
+    GetMasterFingerprint = 0x05,
+    SignMessage = 0x10,
+}
+
+/// An error status word returned by the Bitcoin app.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusError(pub u16);
+
+impl StatusError {
+    /// Whether the user rejected the operation on the device.
+    pub fn is_user_refusal(&self) -> bool {
+        self.0 == StatusCode::ConditionsOfUseNotSatisfied as u16
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // https://github.com/LedgerHQ/app-bitcoin-new/blob/develop/doc/bitcoin.md#status-words
+        let reason = match self.0 {
+            c if c == StatusCode::LockedDevice as u16 => "the device is locked",
+            c if c == StatusCode::ConditionsOfUseNotSatisfied as u16 => {
+                "the operation was rejected on the device"
+            }
+            c if c == StatusCode::ClaNotSupported as u16 => {
+                "the Bitcoin app isn't open or is too old"
+            }
+            c if c == StatusCode::InsNotSupported as u16 => "the command isn't supported",
+            c if c == StatusCode::IncorrectData as u16 => "the app rejected the data sent",
+            _ => "unexpected status",
+        };
+        write!(f, "Bitcoin app error {:#06x}: {}.", self.0, reason)
+    }
+}
+
+impl error::Error for StatusError {}
+
+// Send a command to the Bitcoin app and return the data of its answer. The client commands it may
+// send in the meantime are answered by the given interpreter.
+fn request<T: Transport + ?Sized>(
+    transport: &T,
+    ins: BitcoinIns,
+    p1: u8,
+    data: Vec<u8>,
+    interpreter: &mut ClientCommandInterpreter,
+) -> Result<Vec<u8>, Box<dyn error::Error>> {
+    let command = APDUCommand {
+        cla: CLA_BTC,
+        ins: ins as u8,
+        p1,
+        p2: CURRENT_PROTOCOL_VERSION,
+        data,
+    };
+    let mut answer = transport.exchange(&command)?;
+    while answer.retcode() == SW_INTERRUPTED_EXECUTION {
+        let response = interpreter.execute(answer.data())?;
+        answer = transport.exchange(&APDUCommand {
+            cla: CLA_FRAMEWORK,
+            ins: INS_CONTINUE_INTERRUPTED,
+            p1: 0,
+            p2: 0,
+            data: response,
+        })?;
+    }
+    if answer.retcode() != StatusCode::OK as u16 {
+        return Err(StatusError(answer.retcode()).into());
+    }
+    Ok(answer.data().to_vec())
+}
+
+/// Get the fingerprint of the master key of the device.
+pub fn get_master_fingerprint<T: Transport + ?Sized>(
+    transport: &T,
+) -> Result<[u8; 4], Box<dyn error::Error>> {
+    let data = request(
+        transport,
+        BitcoinIns::GetMasterFingerprint,
+        0,
+        Vec::new(),
+        &mut ClientCommandInterpreter::default(),
+    )?;
+    Ok(data
+        .as_slice()
+        .try_into()
+        .map_err(|_| "Invalid master fingerprint length.")?)
+}
+
+/// Get the extended public key at this derivation path. Set `display` to have the user confirm it
+/// on the device, which is mandatory for some non-standard paths.
+pub fn get_extended_pubkey<T: Transport + ?Sized>(
+    transport: &T,
+    path: &DerivationPath,
+    display: bool,
+) -> Result<String, Box<dyn error::Error>> {
+    let mut data = vec![display as u8];
+    data.extend_from_slice(&path.serialize());
+    let xpub = request(
+        transport,
+        BitcoinIns::GetExtendedPubkey,
+        0,
+        data,
+        &mut ClientCommandInterpreter::default(),
+    )?;
+    Ok(str::from_utf8(&xpub)?.to_string())
+}
+
+/// Get the extended public key at this derivation path along with its origin, ready to be used in
+/// a descriptor.
+pub fn get_key_origin<T: Transport + ?Sized>(
+    transport: &T,
+    path: &DerivationPath,
+    display: bool,
+) -> Result<KeyOrigin, Box<dyn error::Error>> {
+    let fingerprint = get_master_fingerprint(transport)?;
+    let xpub = get_extended_pubkey(transport, path, display)?;
+    Ok(KeyOrigin {
+        fingerprint,
+        path: path.clone(),
+        xpub,
+    })
+}
+
+/// Register this wallet policy on the device. The user has to review the policy and each of its
+/// keys on the device before approving it. The returned HMAC must be stored (see
+/// [`PolicyRegistry`]) as it is required to later use the policy.
+pub fn register_wallet<T: Transport + ?Sized>(
+    transport: &T,
+    policy: &WalletPolicy,
+) -> Result<RegisteredPolicy, Box<dyn error::Error>> {
+    if policy.name.is_empty() {
+        return Err("A policy must have a name to be registered.".into());
+    }
+
+    let serialized_policy = policy.serialize();
+    let mut interpreter = ClientCommandInterpreter::default();
+    interpreter.add_known_preimage(serialized_policy.clone());
+    interpreter.add_known_list(&policy.keys);
+    interpreter.add_known_preimage(policy.descriptor_template.as_bytes().to_vec());
+
+    let mut data = write_varint(serialized_policy.len() as u64);
+    data.extend(serialized_policy);
+    let resp = request(
+        transport,
+        BitcoinIns::RegisterWallet,
+        0,
+        data,
+        &mut interpreter,
+    )?;
+    if resp.len() != 64 {
+        return Err("Invalid response length when registering the policy.".into());
+    }
+
+    let id: [u8; 32] = resp[..32].try_into()?;
+    if id != policy.id() {
+        return Err("The policy id returned by the device doesn't match the policy.".into());
+    }
+    Ok(RegisteredPolicy {
+        policy: policy.clone(),
+        id,
+        hmac: resp[32..].try_into()?,
+    })
+}
+
+/// Get the address of a wallet policy at this derivation index, as computed by the device. Set
+/// `display` to show it on the device for the user to check it against the address shown by their
+/// wallet. The `hmac` must be provided for registered policies, and be `None` for the standard
+/// single-sig ones.
+pub fn get_wallet_address<T: Transport + ?Sized>(
+    transport: &T,
+    policy: &WalletPolicy,
+    hmac: Option<&[u8; 32]>,
+    change: bool,
+    index: u32,
+    display: bool,
+) -> Result<String, Box<dyn error::Error>> {
+    let serialized_policy = policy.serialize();
+    let mut interpreter = ClientCommandInterpreter::default();
+    interpreter.add_known_list(&policy.keys);
+    interpreter.add_known_preimage(serialized_policy.clone());
+    interpreter.add_known_preimage(policy.descriptor_template.as_bytes().to_vec());
+
+    let mut data = vec![display as u8];
+    data.extend_from_slice(&policy.id());
+    data.extend_from_slice(hmac.unwrap_or(&[0; 32]));
+    data.push(change as u8);
+    data.extend_from_slice(&index.to_be_bytes());
+    let address = request(
+        transport,
+        BitcoinIns::GetWalletAddress,
+        0,
+        data,
+        &mut interpreter,
+    )?;
+    Ok(str::from_utf8(&address)?.to_string())
+}
+
+/// The address at this derivation index of the standard single-sig account of this key, as the
+/// device computes it for the policy of [`WalletPolicy::single_sig`].
+pub fn single_sig_address(
+    key: &KeyOrigin,
+    change: bool,
+    index: u32,
+) -> Result<String, Box<dyn error::Error>> {
+    let is_testnet = xpub_is_testnet(&key.xpub)?;
+    let pubkey = derive_public_key(&key.xpub, &[change as u32, index])?;
+    let purpose = key
+        .path
+        .steps()
+        .first()
+        .map(|p| p.wrapping_sub(HARDENED_INDEX));
+    match AddressType::from_path(&key.path) {
+        Some(address_type) => Ok(address(&pubkey, address_type, is_testnet)),
+        None if purpose == Some(86) => taproot_address(&pubkey, is_testnet),
+        None => Err("Not a standard single-sig account.".into()),
+    }
+}
+
+/// Display on the device the address at this derivation index of the standard single-sig account
+/// at `account_path` (for instance `m/84'/0'/0'`), and return it. The address is checked against
+/// the one derived from the account key.
+pub fn display_single_sig_address<T: Transport + ?Sized>(
+    transport: &T,
+    account_path: &DerivationPath,
+    change: bool,
+    index: u32,
+) -> Result<String, Box<dyn error::Error>> {
+    let key = get_key_origin(transport, account_path, false)?;
+    let policy = WalletPolicy::single_sig(&key)?;
+    let address = get_wallet_address(transport, &policy, None, change, index, true)?;
+    let expected = single_sig_address(&key, change, index)?;
+    if address != expected {
+        return Err(format!(
+            "The device returned the address {} but the account key gives {}.",
+            address, expected
+        )
+        .into());
+    }
+    Ok(address)
+}
+
+/// Display on the device the address at this derivation index of a registered policy, and return
+/// it.
+pub fn display_registered_address<T: Transport + ?Sized>(
+    transport: &T,
+    policy: &RegisteredPolicy,
+    change: bool,
+    index: u32,
+) -> Result<String, Box<dyn error::Error>> {
+    get_wallet_address(
+        transport,
+        &policy.policy,
+        Some(&policy.hmac),
+        change,
+        index,
+        true,
+    )
+}
+
+// Parse a signature yielded by the app when signing a PSBT: the input index as a varint, the
+// length of the "augmented" public key, the augmented public key and the signature.
+fn parse_yielded_signature(
+    data: &[u8],
+) -> Result<(usize, PartialSignature), Box<dyn error::Error>> {
+    let (input_index, read) = read_varint(data).ok_or("Invalid signature yielded by the app.")?;
+    let data = &data[read..];
+    let (pubkey_len, data) = data
+        .split_first()
+        .ok_or("Invalid signature yielded by the app.")?;
+    if data.len() < *pubkey_len as usize {
+        return Err("Invalid signature yielded by the app.".into());
+    }
+    let (pubkey, signature) = data.split_at(*pubkey_len as usize);
+    let signature = signature.to_vec();
+
+    let sig = match pubkey.len() {
+        33 => PartialSignature::Ecdsa {
+            pubkey: pubkey.try_into()?,
+            signature,
+        },
+        32 => PartialSignature::Schnorr {
+            xonly_pubkey: pubkey.try_into()?,
+            leaf_hash: None,
+            signature,
+        },
+        64 => PartialSignature::Schnorr {
+            xonly_pubkey: pubkey[..32].try_into()?,
+            leaf_hash: Some(pubkey[32..].try_into()?),
+            signature,
+        },
+        l => return Err(format!("Unexpected public key length {} yielded by the app.", l).into()),
+    };
+    Ok((input_index as usize, sig))
+}
+
+/// Sign a PSBT (of version 0 or 2) spending from the given wallet policy. The `hmac` must be
+/// provided for registered policies, and be `None` for the standard single-sig ones. The user
+/// has to review and approve the transaction on the device. Returns the PSBT, of the same version,
+/// with the signatures from the device.
+pub fn sign_psbt<T: Transport + ?Sized>(
+    transport: &T,
+    psbt: &Psbt,
+    policy: &WalletPolicy,
+    hmac: Option<&[u8; 32]>,
+) -> Result<Psbt, Box<dyn error::Error>> {
+    // The app only understands PSBTv2, and queries it as merkleized maps.
+    let psbt_v2 = psbt.to_v2()?;
+    let input_commitments: Vec<_> = psbt_v2
+        .inputs
+        .iter()
+        .map(merkleized_map_commitment)
+        .collect();
+    let output_commitments: Vec<_> = psbt_v2
+        .outputs
+        .iter()
+        .map(merkleized_map_commitment)
+        .collect();
+
+    let mut interpreter = ClientCommandInterpreter::default();
+    interpreter.add_known_list(&policy.keys);
+    interpreter.add_known_preimage(policy.serialize());
+    interpreter.add_known_preimage(policy.descriptor_template.as_bytes().to_vec());
+    interpreter.add_known_mapping(&psbt_v2.global);
+    for map in psbt_v2.inputs.iter().chain(psbt_v2.outputs.iter()) {
+        interpreter.add_known_mapping(map);
+    }
+    interpreter.add_known_list(&input_commitments);
+    interpreter.add_known_list(&output_commitments);
+
+    let mut data = merkleized_map_commitment(&psbt_v2.global);
+    data.extend(write_varint(psbt_v2.inputs.len() as u64));
+    data.extend_from_slice(&MerkleTree::from_elements(&input_commitments).root());
+    data.extend(write_varint(psbt_v2.outputs.len() as u64));
+    data.extend_from_slice(&MerkleTree::from_elements(&output_commitments).root());
+    data.extend_from_slice(&policy.id());
+    data.extend_from_slice(hmac.unwrap_or(&[0; 32]));
+    request(transport, BitcoinIns::SignPsbt, 0, data, &mut interpreter)?;
+
+    let mut signed_psbt = psbt.clone();
+    for yielded in interpreter.yielded() {
+        let (input_index, sig) = parse_yielded_signature(yielded)?;
+        signed_psbt.insert_signature(input_index, sig)?;
+    }
+    Ok(signed_psbt)
+}
+
+/// Sign a PSBT spending from the standard single-sig account at `account_path` (for instance
+/// `m/84'/0'/0'`).
+pub fn sign_psbt_single_sig<T: Transport + ?Sized>(
+    transport: &T,
+    psbt: &Psbt,
+    account_path: &DerivationPath,
+) -> Result<Psbt, Box<dyn error::Error>> {
+    let key = get_key_origin(transport, account_path, false)?;
+    let policy = WalletPolicy::single_sig(&key)?;
+    sign_psbt(transport, psbt, &policy, None)
+}
+
+/// Sign a PSBT spending from a registered policy.
+pub fn sign_psbt_registered<T: Transport + ?Sized>(
+    transport: &T,
+    psbt: &Psbt,
+    policy: &RegisteredPolicy,
+) -> Result<Psbt, Box<dyn error::Error>> {
+    sign_psbt(transport, psbt, &policy.policy, Some(&policy.hmac))
+}
+
+/// Sign a message with the key at this derivation path, the way Bitcoin Core does with
+/// `signmessage`. The user has to review the message on the device. Returns the signature in
+/// base64, in the format from BIP-137 for a compressed P2PKH key.
+pub fn sign_message<T: Transport + ?Sized>(
+    transport: &T,
+    message: &[u8],
+    path: &DerivationPath,
+) -> Result<String, Box<dyn error::Error>> {
+    let chunks: Vec<&[u8]> = message.chunks(message::MESSAGE_CHUNK_SIZE).collect();
+    let mut interpreter = ClientCommandInterpreter::default();
+    interpreter.add_known_list(&chunks);
+
+    let mut data = path.serialize();
+    data.extend(write_varint(message.len() as u64));
+    data.extend_from_slice(&MerkleTree::from_elements(&chunks).root());
+    let signature = request(
+        transport,
+        BitcoinIns::SignMessage,
+        0,
+        data,
+        &mut interpreter,
+    )?;
+    if signature.len() != 65 {
+        return Err("Invalid signature length returned by the device.".into());
+    }
+    Ok(base64::engine::general_purpose::STANDARD.encode(signature))
+}
+
+/// Sign a message following BIP-322 ("simple" signature) with the key at this derivation path,
+/// which must be a BIP84 (P2WPKH) address path such as `m/84'/0'/0'/0/0`. The user has to review
+/// the (virtual) transaction on the device. Returns the signature in base64.
+pub fn sign_message_bip322<T: Transport + ?Sized>(
+    transport: &T,
+    message: &[u8],
+    path: &DerivationPath,
+) -> Result<String, Box<dyn error::Error>> {
+    if path.steps().len() != 5 || AddressType::from_path(path) != Some(AddressType::NativeSegwit) {
+        return Err("BIP-322 signing is only supported for BIP84 address paths for now.".into());
+    }
+    let account_path = DerivationPath(path.steps()[..3].to_vec());
+    let account = get_key_origin(transport, &account_path, false)?;
+    let policy = WalletPolicy::single_sig(&account)?;
+    let pubkey = xpub_public_key(&get_extended_pubkey(transport, path, false)?)?;
+
+    let psbt = message::bip322_psbt(message, &pubkey, account.fingerprint, path);
+    let signed_psbt = sign_psbt(transport, &psbt, &policy, None)?;
+    let mut sig_key = vec![0x02];
+    sig_key.extend_from_slice(&pubkey);
+    let signature = signed_psbt.inputs[0]
+        .get(&sig_key)
+        .ok_or("The device didn't sign the BIP-322 transaction.")?;
+    Ok(message::bip322_simple_signature(signature, &pubkey))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The standard accounts of the test mnemonic "abandon abandon ... about", with the addresses
+    // from BIP44, BIP49, BIP84 and BIP86.
+    const ACCOUNTS: &[(&str, &str, &[&str])] = &[
+        (
+            "m/44'/0'/0'",
+            "xpub6BosfCnifzxcFwrSzQiqu2DBVTshkCXacvNsWGYJVVhhawA7d4R5WSWGFNbi8Aw6ZRc1brxMyWMzG3DSSSSoekkudhUd9yLb6qx39T9nMdj",
+            &["1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA"],
+        ),
+        (
+            "m/49'/0'/0'",
+            "xpub6C6nQwHaWbSrzs5tZ1q7m5R9cPK9eYpNMFesiXsYrgc1P8bvLLAet9JfHjYXKjToD8cBRswJXXbbFpXgwsswVPAZzKMa1jUp2kVkGVUaJa7",
+            &["37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf"],
+        ),
+        (
+            "m/84'/0'/0'",
+            "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V",
+            &[
+                "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+                "bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g",
+                "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el",
+            ],
+        ),
+        (
+            "m/86'/0'/0'",
+            "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ",
+            &[
+                "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+                "bc1p4qhjn9zdvkux4e44uhx8tc55attvtyu358kutcqkudyccelu0was9fqzwh",
+                "bc1p3qkhfews2uk44qtvauqyr2ttdsw7svhkl9nkm9s9c3x4ax5h60wqwruhk7",
+            ],
+        ),
+    ];
+
+    fn key(path: &str, xpub: &str) -> KeyOrigin {
+        KeyOrigin {
+            fingerprint: [0x73, 0xc5, 0xda, 0x0a],
+            path: path.parse().unwrap(),
+            xpub: xpub.to_string(),
+        }
+    }
+
+    #[test]
+    fn single_sig_addresses() {
+        for (path, xpub, addresses) in ACCOUNTS {
+            let key = key(path, xpub);
+            // The receive addresses at index 0 and 1, then the change address at index 0.
+            for (address, (change, index)) in
+                addresses.iter().zip([(false, 0), (false, 1), (true, 0)])
+            {
+                assert_eq!(
+                    single_sig_address(&key, change, index).unwrap(),
+                    *address,
+                    "{} {} {}",
+                    path,
+                    change,
+                    index
+                );
+            }
+        }
+
+        let key = key(
+            "m/84'/1'/0'",
+            "tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M",
+        );
+        assert_eq!(
+            single_sig_address(&key, false, 0).unwrap(),
+            "tb1q6rz28mcfaxtmd6v789l9rrlrusdprr9pqcpvkl"
+        );
+        let key = KeyOrigin {
+            path: "m/48'/0'/0'/2'".parse().unwrap(),
+            ..key
+        };
+        assert!(single_sig_address(&key, false, 0).is_err());
+    }
+
+    #[test]
+    fn single_sig_descriptors() {
+        let (path, xpub, _) = ACCOUNTS[3];
+        let policy = WalletPolicy::single_sig(&key(path, xpub)).unwrap();
+        assert_eq!(
+            policy.descriptor().unwrap(),
+            format!("tr([73c5da0a/86'/0'/0']{}/<0;1>/*)", xpub)
+        );
+        let (path, xpub, _) = ACCOUNTS[2];
+        let policy = WalletPolicy::single_sig(&key(path, xpub)).unwrap();
+        assert_eq!(
+            policy.descriptor().unwrap(),
+            format!("wpkh([73c5da0a/84'/0'/0']{}/<0;1>/*)", xpub)
+        );
+    }
+}
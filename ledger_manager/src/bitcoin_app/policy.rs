@@ -0,0 +1,265 @@
+//! BIP-388 wallet policies, the way the Bitcoin app describes the wallets it signs for.
+//!
+//! A wallet policy is a descriptor template, in which keys are replaced by placeholders (`@0`,
+//! `@1`, ...), along with the list of keys. See
+//! https://github.com/bitcoin/bips/blob/master/bip-0388.mediawiki.
+
+use super::{
+    bip32::{KeyOrigin, HARDENED_INDEX},
+    encoding::{sha256, write_varint},
+    merkle::MerkleTree,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use std::error;
+
+// The version of the wallet policy serialization. Version 2 commits to the hash of the descriptor
+// template rather than the template itself, and is the one used by the app since 2.1.0.
+const WALLET_POLICY_V2: u8 = 0x02;
+
+/// The maximum length of the name of a registered policy, as enforced by the app.
+pub const MAX_POLICY_NAME_LEN: usize = 64;
+
+/// A BIP-388 wallet policy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalletPolicy {
+    /// The name under which the policy is shown on the device. Empty for the default (single-sig)
+    /// policies which don't need to be registered.
+    pub name: String,
+    /// The descriptor template, such as `wsh(sortedmulti(2,@0/**,@1/**))`.
+    pub descriptor_template: String,
+    /// The keys with their origin, such as `[f5acc2fd/48'/0'/0'/2']xpub...`, in the order of the
+    /// placeholders.
+    pub keys: Vec<String>,
+}
+
+impl WalletPolicy {
+    pub fn new(
+        name: impl Into<String>,
+        descriptor_template: impl Into<String>,
+        keys: Vec<String>,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        let policy = Self {
+            name: name.into(),
+            descriptor_template: descriptor_template.into(),
+            keys,
+        };
+        if policy.name.len() > MAX_POLICY_NAME_LEN {
+            return Err(format!(
+                "Policy name is too long ({} bytes, maximum {}).",
+                policy.name.len(),
+                MAX_POLICY_NAME_LEN
+            )
+            .into());
+        }
+        if !policy.name.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+            return Err("Policy name must only contain printable ASCII characters.".into());
+        }
+        if policy.keys.is_empty() {
+            return Err("A wallet policy must have at least one key.".into());
+        }
+        // Make sure all placeholders refer to a key. This also checks the template is ASCII.
+        policy.descriptor()?;
+        Ok(policy)
+    }
+
+    /// The standard single-sig policy for this account key, as defined by the purpose of its
+    /// derivation path (BIP44, BIP49, BIP84 or BIP86). Such policies don't need to be registered.
+    pub fn single_sig(key: &KeyOrigin) -> Result<Self, Box<dyn error::Error>> {
+        let steps = key.path.steps();
+        if steps.len() != 3 || steps.iter().any(|s| *s < HARDENED_INDEX) {
+            return Err(
+                "A standard account path must have 3 hardened steps (purpose'/coin_type'/account')."
+                    .into(),
+            );
+        }
+        let template = match steps[0] - HARDENED_INDEX {
+            44 => "pkh(@0/**)",
+            49 => "sh(wpkh(@0/**))",
+            84 => "wpkh(@0/**)",
+            86 => "tr(@0/**)",
+            p => return Err(format!("Unsupported purpose {}' for a standard account.", p).into()),
+        };
+        Ok(Self {
+            name: String::new(),
+            descriptor_template: template.to_string(),
+            keys: vec![key.to_string()],
+        })
+    }
+
+    /// Serialize this policy as the Bitcoin app expects it.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ser = vec![WALLET_POLICY_V2];
+        ser.extend(write_varint(self.name.len() as u64));
+        ser.extend_from_slice(self.name.as_bytes());
+        ser.extend(write_varint(self.descriptor_template.len() as u64));
+        ser.extend_from_slice(&sha256(self.descriptor_template.as_bytes()));
+        ser.extend(write_varint(self.keys.len() as u64));
+        ser.extend_from_slice(&self.keys_tree().root());
+        ser
+    }
+
+    /// The identifier of this policy, as computed by the app.
+    pub fn id(&self) -> [u8; 32] {
+        sha256(&self.serialize())
+    }
+
+    pub(crate) fn keys_tree(&self) -> MerkleTree {
+        MerkleTree::from_elements(&self.keys)
+    }
+
+    /// The output descriptor described by this policy, with the keys in place of the
+    /// placeholders.
+    pub fn descriptor(&self) -> Result<String, Box<dyn error::Error>> {
+        let template = self.descriptor_template.as_bytes();
+        if !template.is_ascii() {
+            return Err("Descriptor template must be ASCII.".into());
+        }
+
+        let mut desc = String::with_capacity(template.len());
+        let mut i = 0;
+        while i < template.len() {
+            if template[i] != b'@' {
+                desc.push(template[i] as char);
+                i += 1;
+                continue;
+            }
+
+            let start = i + 1;
+            i = start;
+            while i < template.len() && template[i].is_ascii_digit() {
+                i += 1;
+            }
+            let index: usize = self.descriptor_template[start..i]
+                .parse()
+                .map_err(|_| "Invalid key placeholder in descriptor template.")?;
+            let key = self
+                .keys
+                .get(index)
+                .ok_or_else(|| format!("Key placeholder @{} doesn't refer to any key.", index))?;
+            desc.push_str(key);
+            // The "/**" shorthand stands for the usual receive and change derivation steps.
+            if template[i..].starts_with(b"/**") {
+                desc.push_str("/<0;1>/*");
+                i += 3;
+            }
+        }
+
+        Ok(desc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_0: &str = "[76223a6e/48'/1'/0'/2']tpubE7NQ4fV9pEgpLgcSrmdsMrMSm2EJ9NB9AHB9a8PV4PWpTF4ahE8LZm69tFSWWvgKGSWXQ4Bf1SmxMFk8kPeq4yMTMMbWVsCCZL5FuP8bYC";
+    const KEY_1: &str = "[f5acc2fd/48'/1'/0'/2']tpubDFAqEGNyad35aBCKUAXbQGDjdVhNueno5ZZVEn3sQbW5ci457gLR7HyTmHBg93oourBssgUxuWz1jX5uhc1qaqFo9VsybY1J5FuedLfm4dK";
+
+    fn multisig() -> WalletPolicy {
+        WalletPolicy::new(
+            "Cold storage",
+            "wsh(sortedmulti(2,@0/**,@1/**))",
+            vec![KEY_0.to_string(), KEY_1.to_string()],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn serialization() {
+        // Computed with an independent implementation of the serialization.
+        let policy = multisig();
+        assert_eq!(
+            hex::encode(policy.serialize()),
+            "020c436f6c642073746f72616765\
+             1fb56c3d5542fa09b3956834a9ff6a1df5c36a38e5b02c63c54b41a9a04403b826\
+             02ff2c957baf9fda169bca648d617b85dca2090f751aadb1b147b74b46d1d90f8e"
+        );
+        assert_eq!(
+            hex::encode(policy.id()),
+            "74aa501b81246812ada53cb900adc5481afa1235d98a8ae92196427aaf98f4f3"
+        );
+    }
+
+    #[test]
+    fn descriptors() {
+        assert_eq!(
+            multisig().descriptor().unwrap(),
+            format!("wsh(sortedmulti(2,{}/<0;1>/*,{}/<0;1>/*))", KEY_0, KEY_1)
+        );
+        let policy = WalletPolicy::new(
+            "Timelocked",
+            "tr(@0/<2;3>/*,and_v(v:pk(@1/**),older(65535)))",
+            vec![KEY_0.to_string(), KEY_1.to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            policy.descriptor().unwrap(),
+            format!(
+                "tr({}/<2;3>/*,and_v(v:pk({}/<0;1>/*),older(65535)))",
+                KEY_0, KEY_1
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_policies() {
+        let keys = vec![KEY_0.to_string(), KEY_1.to_string()];
+        for (name, template, keys) in [
+            (
+                "a".repeat(MAX_POLICY_NAME_LEN + 1),
+                "wsh(@0/**)",
+                keys.clone(),
+            ),
+            ("Tab\t".to_string(), "wsh(@0/**)", keys.clone()),
+            ("No keys".to_string(), "wsh(@0/**)", Vec::new()),
+            (
+                "Missing key".to_string(),
+                "wsh(multi(1,@0/**,@2/**))",
+                keys.clone(),
+            ),
+            (
+                "Bad placeholder".to_string(),
+                "wsh(multi(1,@/**))",
+                keys.clone(),
+            ),
+            ("Non ASCII".to_string(), "wsh(pk(@0/**)é)", keys.clone()),
+        ] {
+            assert!(
+                WalletPolicy::new(name.clone(), template, keys).is_err(),
+                "{}",
+                name
+            );
+        }
+        assert!(WalletPolicy::new("a".repeat(MAX_POLICY_NAME_LEN), "wsh(@0/**)", keys).is_ok());
+    }
+
+    #[test]
+    fn single_sig() {
+        for (path, template) in [
+            ("m/44'/0'/0'", "pkh(@0/**)"),
+            ("m/49'/0'/0'", "sh(wpkh(@0/**))"),
+            ("m/84'/1'/0'", "wpkh(@0/**)"),
+            ("m/86'/0'/3'", "tr(@0/**)"),
+        ] {
+            let key = KeyOrigin {
+                fingerprint: [0xf5, 0xac, 0xc2, 0xfd],
+                path: path.parse().unwrap(),
+                xpub: "tpubXXX".to_string(),
+            };
+            let policy = WalletPolicy::single_sig(&key).unwrap();
+            assert_eq!(policy.descriptor_template, template);
+            assert_eq!(policy.name, "");
+            assert_eq!(policy.keys, [key.to_string()]);
+        }
+        for path in ["m/84'/0'", "m/84'/0'/0", "m/48'/0'/0'", "m/84'/0'/0'/0'"] {
+            let key = KeyOrigin {
+                fingerprint: [0; 4],
+                path: path.parse().unwrap(),
+                xpub: "tpubXXX".to_string(),
+            };
+            assert!(WalletPolicy::single_sig(&key).is_err(), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,849 @@
+//! A minimal PSBT (BIP-174 and BIP-370) container.
+//!
+//! We don't interpret most of the fields: the Bitcoin app does. We only check the size of the
+//! known ones, convert version 0 PSBTs to version 2 ones, which is the only version the app
+//! understands, and insert the signatures it returns.
+
+use super::encoding::{read_varint, write_varint};
+
+use base64::Engine;
+
+use std::{collections::BTreeMap, error};
+
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+// https://github.com/bitcoin/bips/blob/master/bip-0174.mediawiki#specification
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_GLOBAL_XPUB: u8 = 0x01;
+const PSBT_GLOBAL_TX_VERSION: u8 = 0x02;
+const PSBT_GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const PSBT_GLOBAL_INPUT_COUNT: u8 = 0x04;
+const PSBT_GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const PSBT_GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const PSBT_GLOBAL_VERSION: u8 = 0xfb;
+const PSBT_IN_NON_WITNESS_UTXO: u8 = 0x00;
+const PSBT_IN_WITNESS_UTXO: u8 = 0x01;
+const PSBT_IN_PARTIAL_SIG: u8 = 0x02;
+const PSBT_IN_SIGHASH_TYPE: u8 = 0x03;
+const PSBT_IN_REDEEM_SCRIPT: u8 = 0x04;
+const PSBT_IN_WITNESS_SCRIPT: u8 = 0x05;
+const PSBT_IN_BIP32_DERIVATION: u8 = 0x06;
+const PSBT_IN_FINAL_SCRIPTSIG: u8 = 0x07;
+const PSBT_IN_FINAL_SCRIPTWITNESS: u8 = 0x08;
+const PSBT_IN_PREVIOUS_TXID: u8 = 0x0e;
+const PSBT_IN_OUTPUT_INDEX: u8 = 0x0f;
+const PSBT_IN_SEQUENCE: u8 = 0x10;
+const PSBT_IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const PSBT_IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+const PSBT_IN_TAP_KEY_SIG: u8 = 0x13;
+const PSBT_IN_TAP_SCRIPT_SIG: u8 = 0x14;
+const PSBT_IN_TAP_LEAF_SCRIPT: u8 = 0x15;
+const PSBT_IN_TAP_BIP32_DERIVATION: u8 = 0x16;
+const PSBT_IN_TAP_INTERNAL_KEY: u8 = 0x17;
+const PSBT_IN_TAP_MERKLE_ROOT: u8 = 0x18;
+const PSBT_OUT_REDEEM_SCRIPT: u8 = 0x00;
+const PSBT_OUT_WITNESS_SCRIPT: u8 = 0x01;
+const PSBT_OUT_BIP32_DERIVATION: u8 = 0x02;
+const PSBT_OUT_AMOUNT: u8 = 0x03;
+const PSBT_OUT_SCRIPT: u8 = 0x04;
+const PSBT_OUT_TAP_INTERNAL_KEY: u8 = 0x05;
+const PSBT_OUT_TAP_TREE: u8 = 0x06;
+const PSBT_OUT_TAP_BIP32_DERIVATION: u8 = 0x07;
+
+// Locktimes below this are block heights, and above are timestamps.
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// A key-value map of a PSBT. Keys include their type as first byte.
+pub type PsbtMap = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// A Partially Signed Bitcoin Transaction, of version 0 or 2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Psbt {
+    pub global: PsbtMap,
+    pub inputs: Vec<PsbtMap>,
+    pub outputs: Vec<PsbtMap>,
+}
+
+/// A signature returned by the Bitcoin app for one of the inputs of a PSBT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartialSignature {
+    /// An ECDSA signature for this compressed public key.
+    Ecdsa {
+        pubkey: [u8; 33],
+        signature: Vec<u8>,
+    },
+    /// A Schnorr signature for the taproot output key or, if a leaf hash is given, for this
+    /// x-only key in this script leaf.
+    Schnorr {
+        xonly_pubkey: [u8; 32],
+        leaf_hash: Option<[u8; 32]>,
+        signature: Vec<u8>,
+    },
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn read(&mut self, len: usize) -> Result<&'a [u8], Box<dyn error::Error>> {
+        let end = self.pos.checked_add(len).ok_or("Unexpected end of data.")?;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or("Unexpected end of data.")?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_varint(&mut self) -> Result<u64, Box<dyn error::Error>> {
+        let (n, len) = self
+            .data
+            .get(self.pos..)
+            .and_then(read_varint)
+            .ok_or("Unexpected end of data.")?;
+        self.pos += len;
+        Ok(n)
+    }
+
+    // Read a length, which must fit in the remaining data.
+    fn read_len(&mut self) -> Result<usize, Box<dyn error::Error>> {
+        usize::try_from(self.read_varint()?).map_err(|_| "Unexpected end of data.".into())
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Box<dyn error::Error>> {
+        Ok(u32::from_le_bytes(self.read(4)?.try_into()?))
+    }
+
+    fn read_map(&mut self) -> Result<PsbtMap, Box<dyn error::Error>> {
+        let mut map = PsbtMap::new();
+        loop {
+            let key_len = self.read_len()?;
+            if key_len == 0 {
+                return Ok(map);
+            }
+            let key = self.read(key_len)?.to_vec();
+            let value_len = self.read_len()?;
+            let value = self.read(value_len)?.to_vec();
+            if map.insert(key, value).is_some() {
+                return Err("Duplicate key in PSBT map.".into());
+            }
+        }
+    }
+}
+
+// The kind of a map of a PSBT, telling how to interpret the key types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MapKind {
+    Global,
+    Input,
+    Output,
+}
+
+fn is_pubkey(data: &[u8]) -> bool {
+    secp256k1::PublicKey::from_slice(data).is_ok()
+}
+
+// A master key fingerprint followed by derivation steps.
+fn is_key_origin(data: &[u8]) -> bool {
+    !data.is_empty() && data.len().is_multiple_of(4)
+}
+
+// The leaf hashes followed by the key origin, for taproot derivations.
+fn is_tap_key_origin(data: &[u8]) -> bool {
+    let Some((n_hashes, len)) = read_varint(data) else {
+        return false;
+    };
+    usize::try_from(n_hashes)
+        .ok()
+        .and_then(|n| n.checked_mul(32))
+        .and_then(|hashes_len| data.len().checked_sub(len)?.checked_sub(hashes_len))
+        .is_some_and(|origin_len| is_key_origin(&data[data.len() - origin_len..]))
+}
+
+fn is_schnorr_signature(data: &[u8]) -> bool {
+    data.len() == 64 || data.len() == 65
+}
+
+// A control block: the leaf version and internal key, then the path of up to 128 hashes.
+fn is_control_block(data: &[u8]) -> bool {
+    data.len() >= 33 && (data.len() - 33).is_multiple_of(32) && (data.len() - 33) / 32 <= 128
+}
+
+// Whether this key type is only defined for PSBTv2 (BIP-370). They are unknown types in a PSBTv0.
+fn is_v2_only(kind: MapKind, key_type: u8) -> bool {
+    match kind {
+        MapKind::Global => (PSBT_GLOBAL_TX_VERSION..=PSBT_GLOBAL_TX_MODIFIABLE).contains(&key_type),
+        MapKind::Input => {
+            (PSBT_IN_PREVIOUS_TXID..=PSBT_IN_REQUIRED_HEIGHT_LOCKTIME).contains(&key_type)
+        }
+        MapKind::Output => key_type == PSBT_OUT_AMOUNT || key_type == PSBT_OUT_SCRIPT,
+    }
+}
+
+// Check the size of the key data and value of the fields we know of (BIP-174, BIP-370 and
+// BIP-371) in a map of a PSBT of this version. Unknown fields are left alone.
+fn check_map(kind: MapKind, version: u32, map: &PsbtMap) -> Result<(), Box<dyn error::Error>> {
+    for (key, value) in map {
+        check_field(kind, version, key, value)?;
+    }
+    Ok(())
+}
+
+fn check_field(
+    kind: MapKind,
+    version: u32,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(), Box<dyn error::Error>> {
+    let Some((&key_type, key_data)) = key.split_first() else {
+        return Err("Empty PSBT key.".into());
+    };
+    if version == 0 && is_v2_only(kind, key_type) {
+        // Unknown in a PSBTv0, and rejected by check_version_fields() without key data.
+        return Ok(());
+    }
+    let no_key_data = key_data.is_empty();
+    let valid = match (kind, key_type) {
+        (MapKind::Global, PSBT_GLOBAL_UNSIGNED_TX) => no_key_data,
+        (MapKind::Global, PSBT_GLOBAL_XPUB) => key_data.len() == 78 && is_key_origin(value),
+        (
+            MapKind::Global,
+            PSBT_GLOBAL_TX_VERSION | PSBT_GLOBAL_FALLBACK_LOCKTIME | PSBT_GLOBAL_VERSION,
+        ) => no_key_data && value.len() == 4,
+        (MapKind::Global, PSBT_GLOBAL_INPUT_COUNT | PSBT_GLOBAL_OUTPUT_COUNT) => {
+            no_key_data && read_varint(value).is_some_and(|(_, len)| len == value.len())
+        }
+        (MapKind::Global, PSBT_GLOBAL_TX_MODIFIABLE) => no_key_data && value.len() == 1,
+        (
+            MapKind::Input,
+            PSBT_IN_NON_WITNESS_UTXO
+            | PSBT_IN_WITNESS_UTXO
+            | PSBT_IN_REDEEM_SCRIPT
+            | PSBT_IN_WITNESS_SCRIPT
+            | PSBT_IN_FINAL_SCRIPTSIG
+            | PSBT_IN_FINAL_SCRIPTWITNESS,
+        )
+        | (
+            MapKind::Output,
+            PSBT_OUT_REDEEM_SCRIPT | PSBT_OUT_WITNESS_SCRIPT | PSBT_OUT_SCRIPT | PSBT_OUT_TAP_TREE,
+        ) => no_key_data,
+        (MapKind::Input, PSBT_IN_PARTIAL_SIG) => is_pubkey(key_data),
+        (MapKind::Input, PSBT_IN_BIP32_DERIVATION)
+        | (MapKind::Output, PSBT_OUT_BIP32_DERIVATION) => {
+            is_pubkey(key_data) && is_key_origin(value)
+        }
+        (
+            MapKind::Input,
+            PSBT_IN_SIGHASH_TYPE
+            | PSBT_IN_OUTPUT_INDEX
+            | PSBT_IN_SEQUENCE
+            | PSBT_IN_REQUIRED_TIME_LOCKTIME
+            | PSBT_IN_REQUIRED_HEIGHT_LOCKTIME,
+        ) => no_key_data && value.len() == 4,
+        (
+            MapKind::Input,
+            PSBT_IN_PREVIOUS_TXID | PSBT_IN_TAP_INTERNAL_KEY | PSBT_IN_TAP_MERKLE_ROOT,
+        )
+        | (MapKind::Output, PSBT_OUT_TAP_INTERNAL_KEY) => no_key_data && value.len() == 32,
+        (MapKind::Input, PSBT_IN_TAP_KEY_SIG) => no_key_data && is_schnorr_signature(value),
+        (MapKind::Input, PSBT_IN_TAP_SCRIPT_SIG) => {
+            key_data.len() == 64 && is_schnorr_signature(value)
+        }
+        (MapKind::Input, PSBT_IN_TAP_LEAF_SCRIPT) => {
+            is_control_block(key_data) && !value.is_empty()
+        }
+        (MapKind::Input, PSBT_IN_TAP_BIP32_DERIVATION)
+        | (MapKind::Output, PSBT_OUT_TAP_BIP32_DERIVATION) => {
+            key_data.len() == 32 && is_tap_key_origin(value)
+        }
+        (MapKind::Output, PSBT_OUT_AMOUNT) => no_key_data && value.len() == 8,
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid PSBT field of type {:#04x}.", key_type).into())
+    }
+}
+
+// Check the fields required or forbidden by the version of the PSBT are (or aren't) there.
+fn check_version_fields(
+    version: u32,
+    global: &PsbtMap,
+    inputs: &[PsbtMap],
+    outputs: &[PsbtMap],
+) -> Result<(), Box<dyn error::Error>> {
+    let has = |map: &PsbtMap, key_type: u8| map.contains_key(&vec![key_type]);
+    if version == 0 {
+        let v2_only = [
+            PSBT_GLOBAL_TX_VERSION,
+            PSBT_GLOBAL_FALLBACK_LOCKTIME,
+            PSBT_GLOBAL_INPUT_COUNT,
+            PSBT_GLOBAL_OUTPUT_COUNT,
+            PSBT_GLOBAL_TX_MODIFIABLE,
+        ];
+        let v2_only_in = [
+            PSBT_IN_PREVIOUS_TXID,
+            PSBT_IN_OUTPUT_INDEX,
+            PSBT_IN_SEQUENCE,
+            PSBT_IN_REQUIRED_TIME_LOCKTIME,
+            PSBT_IN_REQUIRED_HEIGHT_LOCKTIME,
+        ];
+        if v2_only.iter().any(|t| has(global, *t))
+            || inputs.iter().any(|i| v2_only_in.iter().any(|t| has(i, *t)))
+            || outputs
+                .iter()
+                .any(|o| has(o, PSBT_OUT_AMOUNT) || has(o, PSBT_OUT_SCRIPT))
+        {
+            return Err("PSBTv0 with fields only allowed in PSBTv2.".into());
+        }
+        return Ok(());
+    }
+
+    if has(global, PSBT_GLOBAL_UNSIGNED_TX) {
+        return Err("PSBTv2 with an unsigned transaction.".into());
+    }
+    if !has(global, PSBT_GLOBAL_TX_VERSION) {
+        return Err("Missing transaction version in PSBTv2.".into());
+    }
+    for input in inputs {
+        if !has(input, PSBT_IN_PREVIOUS_TXID) || !has(input, PSBT_IN_OUTPUT_INDEX) {
+            return Err("Missing previous output of an input in PSBTv2.".into());
+        }
+        if get_u32(input, PSBT_IN_REQUIRED_TIME_LOCKTIME)?.is_some_and(|t| t < LOCKTIME_THRESHOLD)
+            || get_u32(input, PSBT_IN_REQUIRED_HEIGHT_LOCKTIME)?
+                .is_some_and(|h| h == 0 || h >= LOCKTIME_THRESHOLD)
+        {
+            return Err("Invalid required locktime of an input in PSBTv2.".into());
+        }
+    }
+    if outputs
+        .iter()
+        .any(|o| !has(o, PSBT_OUT_AMOUNT) || !has(o, PSBT_OUT_SCRIPT))
+    {
+        return Err("Missing amount or script of an output in PSBTv2.".into());
+    }
+    Ok(())
+}
+
+fn write_map(out: &mut Vec<u8>, map: &PsbtMap) {
+    for (key, value) in map {
+        out.extend(write_varint(key.len() as u64));
+        out.extend_from_slice(key);
+        out.extend(write_varint(value.len() as u64));
+        out.extend_from_slice(value);
+    }
+    out.push(0x00);
+}
+
+fn get_u32(map: &PsbtMap, key: u8) -> Result<Option<u32>, Box<dyn error::Error>> {
+    map.get(&vec![key])
+        .map(|v| Ok(u32::from_le_bytes(v.as_slice().try_into()?)))
+        .transpose()
+}
+
+fn get_count(map: &PsbtMap, key: u8) -> Result<usize, Box<dyn error::Error>> {
+    let value = map
+        .get(&vec![key])
+        .ok_or("Missing input or output count in PSBTv2.")?;
+    let count = read_varint(value).ok_or("Invalid count in PSBTv2.")?.0;
+    Ok(usize::try_from(count).map_err(|_| "Invalid count in PSBTv2.")?)
+}
+
+// The fields of an unsigned transaction we need to convert a PSBTv0 into a PSBTv2.
+struct UnsignedTx {
+    version: u32,
+    // (previous txid, previous output index, sequence)
+    inputs: Vec<([u8; 32], u32, u32)>,
+    // (amount, scriptPubKey)
+    outputs: Vec<(u64, Vec<u8>)>,
+    locktime: u32,
+}
+
+impl UnsignedTx {
+    fn deserialize(data: &[u8]) -> Result<Self, Box<dyn error::Error>> {
+        let mut reader = Reader::new(data);
+        let version = reader.read_u32()?;
+
+        // The unsigned transaction has no signatures, so it's never serialized in the segwit
+        // format (whose marker would read as no inputs).
+        let n_inputs = reader.read_varint()?;
+        if n_inputs == 0 {
+            return Err("Unsigned transaction without inputs, or in the segwit format.".into());
+        }
+        let mut inputs = Vec::new();
+        for _ in 0..n_inputs {
+            let txid = reader.read(32)?.try_into()?;
+            let vout = reader.read_u32()?;
+            if reader.read_varint()? != 0 {
+                return Err("Unsigned transaction with a scriptSig.".into());
+            }
+            let sequence = reader.read_u32()?;
+            inputs.push((txid, vout, sequence));
+        }
+
+        let n_outputs = reader.read_varint()?;
+        let mut outputs = Vec::new();
+        for _ in 0..n_outputs {
+            let amount = u64::from_le_bytes(reader.read(8)?.try_into()?);
+            let script_len = reader.read_len()?;
+            outputs.push((amount, reader.read(script_len)?.to_vec()));
+        }
+
+        let locktime = reader.read_u32()?;
+        if !reader.is_empty() {
+            return Err("Trailing data after the unsigned transaction.".into());
+        }
+
+        Ok(Self {
+            version,
+            inputs,
+            outputs,
+            locktime,
+        })
+    }
+}
+
+impl Psbt {
+    /// Parse a PSBT from its binary serialization.
+    pub fn deserialize(data: &[u8]) -> Result<Self, Box<dyn error::Error>> {
+        let mut reader = Reader::new(data);
+        if reader.read(PSBT_MAGIC.len())? != PSBT_MAGIC {
+            return Err("Invalid PSBT magic bytes.".into());
+        }
+
+        let global = reader.read_map()?;
+        let version = get_u32(&global, PSBT_GLOBAL_VERSION)?.unwrap_or(0);
+        let (n_inputs, n_outputs) = match version {
+            0 => {
+                let tx = global
+                    .get(&vec![PSBT_GLOBAL_UNSIGNED_TX])
+                    .ok_or("Missing unsigned transaction in PSBTv0.")?;
+                let tx = UnsignedTx::deserialize(tx)?;
+                (tx.inputs.len(), tx.outputs.len())
+            }
+            2 => (
+                get_count(&global, PSBT_GLOBAL_INPUT_COUNT)?,
+                get_count(&global, PSBT_GLOBAL_OUTPUT_COUNT)?,
+            ),
+            v => return Err(format!("Unsupported PSBT version {}.", v).into()),
+        };
+
+        let inputs: Vec<_> = (0..n_inputs)
+            .map(|_| reader.read_map())
+            .collect::<Result<_, _>>()?;
+        let outputs: Vec<_> = (0..n_outputs)
+            .map(|_| reader.read_map())
+            .collect::<Result<_, _>>()?;
+        if !reader.is_empty() {
+            return Err("Trailing data after the PSBT.".into());
+        }
+
+        let psbt = Self {
+            global,
+            inputs,
+            outputs,
+        };
+        psbt.check()?;
+        Ok(psbt)
+    }
+
+    // Check the fields we know of are well-formed, and consistent with the version.
+    fn check(&self) -> Result<(), Box<dyn error::Error>> {
+        let version = self.version();
+        check_map(MapKind::Global, version, &self.global)?;
+        for input in &self.inputs {
+            check_map(MapKind::Input, version, input)?;
+        }
+        for output in &self.outputs {
+            check_map(MapKind::Output, version, output)?;
+        }
+        check_version_fields(version, &self.global, &self.inputs, &self.outputs)
+    }
+
+    /// Parse a PSBT from its base64 encoding.
+    pub fn from_base64(s: &str) -> Result<Self, Box<dyn error::Error>> {
+        let data = base64::engine::general_purpose::STANDARD.decode(s.trim())?;
+        Self::deserialize(&data)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = PSBT_MAGIC.to_vec();
+        write_map(&mut out, &self.global);
+        for map in self.inputs.iter().chain(self.outputs.iter()) {
+            write_map(&mut out, map);
+        }
+        out
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.serialize())
+    }
+
+    /// The version of this PSBT, 0 or 2.
+    pub fn version(&self) -> u32 {
+        get_u32(&self.global, PSBT_GLOBAL_VERSION)
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+    }
+
+    /// A copy of this PSBT as version 2. This is a no-op for a PSBTv2.
+    pub fn to_v2(&self) -> Result<Self, Box<dyn error::Error>> {
+        if self.version() == 2 {
+            return Ok(self.clone());
+        }
+
+        let mut psbt = self.clone();
+        let tx = psbt
+            .global
+            .remove(&vec![PSBT_GLOBAL_UNSIGNED_TX])
+            .ok_or("Missing unsigned transaction in PSBTv0.")?;
+        let tx = UnsignedTx::deserialize(&tx)?;
+
+        psbt.global
+            .insert(vec![PSBT_GLOBAL_VERSION], 2u32.to_le_bytes().to_vec());
+        psbt.global.insert(
+            vec![PSBT_GLOBAL_TX_VERSION],
+            tx.version.to_le_bytes().to_vec(),
+        );
+        psbt.global.insert(
+            vec![PSBT_GLOBAL_FALLBACK_LOCKTIME],
+            tx.locktime.to_le_bytes().to_vec(),
+        );
+        psbt.global.insert(
+            vec![PSBT_GLOBAL_INPUT_COUNT],
+            write_varint(tx.inputs.len() as u64),
+        );
+        psbt.global.insert(
+            vec![PSBT_GLOBAL_OUTPUT_COUNT],
+            write_varint(tx.outputs.len() as u64),
+        );
+        for (map, (txid, vout, sequence)) in psbt.inputs.iter_mut().zip(tx.inputs) {
+            map.insert(vec![PSBT_IN_PREVIOUS_TXID], txid.to_vec());
+            map.insert(vec![PSBT_IN_OUTPUT_INDEX], vout.to_le_bytes().to_vec());
+            map.insert(vec![PSBT_IN_SEQUENCE], sequence.to_le_bytes().to_vec());
+        }
+        for (map, (amount, script)) in psbt.outputs.iter_mut().zip(tx.outputs) {
+            map.insert(vec![PSBT_OUT_AMOUNT], amount.to_le_bytes().to_vec());
+            map.insert(vec![PSBT_OUT_SCRIPT], script);
+        }
+
+        // Unknown fields of the PSBTv0 may be of types defined for PSBTv2.
+        psbt.check()
+            .map_err(|e| format!("Cannot convert the PSBT to version 2: {}", e))?;
+        Ok(psbt)
+    }
+
+    /// Record a signature for the input at this index.
+    pub fn insert_signature(
+        &mut self,
+        input_index: usize,
+        signature: PartialSignature,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let input = self
+            .inputs
+            .get_mut(input_index)
+            .ok_or("Signature for an input index out of range.")?;
+        let (key, value) = match signature {
+            PartialSignature::Ecdsa { pubkey, signature } => {
+                let mut key = vec![PSBT_IN_PARTIAL_SIG];
+                key.extend_from_slice(&pubkey);
+                (key, signature)
+            }
+            PartialSignature::Schnorr {
+                xonly_pubkey,
+                leaf_hash,
+                signature,
+            } => match leaf_hash {
+                None => (vec![PSBT_IN_TAP_KEY_SIG], signature),
+                Some(leaf_hash) => {
+                    let mut key = vec![PSBT_IN_TAP_SCRIPT_SIG];
+                    key.extend_from_slice(&xonly_pubkey);
+                    key.extend_from_slice(&leaf_hash);
+                    (key, signature)
+                }
+            },
+        };
+        input.insert(key, value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The test vectors from BIP-174 and BIP-371 in this set, as (description, data).
+    fn vectors(set: &str) -> Vec<(String, Vec<u8>)> {
+        let vectors: serde_json::Value =
+            serde_json::from_str(include_str!("../../tests/data/psbt_vectors.json")).unwrap();
+        vectors[set]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| {
+                (
+                    v["description"].as_str().unwrap().to_string(),
+                    hex::decode(v["psbt"].as_str().unwrap()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    // A PSBTv2 with two inputs and two outputs, converted from a BIP-174 test vector.
+    fn psbt_v2() -> Psbt {
+        let (_, data) = &vectors("bip174_valid")[3];
+        Psbt::deserialize(data).unwrap().to_v2().unwrap()
+    }
+
+    fn reparse(psbt: &Psbt) -> Result<Psbt, Box<dyn error::Error>> {
+        Psbt::deserialize(&psbt.serialize())
+    }
+
+    #[test]
+    fn valid_vectors() {
+        for (description, data) in vectors("bip174_valid")
+            .into_iter()
+            .chain(vectors("bip371_valid"))
+        {
+            let psbt =
+                Psbt::deserialize(&data).unwrap_or_else(|e| panic!("{}: {}", description, e));
+            assert_eq!(psbt.version(), 0);
+            assert_eq!(psbt.serialize(), data, "{}", description);
+            assert_eq!(Psbt::from_base64(&psbt.to_base64()).unwrap(), psbt);
+
+            let Ok(v2) = psbt.to_v2() else {
+                // The unknown fields of this one are of types defined for PSBTv2.
+                assert_eq!(description, "PSBT with unknown types in the inputs.");
+                continue;
+            };
+            assert_eq!(v2.version(), 2);
+            assert_eq!(reparse(&v2).unwrap(), v2, "{}", description);
+            assert_eq!(v2.to_v2().unwrap(), v2);
+        }
+    }
+
+    #[test]
+    fn invalid_vectors() {
+        for (description, data) in vectors("bip174_invalid")
+            .into_iter()
+            .chain(vectors("bip371_invalid"))
+        {
+            assert!(Psbt::deserialize(&data).is_err(), "{}", description);
+        }
+    }
+
+    #[test]
+    fn v2_conversion() {
+        let psbt = psbt_v2();
+        let get = |map: &PsbtMap, key_type: u8| map.get(&vec![key_type]).unwrap().clone();
+        assert!(!psbt.global.contains_key(&vec![PSBT_GLOBAL_UNSIGNED_TX]));
+        assert_eq!(get(&psbt.global, PSBT_GLOBAL_TX_VERSION), [2, 0, 0, 0]);
+        assert_eq!(
+            get(&psbt.global, PSBT_GLOBAL_FALLBACK_LOCKTIME),
+            [0, 0, 0, 0]
+        );
+        assert_eq!(get(&psbt.global, PSBT_GLOBAL_INPUT_COUNT), [2]);
+        assert_eq!(get(&psbt.global, PSBT_GLOBAL_OUTPUT_COUNT), [2]);
+        // As serialized in the unsigned transaction.
+        assert_eq!(
+            hex::encode(get(&psbt.inputs[0], PSBT_IN_PREVIOUS_TXID)),
+            "ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be4"
+        );
+        assert_eq!(get(&psbt.inputs[0], PSBT_IN_OUTPUT_INDEX), [0, 0, 0, 0]);
+        assert_eq!(get(&psbt.inputs[1], PSBT_IN_OUTPUT_INDEX), [1, 0, 0, 0]);
+        assert_eq!(
+            get(&psbt.inputs[0], PSBT_IN_SEQUENCE),
+            [0xfe, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(
+            get(&psbt.outputs[0], PSBT_OUT_AMOUNT),
+            199_900_000u64.to_le_bytes()
+        );
+        assert_eq!(
+            hex::encode(get(&psbt.outputs[0], PSBT_OUT_SCRIPT)),
+            "76a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac"
+        );
+    }
+
+    // The invalid cases of BIP-370, applied to a valid PSBTv2.
+    #[test]
+    fn invalid_v2() {
+        type Mutation = fn(&mut Psbt);
+        let cases: Vec<(&str, Mutation)> = vec![
+            ("PSBTv2 with an unsigned transaction", |p| {
+                let (_, data) = &vectors("bip174_valid")[3];
+                let v0 = Psbt::deserialize(data).unwrap();
+                let tx = v0.global[&vec![PSBT_GLOBAL_UNSIGNED_TX]].clone();
+                p.global.insert(vec![PSBT_GLOBAL_UNSIGNED_TX], tx);
+            }),
+            ("Missing transaction version", |p| {
+                p.global.remove(&vec![PSBT_GLOBAL_TX_VERSION]);
+            }),
+            ("Missing input count", |p| {
+                p.global.remove(&vec![PSBT_GLOBAL_INPUT_COUNT]);
+            }),
+            ("Missing output count", |p| {
+                p.global.remove(&vec![PSBT_GLOBAL_OUTPUT_COUNT]);
+            }),
+            ("More inputs than in the input count", |p| {
+                p.global.insert(vec![PSBT_GLOBAL_INPUT_COUNT], vec![1]);
+            }),
+            ("Fewer outputs than in the output count", |p| {
+                p.global.insert(vec![PSBT_GLOBAL_OUTPUT_COUNT], vec![3]);
+            }),
+            ("Missing previous txid", |p| {
+                p.inputs[1].remove(&vec![PSBT_IN_PREVIOUS_TXID]);
+            }),
+            ("Missing output index", |p| {
+                p.inputs[0].remove(&vec![PSBT_IN_OUTPUT_INDEX]);
+            }),
+            ("Previous txid of 31 bytes", |p| {
+                p.inputs[0].insert(vec![PSBT_IN_PREVIOUS_TXID], vec![0; 31]);
+            }),
+            ("Required time locktime below the threshold", |p| {
+                p.inputs[0].insert(
+                    vec![PSBT_IN_REQUIRED_TIME_LOCKTIME],
+                    499_999_999u32.to_le_bytes().to_vec(),
+                );
+            }),
+            ("Required height locktime above the threshold", |p| {
+                p.inputs[0].insert(
+                    vec![PSBT_IN_REQUIRED_HEIGHT_LOCKTIME],
+                    LOCKTIME_THRESHOLD.to_le_bytes().to_vec(),
+                );
+            }),
+            ("Required height locktime of 0", |p| {
+                p.inputs[0].insert(vec![PSBT_IN_REQUIRED_HEIGHT_LOCKTIME], vec![0; 4]);
+            }),
+            ("Missing output amount", |p| {
+                p.outputs[0].remove(&vec![PSBT_OUT_AMOUNT]);
+            }),
+            ("Missing output script", |p| {
+                p.outputs[1].remove(&vec![PSBT_OUT_SCRIPT]);
+            }),
+            ("Unsupported version", |p| {
+                p.global.insert(vec![PSBT_GLOBAL_VERSION], vec![1, 0, 0, 0]);
+            }),
+        ];
+        assert!(reparse(&psbt_v2()).is_ok());
+        for (description, mutate) in cases {
+            let mut psbt = psbt_v2();
+            mutate(&mut psbt);
+            assert!(reparse(&psbt).is_err(), "{}", description);
+        }
+
+        // The required locktimes at their limits are valid.
+        let mut psbt = psbt_v2();
+        psbt.inputs[0].insert(
+            vec![PSBT_IN_REQUIRED_TIME_LOCKTIME],
+            LOCKTIME_THRESHOLD.to_le_bytes().to_vec(),
+        );
+        psbt.inputs[1].insert(
+            vec![PSBT_IN_REQUIRED_HEIGHT_LOCKTIME],
+            (LOCKTIME_THRESHOLD - 1).to_le_bytes().to_vec(),
+        );
+        assert_eq!(reparse(&psbt).unwrap(), psbt);
+    }
+
+    #[test]
+    fn invalid_v0() {
+        let (_, data) = &vectors("bip174_valid")[3];
+        let psbt = Psbt::deserialize(data).unwrap();
+        let cases: Vec<(&str, u8, MapKind)> = vec![
+            (
+                "Transaction version",
+                PSBT_GLOBAL_TX_VERSION,
+                MapKind::Global,
+            ),
+            ("Input count", PSBT_GLOBAL_INPUT_COUNT, MapKind::Global),
+            ("Previous txid", PSBT_IN_PREVIOUS_TXID, MapKind::Input),
+            ("Sequence", PSBT_IN_SEQUENCE, MapKind::Input),
+            ("Output amount", PSBT_OUT_AMOUNT, MapKind::Output),
+        ];
+        for (description, key_type, kind) in cases {
+            let mut psbt = psbt.clone();
+            let map = match kind {
+                MapKind::Global => &mut psbt.global,
+                MapKind::Input => &mut psbt.inputs[0],
+                MapKind::Output => &mut psbt.outputs[0],
+            };
+            map.insert(vec![key_type], vec![0; 4]);
+            assert!(reparse(&psbt).is_err(), "{}", description);
+        }
+    }
+
+    #[test]
+    fn truncated_and_oversized() {
+        let (_, data) = &vectors("bip174_valid")[0];
+        for len in 0..data.len() {
+            assert!(Psbt::deserialize(&data[..len]).is_err());
+        }
+        // Lengths overflowing the position in the data.
+        for length in [
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff][..],
+            &[0xfe, 0xff, 0xff, 0xff, 0xff],
+        ] {
+            let mut data = PSBT_MAGIC.to_vec();
+            data.extend_from_slice(length);
+            assert!(Psbt::deserialize(&data).is_err());
+            // And as the length of a value.
+            let mut data = PSBT_MAGIC.to_vec();
+            data.extend_from_slice(&[0x01, PSBT_GLOBAL_UNSIGNED_TX]);
+            data.extend_from_slice(length);
+            assert!(Psbt::deserialize(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn signatures() {
+        let mut psbt = psbt_v2();
+        let pubkey = [0x02; 33];
+        psbt.insert_signature(
+            1,
+            PartialSignature::Ecdsa {
+                pubkey,
+                signature: vec![0x30, 0x01],
+            },
+        )
+        .unwrap();
+        let mut key = vec![PSBT_IN_PARTIAL_SIG];
+        key.extend_from_slice(&pubkey);
+        assert_eq!(psbt.inputs[1].get(&key), Some(&vec![0x30, 0x01]));
+
+        psbt.insert_signature(
+            0,
+            PartialSignature::Schnorr {
+                xonly_pubkey: [0x01; 32],
+                leaf_hash: Some([0x03; 32]),
+                signature: vec![0xaa; 64],
+            },
+        )
+        .unwrap();
+        let mut key = vec![PSBT_IN_TAP_SCRIPT_SIG];
+        key.extend_from_slice(&[0x01; 32]);
+        key.extend_from_slice(&[0x03; 32]);
+        assert_eq!(psbt.inputs[0].get(&key), Some(&vec![0xaa; 64]));
+        assert!(psbt
+            .insert_signature(
+                2,
+                PartialSignature::Schnorr {
+                    xonly_pubkey: [0x01; 32],
+                    leaf_hash: None,
+                    signature: vec![0xaa; 64],
+                },
+            )
+            .is_err());
+    }
+}
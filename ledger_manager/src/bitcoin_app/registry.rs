@@ -0,0 +1,111 @@
+//! A local registry of the wallet policies registered on a device.
+//!
+//! When registering a policy, the device returns an HMAC which must be provided again each time the
+//! policy is used. The device doesn't store anything, so we keep the HMACs in a file per device,
+//! identified by its master key fingerprint.
+
+use super::policy::WalletPolicy;
+
+use serde_derive::{Deserialize, Serialize};
+
+use std::{
+    error, fs,
+    path::{Path, PathBuf},
+};
+
+/// A wallet policy registered on a device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisteredPolicy {
+    #[serde(flatten)]
+    pub policy: WalletPolicy,
+    /// The policy identifier, as computed by the device.
+    #[serde(with = "crate::hex_bytes")]
+    pub id: [u8; 32],
+    /// The HMAC returned by the device upon registration.
+    #[serde(with = "crate::hex_bytes")]
+    pub hmac: [u8; 32],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RegistryFile {
+    fingerprint: String,
+    policies: Vec<RegisteredPolicy>,
+}
+
+/// The policies registered on a device, as stored on disk.
+#[derive(Debug, Clone)]
+pub struct PolicyRegistry {
+    path: PathBuf,
+    fingerprint: [u8; 4],
+    policies: Vec<RegisteredPolicy>,
+}
+
+impl PolicyRegistry {
+    /// Load the registry of the device with this master key fingerprint from the given directory.
+    /// The registry is empty if none was stored yet.
+    pub fn load(dir: &Path, fingerprint: [u8; 4]) -> Result<Self, Box<dyn error::Error>> {
+        let path = dir.join(format!("{}.json", hex::encode(fingerprint)));
+        let policies = if path.exists() {
+            let file: RegistryFile = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            if file.fingerprint != hex::encode(fingerprint) {
+                return Err(format!(
+                    "Registry file at '{}' is for another device ({}).",
+                    path.display(),
+                    file.fingerprint
+                )
+                .into());
+            }
+            file.policies
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            path,
+            fingerprint,
+            policies,
+        })
+    }
+
+    /// Write the registry to disk.
+    pub fn save(&self) -> Result<(), Box<dyn error::Error>> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first to never leave a truncated registry behind.
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, self.export()?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// The master key fingerprint of the device this registry is for.
+    pub fn fingerprint(&self) -> [u8; 4] {
+        self.fingerprint
+    }
+
+    /// The registered policies.
+    pub fn policies(&self) -> &[RegisteredPolicy] {
+        &self.policies
+    }
+
+    /// Get a registered policy by name.
+    pub fn get(&self, name: &str) -> Option<&RegisteredPolicy> {
+        self.policies.iter().find(|p| p.policy.name == name)
+    }
+
+    /// Record a newly registered policy. It replaces any policy with the same name.
+    pub fn insert(&mut self, policy: RegisteredPolicy) {
+        self.policies
+            .retain(|p| p.policy.name != policy.policy.name);
+        self.policies.push(policy);
+    }
+
+    /// Export the registry as JSON.
+    pub fn export(&self) -> Result<String, Box<dyn error::Error>> {
+        Ok(serde_json::to_string_pretty(&RegistryFile {
+            fingerprint: hex::encode(self.fingerprint),
+            policies: self.policies.clone(),
+        })?)
+    }
+}
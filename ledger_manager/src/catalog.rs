@@ -0,0 +1,54 @@
+//! A local cache of the apps seen in the catalog, used to identify the installed apps without
+//! sending their hashes to Ledger.
+//!
+//! The catalog for a device only contains the latest version of each app. Caching the apps seen
+//! over time lets us identify installed apps which were since superseded. The cache is a JSON
+//! array of [`BitcoinAppInfo`], which may also be filled by hand as an allowlist.
+
+use crate::BitcoinAppInfo;
+
+use std::{error, fs, io, path::Path};
+
+/// Load the apps from the cache at this path. A missing cache is empty.
+pub fn load(path: &Path) -> Result<Vec<BitcoinAppInfo>, Box<dyn error::Error>> {
+    match fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)
+            .map_err(|e| format!("Invalid catalog cache at '{}': {}", path.display(), e))?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Add these apps to the cache at this path, if not already present.
+pub fn update(path: &Path, apps: &[BitcoinAppInfo]) -> Result<(), Box<dyn error::Error>> {
+    let mut cached = load(path)?;
+    let len = cached.len();
+    for app in apps {
+        if !cached.iter().any(|a| a.hash == app.hash) {
+            cached.push(app.clone());
+        }
+    }
+    if cached.len() == len {
+        return Ok(());
+    }
+
+    // Write to a temporary file first to never leave a corrupted cache behind.
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(&cached)?)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Match these app hashes against the known apps. Unknown apps are `None`.
+pub fn resolve(hashes: &[Vec<u8>], known: &[BitcoinAppInfo]) -> Vec<Option<BitcoinAppInfo>> {
+    hashes
+        .iter()
+        .map(|hash| {
+            let hash = hex::encode(hash);
+            known.iter().find(|app| app.hash == hash).cloned()
+        })
+        .collect()
+}
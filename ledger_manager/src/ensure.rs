@@ -0,0 +1,377 @@
+//! Making sure the Bitcoin app is installed at a required version, installing or updating it only
+//! if needed. This spares callers from handling [`InstallErr::AlreadyInstalled`] and
+//! [`UpdateErr::NotInstalled`] or [`UpdateErr::AlreadyLatest`] themselves.
+//!
+//! [`InstallErr::AlreadyInstalled`]: crate::InstallErr::AlreadyInstalled
+
+use crate::{
+    apps_by_hashes, bitcoin_app::AppVersion, bitcoin_app_installed, bitcoin_latest_app,
+    ensure_dashboard, install_app, update_installed_app, ApiConfig, DeviceInfo, InstallReport,
+    UpdateErr, UpdateReport, VerificationErr,
+};
+
+use ledger_transport_hidapi::TransportNativeHID;
+use serde_derive::Serialize;
+
+use std::{error, fmt};
+
+/// The network the app is for: the Bitcoin app for mainnet, the Bitcoin Test app for the test
+/// networks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+}
+
+impl Network {
+    pub fn is_testnet(&self) -> bool {
+        *self == Self::Testnet
+    }
+}
+
+/// The version the app must be installed at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionRequirement {
+    /// The latest version from the catalog, or a later one.
+    Latest,
+    /// Any version. The app is only installed if missing.
+    Any,
+    AtLeast(AppVersion),
+    Exact(AppVersion),
+}
+
+impl VersionRequirement {
+    /// Whether this version satisfies the requirement. For [`Self::Latest`], whether it's the
+    /// `latest` version or a later one.
+    pub fn matches(&self, version: &AppVersion, latest: &AppVersion) -> bool {
+        match self {
+            Self::Latest => version >= latest,
+            Self::Any => true,
+            Self::AtLeast(v) => version >= v,
+            Self::Exact(v) => version == v,
+        }
+    }
+}
+
+impl fmt::Display for VersionRequirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Latest => write!(f, "the latest version"),
+            Self::Any => write!(f, "any version"),
+            Self::AtLeast(v) => write!(f, "version {} or later", v),
+            Self::Exact(v) => write!(f, "version {}", v),
+        }
+    }
+}
+
+/// What [`ensure_bitcoin_app`] did.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum EnsureAction {
+    /// The app was already installed at a version satisfying the requirement.
+    Nothing {
+        /// The version of the installed app, if it was identified. It isn't looked up for
+        /// [`VersionRequirement::Any`].
+        version: Option<String>,
+        /// The hash of the installed app, hex encoded.
+        hash: String,
+    },
+    Installed(InstallReport),
+    Updated(Box<UpdateReport>),
+}
+
+impl EnsureAction {
+    /// The version of the app now installed, if known.
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            Self::Nothing { version, .. } => version.as_deref(),
+            Self::Installed(report) => Some(&report.version),
+            Self::Updated(report) => Some(&report.target_version),
+        }
+    }
+
+    /// The hash of the app now installed, hex encoded, if known.
+    pub fn hash(&self) -> Option<&str> {
+        match self {
+            Self::Nothing { hash, .. } => Some(hash),
+            Self::Installed(report) => Some(&report.hash),
+            Self::Updated(report) => report.install.as_ref().map(|i| i.hash.as_str()),
+        }
+    }
+}
+
+/// An error arising when making sure the Bitcoin app is installed.
+#[derive(Debug)]
+pub enum EnsureErr {
+    /// Couldn't get info about the Bitcoin app.
+    AppNotFound,
+    /// The catalog only serves the latest version of the app for the firmware of the device, and
+    /// it doesn't satisfy the requirement.
+    Unsatisfiable {
+        requirement: VersionRequirement,
+        latest: String,
+    },
+    /// The app found on the device after installing it isn't the one from the catalog.
+    Verification(VerificationErr),
+    /// The update failed after the installed app was removed, see [`UpdateErr::Failed`].
+    Failed(Box<UpdateReport>),
+    Any(Box<dyn error::Error>),
+}
+
+impl fmt::Display for EnsureErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AppNotFound => write!(f, "Could not get info about the Bitcoin app"),
+            Self::Unsatisfiable {
+                requirement,
+                latest,
+            } => write!(
+                f,
+                "The app can't be installed at {}: the catalog only has version {} for this firmware",
+                requirement, latest
+            ),
+            Self::Verification(e) => write!(f, "{}", e),
+            Self::Failed(report) => write!(f, "{}", report),
+            Self::Any(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for EnsureErr {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Verification(e) => Some(e),
+            Self::Any(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<Box<dyn error::Error>> for EnsureErr {
+    fn from(e: Box<dyn error::Error>) -> Self {
+        match e.downcast::<VerificationErr>() {
+            Ok(e) => Self::Verification(*e),
+            Err(e) => Self::Any(e),
+        }
+    }
+}
+
+// What to do about the app to satisfy the requirement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Plan {
+    Nothing,
+    Install,
+    Update,
+    // The latest version, the only one the catalog serves, doesn't satisfy the requirement.
+    Unsatisfiable,
+}
+
+// Decide what to do given the installed app, if any, with its version if it was identified.
+fn plan(
+    requirement: &VersionRequirement,
+    installed: Option<Option<&AppVersion>>,
+    latest: &AppVersion,
+) -> Plan {
+    if let Some(version) = installed {
+        let satisfied = *requirement == VersionRequirement::Any
+            || version.is_some_and(|version| requirement.matches(version, latest));
+        if satisfied {
+            return Plan::Nothing;
+        }
+    }
+    if !requirement.matches(latest, latest) {
+        Plan::Unsatisfiable
+    } else if installed.is_none() {
+        Plan::Install
+    } else {
+        Plan::Update
+    }
+}
+
+/// Make sure the Bitcoin app for this network is installed at a version satisfying the
+/// requirement: install it if it's missing, update it if it's too old, or else do nothing. The
+/// app is installed and updated at the latest version from the catalog, so an [`Exact`] or
+/// [`AtLeast`] requirement it doesn't satisfy is an error. This is idempotent.
+///
+/// The version of the installed app is identified as with
+/// [`list_installed_apps`](crate::list_installed_apps). If it can't be (for instance in privacy
+/// mode for a version missing from the catalog cache) it is only left alone for [`Any`], or if it
+/// has the hash of the latest version.
+///
+/// [`Exact`]: VersionRequirement::Exact
+/// [`AtLeast`]: VersionRequirement::AtLeast
+/// [`Any`]: VersionRequirement::Any
+pub fn ensure_bitcoin_app(
+    ledger_api: &TransportNativeHID,
+    network: Network,
+    requirement: VersionRequirement,
+    config: &ApiConfig,
+) -> Result<EnsureAction, EnsureErr> {
+    let is_testnet = network.is_testnet();
+    // We can only list and install apps from the dashboard.
+    ensure_dashboard(ledger_api)?;
+
+    let installed = bitcoin_app_installed(ledger_api, is_testnet)?;
+    if let (Some(installed), VersionRequirement::Any) = (&installed, &requirement) {
+        return Ok(EnsureAction::Nothing {
+            version: None,
+            hash: hex::encode(&installed.hash),
+        });
+    }
+
+    let device_info = DeviceInfo::new(ledger_api)?;
+    let latest =
+        bitcoin_latest_app(&device_info, is_testnet, config)?.ok_or(EnsureErr::AppNotFound)?;
+    let latest_version: AppVersion = latest.version.parse()?;
+
+    let installed_hash = installed.as_ref().map(|app| hex::encode(&app.hash));
+    let previous = match &installed {
+        Some(app) => apps_by_hashes(ledger_api, vec![app.hash.clone()], config)?
+            .into_iter()
+            .next()
+            .flatten(),
+        None => None,
+    };
+    // The version of the installed app, known from the catalog if it's the latest.
+    let version = match (&installed_hash, &previous) {
+        (Some(hash), _) if *hash == latest.hash => Some(latest.version.clone()),
+        (_, previous) => previous.as_ref().map(|app| app.version.clone()),
+    };
+    let parsed_version = version.as_ref().and_then(|v| v.parse::<AppVersion>().ok());
+
+    match plan(
+        &requirement,
+        installed_hash.as_ref().map(|_| parsed_version.as_ref()),
+        &latest_version,
+    ) {
+        Plan::Nothing => Ok(EnsureAction::Nothing {
+            version,
+            hash: installed_hash.unwrap_or_default(),
+        }),
+        Plan::Unsatisfiable => Err(EnsureErr::Unsatisfiable {
+            requirement,
+            latest: latest.version,
+        }),
+        Plan::Install => Ok(EnsureAction::Installed(install_app(
+            ledger_api,
+            &device_info,
+            &latest,
+            config,
+        )?)),
+        Plan::Update => match update_installed_app(
+            ledger_api,
+            &device_info,
+            is_testnet,
+            previous.as_ref(),
+            &latest,
+            config,
+        ) {
+            Ok(report) => Ok(EnsureAction::Updated(Box::new(report))),
+            Err(UpdateErr::Failed(report)) => Err(EnsureErr::Failed(report)),
+            Err(UpdateErr::Verification(e)) => Err(EnsureErr::Verification(e)),
+            Err(UpdateErr::AppNotFound) => Err(EnsureErr::AppNotFound),
+            Err(e @ (UpdateErr::NotInstalled | UpdateErr::AlreadyLatest)) => Err(EnsureErr::Any(
+                format!("Unexpected update error: {:?}", e).into(),
+            )),
+            Err(UpdateErr::Any(e)) => Err(EnsureErr::Any(e)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> AppVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn requirements() {
+        let latest = version("2.2.0");
+        let matches = |req: &VersionRequirement, v: &str| req.matches(&version(v), &latest);
+
+        assert!(matches(&VersionRequirement::Latest, "2.2.0"));
+        assert!(matches(&VersionRequirement::Latest, "2.3.0"));
+        assert!(!matches(&VersionRequirement::Latest, "2.1.3"));
+        assert!(!matches(&VersionRequirement::Latest, "2.2.0-rc1"));
+        assert!(matches(&VersionRequirement::Any, "1.0.0"));
+
+        let at_least = VersionRequirement::AtLeast(version("2.1.0"));
+        assert!(matches(&at_least, "2.1.0"));
+        assert!(matches(&at_least, "2.3.0"));
+        assert!(!matches(&at_least, "2.0.9"));
+        assert!(!matches(&at_least, "2.1.0-rc1"));
+
+        let exact = VersionRequirement::Exact(version("2.2.0"));
+        assert!(matches(&exact, "2.2.0"));
+        assert!(!matches(&exact, "2.2.0-rc1"));
+        assert!(!matches(&exact, "2.2.1"));
+    }
+
+    #[test]
+    fn plans() {
+        let latest = version("2.2.0");
+        let (old, newer) = (version("2.1.0"), version("2.3.0"));
+        let plan = |req: VersionRequirement, installed: Option<Option<&AppVersion>>| {
+            plan(&req, installed, &latest)
+        };
+
+        // Missing.
+        assert_eq!(plan(VersionRequirement::Latest, None), Plan::Install);
+        assert_eq!(plan(VersionRequirement::Any, None), Plan::Install);
+        assert_eq!(
+            plan(VersionRequirement::Exact(old.clone()), None),
+            Plan::Unsatisfiable
+        );
+
+        // Latest: a newer version isn't downgraded.
+        assert_eq!(
+            plan(VersionRequirement::Latest, Some(Some(&latest))),
+            Plan::Nothing
+        );
+        assert_eq!(
+            plan(VersionRequirement::Latest, Some(Some(&newer))),
+            Plan::Nothing
+        );
+        assert_eq!(
+            plan(VersionRequirement::Latest, Some(Some(&old))),
+            Plan::Update
+        );
+        assert_eq!(plan(VersionRequirement::Latest, Some(None)), Plan::Update);
+
+        // Any: whatever the version.
+        assert_eq!(plan(VersionRequirement::Any, Some(None)), Plan::Nothing);
+
+        // At least: updated if the latest version satisfies it.
+        assert_eq!(
+            plan(VersionRequirement::AtLeast(old.clone()), Some(Some(&old))),
+            Plan::Nothing
+        );
+        assert_eq!(
+            plan(
+                VersionRequirement::AtLeast(latest.clone()),
+                Some(Some(&old))
+            ),
+            Plan::Update
+        );
+        assert_eq!(
+            plan(VersionRequirement::AtLeast(newer.clone()), Some(Some(&old))),
+            Plan::Unsatisfiable
+        );
+
+        // Exact: only the latest version can be installed.
+        assert_eq!(
+            plan(VersionRequirement::Exact(old.clone()), Some(Some(&old))),
+            Plan::Nothing
+        );
+        assert_eq!(
+            plan(VersionRequirement::Exact(latest.clone()), Some(Some(&old))),
+            Plan::Update
+        );
+        assert_eq!(
+            plan(VersionRequirement::Exact(old.clone()), Some(Some(&newer))),
+            Plan::Unsatisfiable
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Serialize byte strings as lowercase hex, for use with `#[serde(with = "crate::hex_bytes")]`. It
+//! reads back into a `Vec<u8>` or a fixed-size array, which must then have the exact length.
+
+use serde::{de, Deserialize, Deserializer, Serializer};
+
+pub fn serialize<S: Serializer, B: AsRef<[u8]>>(bytes: B, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&hex::encode(bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>, B: TryFrom<Vec<u8>>>(d: D) -> Result<B, D::Error> {
+    let s = String::deserialize(d)?;
+    let bytes = hex::decode(s).map_err(de::Error::custom)?;
+    let len = bytes.len();
+    B::try_from(bytes)
+        .map_err(|_| de::Error::invalid_length(len, &"a byte string of the expected length"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Hashes {
+        #[serde(with = "crate::hex_bytes")]
+        vec: Vec<u8>,
+        #[serde(with = "crate::hex_bytes")]
+        array: [u8; 4],
+    }
+
+    #[test]
+    fn roundtrip() {
+        let hashes = Hashes {
+            vec: vec![0xab, 0x01],
+            array: [0xde, 0xad, 0xbe, 0xef],
+        };
+        let json = serde_json::to_string(&hashes).unwrap();
+        assert_eq!(json, r#"{"vec":"ab01","array":"deadbeef"}"#);
+        assert_eq!(serde_json::from_str::<Hashes>(&json).unwrap(), hashes);
+    }
+
+    #[test]
+    fn invalid() {
+        // Not hex.
+        assert!(serde_json::from_str::<Hashes>(r#"{"vec":"zz","array":"deadbeef"}"#).is_err());
+        // Too short for the array.
+        assert!(serde_json::from_str::<Hashes>(r#"{"vec":"","array":"dead"}"#).is_err());
+    }
+}
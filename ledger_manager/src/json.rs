@@ -0,0 +1,172 @@
+//! The JSON representation of the public types.
+//!
+//! This representation is stable: fields may be added, but an existing field is never removed,
+//! renamed or given a different type without bumping [`SCHEMA_VERSION`]. Documents meant to be
+//! stored or passed to other tools should be wrapped in a [`Versioned`], which records the version
+//! of the schema they were written with.
+//!
+//! Byte strings (hashes, flags) are encoded as lowercase hex, except the flags of an
+//! [`InstalledApp`] which are a number. The flags of a [`DeviceInfo`] are decoded, and the raw
+//! flags kept alongside. For instance a [`DeviceInfo`] is represented as:
+//! ```json
+//! {
+//!   "target_id": 857735172,
+//!   "version": "1.1.1",
+//!   "flags": { "raw": "8e000000", "manager_allowed": true, "pin_validated": true },
+//!   "is_bootloader": false,
+//!   "se_version": "1.1.1",
+//!   "se_target_id": 857735172,
+//!   "mcu_version": "4.03"
+//! }
+//! ```
+//! And an [`InstalledApp`] as:
+//! ```json
+//! { "name": "Bitcoin", "hash": "<hex>", "hash_code_data": "<hex>", "blocks": 212, "flags": 2624 }
+//! ```
+//! A [`BitcoinAppInfo`] keeps the field names used by the Ledger API. A [`RunningApp`] has a
+//! `name`, a `version` and hex `flags`.
+//!
+//! [`DeviceInfo`]: crate::DeviceInfo
+//! [`InstalledApp`]: crate::InstalledApp
+//! [`BitcoinAppInfo`]: crate::BitcoinAppInfo
+//! [`RunningApp`]: crate::RunningApp
+
+use serde_derive::{Deserialize, Serialize};
+
+/// The version of the JSON representation of the public types.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A document along with the version of the schema it was serialized with. Its fields are
+/// flattened next to the `schema_version`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub data: T,
+}
+
+impl<T> Versioned<T> {
+    /// Wrap this document with the current schema version.
+    pub fn new(data: T) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            data,
+        }
+    }
+}
+
+// Durations as a number of milliseconds.
+pub(crate) mod millis {
+    use serde::Serializer;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(duration.as_millis() as u64)
+    }
+}
+
+// The flags of a DeviceInfo, decoded. Only the raw flags are read back.
+pub(crate) mod device_flags {
+    use crate::{device_flag, MANAGER_ALLOWED_FLAG, PIN_VALIDATED_FLAG};
+    use serde::{Deserializer, Serializer};
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Serialize)]
+    struct Decoded<'a> {
+        #[serde(with = "crate::hex_bytes")]
+        raw: &'a [u8],
+        manager_allowed: bool,
+        pin_validated: bool,
+    }
+
+    #[derive(Deserialize)]
+    struct Raw {
+        #[serde(with = "crate::hex_bytes")]
+        raw: Vec<u8>,
+    }
+
+    pub fn serialize<S: Serializer>(flags: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(
+            &Decoded {
+                raw: flags,
+                manager_allowed: device_flag(flags, MANAGER_ALLOWED_FLAG),
+                pin_validated: device_flag(flags, PIN_VALIDATED_FLAG),
+            },
+            s,
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let raw: Raw = serde::Deserialize::deserialize(d)?;
+        Ok(raw.raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceInfo, InstalledApp, RunningApp};
+
+    #[test]
+    fn round_trip() {
+        let info = DeviceInfo {
+            target_id: 0x33200004,
+            version: "1.1.1".to_string(),
+            flags: vec![0x8e, 0, 0, 0],
+            is_bootloader: false,
+            se_version: Some("1.1.1".to_string()),
+            se_target_id: 0x33200004,
+            mcu_version: Some("4.03".to_string()),
+        };
+        let json = serde_json::to_value(Versioned::new(&info)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "schema_version": SCHEMA_VERSION,
+                "target_id": 857735172,
+                "version": "1.1.1",
+                "flags": { "raw": "8e000000", "manager_allowed": true, "pin_validated": true },
+                "is_bootloader": false,
+                "se_version": "1.1.1",
+                "se_target_id": 857735172,
+                "mcu_version": "4.03"
+            })
+        );
+        let read: Versioned<DeviceInfo> = serde_json::from_value(json).unwrap();
+        assert_eq!(read.schema_version, SCHEMA_VERSION);
+        assert_eq!(read.data.flags, info.flags);
+        assert_eq!(read.data.version, info.version);
+        assert_eq!(read.data.mcu_version, info.mcu_version);
+        assert!(read.data.manager_allowed());
+
+        let app = InstalledApp {
+            name: "Bitcoin".to_string(),
+            hash: vec![0xab; 32],
+            hash_code_data: vec![0xcd; 32],
+            blocks: 212,
+            flags: 2624,
+        };
+        let json = serde_json::to_string(&Versioned::new(&app)).unwrap();
+        let read: Versioned<InstalledApp> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.data.name, app.name);
+        assert_eq!(read.data.hash, app.hash);
+        assert_eq!(read.data.hash_code_data, app.hash_code_data);
+        assert_eq!(read.data.blocks, app.blocks);
+        assert_eq!(read.data.flags, app.flags);
+
+        let running = Versioned::new(RunningApp {
+            name: "Bitcoin".to_string(),
+            version: "2.2.0-rc1".to_string(),
+            flags: vec![0x02],
+        });
+        let json = serde_json::to_string(&running).unwrap();
+        assert_eq!(
+            json,
+            r#"{"schema_version":1,"name":"Bitcoin","version":"2.2.0-rc1","flags":"02"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Versioned<_>>(&json).unwrap(),
+            running
+        );
+    }
+}
@@ -4,15 +4,35 @@
 //! This is performed by both talking to the Ledger device connected by USB but also by making HTTP
 //! request to the Ledger API used by Ledger Live.
 
+pub mod bitcoin_app;
+pub mod catalog;
+mod ensure;
+mod hex_bytes;
+pub mod json;
+mod net;
+mod progress;
+pub mod snapshot;
+mod tls;
+pub mod transport;
+mod upgrade;
+
+pub use ensure::{ensure_bitcoin_app, EnsureAction, EnsureErr, Network, VersionRequirement};
 pub use ledger_apdu;
 pub use ledger_transport_hidapi;
+pub use net::{NetError, Proxy};
+pub use progress::{Event, EventHandler};
+pub use tls::SpkiPin;
+pub use upgrade::{
+    plan_upgrade, run_upgrade_step, FinalFirmware, OsuFirmware, UpgradePlan, UpgradeStep,
+};
 
 use form_urlencoded::Serializer as UrlSerializer;
 use ledger_apdu::APDUCommand;
 use ledger_transport_hidapi::TransportNativeHID;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+use transport::Transport;
 
-use std::{error, str};
+use std::{error, fmt, path::PathBuf, str, thread, time};
 
 // https://github.com/LedgerHQ/ledger-live/blob/dd1d17fd3ce7ed42558204b2f93707fb9b1599de/libs/device-core/src/commands/use-cases/getVersion.ts#L6
 const GET_VERSION_COMMAND: APDUCommand<&[u8]> = APDUCommand {
@@ -50,6 +70,37 @@ const OPEN_APP_COMMAND_TEMPLATE: APDUCommand<&[u8]> = APDUCommand {
     data: &[],
 };
 
+// https://github.com/LedgerHQ/ledger-live/blob/5a0a1aa5dc183116839851b79bceb6704f1de4b9/libs/ledger-live-common/src/hw/quitApp.ts#L4
+const QUIT_APP_COMMAND: APDUCommand<&[u8]> = APDUCommand {
+    cla: 0xb0,
+    ins: 0xa7,
+    p1: 0x00,
+    p2: 0x00,
+    data: &[],
+};
+
+// https://github.com/LedgerHQ/ledger-live/blob/5a0a1aa5dc183116839851b79bceb6704f1de4b9/libs/ledger-live-common/src/hw/getAppAndVersion.ts#L7
+const GET_APP_AND_VERSION_COMMAND: APDUCommand<&[u8]> = APDUCommand {
+    cla: 0xb0,
+    ins: 0x01,
+    p1: 0x00,
+    p2: 0x00,
+    data: &[],
+};
+
+// The name the dashboard answers GET_APP_AND_VERSION with.
+const DASHBOARD_APP_NAME: &str = "BOLOS";
+
+/// How long to wait for an app to be started after asking the dashboard to open it. The user may
+/// have to confirm on the device.
+pub const OPEN_APP_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// How long to wait for the dashboard to come back after asking the running app to quit.
+pub const QUIT_APP_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// How often to poll the device while waiting for the dashboard.
+const DASHBOARD_POLL_INTERVAL: time::Duration = time::Duration::from_millis(300);
+
 /// The Ledger Live API requires request to set their claimed version of Ledger Live. This was
 /// chosen arbitrarily as a working value.
 pub const LIVE_COMMON_VERSION: &str = "34.0.0";
@@ -64,57 +115,288 @@ pub const BASE_API_V1_URL: &str = "https://manager.api.live.ledger.com/api";
 pub const BASE_API_V2_URL: &str = "https://manager.api.live.ledger.com/api/v2";
 pub const BASE_SOCKET_URL: &str = "wss://scriptrunner.api.live.ledger.com/update";
 
+/// How long to wait for a connection to the Ledger API (or the proxy) by default.
+pub const DEFAULT_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(15);
+/// How long to wait for data from the Ledger API by default, on both HTTP requests and websockets.
+pub const DEFAULT_READ_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+/// How many times idempotent requests are retried by default.
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Settings for the queries to the Ledger API.
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    /// Never send the hashes of the installed apps to Ledger, as they tell exactly which apps the
+    /// user holds. Installed apps are instead identified locally, by matching their hash against
+    /// the catalog for this device model and firmware (queried without any identifying data) and
+    /// against the catalog cache. Apps which can't be identified are reported as unknown.
+    pub privacy: bool,
+    /// Where to cache the apps seen in the catalog. See the [`catalog`] module.
+    pub catalog_cache: Option<PathBuf>,
+    /// The proxy to connect to the Ledger API through, for both the HTTP requests and the
+    /// websockets. Use the SOCKS port of Tor not to reveal your IP address to Ledger.
+    pub proxy: Option<Proxy>,
+    /// How long to wait for a connection to be established, to the server or the proxy.
+    pub connect_timeout: time::Duration,
+    /// How long to wait for data from the server, including the messages on the websockets.
+    /// A stalled server fails the query with [`NetError::Timeout`] instead of blocking forever.
+    pub read_timeout: time::Duration,
+    /// How many times to retry idempotent requests, such as querying the catalog, on transient
+    /// failures. The delay between attempts starts at `retry_backoff` and doubles each time.
+    pub retries: u32,
+    pub retry_backoff: time::Duration,
+    /// The public keys the Ledger API servers must present, on top of having a certificate
+    /// trusted by the system. Connections to a server presenting none of them are aborted
+    /// before any data is exchanged, in particular before the HSM can send any APDU to the device.
+    /// Configure more than one key (for instance a backup key) to be able to rotate them. No
+    /// pinning if empty.
+    pub tls_pins: Vec<SpkiPin>,
+    /// The base URL of the Manager API, [`BASE_API_V1_URL`] by default. The v2 API is expected
+    /// under `/v2`.
+    pub api_url: String,
+    /// The base URL of the scriptrunner websockets, [`BASE_SOCKET_URL`] by default.
+    pub socket_url: String,
+    /// Called with the steps of the operations using this configuration, and the progress of the
+    /// sessions with Ledger's HSM. See the [`Event`]s.
+    pub on_event: Option<EventHandler>,
+}
+
+impl ApiConfig {
+    // Report what we are doing, if anyone is listening.
+    fn emit(&self, event: Event) {
+        if let Some(handler) = &self.on_event {
+            handler.emit(event);
+        }
+    }
+
+    fn status(&self, status: &str) {
+        self.emit(Event::Status(status.to_string()));
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            privacy: false,
+            catalog_cache: None,
+            proxy: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            retry_backoff: time::Duration::from_millis(500),
+            tls_pins: Vec::new(),
+            api_url: BASE_API_V1_URL.to_string(),
+            socket_url: BASE_SOCKET_URL.to_string(),
+            on_event: None,
+        }
+    }
+}
+
 /// The return code when sending an APDU command to a Ledger device. Taken from
 /// https://github.com/LedgerHQ/ledger-live/blob/4d1d7bb3462fd0c986ed587f0cf426afc96850c8/libs/ledgerjs/packages/errors/src/index.ts#L233
 #[derive(Debug, Clone, Copy)]
 pub enum StatusCode {
-    //ACCESS_CONDITION_NOT_FULFILLED = 0x9804,
-    //ALGORITHM_NOT_SUPPORTED = 0x9484,
-    //CLA_NOT_SUPPORTED = 0x6e00,
-    //CODE_BLOCKED = 0x9840,
-    //CODE_NOT_INITIALIZED = 0x9802,
-    //COMMAND_INCOMPATIBLE_FILE_STRUCTURE = 0x6981,
-    //CONDITIONS_OF_USE_NOT_SATISFIED = 0x6985,
-    //CONTRADICTION_INVALIDATION = 0x9810,
-    //CONTRADICTION_SECRET_CODE_STATUS = 0x9808,
-    //CUSTOM_IMAGE_BOOTLOADER = 0x662f,
-    //CUSTOM_IMAGE_EMPTY = 0x662e,
-    //FILE_ALREADY_EXISTS = 0x6a89,
-    //FILE_NOT_FOUND = 0x9404,
-    //GP_AUTH_FAILED = 0x6300,
-    //HALTED = 0x6faa,
-    //INCONSISTENT_FILE = 0x9408,
-    //INCORRECT_DATA = 0x6a80,
-    //INCORRECT_LENGTH = 0x6700,
-    //INCORRECT_P1_P2 = 0x6b00,
-    //INS_NOT_SUPPORTED = 0x6d00,
-    //DEVICE_NOT_ONBOARDED = 0x6d07,
-    //DEVICE_NOT_ONBOARDED_2 = 0x6611,
-    //INVALID_KCV = 0x9485,
-    //INVALID_OFFSET = 0x9402,
-    //LICENSING = 0x6f42,
+    AccessConditionNotFulfilled = 0x9804,
+    AlgorithmNotSupported = 0x9484,
+    ClaNotSupported = 0x6e00,
+    CodeBlocked = 0x9840,
+    CodeNotInitialized = 0x9802,
+    CommandIncompatibleFileStructure = 0x6981,
+    ConditionsOfUseNotSatisfied = 0x6985,
+    ContradictionInvalidation = 0x9810,
+    ContradictionSecretCodeStatus = 0x9808,
+    CustomImageBootloader = 0x662f,
+    CustomImageEmpty = 0x662e,
+    FileAlreadyExists = 0x6a89,
+    FileNotFound = 0x9404,
+    GpAuthFailed = 0x6300,
+    Halted = 0x6faa,
+    InconsistentFile = 0x9408,
+    IncorrectData = 0x6a80,
+    IncorrectLength = 0x6700,
+    IncorrectP1P2 = 0x6b00,
+    InsNotSupported = 0x6d00,
+    DeviceNotOnboarded = 0x6d07,
+    DeviceNotOnboarded2 = 0x6611,
+    InvalidKcv = 0x9485,
+    InvalidOffset = 0x9402,
+    Licensing = 0x6f42,
     LockedDevice = 0x5515,
-    //MAX_VALUE_REACHED = 0x9850,
-    //MEMORY_PROBLEM = 0x9240,
-    //MISSING_CRITICAL_PARAMETER = 0x6800,
-    //NO_EF_SELECTED = 0x9400,
-    //NOT_ENOUGH_MEMORY_SPACE = 0x6a84,
+    MaxValueReached = 0x9850,
+    MemoryProblem = 0x9240,
+    MissingCriticalParameter = 0x6800,
+    NoEfSelected = 0x9400,
+    NotEnoughMemorySpace = 0x6a84,
     OK = 0x9000,
-    //PIN_REMAINING_ATTEMPTS = 0x63c0,
-    //REFERENCED_DATA_NOT_FOUND = 0x6a88,
-    //SECURITY_STATUS_NOT_SATISFIED = 0x6982,
-    //TECHNICAL_PROBLEM = 0x6f00,
-    //UNKNOWN_APDU = 0x6d02,
-    //USER_REFUSED_ON_DEVICE = 0x5501,
-    //NOT_ENOUGH_SPACE = 0x5102,
+    PinRemainingAttempts = 0x63c0,
+    ReferencedDataNotFound = 0x6a88,
+    SecurityStatusNotSatisfied = 0x6982,
+    TechnicalProblem = 0x6f00,
+    UnknownApdu = 0x6d02,
+    UserRefusedOnDevice = 0x5501,
+    NotEnoughSpace = 0x5102,
+}
+
+impl StatusCode {
+    /// The status code for this status word, if known.
+    pub fn from_u16(status: u16) -> Option<Self> {
+        match status {
+            0x9804 => Some(Self::AccessConditionNotFulfilled),
+            0x9484 => Some(Self::AlgorithmNotSupported),
+            0x6e00 => Some(Self::ClaNotSupported),
+            0x9840 => Some(Self::CodeBlocked),
+            0x9802 => Some(Self::CodeNotInitialized),
+            0x6981 => Some(Self::CommandIncompatibleFileStructure),
+            0x6985 => Some(Self::ConditionsOfUseNotSatisfied),
+            0x9810 => Some(Self::ContradictionInvalidation),
+            0x9808 => Some(Self::ContradictionSecretCodeStatus),
+            0x662f => Some(Self::CustomImageBootloader),
+            0x662e => Some(Self::CustomImageEmpty),
+            0x6a89 => Some(Self::FileAlreadyExists),
+            0x9404 => Some(Self::FileNotFound),
+            0x6300 => Some(Self::GpAuthFailed),
+            0x6faa => Some(Self::Halted),
+            0x9408 => Some(Self::InconsistentFile),
+            0x6a80 => Some(Self::IncorrectData),
+            0x6700 => Some(Self::IncorrectLength),
+            0x6b00 => Some(Self::IncorrectP1P2),
+            0x6d00 => Some(Self::InsNotSupported),
+            0x6d07 => Some(Self::DeviceNotOnboarded),
+            0x6611 => Some(Self::DeviceNotOnboarded2),
+            0x9485 => Some(Self::InvalidKcv),
+            0x9402 => Some(Self::InvalidOffset),
+            0x6f42 => Some(Self::Licensing),
+            0x5515 => Some(Self::LockedDevice),
+            0x9850 => Some(Self::MaxValueReached),
+            0x9240 => Some(Self::MemoryProblem),
+            0x6800 => Some(Self::MissingCriticalParameter),
+            0x9400 => Some(Self::NoEfSelected),
+            0x6a84 => Some(Self::NotEnoughMemorySpace),
+            0x9000 => Some(Self::OK),
+            0x63c0 => Some(Self::PinRemainingAttempts),
+            0x6a88 => Some(Self::ReferencedDataNotFound),
+            0x6982 => Some(Self::SecurityStatusNotSatisfied),
+            0x6f00 => Some(Self::TechnicalProblem),
+            0x6d02 => Some(Self::UnknownApdu),
+            0x5501 => Some(Self::UserRefusedOnDevice),
+            0x5102 => Some(Self::NotEnoughSpace),
+            _ => None,
+        }
+    }
+}
+
+/// An error status word returned by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceError(pub u16);
+
+impl DeviceError {
+    /// Whether the device is locked, and needs the user to enter their PIN.
+    pub fn is_locked(&self) -> bool {
+        self.0 == StatusCode::LockedDevice as u16
+    }
+
+    /// Whether the user rejected the operation on the device.
+    pub fn is_user_refusal(&self) -> bool {
+        self.0 == StatusCode::ConditionsOfUseNotSatisfied as u16
+            || self.0 == StatusCode::UserRefusedOnDevice as u16
+    }
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_locked() {
+            write!(f, "Device is locked.")
+        } else if self.is_user_refusal() {
+            write!(f, "The operation was rejected on the device.")
+        } else {
+            write!(f, "Device error. Return code: {:#06x}.", self.0)
+        }
+    }
+}
+
+impl error::Error for DeviceError {}
+
+// The flags in the first byte of the device information. See
+// https://github.com/LedgerHQ/ledger-live/blob/dd1d17fd3ce7ed42558204b2f93707fb9b1599de/libs/device-core/src/commands/use-cases/parseGetVersionResponse.ts
+pub(crate) const MANAGER_ALLOWED_FLAG: u8 = 0x08;
+pub(crate) const PIN_VALIDATED_FLAG: u8 = 0x80;
+
+pub(crate) fn device_flag(flags: &[u8], mask: u8) -> bool {
+    flags.first().is_some_and(|f| f & mask != 0)
+}
+
+/// A model of Ledger device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceModel {
+    NanoS,
+    NanoX,
+    NanoSPlus,
+    Stax,
+    Flex,
+}
+
+impl DeviceModel {
+    /// The target id of the current firmwares for this model, as reported in [`DeviceInfo`].
+    pub fn target_id(&self) -> u32 {
+        match self {
+            Self::NanoS => 0x31100004,
+            Self::NanoX => 0x33000004,
+            Self::NanoSPlus => 0x33100004,
+            Self::Stax => 0x33200004,
+            Self::Flex => 0x33300004,
+        }
+    }
+
+    /// The model of a device with this target id, if known.
+    pub fn from_target_id(target_id: u32) -> Option<Self> {
+        [
+            Self::NanoS,
+            Self::NanoX,
+            Self::NanoSPlus,
+            Self::Stax,
+            Self::Flex,
+        ]
+        .into_iter()
+        .find(|model| model.target_id() == target_id)
+    }
+}
+
+impl str::FromStr for DeviceModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace([' ', '_', '-'], "").as_str() {
+            "nanos" => Ok(Self::NanoS),
+            "nanox" => Ok(Self::NanoX),
+            "nanosp" | "nanos+" | "nanosplus" => Ok(Self::NanoSPlus),
+            "stax" => Ok(Self::Stax),
+            "flex" => Ok(Self::Flex),
+            _ => Err(format!(
+                "Unknown device model '{}'. Expected one of nanos, nanox, nanosp, stax, flex.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DeviceModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NanoS => write!(f, "Nano S"),
+            Self::NanoX => write!(f, "Nano X"),
+            Self::NanoSPlus => write!(f, "Nano S+"),
+            Self::Stax => write!(f, "Stax"),
+            Self::Flex => write!(f, "Flex"),
+        }
+    }
 }
 
 /// Information queried from a Ledger device.
 // NOTE: MCU target id is always == target_id in Ledger Live
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceInfo {
     pub target_id: u32,
     pub version: String,
+    #[serde(with = "json::device_flags")]
     pub flags: Vec<u8>,
     pub is_bootloader: bool,
     pub se_version: Option<String>,
@@ -123,6 +405,22 @@ pub struct DeviceInfo {
 }
 
 impl DeviceInfo {
+    /// Whether the Ledger manager was allowed on the device during this session.
+    pub fn manager_allowed(&self) -> bool {
+        device_flag(&self.flags, MANAGER_ALLOWED_FLAG)
+    }
+
+    /// Whether the PIN was entered on the device.
+    pub fn pin_validated(&self) -> bool {
+        device_flag(&self.flags, PIN_VALIDATED_FLAG)
+    }
+
+    /// Whether the device is running the firmware updater ("OSU"), in the middle of a firmware
+    /// update.
+    pub fn is_osu(&self) -> bool {
+        self.version.contains("-osu")
+    }
+
     /// Query information about this device.
     ///
     /// Adapted from https://github.com/LedgerHQ/ledger-live/blob/dd1d17fd3ce7ed42558204b2f93707fb9b1599de/libs/device-core/src/commands/use-cases/parseGetVersionResponse.ts
@@ -130,7 +428,7 @@ impl DeviceInfo {
         let ver_answer = ledger_api.exchange(&GET_VERSION_COMMAND)?;
         let ret = ver_answer.retcode();
         if ret == StatusCode::LockedDevice as u16 {
-            return Err("Device is locked.".into());
+            return Err(DeviceError(ret).into());
         } else if ret != StatusCode::OK as u16 {
             return Err(format!("Device isn't ready. Return code: {}.", ret).into());
         }
@@ -234,7 +532,6 @@ impl DeviceInfo {
 
             //let osu_str = b"-osu";
             //if raw_ver.windows(osu_str.len()).any(|w| w == osu_str) {}
-            //TODO. See https://github.com/LedgerHQ/ledger-live/blob/dcbda65e65ead4014e767778da6022b78d8eddad/libs/ledgerjs/packages/devices/src/index.ts#L3-L156
 
             Self {
                 target_id,
@@ -250,10 +547,12 @@ impl DeviceInfo {
 }
 
 /// Information about an application as queried directly from the device.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InstalledApp {
     pub name: String,
+    #[serde(with = "crate::hex_bytes")]
     pub hash: Vec<u8>,
+    #[serde(with = "crate::hex_bytes")]
     pub hash_code_data: Vec<u8>,
     pub blocks: u16,
     pub flags: u16,
@@ -271,41 +570,138 @@ struct HsmMessage {
     pub query: String,
     pub nonce: u32,
     pub data: Option<HsmMessageData>,
+    // Sent along with a success, for instance the result of a genuine check.
+    #[serde(default)]
+    pub result: Option<String>,
 }
 
-fn deser_apdu_command(hex_str: &str) -> Result<APDUCommand<Vec<u8>>, Box<dyn error::Error>> {
-    let bytes = hex::decode(hex_str)?;
-    if bytes.len() < 5 {
-        return Err("Invalid command".into());
+/// The maximum length of the data of a command with a single byte for the length of the data
+/// (short form), the only form the Ledger devices accept.
+pub const MAX_APDU_DATA_LEN: usize = u8::MAX as usize;
+
+/// The maximum length of the data of a command with two bytes for the length of the data
+/// (extended form).
+pub const MAX_EXTENDED_APDU_DATA_LEN: usize = u16::MAX as usize;
+
+/// Serialize an APDU command: the class, instruction and parameters, followed by the length of the
+/// data and the data. The length is in the short form if it fits, in the extended form otherwise.
+///
+/// # Panics
+///
+/// If the data is longer than [`MAX_EXTENDED_APDU_DATA_LEN`].
+pub fn ser_apdu_command(command: &APDUCommand<Vec<u8>>) -> Vec<u8> {
+    let mut ser = vec![command.cla, command.ins, command.p1, command.p2];
+    match u8::try_from(command.data.len()) {
+        Ok(len) => ser.push(len),
+        Err(_) => {
+            let len = u16::try_from(command.data.len())
+                .expect("The data of an APDU command is at most 65535 bytes long.");
+            ser.push(0);
+            ser.extend_from_slice(&len.to_be_bytes());
+        }
     }
+    ser.extend_from_slice(&command.data);
+    ser
+}
 
-    let (cla, ins, p1, p2, data_len) = (bytes[0], bytes[1], bytes[2], bytes[3], bytes[4] as usize);
-    if bytes.len() != 5 + data_len {
-        return Err("Invalid command".into());
-    }
+/// Parse an APDU command from its hex encoding: the class, instruction and parameters, followed by
+/// the length of the data and the data. The length is a single byte (short form) or, if the first
+/// byte is zero and followed by data, two big-endian bytes (extended form, for up to
+/// [`MAX_EXTENDED_APDU_DATA_LEN`] bytes of data). The length may be omitted for a command without
+/// data. Whitespace is ignored. This leniency is meant for commands written by hand, the commands
+/// sent by Ledger's HSM are parsed strictly.
+pub fn deser_apdu_command(hex_str: &str) -> Result<APDUCommand<Vec<u8>>, Box<dyn error::Error>> {
+    let hex_str: String = hex_str.split_whitespace().collect();
+    let bytes =
+        hex::decode(&hex_str).map_err(|e| format!("Invalid command '{}': {}", hex_str, e))?;
+    let (header, rest) = match bytes.split_at_checked(4) {
+        Some(split) => split,
+        None => return Err(format!("Invalid command '{}': too short.", hex_str).into()),
+    };
 
+    let data = match rest {
+        [] => &[][..],
+        [0, len_hi, len_lo, data @ ..] if !data.is_empty() || (*len_hi, *len_lo) == (0, 0) => {
+            let len = u16::from_be_bytes([*len_hi, *len_lo]) as usize;
+            if data.len() != len {
+                return Err(format!(
+                    "Invalid command '{}': {} bytes of data announced, {} given.",
+                    hex_str,
+                    len,
+                    data.len()
+                )
+                .into());
+            }
+            data
+        }
+        [len, data @ ..] => {
+            if data.len() != *len as usize {
+                return Err(format!(
+                    "Invalid command '{}': {} bytes of data announced, {} given.",
+                    hex_str,
+                    len,
+                    data.len()
+                )
+                .into());
+            }
+            data
+        }
+    };
     Ok(APDUCommand {
-        cla,
-        ins,
-        p1,
-        p2,
-        data: bytes[5..].to_vec(),
+        cla: header[0],
+        ins: header[1],
+        p1: header[2],
+        p2: header[3],
+        data: data.to_vec(),
     })
 }
 
+// Parse an APDU command sent by the HSM, which is always in the short form with a length byte.
+// Unlike deser_apdu_command, anything else is rejected rather than guessed.
+fn deser_hsm_apdu_command(hex_str: &str) -> Result<APDUCommand<Vec<u8>>, Box<dyn error::Error>> {
+    let bytes = hex::decode(hex_str).map_err(|e| format!("Invalid HSM command: {}", e))?;
+    match bytes.as_slice() {
+        [cla, ins, p1, p2, len, data @ ..] if data.len() == *len as usize => Ok(APDUCommand {
+            cla: *cla,
+            ins: *ins,
+            p1: *p1,
+            p2: *p2,
+            data: data.to_vec(),
+        }),
+        _ => Err(format!("Invalid HSM command '{}'.", hex_str).into()),
+    }
+}
+
 /// Some actions, such as installing apps or upgrading the firmware, are done in Ledger Live by
 /// opening a socket so a remote server communicates directly with the Ledger. It appears to be
 /// talking to an HSM up there which would manage sensitive actions.
 /// Parameters are passed directly in the url. Don't forget to escape the necessary characters!
+/// Returns the result sent along with the success, if any. If the HSM aborts the session after the
+/// device rejected a command, the error is a [`DeviceError`] with the status of this command.
+/// The `operation`, such as "installing the app", describes the session in the logs.
 pub fn query_via_websocket(
     ledger_api: &TransportNativeHID,
     url: &str,
-) -> Result<(), Box<dyn error::Error>> {
-    let (mut socket, _) = tungstenite::connect(url)?;
+    operation: &str,
+    config: &ApiConfig,
+) -> Result<Option<String>, Box<dyn error::Error>> {
+    websocket_session(ledger_api, url, operation, config).map(|(result, _)| result)
+}
+
+// Like query_via_websocket, also returning the number of APDUs sent to the device.
+fn websocket_session(
+    ledger_api: &TransportNativeHID,
+    url: &str,
+    operation: &str,
+    config: &ApiConfig,
+) -> Result<(Option<String>, usize), Box<dyn error::Error>> {
+    let mut socket = net::connect_websocket(url, config)?;
+    let mut device_error = None;
+    let mut apdu_count = 0;
 
     // https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/socket/index.ts#L95
     loop {
-        let msg = socket.read()?;
+        let msg = socket.read().map_err(NetError::from)?;
         match msg {
             // It appears they only exchange JSON text messages.
             tungstenite::Message::Text(text) => {
@@ -320,19 +716,22 @@ pub fn query_via_websocket(
                         Some(HsmMessageData::Command(h)) => h,
                         _ => return Err("A single command is expected in 'exchange' mode.".into()),
                     };
-                    let command = deser_apdu_command(&command_hex)?;
+                    let command = deser_hsm_apdu_command(&command_hex)?;
 
                     // NOTE: the HSM expects only the data, not the last two bytes of the raw
                     // response (the status) in the "data" field below.
-                    let resp = ledger_api.exchange(&command)?;
+                    let resp = Transport::exchange(ledger_api, &command)?;
+                    apdu_count += 1;
                     let response = if resp.retcode() == StatusCode::OK as u16 {
                         "success"
                     } else {
-                        eprintln!(
-                            "Error when installing app. Error code: {:#02x}. Resp: {:?}.",
+                        log::warn!(
+                            "Error when {}. Error code: {:#02x}. Resp: {:?}.",
+                            operation,
                             resp.retcode(),
                             resp
                         );
+                        device_error = Some(DeviceError(resp.retcode()));
                         "error"
                     };
                     let resp_data = hex::encode(resp.data());
@@ -342,7 +741,9 @@ pub fn query_via_websocket(
                         "response": response,
                         "data": resp_data,
                     });
-                    socket.send(tungstenite::Message::Text(serde_json::to_string(&ws_resp)?))?;
+                    socket
+                        .send(tungstenite::Message::Text(serde_json::to_string(&ws_resp)?))
+                        .map_err(NetError::from)?;
                 } else if msg.query == "bulk" {
                     // Ledger Live closes the socket immediately after receiving a bulk. It doesn't
                     // appear to be necessary, on the contrary if we don't we get a clean "success"
@@ -353,12 +754,13 @@ pub fn query_via_websocket(
                         Some(HsmMessageData::CommandList(l)) => l,
                         _ => return Err("Expecting a list of commands in bulk mode.".into()),
                     };
-                    for cmd_hex in commands {
-                        if cmd_hex.is_empty() {
-                            continue;
-                        }
-                        let command = deser_apdu_command(&cmd_hex)?;
-                        let _ = ledger_api.exchange(&command)?;
+                    let total = commands.iter().filter(|c| !c.is_empty()).count();
+                    config.emit(Event::Progress { done: 0, total });
+                    for (i, cmd_hex) in commands.iter().filter(|c| !c.is_empty()).enumerate() {
+                        let command = deser_hsm_apdu_command(cmd_hex)?;
+                        let _ = Transport::exchange(ledger_api, &command)?;
+                        apdu_count += 1;
+                        config.emit(Event::Progress { done: i + 1, total });
                     }
 
                     let ws_resp = serde_json::json!({
@@ -366,15 +768,21 @@ pub fn query_via_websocket(
                         "response": "success",
                         "data": "",
                     });
-                    socket.send(tungstenite::Message::Text(serde_json::to_string(&ws_resp)?))?;
+                    socket
+                        .send(tungstenite::Message::Text(serde_json::to_string(&ws_resp)?))
+                        .map_err(NetError::from)?;
                 } else if msg.query == "success" {
-                    return Ok(());
+                    return Ok((msg.result, apdu_count));
                 } else if msg.query == "error" {
+                    if let Some(e) = device_error {
+                        log::debug!("Got an 'error' query on the ws. Full message: {}.", text);
+                        return Err(e.into());
+                    }
                     return Err(
                         format!("Got an 'error' query on the ws. Full message: {}.", text).into(),
                     );
                 } else if msg.query == "warning" {
-                    eprintln!("Got a 'warning' query on the ws. Full message: {}.", text);
+                    log::warn!("Got a 'warning' query on the ws. Full message: {}.", text);
                 } else {
                     return Err(format!(
                         "Got an unsupported query on the ws. Full message: {}.",
@@ -452,18 +860,47 @@ pub fn list_installed_apps_raw(
 }
 
 /// Get the metadata of the applications installed on the device. This calls the Ledger API, to
-/// only query the data available from the device see `list_installed_apps_raw`.
+/// only query the data available from the device see `list_installed_apps_raw`. In privacy mode
+/// the hashes of the apps are never sent, and unknown apps are `None`.
 pub fn list_installed_apps(
     ledger_api: &TransportNativeHID,
+    config: &ApiConfig,
 ) -> Result<Vec<Option<BitcoinAppInfo>>, Box<dyn error::Error>> {
-    let hashes = list_installed_apps_raw(ledger_api)?
-        .into_iter()
-        .map(|a| a.hash)
-        .collect::<Vec<_>>();
-    if hashes.is_empty() {
+    config.status("Listing the installed apps. You might have to confirm on the device.");
+    identify_apps(ledger_api, &list_installed_apps_raw(ledger_api)?, config)
+}
+
+/// Get the metadata of these apps as listed by [`list_installed_apps_raw`], in the same order.
+/// Like [`list_installed_apps`] but without listing them again from the device.
+pub fn identify_apps(
+    ledger_api: &TransportNativeHID,
+    apps: &[InstalledApp],
+    config: &ApiConfig,
+) -> Result<Vec<Option<BitcoinAppInfo>>, Box<dyn error::Error>> {
+    if apps.is_empty() {
         return Ok(Vec::new());
     }
-    bitcoin_apps_by_hashes(hashes)
+    let hashes = apps.iter().map(|a| a.hash.clone()).collect();
+    apps_by_hashes(ledger_api, hashes, config)
+}
+
+// Get metadata about a list of apps identified by their hash. In privacy mode they are matched
+// against the catalog and the local cache instead of sending the hashes to the Ledger API.
+fn apps_by_hashes(
+    ledger_api: &TransportNativeHID,
+    hashes: Vec<Vec<u8>>,
+    config: &ApiConfig,
+) -> Result<Vec<Option<BitcoinAppInfo>>, Box<dyn error::Error>> {
+    if !config.privacy {
+        return bitcoin_apps_by_hashes(hashes, config);
+    }
+
+    let device_info = DeviceInfo::new(ledger_api)?;
+    let mut known = get_catalog(&device_info, config)?;
+    if let Some(path) = &config.catalog_cache {
+        known.extend(catalog::load(path)?);
+    }
+    Ok(catalog::resolve(&hashes, &known))
 }
 
 /// Get the installed Bitcoin app, if any. Set `is_testnet` to look for the testnet Bitcoin app.
@@ -481,6 +918,25 @@ pub fn bitcoin_app_installed(
         .find(|app| app.name.to_lowercase() == lowercase_app_name))
 }
 
+/// Get the version of the installed Bitcoin app, if any, as reported by the Ledger API for its
+/// hash. Set `is_testnet` to look for the testnet Bitcoin app.
+pub fn bitcoin_app_version(
+    ledger_api: &TransportNativeHID,
+    is_testnet: bool,
+    config: &ApiConfig,
+) -> Result<Option<bitcoin_app::AppVersion>, Box<dyn error::Error>> {
+    let app = match bitcoin_app_installed(ledger_api, is_testnet)? {
+        Some(app) => app,
+        None => return Ok(None),
+    };
+    let app_info = apps_by_hashes(ledger_api, vec![app.hash], config)?
+        .into_iter()
+        .next()
+        .flatten()
+        .ok_or("Could not get info about the installed Bitcoin app.")?;
+    Ok(Some(app_info.version.parse()?))
+}
+
 /// Whether the Bitcoin app is installed on this device.
 pub fn is_bitcoin_app_installed(
     ledger_api: &TransportNativeHID,
@@ -489,51 +945,56 @@ pub fn is_bitcoin_app_installed(
     Ok(bitcoin_app_installed(ledger_api, is_testnet)?.is_some())
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DeviceVersion {
     pub id: i64,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl DeviceVersion {
+    pub fn from_device(
+        device_info: &DeviceInfo,
+        config: &ApiConfig,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        let dev_ver_resp = net::Request::post(format!("{}/get_device_version", config.api_url))
+            .with_param("livecommonversion", LIVE_COMMON_VERSION)
+            .with_json(&serde_json::json!({
+            "provider": PROVIDER,
+            "target_id": device_info.target_id,
+            }))?
+            .idempotent()
+            .send(config)?;
+        dev_ver_resp.json::<DeviceVersion>()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct FirmwareInfo {
+    /// The id of this firmware version in the Ledger API.
+    pub id: i64,
     pub perso: String,
 }
 
 impl FirmwareInfo {
-    pub fn from_device(device_info: &DeviceInfo) -> Self {
-        let dev_ver_resp = minreq::Request::new(
-            minreq::Method::Post,
-            &format!("{}/get_device_version", BASE_API_V1_URL),
-        )
-        .with_param("livecommonversion", LIVE_COMMON_VERSION)
-        .with_json(&serde_json::json!({
-        "provider": PROVIDER,
-        "target_id": device_info.target_id,
-        }))
-        .unwrap()
-        .send()
-        .unwrap();
-        let device_version = dev_ver_resp.json::<DeviceVersion>().unwrap();
-
-        let firm_resp = minreq::Request::new(
-            minreq::Method::Post,
-            &format!("{}/get_firmware_version", BASE_API_V1_URL),
-        )
-        .with_param("livecommonversion", LIVE_COMMON_VERSION)
-        .with_json(&serde_json::json!({
-        "provider": PROVIDER,
-        "device_version": device_version.id,
-        "version_name": &device_info.version,
-        }))
-        .unwrap()
-        .send()
-        .unwrap();
-        firm_resp.json::<FirmwareInfo>().unwrap()
+    pub fn from_device(
+        device_info: &DeviceInfo,
+        config: &ApiConfig,
+    ) -> Result<Self, Box<dyn error::Error>> {
+        let device_version = DeviceVersion::from_device(device_info, config)?;
+        let firm_resp = net::Request::post(format!("{}/get_firmware_version", config.api_url))
+            .with_param("livecommonversion", LIVE_COMMON_VERSION)
+            .with_json(&serde_json::json!({
+            "provider": PROVIDER,
+            "device_version": device_version.id,
+            "version_name": &device_info.version,
+            }))?
+            .idempotent()
+            .send(config)?;
+        firm_resp.json::<FirmwareInfo>()
     }
 }
 
 /// Information about a Bitcoin application as queried from the Ledger API (not the Ledger device).
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BitcoinAppInfo {
     #[serde(rename = "versionName")]
     pub version_name: String,
@@ -541,35 +1002,88 @@ pub struct BitcoinAppInfo {
     pub version_id: u32,
     pub version: String,
     pub perso: String,
+    /// The path to the binary uninstalling this app. Missing in catalog caches written by older
+    /// versions.
+    #[serde(default)]
+    pub delete: String,
     #[serde(rename = "deleteKey")]
     pub delete_key: String,
     pub firmware: String,
     #[serde(rename = "firmwareKey")]
     pub firmware_key: String,
     pub hash: String,
+    /// The size of the app, in bytes. Missing in catalog caches written by older versions.
+    #[serde(default)]
+    pub bytes: Option<u64>,
+    /// The name of the app this one depends on and which must be installed first, such as
+    /// Ethereum for its plugins.
+    #[serde(rename = "parentName", default)]
+    pub parent_name: Option<String>,
+}
+
+impl BitcoinAppInfo {
+    /// The version of the firmware this binary of the app is for, from its path such as
+    /// `nanox/2.2.3/bitcoin/app_2.2.3`.
+    pub fn firmware_version(&self) -> Option<&str> {
+        self.firmware.split('/').nth(1)
+    }
 }
 
 // Returns a Vec of Options as some elements in the response's JSON array may be `null`.
 /// Get metadata about a list of Bitcoin apps identified by their hash. Elements returned seem to
-/// be in the same order as the hashes, with `None` for not found.
+/// be in the same order as the hashes, with `None` for not found. Note this sends the hashes to
+/// Ledger: use [`list_installed_apps`] in privacy mode to avoid it.
 pub fn bitcoin_apps_by_hashes(
     hashes: Vec<Vec<u8>>,
+    config: &ApiConfig,
 ) -> Result<Vec<Option<BitcoinAppInfo>>, Box<dyn error::Error>> {
     if hashes.is_empty() {
         let e: Vec<Option<BitcoinAppInfo>> = Vec::new();
         return Ok(e);
     }
     let hashes_hex: Vec<_> = hashes.into_iter().map(|h| hex::encode(&h).into()).collect();
-    let resp_apps = minreq::Request::new(
-        minreq::Method::Post,
-        format!("{}/apps/hash", BASE_API_V2_URL),
-    )
-    .with_param("livecommonversion", LIVE_COMMON_VERSION)
-    .with_json(&serde_json::Value::Array(hashes_hex))?
-    .send()?;
+    let resp_apps = net::Request::post(format!("{}/v2/apps/hash", config.api_url))
+        .with_param("livecommonversion", LIVE_COMMON_VERSION)
+        .with_json(&serde_json::Value::Array(hashes_hex))?
+        .idempotent()
+        .send(config)?;
     Ok(resp_apps.json::<Vec<_>>()?.into_iter().collect())
 }
 
+/// Get the latest version of all the apps available for this device model and firmware from the
+/// "catalog" (as Ledger Live calls it). This doesn't send any information identifying the user.
+/// The apps are added to the catalog cache, if any.
+pub fn get_catalog(
+    device_info: &DeviceInfo,
+    config: &ApiConfig,
+) -> Result<Vec<BitcoinAppInfo>, Box<dyn error::Error>> {
+    get_catalog_for_target(device_info.target_id, &device_info.version, config)
+}
+
+/// Like [`get_catalog`], for a device identified by its target id (see
+/// [`DeviceModel::target_id`]) and the version of its firmware. This doesn't need a device.
+pub fn get_catalog_for_target(
+    target_id: u32,
+    firmware_version: &str,
+    config: &ApiConfig,
+) -> Result<Vec<BitcoinAppInfo>, Box<dyn error::Error>> {
+    config.status("Querying the catalog of apps from the Ledger API.");
+    let apps = net::Request::get(format!("{}/v2/apps/by-target", config.api_url))
+        .with_param("livecommonversion", LIVE_COMMON_VERSION)
+        .with_param("provider", PROVIDER.to_string())
+        .with_param("target_id", target_id.to_string())
+        .with_param("firmware_version_name", firmware_version.to_string())
+        .send(config)?
+        .json::<Vec<BitcoinAppInfo>>()?;
+
+    if let Some(path) = &config.catalog_cache {
+        if let Err(e) = catalog::update(path, &apps) {
+            log::warn!("Error updating the catalog cache: {}", e);
+        }
+    }
+    Ok(apps)
+}
+
 /// Get the Bitcoin apps information for this device from the "catalog" (as Ledger Live calls it).
 // This uses the v2 API. See for reference:
 // - https://github.com/LedgerHQ/ledger-live/blob/5a0a1aa5dc183116839851b79bceb6704f1de4b9/libs/ledger-live-common/src/apps/listApps/v2.ts
@@ -578,21 +1092,12 @@ pub fn bitcoin_apps_by_hashes(
 // https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/manager/index.ts#L103-L104.
 pub fn get_latest_apps(
     device_info: &DeviceInfo,
+    config: &ApiConfig,
 ) -> Result<(Option<BitcoinAppInfo>, Option<BitcoinAppInfo>), Box<dyn error::Error>> {
     let mut bitcoin = None;
     let mut test = None;
 
-    let resp_apps = minreq::Request::new(
-        minreq::Method::Get,
-        format!("{}/apps/by-target", BASE_API_V2_URL),
-    )
-    .with_param("livecommonversion", LIVE_COMMON_VERSION)
-    .with_param("provider", PROVIDER.to_string())
-    .with_param("target_id", device_info.target_id.to_string())
-    .with_param("firmware_version_name", device_info.version.clone())
-    .send()?;
-    resp_apps
-        .json::<Vec<BitcoinAppInfo>>()?
+    get_catalog(device_info, config)?
         .into_iter()
         .for_each(|app| {
             // FIXME: is versionName guaranteed to be the name? What's "version" for?
@@ -606,13 +1111,32 @@ pub fn get_latest_apps(
     Ok((bitcoin, test))
 }
 
+/// Get the latest version of the Bitcoin apps (the mainnet and testnet apps) available for a
+/// device with this target id and firmware version, without a device. The catalog only serves
+/// the latest version of each app for a given firmware, so there is at most one of each: query it
+/// for each firmware version to know the versions available to a device being upgraded.
+pub fn latest_bitcoin_apps_for_target(
+    target_id: u32,
+    firmware_version: &str,
+    config: &ApiConfig,
+) -> Result<Vec<BitcoinAppInfo>, Box<dyn error::Error>> {
+    Ok(get_catalog_for_target(target_id, firmware_version, config)?
+        .into_iter()
+        .filter(|app| {
+            let name = app.version_name.to_lowercase();
+            name == "bitcoin" || name == "bitcoin test"
+        })
+        .collect())
+}
+
 /// Get the Bitcoin app information for this device from the "catalog" (as Ledger Live calls it).
 /// Set `is_testnet` to `true` to get the Test app instead.
 pub fn bitcoin_latest_app(
     device_info: &DeviceInfo,
     is_testnet: bool,
+    config: &ApiConfig,
 ) -> Result<Option<BitcoinAppInfo>, Box<dyn error::Error>> {
-    let apps = get_latest_apps(device_info)?;
+    let apps = get_latest_apps(device_info, config)?;
     Ok(if is_testnet { apps.1 } else { apps.0 })
 }
 
@@ -629,6 +1153,10 @@ pub fn open_bitcoin_app(
     };
 
     let resp = ledger_api.exchange(&command)?;
+    let error = DeviceError(resp.retcode());
+    if error.is_locked() || error.is_user_refusal() {
+        return Err(error.into());
+    }
     if resp.retcode() != StatusCode::OK as u16 {
         return Err(format!("Error opening app. Ledger response: {:#x?}.", resp).into());
     }
@@ -636,16 +1164,270 @@ pub fn open_bitcoin_app(
     Ok(())
 }
 
-/// Check whether the Ledger device is genuine.
-pub fn genuine_check(ledger_api: &TransportNativeHID) -> Result<(), Box<dyn error::Error>> {
-    let device_info = DeviceInfo::new(ledger_api)?;
-    let firmware_info = FirmwareInfo::from_device(&device_info);
+/// The app running on the device, as reported by the app itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunningApp {
+    pub name: String,
+    pub version: String,
+    #[serde(with = "crate::hex_bytes")]
+    pub flags: Vec<u8>,
+}
+
+impl RunningApp {
+    /// Whether this is the dashboard rather than an app.
+    pub fn is_dashboard(&self) -> bool {
+        self.name == DASHBOARD_APP_NAME
+    }
+
+    /// Whether this is the Bitcoin app. Set `is_testnet` for the testnet Bitcoin app.
+    pub fn is_bitcoin_app(&self, is_testnet: bool) -> bool {
+        self.name
+            == if is_testnet {
+                "Bitcoin Test"
+            } else {
+                "Bitcoin"
+            }
+    }
+
+    /// The version of the app.
+    pub fn app_version(&self) -> Result<bitcoin_app::AppVersion, Box<dyn error::Error>> {
+        self.version.parse()
+    }
+
+    /// The protocol spoken by this Bitcoin app.
+    pub fn protocol(&self) -> Result<bitcoin_app::ClientProtocol, Box<dyn error::Error>> {
+        Ok(bitcoin_app::ClientProtocol::of(
+            &self.name,
+            &self.app_version()?,
+        ))
+    }
+}
+
+/// Get the name, version and flags of the app currently running on the device. This works from
+/// any app, as well as from the dashboard.
+pub fn get_running_app<T: Transport + ?Sized>(
+    transport: &T,
+) -> Result<RunningApp, Box<dyn error::Error>> {
+    let command = APDUCommand {
+        cla: GET_APP_AND_VERSION_COMMAND.cla,
+        ins: GET_APP_AND_VERSION_COMMAND.ins,
+        p1: GET_APP_AND_VERSION_COMMAND.p1,
+        p2: GET_APP_AND_VERSION_COMMAND.p2,
+        data: Vec::new(),
+    };
+    let answer = transport.exchange(&command)?;
+    if answer.retcode() == StatusCode::LockedDevice as u16 {
+        return Err(DeviceError(answer.retcode()).into());
+    }
+    if answer.retcode() != StatusCode::OK as u16 {
+        return Err(format!(
+            "Error getting the running app. Ledger response: {:#x}.",
+            answer.retcode()
+        )
+        .into());
+    }
+
+    // A format byte followed by the length-prefixed name, version and flags.
+    fn read_field<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], Box<dyn error::Error>> {
+        let (len, rest) = data
+            .split_first()
+            .ok_or("Invalid app and version response.")?;
+        let field = rest
+            .get(..*len as usize)
+            .ok_or("Invalid app and version response.")?;
+        *data = &rest[*len as usize..];
+        Ok(field)
+    }
+    let mut data = answer
+        .data()
+        .get(1..)
+        .ok_or("Empty app and version response.")?;
+    let name = str::from_utf8(read_field(&mut data)?)?.to_string();
+    let version = str::from_utf8(read_field(&mut data)?)?.to_string();
+    let flags = read_field(&mut data)
+        .map(|f| f.to_vec())
+        .unwrap_or_default();
+
+    Ok(RunningApp {
+        name,
+        version,
+        flags,
+    })
+}
+
+/// Open the Bitcoin app if it isn't already running, and get its name, version and flags from
+/// the app itself. Unlike [`bitcoin_app_version`] this doesn't make any network call, and works
+/// for versions of the app unknown to the Ledger API. Set `is_testnet` for the testnet Bitcoin
+/// app.
+pub fn running_bitcoin_app(
+    ledger_api: &TransportNativeHID,
+    is_testnet: bool,
+    timeout: time::Duration,
+) -> Result<RunningApp, Box<dyn error::Error>> {
+    let app = get_running_app(ledger_api)?;
+    if app.is_bitcoin_app(is_testnet) {
+        return Ok(app);
+    }
+    if !app.is_dashboard() {
+        log::info!("Another app is running on the device, quitting it.");
+        quit_app(ledger_api, QUIT_APP_TIMEOUT)?;
+    }
+    open_bitcoin_app(ledger_api, is_testnet)?;
+
+    // Like when quitting an app, the device may not answer while it's starting the app.
+    let start = time::Instant::now();
+    loop {
+        if let Ok(app) = get_running_app(ledger_api) {
+            if app.is_bitcoin_app(is_testnet) {
+                return Ok(app);
+            }
+        }
+        if start.elapsed() >= timeout {
+            return Err("Timed out waiting for the Bitcoin app to start.".into());
+        }
+        thread::sleep(DASHBOARD_POLL_INTERVAL);
+    }
+}
+
+/// Whether the device is currently on its dashboard, as opposed to running an app. Most management
+/// operations (listing apps, installing, genuine check) are only available from the dashboard.
+pub fn is_dashboard_open<T: Transport + ?Sized>(
+    transport: &T,
+) -> Result<bool, Box<dyn error::Error>> {
+    Ok(get_running_app(transport)?.is_dashboard())
+}
 
-    let genuine_ws_url = UrlSerializer::new(format!("{}/genuine?", BASE_SOCKET_URL))
+/// An error arising when quitting the running app.
+#[derive(Debug)]
+pub enum QuitAppErr {
+    /// The dashboard didn't come back before the timeout.
+    Timeout,
+    Any(Box<dyn error::Error>),
+}
+
+impl fmt::Display for QuitAppErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(
+                f,
+                "Timed out waiting for the device to return to the dashboard. If the app did quit, \
+                 reconnect to the device."
+            ),
+            Self::Any(e) => write!(f, "Error quitting the running app: {}", e),
+        }
+    }
+}
+
+impl error::Error for QuitAppErr {}
+
+/// Quit the app currently running on the device and wait for the dashboard to answer again, for at
+/// most `timeout`.
+///
+/// The device is polled on the same handle. Some devices re-enumerate on USB when the app exits,
+/// which leaves this handle stale: the dashboard never answers on it and this times out. The caller
+/// should then open a new transport to the device, which will be on the dashboard.
+pub fn quit_app(
+    ledger_api: &TransportNativeHID,
+    timeout: time::Duration,
+) -> Result<(), QuitAppErr> {
+    let resp = ledger_api
+        .exchange(&QUIT_APP_COMMAND)
+        .map_err(|e| QuitAppErr::Any(e.into()))?;
+    if resp.retcode() != StatusCode::OK as u16 {
+        return Err(QuitAppErr::Any(
+            format!("Error quitting app. Ledger response: {:#x?}.", resp).into(),
+        ));
+    }
+
+    // The device may not answer at all while it's switching back to the dashboard, so don't bail
+    // on errors here. Just keep polling until it answers or we time out.
+    let start = time::Instant::now();
+    loop {
+        if get_running_app(ledger_api).is_ok_and(|app| app.is_dashboard()) {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(QuitAppErr::Timeout);
+        }
+        thread::sleep(DASHBOARD_POLL_INTERVAL);
+    }
+}
+
+// Make sure the device is on the dashboard, quitting the running app if necessary.
+fn ensure_dashboard(ledger_api: &TransportNativeHID) -> Result<(), Box<dyn error::Error>> {
+    if !is_dashboard_open(ledger_api)? {
+        log::info!("An app is running on the device, quitting it.");
+        quit_app(ledger_api, QUIT_APP_TIMEOUT)?;
+    }
+    Ok(())
+}
+
+// The result of the genuine check for a genuine device.
+const GENUINE_RESULT: &str = "0000";
+
+/// An error arising when checking whether the device is genuine.
+#[derive(Debug)]
+pub enum GenuineCheckErr {
+    /// The HSM could not authenticate the device. Contains the result it returned.
+    NotGenuine(String),
+    /// The HSM ended the session without a result, so the device couldn't be checked.
+    NoResult,
+    Any(Box<dyn error::Error>),
+}
+
+impl fmt::Display for GenuineCheckErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotGenuine(result) => write!(
+                f,
+                "The device is not genuine. Result of the check: '{}'.",
+                result
+            ),
+            Self::NoResult => write!(
+                f,
+                "The genuine check ended without a result, the device could not be checked."
+            ),
+            Self::Any(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for GenuineCheckErr {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Any(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Check whether the Ledger device is genuine.
+pub fn genuine_check(
+    ledger_api: &TransportNativeHID,
+    config: &ApiConfig,
+) -> Result<(), GenuineCheckErr> {
+    ensure_dashboard(ledger_api).map_err(GenuineCheckErr::Any)?;
+    let device_info = DeviceInfo::new(ledger_api).map_err(GenuineCheckErr::Any)?;
+    let firmware_info =
+        FirmwareInfo::from_device(&device_info, config).map_err(GenuineCheckErr::Any)?;
+
+    let genuine_ws_url = UrlSerializer::new(format!("{}/genuine?", config.socket_url))
         .append_pair("targetId", &device_info.target_id.to_string())
         .append_pair("perso", &firmware_info.perso)
         .finish();
-    query_via_websocket(ledger_api, &genuine_ws_url)
+    config.status("Checking the device is genuine. You might have to confirm on the device.");
+    match query_via_websocket(
+        ledger_api,
+        &genuine_ws_url,
+        "checking the device is genuine",
+        config,
+    )
+    .map_err(GenuineCheckErr::Any)?
+    {
+        Some(result) if result == GENUINE_RESULT => Ok(()),
+        Some(result) => Err(GenuineCheckErr::NotGenuine(result)),
+        None => Err(GenuineCheckErr::NoResult),
+    }
 }
 
 /// An error arising when installing the Bitcoin app.
@@ -655,16 +1437,75 @@ pub enum InstallErr {
     AlreadyInstalled,
     /// Couldn't get info about the Bitcoin app.
     AppNotFound,
+    /// The app found on the device after the installation isn't the one from the catalog.
+    Verification(VerificationErr),
     Any(Box<dyn error::Error>),
 }
 
+impl From<Box<dyn error::Error>> for InstallErr {
+    fn from(e: Box<dyn error::Error>) -> Self {
+        match e.downcast::<VerificationErr>() {
+            Ok(e) => Self::Verification(*e),
+            Err(e) => Self::Any(e),
+        }
+    }
+}
+
+/// What was installed on the device, as checked after the installation.
+#[derive(Debug, Clone, Serialize)]
+pub struct InstallReport {
+    pub name: String,
+    pub version: String,
+    /// The hash of the installed app, hex encoded. It matches the hash from the catalog.
+    pub hash: String,
+    /// The number of blocks of the device storage used by the app.
+    pub blocks: u16,
+    /// The number of APDUs sent to the device during the installation.
+    pub apdu_count: usize,
+    #[serde(rename = "duration_ms", with = "json::millis")]
+    pub duration: time::Duration,
+}
+
+/// The app found on the device after an installation isn't the one which was installed.
+#[derive(Debug, Clone)]
+pub struct VerificationErr {
+    pub name: String,
+    /// The hash from the catalog, hex encoded.
+    pub expected_hash: String,
+    /// The hash of the app of this name installed on the device, if any.
+    pub installed_hash: Option<String>,
+}
+
+impl fmt::Display for VerificationErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.installed_hash {
+            Some(hash) => write!(
+                f,
+                "The installed {} app has hash {}, not the expected {}",
+                self.name, hash, self.expected_hash
+            ),
+            None => write!(
+                f,
+                "The {} app is not on the device after installing it",
+                self.name
+            ),
+        }
+    }
+}
+
+impl error::Error for VerificationErr {}
+
+// Install this app, then list the installed apps to check it's there with the hash from the
+// catalog. A mismatch is a VerificationErr.
 fn install_app(
     ledger_api: &TransportNativeHID,
     device_info: &DeviceInfo,
     app: &BitcoinAppInfo,
-) -> Result<(), Box<dyn error::Error>> {
+    config: &ApiConfig,
+) -> Result<InstallReport, Box<dyn error::Error>> {
+    let start = time::Instant::now();
     // Make sure to properly escape the parameters in the request's parameter.
-    let install_ws_url = UrlSerializer::new(format!("{}/install?", BASE_SOCKET_URL))
+    let install_ws_url = UrlSerializer::new(format!("{}/install?", config.socket_url))
         .append_pair("targetId", &device_info.target_id.to_string())
         .append_pair("perso", &app.perso)
         .append_pair("deleteKey", &app.delete_key)
@@ -672,15 +1513,47 @@ fn install_app(
         .append_pair("firmwareKey", &app.firmware_key)
         .append_pair("hash", &app.hash)
         .finish();
-    query_via_websocket(ledger_api, &install_ws_url)
+    config.status(&format!(
+        "Installing {} {}. Allow the Ledger manager on the device.",
+        app.version_name, app.version
+    ));
+    let (_, apdu_count) =
+        websocket_session(ledger_api, &install_ws_url, "installing the app", config)?;
+    let duration = start.elapsed();
+
+    config.status("Checking the installed app. You might have to confirm on the device.");
+    let installed = list_installed_apps_raw(ledger_api)?
+        .into_iter()
+        .find(|a| a.name.eq_ignore_ascii_case(&app.version_name));
+    match installed {
+        Some(installed) if hex::encode(&installed.hash) == app.hash => Ok(InstallReport {
+            name: installed.name,
+            version: app.version.clone(),
+            hash: app.hash.clone(),
+            blocks: installed.blocks,
+            apdu_count,
+            duration,
+        }),
+        installed => Err(VerificationErr {
+            name: app.version_name.clone(),
+            expected_hash: app.hash.clone(),
+            installed_hash: installed.map(|a| hex::encode(a.hash)),
+        }
+        .into()),
+    }
 }
 
 /// Install the Bitcoin application on this device. Set `is_testnet` to `true` to install the
-/// testnet app instead.
+/// testnet app instead. The app is then checked to be on the device with the hash from the
+/// catalog, see [`InstallReport`].
 pub fn install_bitcoin_app(
     ledger_api: &TransportNativeHID,
     is_testnet: bool,
-) -> Result<(), InstallErr> {
+    config: &ApiConfig,
+) -> Result<InstallReport, InstallErr> {
+    // We can only list and install apps from the dashboard.
+    ensure_dashboard(ledger_api).map_err(InstallErr::Any)?;
+
     // First of all make sure it's not already installed.
     if is_bitcoin_app_installed(ledger_api, is_testnet).map_err(InstallErr::Any)? {
         return Err(InstallErr::AlreadyInstalled);
@@ -688,14 +1561,12 @@ pub fn install_bitcoin_app(
 
     // Get the app info, necessary for the websocket query below.
     let device_info = DeviceInfo::new(ledger_api).map_err(InstallErr::Any)?;
-    let bitcoin_app = bitcoin_latest_app(&device_info, is_testnet)
+    let bitcoin_app = bitcoin_latest_app(&device_info, is_testnet, config)
         .map_err(InstallErr::Any)?
         .ok_or(InstallErr::AppNotFound)?;
 
     // Now install the app by connecting through their websocket thing to their HSM.
-    install_app(ledger_api, &device_info, &bitcoin_app).map_err(InstallErr::Any)?;
-
-    Ok(())
+    Ok(install_app(ledger_api, &device_info, &bitcoin_app, config)?)
 }
 
 /// An error arising when updating the Bitcoin app.
@@ -707,20 +1578,214 @@ pub enum UpdateErr {
     AppNotFound,
     /// The installed app is already the latest.
     AlreadyLatest,
+    /// Installing the new version failed after the installed one was removed. The report tells
+    /// whether the previous version could be reinstalled.
+    Failed(Box<UpdateReport>),
+    /// The app found on the device after the update isn't the one from the catalog.
+    Verification(VerificationErr),
     Any(Box<dyn error::Error>),
 }
 
+impl From<Box<dyn error::Error>> for UpdateErr {
+    fn from(e: Box<dyn error::Error>) -> Self {
+        match e.downcast::<VerificationErr>() {
+            Ok(e) => Self::Verification(*e),
+            Err(e) => Self::Any(e),
+        }
+    }
+}
+
+/// How many times to try installing the new version again if the app was removed in the process.
+const UPDATE_RETRIES: usize = 1;
+
+/// An attempt at installing a version of the app during an update.
+#[derive(Debug, Clone, Serialize)]
+pub struct InstallAttempt {
+    pub version: String,
+    /// Why the install failed, if it did.
+    pub error: Option<String>,
+}
+
+/// What became of the app after an update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateOutcome {
+    /// The new version is installed.
+    Updated,
+    /// The new version couldn't be installed, the previous version was installed again.
+    RolledBack,
+    /// Neither the new nor the previous version could be installed: the app is gone.
+    Removed,
+}
+
+/// What happened during an update of the Bitcoin app.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateReport {
+    /// The version installed before the update, if it could be identified. It can only be
+    /// reinstalled if so.
+    pub previous_version: Option<String>,
+    /// The version the app was updated to.
+    pub target_version: String,
+    /// The installs tried, in order: the new version, again if the app was removed, and the
+    /// previous version to roll back.
+    pub attempts: Vec<InstallAttempt>,
+    pub outcome: UpdateOutcome,
+    /// The successful installation, of the new version or of the previous one. Missing if the
+    /// new version was found on the device after its installation failed.
+    pub install: Option<InstallReport>,
+    /// Why the previous version wasn't reinstalled, if the app was removed.
+    pub rollback_error: Option<String>,
+}
+
+impl fmt::Display for UpdateReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The failure of the rollback, if any, is told with the outcome.
+        let failures: Vec<_> = self
+            .attempts
+            .iter()
+            .filter(|a| a.version == self.target_version)
+            .filter_map(|a| a.error.as_ref().map(|e| format!("{} ({})", a.version, e)))
+            .collect();
+        match self.outcome {
+            UpdateOutcome::Updated => write!(f, "Updated to version {}", self.target_version)?,
+            UpdateOutcome::RolledBack => write!(
+                f,
+                "Could not update to version {}, reinstalled the previous version {}",
+                self.target_version,
+                self.previous_version.as_deref().unwrap_or("?")
+            )?,
+            UpdateOutcome::Removed => write!(
+                f,
+                "Could not update to version {} nor reinstall the previous version ({}), the app \
+                 is no longer installed",
+                self.target_version,
+                self.rollback_error.as_deref().unwrap_or("unknown error")
+            )?,
+        }
+        if !failures.is_empty() {
+            write!(f, ". Failed installs: {}", failures.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// The previous version of the app to reinstall should the update remove it, or why it can't be.
+// The HSM only serves the binaries of an app for a given firmware version, the one in their path.
+fn rollback_app<'a>(
+    previous: Option<&'a BitcoinAppInfo>,
+    firmware_version: &str,
+) -> Result<&'a BitcoinAppInfo, String> {
+    let previous = previous.ok_or_else(|| "the previous version is unknown".to_string())?;
+    match previous.firmware_version() {
+        Some(version) if version == firmware_version => Ok(previous),
+        _ => Err(format!(
+            "version {} isn't available for firmware {}",
+            previous.version, firmware_version
+        )),
+    }
+}
+
+// Install the latest version of the app over the previous one, trying again and then rolling back
+// if the app was removed in the process. The device is accessed through `install`, which installs
+// a version of the app, and `installed_hash`, which gives the hash of the app on the device.
+fn update_app(
+    previous: Option<&BitcoinAppInfo>,
+    latest: &BitcoinAppInfo,
+    firmware_version: &str,
+    config: &ApiConfig,
+    mut install: impl FnMut(&BitcoinAppInfo) -> Result<InstallReport, Box<dyn error::Error>>,
+    mut installed_hash: impl FnMut() -> Result<Option<String>, Box<dyn error::Error>>,
+) -> Result<UpdateReport, UpdateErr> {
+    // Check before the app is removed whether it could be reinstalled.
+    let rollback = rollback_app(previous, firmware_version);
+    if let Err(e) = &rollback {
+        config.status(&format!(
+            "The app couldn't be reinstalled if the update fails: {}.",
+            e
+        ));
+    }
+
+    let mut report = UpdateReport {
+        previous_version: previous.map(|app| app.version.clone()),
+        target_version: latest.version.clone(),
+        attempts: Vec::new(),
+        outcome: UpdateOutcome::Updated,
+        install: None,
+        rollback_error: None,
+    };
+    let mut attempt = |report: &mut UpdateReport, app: &BitcoinAppInfo| {
+        let res = install(app);
+        report.attempts.push(InstallAttempt {
+            version: app.version.clone(),
+            error: res.as_ref().err().map(|e| e.to_string()),
+        });
+        match res {
+            Ok(install) => {
+                report.install = Some(install);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    };
+
+    let Err(e) = attempt(&mut report, latest) else {
+        return Ok(report);
+    };
+    // The install may have failed before the installed app was removed, or after the new one was
+    // installed. Only go on if the app is gone.
+    match installed_hash().map_err(UpdateErr::Any)? {
+        Some(hash) if hash == latest.hash => return Ok(report),
+        Some(_) => return Err(e.into()),
+        None => log::warn!("The app was removed by the failed update: {}", e),
+    }
+
+    for _ in 0..UPDATE_RETRIES {
+        config.status("The app was removed by the failed update. Trying again.");
+        if attempt(&mut report, latest).is_ok() {
+            return Ok(report);
+        }
+    }
+
+    report.outcome = UpdateOutcome::Removed;
+    match rollback {
+        Ok(previous) => {
+            config.status("Reinstalling the previous version of the app.");
+            match attempt(&mut report, previous) {
+                Ok(()) => report.outcome = UpdateOutcome::RolledBack,
+                Err(e) => report.rollback_error = Some(e.to_string()),
+            }
+        }
+        Err(e) => {
+            log::warn!("Not reinstalling the previous version of the app: {}", e);
+            report.rollback_error = Some(e);
+        }
+    }
+    Err(UpdateErr::Failed(Box::new(report)))
+}
+
 /// Update the Bitcoin application on this device. Set `is_testnet` to `true` to install the
 /// testnet app instead.
+///
+/// The HSM removes the installed app before installing the new one. If the install fails, the
+/// apps are listed again to find out whether the app was removed. If so, the install is tried
+/// again, then the previous version is reinstalled if it was identified (see
+/// [`list_installed_apps`]) and is still available for the firmware of the device. Otherwise the
+/// app is left uninstalled.
+/// The report tells what happened, and is returned in [`UpdateErr::Failed`] if the new version
+/// couldn't be installed.
 pub fn update_bitcoin_app(
     ledger_api: &TransportNativeHID,
     is_testnet: bool,
-) -> Result<(), UpdateErr> {
+    config: &ApiConfig,
+) -> Result<UpdateReport, UpdateErr> {
+    // We can only list and install apps from the dashboard.
+    ensure_dashboard(ledger_api).map_err(UpdateErr::Any)?;
+
     // First of all make sure the app is installed. Get its details.
     let app = bitcoin_app_installed(ledger_api, is_testnet)
         .map_err(UpdateErr::Any)?
         .ok_or(UpdateErr::NotInstalled)?;
-    let installed_app = bitcoin_apps_by_hashes(vec![app.hash])
+    let installed_app = apps_by_hashes(ledger_api, vec![app.hash.clone()], config)
         .map_err(UpdateErr::Any)?
         .into_iter()
         .next()
@@ -728,7 +1793,7 @@ pub fn update_bitcoin_app(
 
     // Get the latest app info, necessary for the websocket query below.
     let device_info = DeviceInfo::new(ledger_api).map_err(UpdateErr::Any)?;
-    let latest_app = bitcoin_latest_app(&device_info, is_testnet)
+    let latest_app = bitcoin_latest_app(&device_info, is_testnet, config)
         .map_err(UpdateErr::Any)?
         .ok_or(UpdateErr::AppNotFound)?;
 
@@ -736,14 +1801,333 @@ pub fn update_bitcoin_app(
     // version of the installed app. But this is the check Ledger Live does. And it also never uses
     // versionId as far as i can tell. So, do like Ledger.
     if installed_app
+        .as_ref()
         .map(|app| app.version == latest_app.version)
         .unwrap_or(false)
     {
         return Err(UpdateErr::AlreadyLatest);
     }
 
+    update_installed_app(
+        ledger_api,
+        &device_info,
+        is_testnet,
+        installed_app.as_ref(),
+        &latest_app,
+        config,
+    )
+}
+
+// Update the installed Bitcoin app, identified as `previous` if it could be, to the `latest`
+// version. The callers have queried these, see update_bitcoin_app.
+pub(crate) fn update_installed_app(
+    ledger_api: &TransportNativeHID,
+    device_info: &DeviceInfo,
+    is_testnet: bool,
+    previous: Option<&BitcoinAppInfo>,
+    latest: &BitcoinAppInfo,
+    config: &ApiConfig,
+) -> Result<UpdateReport, UpdateErr> {
     // Now install the app by connecting through their websocket thing to their HSM.
-    install_app(ledger_api, &device_info, &latest_app).map_err(UpdateErr::Any)?;
+    update_app(
+        previous,
+        latest,
+        &device_info.version,
+        config,
+        |app| {
+            ensure_dashboard(ledger_api)?;
+            install_app(ledger_api, device_info, app, config)
+        },
+        || {
+            ensure_dashboard(ledger_api)?;
+            Ok(bitcoin_app_installed(ledger_api, is_testnet)?.map(|app| hex::encode(app.hash)))
+        },
+    )
+}
 
-    Ok(())
+/// An error arising when uninstalling the Bitcoin app.
+#[derive(Debug)]
+pub enum UninstallErr {
+    /// The Bitcoin application is not installed.
+    NotInstalled,
+    /// Couldn't get info about the installed Bitcoin app.
+    AppNotFound,
+    Any(Box<dyn error::Error>),
+}
+
+/// Uninstall the Bitcoin application from this device. Set `is_testnet` to `true` to uninstall the
+/// testnet app instead.
+// Like Ledger Live, this is an installation with the binary deleting the app in place of the app's.
+pub fn uninstall_bitcoin_app(
+    ledger_api: &TransportNativeHID,
+    is_testnet: bool,
+    config: &ApiConfig,
+) -> Result<(), UninstallErr> {
+    // We can only list and uninstall apps from the dashboard.
+    ensure_dashboard(ledger_api).map_err(UninstallErr::Any)?;
+
+    // We need the details of the exact version installed to delete it.
+    let app = bitcoin_app_installed(ledger_api, is_testnet)
+        .map_err(UninstallErr::Any)?
+        .ok_or(UninstallErr::NotInstalled)?;
+    let installed_app = apps_by_hashes(ledger_api, vec![app.hash], config)
+        .map_err(UninstallErr::Any)?
+        .into_iter()
+        .next()
+        .flatten()
+        .filter(|app| !app.delete.is_empty())
+        .ok_or(UninstallErr::AppNotFound)?;
+
+    let device_info = DeviceInfo::new(ledger_api).map_err(UninstallErr::Any)?;
+    let uninstall_ws_url = UrlSerializer::new(format!("{}/install?", config.socket_url))
+        .append_pair("targetId", &device_info.target_id.to_string())
+        .append_pair("perso", &installed_app.perso)
+        .append_pair("deleteKey", &installed_app.delete_key)
+        .append_pair("firmware", &installed_app.delete)
+        .append_pair("firmwareKey", &installed_app.delete_key)
+        .append_pair("hash", &installed_app.hash)
+        .finish();
+    config.status(&format!(
+        "Uninstalling {} {}. Allow the Ledger manager on the device.",
+        installed_app.version_name, installed_app.version
+    ));
+    query_via_websocket(
+        ledger_api,
+        &uninstall_ws_url,
+        "uninstalling the app",
+        config,
+    )
+    .map(|_| ())
+    .map_err(UninstallErr::Any)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ledger_apdu::APDUAnswer;
+
+    #[test]
+    fn apdu_commands() {
+        let command = deser_apdu_command("e0 01 00 00 02 abcd").unwrap();
+        assert_eq!(
+            (command.cla, command.ins, command.p1, command.p2),
+            (0xe0, 0x01, 0x00, 0x00)
+        );
+        assert_eq!(command.data, [0xab, 0xcd]);
+        assert_eq!(
+            ser_apdu_command(&command),
+            hex::decode("e001000002abcd").unwrap()
+        );
+
+        // Without data, with or without a length.
+        for hex_str in ["e0010000", "e001000000", "e0010000000000"] {
+            let command = deser_apdu_command(hex_str).unwrap();
+            assert!(command.data.is_empty(), "{}", hex_str);
+            assert_eq!(
+                ser_apdu_command(&command),
+                hex::decode("e001000000").unwrap()
+            );
+        }
+
+        // The extended form, up to 65535 bytes of data. Serialized in the short form if it fits.
+        let command = deser_apdu_command("e0010000 000002 abcd").unwrap();
+        assert_eq!(command.data, [0xab, 0xcd]);
+        assert_eq!(
+            ser_apdu_command(&command),
+            hex::decode("e001000002abcd").unwrap()
+        );
+        for len in [256, MAX_EXTENDED_APDU_DATA_LEN] {
+            let hex_str = format!("e0010000 00{:04x} {}", len, "ab".repeat(len));
+            let command = deser_apdu_command(&hex_str).unwrap();
+            assert_eq!(command.data.len(), len);
+            assert_eq!(
+                hex::encode(ser_apdu_command(&command)),
+                hex_str.replace(' ', "")
+            );
+        }
+
+        for invalid in [
+            "",
+            "e00100",
+            "e0010000zz",
+            // Too much or too little data for the announced length.
+            "e001000002abcdef",
+            "e001000002ab",
+            "e0010000000003abcd",
+            "e0010000000001abcd",
+        ] {
+            assert!(deser_apdu_command(invalid).is_err(), "{}", invalid);
+        }
+
+        // The commands of the HSM are parsed strictly.
+        for (hex_str, data) in [("e001000002abcd", &[0xab, 0xcd][..]), ("e001000000", &[])] {
+            assert_eq!(deser_hsm_apdu_command(hex_str).unwrap().data, data);
+        }
+        for invalid in [
+            "",
+            "e0010000",
+            "e0 01 00 00 00",
+            "e0010000000002abcd",
+            "e001000002abcdef",
+            "e001000002ab",
+        ] {
+            assert!(deser_hsm_apdu_command(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    // A device answering GET_APP_AND_VERSION with this answer.
+    struct RunningAppTransport(Vec<u8>);
+
+    impl Transport for RunningAppTransport {
+        fn exchange(
+            &self,
+            command: &APDUCommand<Vec<u8>>,
+        ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>> {
+            assert_eq!((command.cla, command.ins), (0xb0, 0x01));
+            Ok(APDUAnswer::from_answer(self.0.clone())?)
+        }
+    }
+
+    #[test]
+    fn dashboard_detection() {
+        // Format, name, version and flags, followed by the status.
+        let dashboard =
+            RunningAppTransport(hex::decode("0105424f4c4f5305322e312e30019e9000").unwrap());
+        let app = get_running_app(&dashboard).unwrap();
+        assert_eq!(
+            (app.name.as_str(), app.version.as_str()),
+            ("BOLOS", "2.1.0")
+        );
+        assert_eq!(app.flags, [0x9e]);
+        assert!(is_dashboard_open(&dashboard).unwrap());
+
+        let bitcoin =
+            RunningAppTransport(hex::decode("0107426974636f696e05322e312e3001029000").unwrap());
+        assert!(!is_dashboard_open(&bitcoin).unwrap());
+        assert!(get_running_app(&bitcoin).unwrap().is_bitcoin_app(false));
+
+        let locked = RunningAppTransport(vec![0x55, 0x15]);
+        let err = is_dashboard_open(&locked).unwrap_err();
+        assert!(err.downcast_ref::<DeviceError>().unwrap().is_locked());
+    }
+
+    fn bitcoin_app(version: &str, firmware_version: &str) -> BitcoinAppInfo {
+        BitcoinAppInfo {
+            version_name: "Bitcoin".to_string(),
+            version_id: 1,
+            version: version.to_string(),
+            perso: "perso_11".to_string(),
+            delete: String::new(),
+            delete_key: String::new(),
+            firmware: format!("nanox/{}/bitcoin/app_{}", firmware_version, version),
+            firmware_key: format!("nanox/{}/bitcoin/app_{}_key", firmware_version, version),
+            hash: format!("hash_{}", version),
+            bytes: None,
+            parent_name: None,
+        }
+    }
+
+    // Update the app to 2.2.3 on firmware 2.2.3. The installs have the given results, and after the
+    // first failure the app with the given hash is found on the device.
+    fn update(
+        previous: Option<&BitcoinAppInfo>,
+        installs: &[Result<(), &str>],
+        installed_hash: Option<&str>,
+    ) -> Result<UpdateReport, UpdateErr> {
+        let latest = bitcoin_app("2.2.3", "2.2.3");
+        let mut installs = installs.iter();
+        update_app(
+            previous,
+            &latest,
+            "2.2.3",
+            &ApiConfig::default(),
+            |app| match installs.next().expect("unexpected install") {
+                Ok(()) => Ok(InstallReport {
+                    name: app.version_name.clone(),
+                    version: app.version.clone(),
+                    hash: app.hash.clone(),
+                    blocks: 212,
+                    apdu_count: 0,
+                    duration: time::Duration::ZERO,
+                }),
+                Err(e) => Err(e.to_string().into()),
+            },
+            || Ok(installed_hash.map(str::to_string)),
+        )
+    }
+
+    fn failed(res: Result<UpdateReport, UpdateErr>) -> UpdateReport {
+        match res {
+            Err(UpdateErr::Failed(report)) => *report,
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    fn versions(report: &UpdateReport) -> Vec<&str> {
+        report.attempts.iter().map(|a| a.version.as_str()).collect()
+    }
+
+    #[test]
+    fn update_outcomes() {
+        let previous = bitcoin_app("2.1.0", "2.2.3");
+
+        // Updated, at once or once the app was removed.
+        let report = update(Some(&previous), &[Ok(())], None).unwrap();
+        assert_eq!(report.outcome, UpdateOutcome::Updated);
+        assert_eq!(versions(&report), ["2.2.3"]);
+        assert_eq!(report.install.unwrap().version, "2.2.3");
+        let report = update(Some(&previous), &[Err("timeout"), Ok(())], None).unwrap();
+        assert_eq!(report.outcome, UpdateOutcome::Updated);
+        assert_eq!(report.attempts[0].error.as_deref(), Some("timeout"));
+        assert_eq!(
+            report.to_string(),
+            "Updated to version 2.2.3. Failed installs: 2.2.3 (timeout)"
+        );
+
+        // The install failed after the new version was installed, or before the app was removed.
+        let report = update(Some(&previous), &[Err("closed")], Some("hash_2.2.3")).unwrap();
+        assert_eq!(report.outcome, UpdateOutcome::Updated);
+        assert!(report.install.is_none());
+        match update(Some(&previous), &[Err("denied")], Some("hash_2.1.0")) {
+            Err(UpdateErr::Any(e)) => assert_eq!(e.to_string(), "denied"),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // Rolled back.
+        let report = failed(update(Some(&previous), &[Err("a"), Err("b"), Ok(())], None));
+        assert_eq!(report.outcome, UpdateOutcome::RolledBack);
+        assert_eq!(versions(&report), ["2.2.3", "2.2.3", "2.1.0"]);
+        assert_eq!(report.install.unwrap().version, "2.1.0");
+        assert!(report.rollback_error.is_none());
+
+        // Removed, telling why the previous version wasn't reinstalled.
+        let report = failed(update(
+            Some(&previous),
+            &[Err("a"), Err("b"), Err("c")],
+            None,
+        ));
+        assert_eq!(report.outcome, UpdateOutcome::Removed);
+        assert_eq!(report.rollback_error.as_deref(), Some("c"));
+        assert_eq!(
+            report.to_string(),
+            "Could not update to version 2.2.3 nor reinstall the previous version (c), the app is \
+             no longer installed. Failed installs: 2.2.3 (a), 2.2.3 (b)"
+        );
+        let unavailable = bitcoin_app("2.1.0", "2.1.0");
+        let report = failed(update(Some(&unavailable), &[Err("a"), Err("b")], None));
+        assert_eq!(report.outcome, UpdateOutcome::Removed);
+        assert_eq!(versions(&report), ["2.2.3", "2.2.3"]);
+        assert_eq!(
+            report.rollback_error.as_deref(),
+            Some("version 2.1.0 isn't available for firmware 2.2.3")
+        );
+        let report = failed(update(None, &[Err("a"), Err("b")], None));
+        assert_eq!(report.outcome, UpdateOutcome::Removed);
+        assert_eq!(report.previous_version, None);
+        assert_eq!(
+            report.rollback_error.as_deref(),
+            Some("the previous version is unknown")
+        );
+    }
 }
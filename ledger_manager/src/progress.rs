@@ -0,0 +1,36 @@
+//! Reporting what the operations are doing as they go, for instance to display it in a user
+//! interface. Set [`ApiConfig::on_event`] to receive the events.
+//!
+//! [`ApiConfig::on_event`]: crate::ApiConfig::on_event
+
+use std::{fmt, sync::Arc};
+
+/// A step of an operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// What the operation is doing, for instance waiting for a confirmation on the device.
+    Status(String),
+    /// How many of the commands sent in bulk by Ledger's HSM (for instance the blocks of an app
+    /// being installed) were passed to the device so far.
+    Progress { done: usize, total: usize },
+}
+
+/// Receives the events of the operations. It's called from the thread running the operation.
+#[derive(Clone)]
+pub struct EventHandler(Arc<dyn Fn(Event) + Send + Sync>);
+
+impl EventHandler {
+    pub fn new(handler: impl Fn(Event) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(handler))
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for EventHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventHandler")
+    }
+}
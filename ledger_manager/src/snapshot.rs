@@ -0,0 +1,446 @@
+//! A snapshot of the apps installed on a device, to reinstall them after an operation removing
+//! them such as a firmware update.
+//!
+//! The snapshot records the name and hashes of the installed apps along with their entry in the
+//! catalog when they could be identified. It is a JSON document, meant to be saved to disk before
+//! the operation. The catalog only serves the latest version of each app for a given firmware, so
+//! the apps are restored at their latest version for the firmware of the device at the time.
+
+use crate::{
+    apps_by_hashes, ensure_dashboard, get_catalog, install_app, list_installed_apps_raw, ApiConfig,
+    BitcoinAppInfo, DeviceInfo, InstalledApp,
+};
+
+use ledger_transport_hidapi::TransportNativeHID;
+use serde_derive::{Deserialize, Serialize};
+
+use std::{error, fmt, fs, path::Path};
+
+/// An app installed on the device when the snapshot was taken.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotApp {
+    pub name: String,
+    /// The hash of the app, hex encoded.
+    pub hash: String,
+    /// The hash of the code and data of the app, hex encoded.
+    pub hash_code_data: String,
+    /// The catalog entry for the installed version of the app, if it was identified.
+    pub catalog: Option<BitcoinAppInfo>,
+}
+
+/// The apps installed on a device at some point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The target id of the device the snapshot was taken from.
+    pub target_id: u32,
+    /// The firmware version of the device when the snapshot was taken.
+    pub firmware_version: String,
+    pub apps: Vec<SnapshotApp>,
+}
+
+/// Take a snapshot of the apps installed on this device. The installed apps are identified as
+/// with [`list_installed_apps`](crate::list_installed_apps), so in privacy mode only the apps
+/// found in the catalog or its cache are.
+pub fn take(
+    ledger_api: &TransportNativeHID,
+    config: &ApiConfig,
+) -> Result<Snapshot, Box<dyn error::Error>> {
+    let device_info = DeviceInfo::new(ledger_api)?;
+    ensure_dashboard(ledger_api)?;
+    config.status("Listing the installed apps. You might have to confirm on the device.");
+    let installed = list_installed_apps_raw(ledger_api)?;
+    let hashes = installed.iter().map(|a| a.hash.clone()).collect::<Vec<_>>();
+    let identified = if hashes.is_empty() {
+        Vec::new()
+    } else {
+        apps_by_hashes(ledger_api, hashes, config)?
+    };
+
+    let apps = installed
+        .into_iter()
+        .enumerate()
+        .map(|(i, app)| SnapshotApp {
+            name: app.name,
+            hash: hex::encode(app.hash),
+            hash_code_data: hex::encode(app.hash_code_data),
+            catalog: identified.get(i).cloned().flatten(),
+        })
+        .collect();
+    Ok(Snapshot {
+        target_id: device_info.target_id,
+        firmware_version: device_info.version,
+        apps,
+    })
+}
+
+/// Save this snapshot at this path, replacing any existing file.
+pub fn save(path: &Path, snapshot: &Snapshot) -> Result<(), Box<dyn error::Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_vec_pretty(snapshot)?)?;
+    Ok(())
+}
+
+/// Load the snapshot saved at this path.
+pub fn load(path: &Path) -> Result<Snapshot, Box<dyn error::Error>> {
+    let data = fs::read(path)
+        .map_err(|e| format!("Error reading snapshot at '{}': {}", path.display(), e))?;
+    Ok(serde_json::from_slice(&data)
+        .map_err(|e| format!("Invalid snapshot at '{}': {}", path.display(), e))?)
+}
+
+/// What became of an app of the snapshot when restoring it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum RestoreOutcome {
+    /// The app was installed at this version.
+    Installed { version: String },
+    /// The app is already installed on the device, at whatever version.
+    AlreadyInstalled,
+    /// The catalog has no version of this app for the firmware of the device.
+    NotAvailable,
+    /// Installing the app failed.
+    Failed { error: String },
+}
+
+/// An app of the snapshot, and what became of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoredApp {
+    pub name: String,
+    /// The version of the app when the snapshot was taken, if it was identified.
+    pub previous_version: Option<String>,
+    #[serde(flatten)]
+    pub outcome: RestoreOutcome,
+}
+
+/// The outcome of restoring a snapshot, for each of its apps.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreReport {
+    pub apps: Vec<RestoredApp>,
+}
+
+impl RestoreReport {
+    /// The apps which couldn't be restored, as they are not available or failed to install.
+    pub fn not_restored(&self) -> impl Iterator<Item = &RestoredApp> {
+        self.apps.iter().filter(|app| {
+            matches!(
+                app.outcome,
+                RestoreOutcome::NotAvailable | RestoreOutcome::Failed { .. }
+            )
+        })
+    }
+
+    /// Whether all the apps of the snapshot are installed on the device.
+    pub fn is_complete(&self) -> bool {
+        self.not_restored().next().is_none()
+    }
+}
+
+impl fmt::Display for RestoreOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Installed { version } => write!(f, "installed version {}", version),
+            Self::AlreadyInstalled => write!(f, "already installed"),
+            Self::NotAvailable => write!(f, "not available for this firmware"),
+            Self::Failed { error } => write!(f, "failed to install: {}", error),
+        }
+    }
+}
+
+/// Reinstall the apps of this snapshot missing from the device, at their latest version for its
+/// firmware. The device must be the same model as the one the snapshot was taken from. An app is
+/// installed after the app it depends on in the catalog (such as Ethereum for its plugins). Failing
+/// to install an app doesn't stop the restore: the outcome for each app is in the report.
+pub fn restore(
+    ledger_api: &TransportNativeHID,
+    snapshot: &Snapshot,
+    config: &ApiConfig,
+) -> Result<RestoreReport, Box<dyn error::Error>> {
+    let device_info = DeviceInfo::new(ledger_api)?;
+    if device_info.target_id != snapshot.target_id {
+        return Err(format!(
+            "The snapshot was taken from another device model (target id {:#x}, this device is {:#x}).",
+            snapshot.target_id, device_info.target_id
+        )
+        .into());
+    }
+    ensure_dashboard(ledger_api)?;
+    let catalog = get_catalog(&device_info, config)?;
+    config.status("Listing the installed apps. You might have to confirm on the device.");
+    let names: Vec<_> = snapshot.apps.iter().map(|app| app.name.as_str()).collect();
+    let outcomes = reinstall_apps(ledger_api, &device_info, &names, &catalog, config)?;
+
+    let report = RestoreReport {
+        apps: snapshot
+            .apps
+            .iter()
+            .zip(outcomes)
+            .map(|(app, outcome)| RestoredApp {
+                name: app.name.clone(),
+                previous_version: app.catalog.as_ref().map(|a| a.version.clone()),
+                outcome,
+            })
+            .collect(),
+    };
+    Ok(report)
+}
+
+// Install the apps with these names missing from the device, at their version in the catalog.
+// Failing to install an app doesn't stop the others: the outcome for each name is returned in
+// the same order.
+pub(crate) fn reinstall_apps(
+    ledger_api: &TransportNativeHID,
+    device_info: &DeviceInfo,
+    names: &[&str],
+    catalog: &[BitcoinAppInfo],
+    config: &ApiConfig,
+) -> Result<Vec<RestoreOutcome>, Box<dyn error::Error>> {
+    let installed = list_installed_apps_raw(ledger_api)?;
+    Ok(reinstall(names, &installed, catalog, |app| {
+        install_app(ledger_api, device_info, app, config).map(|_| ())
+    }))
+}
+
+// Like reinstall_apps, installing the apps with `install`. An app is installed after the app it
+// depends on, and not at all if that one isn't installed.
+fn reinstall(
+    names: &[&str],
+    installed: &[InstalledApp],
+    catalog: &[BitcoinAppInfo],
+    mut install: impl FnMut(&BitcoinAppInfo) -> Result<(), Box<dyn error::Error>>,
+) -> Vec<RestoreOutcome> {
+    let mut outcomes = Vec::with_capacity(names.len());
+    let mut to_install = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        if installed.iter().any(|a| a.name.eq_ignore_ascii_case(name)) {
+            outcomes.push(Some(RestoreOutcome::AlreadyInstalled));
+        } else if let Some(app) = catalog
+            .iter()
+            .find(|a| a.version_name.eq_ignore_ascii_case(name))
+        {
+            outcomes.push(None);
+            to_install.push((i, app));
+        } else {
+            outcomes.push(Some(RestoreOutcome::NotAvailable));
+        }
+    }
+
+    // The number of apps to install this one depends on, directly or not. Installing by
+    // increasing depth installs the parents first. Bounded in case of a cycle.
+    let depth = |app: &BitcoinAppInfo| {
+        let mut depth = 0;
+        let mut parent = app.parent_name.as_deref();
+        while let Some(name) = parent.filter(|_| depth < to_install.len()) {
+            let Some((_, app)) = to_install
+                .iter()
+                .find(|(_, a)| a.version_name.eq_ignore_ascii_case(name))
+            else {
+                break;
+            };
+            depth += 1;
+            parent = app.parent_name.as_deref();
+        }
+        depth
+    };
+    let mut order = to_install.clone();
+    order.sort_by_key(|(_, app)| depth(app));
+
+    let mut available: Vec<&str> = installed.iter().map(|a| a.name.as_str()).collect();
+    for (i, app) in order {
+        let missing_parent = app
+            .parent_name
+            .as_deref()
+            .filter(|parent| !available.iter().any(|a| a.eq_ignore_ascii_case(parent)));
+        outcomes[i] = Some(match missing_parent {
+            Some(parent) => RestoreOutcome::Failed {
+                error: format!("it depends on {}, which isn't installed", parent),
+            },
+            None => match install(app) {
+                Ok(()) => {
+                    available.push(&app.version_name);
+                    RestoreOutcome::Installed {
+                        version: app.version.clone(),
+                    }
+                }
+                Err(e) => RestoreOutcome::Failed {
+                    error: e.to_string(),
+                },
+            },
+        });
+    }
+    outcomes
+        .into_iter()
+        .map(|o| o.expect("All the apps have an outcome"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog_app(name: &str, parent: Option<&str>) -> BitcoinAppInfo {
+        serde_json::from_value(serde_json::json!({
+            "versionName": name,
+            "versionId": 1,
+            "version": "1.0.0",
+            "perso": "perso_11",
+            "deleteKey": "",
+            "firmware": format!("nanox/2.2.3/{}/app_1.0.0", name.to_lowercase()),
+            "firmwareKey": "",
+            "hash": "00".repeat(32),
+            "parentName": parent,
+        }))
+        .unwrap()
+    }
+
+    fn installed_app(name: &str) -> InstalledApp {
+        InstalledApp {
+            name: name.to_string(),
+            hash: vec![0; 32],
+            hash_code_data: vec![0; 32],
+            blocks: 10,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn reinstall_order() {
+        let catalog = [
+            catalog_app("Lido", Some("Ethereum")),
+            catalog_app("Paraswap", Some("Ethereum")),
+            catalog_app("Ethereum", None),
+            catalog_app("Bitcoin", None),
+            catalog_app("Solana", None),
+        ];
+        let installed = [installed_app("Bitcoin")];
+        // In snapshot order, the plugins before the app they depend on.
+        let names = [
+            "Lido", "bitcoin", "Paraswap", "Ethereum", "Retired", "Solana",
+        ];
+
+        let mut installs = Vec::new();
+        let outcomes = reinstall(&names, &installed, &catalog, |app| {
+            installs.push(app.version_name.clone());
+            Ok(())
+        });
+        assert_eq!(installs, ["Ethereum", "Solana", "Lido", "Paraswap"]);
+        let outcomes: Vec<_> = outcomes.iter().map(|o| o.to_string()).collect();
+        assert_eq!(
+            outcomes,
+            [
+                "installed version 1.0.0",
+                "already installed",
+                "installed version 1.0.0",
+                "installed version 1.0.0",
+                "not available for this firmware",
+                "installed version 1.0.0",
+            ]
+        );
+
+        // The plugins are not installed if Ethereum fails to, the other apps are.
+        let mut installs = Vec::new();
+        let outcomes = reinstall(&names, &installed, &catalog, |app| {
+            installs.push(app.version_name.clone());
+            match app.version_name.as_str() {
+                "Ethereum" => Err("Not enough space.".into()),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(installs, ["Ethereum", "Solana"]);
+        let outcomes: Vec<_> = outcomes.iter().map(|o| o.to_string()).collect();
+        assert_eq!(
+            outcomes,
+            [
+                "failed to install: it depends on Ethereum, which isn't installed",
+                "already installed",
+                "failed to install: it depends on Ethereum, which isn't installed",
+                "failed to install: Not enough space.",
+                "not available for this firmware",
+                "installed version 1.0.0",
+            ]
+        );
+
+        // A plugin is installed if the app it depends on already is.
+        let mut installs = Vec::new();
+        let outcomes = reinstall(&["Lido"], &[installed_app("Ethereum")], &catalog, |app| {
+            installs.push(app.version_name.clone());
+            Ok(())
+        });
+        assert_eq!(installs, ["Lido"]);
+        assert!(matches!(outcomes[..], [RestoreOutcome::Installed { .. }]));
+
+        // Dependency cycles don't hang.
+        let cycle = [catalog_app("A", Some("B")), catalog_app("B", Some("A"))];
+        let outcomes = reinstall(&["A", "B"], &[], &cycle, |_| Ok(()));
+        assert!(outcomes
+            .iter()
+            .all(|o| matches!(o, RestoreOutcome::Failed { .. })));
+    }
+
+    #[test]
+    fn report() {
+        let report = RestoreReport {
+            apps: vec![
+                RestoredApp {
+                    name: "Bitcoin".to_string(),
+                    previous_version: Some("2.2.2".to_string()),
+                    outcome: RestoreOutcome::Installed {
+                        version: "2.2.3".to_string(),
+                    },
+                },
+                RestoredApp {
+                    name: "Ethereum".to_string(),
+                    previous_version: None,
+                    outcome: RestoreOutcome::AlreadyInstalled,
+                },
+            ],
+        };
+        assert!(report.is_complete());
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({ "apps": [
+                {
+                    "name": "Bitcoin",
+                    "previous_version": "2.2.2",
+                    "outcome": "installed",
+                    "version": "2.2.3"
+                },
+                { "name": "Ethereum", "previous_version": null, "outcome": "already_installed" },
+            ]})
+        );
+
+        let mut report = report;
+        report.apps[1].outcome = RestoreOutcome::Failed {
+            error: "Not enough space.".to_string(),
+        };
+        assert!(!report.is_complete());
+        let not_restored: Vec<_> = report.not_restored().map(|a| a.name.as_str()).collect();
+        assert_eq!(not_restored, ["Ethereum"]);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let snapshot = Snapshot {
+            target_id: 0x33000004,
+            firmware_version: "2.2.3".to_string(),
+            apps: vec![SnapshotApp {
+                name: "Ethereum".to_string(),
+                hash: "11".repeat(32),
+                hash_code_data: "22".repeat(32),
+                catalog: Some(catalog_app("Ethereum", None)),
+            }],
+        };
+        let path = std::env::temp_dir()
+            .join(format!("ledger_manager_snapshot_{}", std::process::id()))
+            .join("apps_snapshot.json");
+        save(&path, &snapshot).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&snapshot).unwrap()
+        );
+        assert!(load(&path).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+//! TLS configuration for the connections to the Ledger API.
+//!
+//! Certificates are verified against the system's trust store. On top of this, the public keys
+//! of the servers may be pinned: the connection is then aborted during the handshake, before any
+//! data is exchanged, unless a certificate in the chain presented by the server has one of the
+//! pinned keys.
+
+use base64::Engine;
+use bitcoin_hashes::{sha256, Hash};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, OtherError, SignatureScheme,
+};
+
+use std::{
+    error, fmt, iter, str,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/// The SHA-256 hash of the SubjectPublicKeyInfo of a certificate, written as `sha256/<base64>`
+/// like for HPKP and curl's `--pinnedpubkey`. It can be computed for a PEM certificate with:
+/// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpkiPin(pub [u8; 32]);
+
+impl SpkiPin {
+    /// The pin of the public key of this DER-encoded X.509 certificate.
+    pub fn of_certificate(cert: &[u8]) -> Result<Self, Box<dyn error::Error>> {
+        let spki = spki_der(cert).ok_or("Could not parse the certificate.")?;
+        Ok(Self(sha256::Hash::hash(spki).to_byte_array()))
+    }
+}
+
+impl str::FromStr for SpkiPin {
+    type Err = Box<dyn error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let b64 = s
+            .strip_prefix("sha256//")
+            .or_else(|| s.strip_prefix("sha256/"))
+            .unwrap_or(s);
+        let hash = base64::engine::general_purpose::STANDARD
+            .decode(b64)
+            .map_err(|e| format!("Invalid pin '{}': {}", s, e))?;
+        Ok(Self(hash.try_into().map_err(|_| {
+            format!("Invalid pin '{}': not a SHA-256 hash.", s)
+        })?))
+    }
+}
+
+impl fmt::Display for SpkiPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sha256/{}",
+            base64::engine::general_purpose::STANDARD.encode(self.0)
+        )
+    }
+}
+
+// Read a DER TLV at the start of data. Returns the tag, the whole TLV and its content.
+fn der_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first_len = *data.get(1)?;
+    let (header_len, len) = if first_len < 0x80 {
+        (2, first_len as usize)
+    } else {
+        let n = (first_len & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + n)?
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        (2 + n, len)
+    };
+    let end = header_len.checked_add(len)?;
+    Some((tag, data.get(..end)?, data.get(header_len..end)?))
+}
+
+// The DER-encoded SubjectPublicKeyInfo of an X.509 certificate.
+fn spki_der(cert: &[u8]) -> Option<&[u8]> {
+    let (_, _, cert) = der_tlv(cert)?;
+    let (_, _, mut tbs) = der_tlv(cert)?;
+    // Skip the optional version, then the serial number, signature algorithm, issuer, validity
+    // and subject.
+    if *tbs.first()? == 0xa0 {
+        tbs = &tbs[der_tlv(tbs)?.1.len()..];
+    }
+    for _ in 0..5 {
+        tbs = &tbs[der_tlv(tbs)?.1.len()..];
+    }
+    match der_tlv(tbs)? {
+        (0x30, spki, _) => Some(spki),
+        _ => None,
+    }
+}
+
+/// None of the certificates presented by a server matched the configured pins.
+#[derive(Debug)]
+pub(crate) struct PinMismatch {
+    pub host: String,
+    pub presented: Vec<SpkiPin>,
+}
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "No certificate of {} matches the pinned keys.",
+            self.host
+        )
+    }
+}
+
+impl error::Error for PinMismatch {}
+
+// Verifies the certificate chain against the trust store, then checks the pins.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<SpkiPin>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let presented: Vec<SpkiPin> = iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|cert| SpkiPin::of_certificate(cert).ok())
+            .collect();
+        if presented.iter().any(|pin| self.pins.contains(pin)) {
+            return Ok(verified);
+        }
+        let mismatch = PinMismatch {
+            host: server_name.to_str().into_owned(),
+            presented,
+        };
+        Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+            OtherError(Arc::new(mismatch)),
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+// A configuration built for these pins.
+type PinnedConfig = (Vec<SpkiPin>, Arc<rustls::ClientConfig>);
+
+// The configurations built so far. Loading the root certificates of the system is slow, so it's
+// only done once per set of pins.
+static CONFIGS: OnceLock<Mutex<Vec<PinnedConfig>>> = OnceLock::new();
+
+/// The TLS configuration, trusting the system's root certificates. If any pin is given, the
+/// servers must also present a certificate with one of these keys. It is built on the first call
+/// with these pins, and shared by the next ones.
+pub(crate) fn config(pins: &[SpkiPin]) -> Result<Arc<rustls::ClientConfig>, Box<dyn error::Error>> {
+    let mut configs = CONFIGS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some((_, config)) = configs.iter().find(|(p, _)| p == pins) {
+        return Ok(config.clone());
+    }
+    let config = build_config(pins)?;
+    configs.push((pins.to_vec(), config.clone()));
+    Ok(config)
+}
+
+fn build_config(pins: &[SpkiPin]) -> Result<Arc<rustls::ClientConfig>, Box<dyn error::Error>> {
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(rustls_native_certs::load_native_certs()?);
+    if added == 0 {
+        return Err("Could not load any root certificate from the system.".into());
+    }
+    if pins.is_empty() {
+        return Ok(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+    }
+
+    let verifier = PinnedVerifier {
+        inner: WebPkiServerVerifier::builder(Arc::new(roots)).build()?,
+        pins: pins.to_vec(),
+    };
+    Ok(Arc::new(
+        rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The root certificates of Let's Encrypt, with the pins they publish.
+    const ISRG_ROOT_X1: &[u8] = include_bytes!("../tests/data/isrg_root_x1.der");
+    const ISRG_ROOT_X2: &[u8] = include_bytes!("../tests/data/isrg_root_x2.der");
+    const ISRG_ROOT_X1_PIN: &str = "sha256/C5+lpZ7tcVwmwQIMcRtPbsQtWLABXhQzejna0wHFr8M=";
+    const ISRG_ROOT_X2_PIN: &str = "sha256/diGVwiVYbubAI3RW4hB9xU8e/CH2GnkuvVFZE8zmgzI=";
+
+    #[test]
+    fn certificate_pins() {
+        for (cert, pin) in [
+            (ISRG_ROOT_X1, ISRG_ROOT_X1_PIN),
+            (ISRG_ROOT_X2, ISRG_ROOT_X2_PIN),
+        ] {
+            let computed = SpkiPin::of_certificate(cert).unwrap();
+            assert_eq!(computed, pin.parse().unwrap());
+            assert_eq!(computed.to_string(), pin);
+        }
+    }
+
+    #[test]
+    fn malformed_certificates() {
+        assert!(spki_der(&[]).is_none());
+        // Truncated anywhere, the certificate can't be parsed.
+        for len in 0..ISRG_ROOT_X2.len() {
+            assert!(spki_der(&ISRG_ROOT_X2[..len]).is_none(), "{}", len);
+        }
+        // A length beyond the data, or encoded on too many bytes.
+        assert!(der_tlv(&[0x30, 0x82, 0xff, 0xff, 0x00]).is_none());
+        assert!(der_tlv(&[0x30, 0x85, 0, 0, 0, 0, 1, 0]).is_none());
+        assert!(der_tlv(&[0x30, 0x80]).is_none());
+        assert_eq!(
+            der_tlv(&[0x02, 0x01, 0x05, 0xff]),
+            Some((0x02, &[0x02, 0x01, 0x05][..], &[0x05][..]))
+        );
+        // The subject public key info must be a sequence.
+        let mut cert = ISRG_ROOT_X2.to_vec();
+        let spki = spki_der(ISRG_ROOT_X2).unwrap();
+        let offset = spki.as_ptr() as usize - ISRG_ROOT_X2.as_ptr() as usize;
+        cert[offset] = 0x31;
+        assert!(spki_der(&cert).is_none());
+        assert!(SpkiPin::of_certificate(&cert).is_err());
+    }
+
+    #[test]
+    fn pins() {
+        let pin: SpkiPin = ISRG_ROOT_X1_PIN.parse().unwrap();
+        assert_eq!(
+            format!("sha256//{}", &ISRG_ROOT_X1_PIN[7..])
+                .parse::<SpkiPin>()
+                .unwrap(),
+            pin
+        );
+        assert_eq!(ISRG_ROOT_X1_PIN[7..].parse::<SpkiPin>().unwrap(), pin);
+        assert!("sha256/AAAA".parse::<SpkiPin>().is_err());
+        assert!("sha256/not base64!".parse::<SpkiPin>().is_err());
+    }
+}
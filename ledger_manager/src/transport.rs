@@ -0,0 +1,128 @@
+//! A common interface over the ways of talking to a Ledger device.
+//!
+//! Management operations are only ever performed on a physical device over HID. But the Bitcoin
+//! app can also be exercised against the Speculos emulator, which exposes a raw APDU socket.
+
+use crate::{ser_apdu_command, MAX_APDU_DATA_LEN};
+
+use ledger_apdu::{APDUAnswer, APDUCommand};
+use ledger_transport_hidapi::TransportNativeHID;
+
+use std::{
+    error,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+};
+
+/// Something we can exchange APDUs with.
+pub trait Transport {
+    /// Send a command and wait for the device's answer.
+    fn exchange(
+        &self,
+        command: &APDUCommand<Vec<u8>>,
+    ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>>;
+}
+
+impl Transport for TransportNativeHID {
+    fn exchange(
+        &self,
+        command: &APDUCommand<Vec<u8>>,
+    ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>> {
+        // The length of longer data would be sent truncated to a single byte.
+        if command.data.len() > MAX_APDU_DATA_LEN {
+            return Err(format!(
+                "Can't send {} bytes of data in a command to the device, the maximum is {}.",
+                command.data.len(),
+                MAX_APDU_DATA_LEN
+            )
+            .into());
+        }
+        Ok(TransportNativeHID::exchange(self, command)?)
+    }
+}
+
+/// A connection to the APDU port of a Speculos emulator (9999 by default).
+pub struct SpeculosTransport {
+    stream: Mutex<TcpStream>,
+}
+
+impl SpeculosTransport {
+    /// Connect to a running Speculos instance.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, Box<dyn error::Error>> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            stream: Mutex::new(stream),
+        })
+    }
+}
+
+impl Transport for SpeculosTransport {
+    fn exchange(
+        &self,
+        command: &APDUCommand<Vec<u8>>,
+    ) -> Result<APDUAnswer<Vec<u8>>, Box<dyn error::Error>> {
+        let mut stream = self.stream.lock().expect("Speculos stream poisoned");
+
+        // Each APDU is prefixed by its length on 4 bytes.
+        let apdu = ser_apdu_command(command);
+        stream.write_all(&(apdu.len() as u32).to_be_bytes())?;
+        stream.write_all(&apdu)?;
+
+        // The answer is prefixed by the length of its data, not accounting for the status word.
+        let mut len = [0; 4];
+        stream.read_exact(&mut len)?;
+        let mut answer = vec![0; u32::from_be_bytes(len) as usize + 2];
+        stream.read_exact(&mut answer)?;
+
+        Ok(APDUAnswer::from_answer(answer)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn speculos_framing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let emulator = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut commands = Vec::new();
+            for _ in 0..2 {
+                let mut len = [0; 4];
+                stream.read_exact(&mut len).unwrap();
+                let mut command = vec![0; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut command).unwrap();
+                commands.push(command);
+                // Two bytes of data and the status.
+                stream
+                    .write_all(&[0, 0, 0, 2, 0xca, 0xfe, 0x90, 0x00])
+                    .unwrap();
+            }
+            commands
+        });
+
+        let transport = SpeculosTransport::connect(addr).unwrap();
+        for len in [3, 300] {
+            let answer = transport
+                .exchange(&APDUCommand {
+                    cla: 0xe0,
+                    ins: 0x01,
+                    p1: 0x02,
+                    p2: 0x03,
+                    data: vec![0xab; len],
+                })
+                .unwrap();
+            assert_eq!(answer.data(), [0xca, 0xfe]);
+            assert_eq!(answer.retcode(), 0x9000);
+        }
+        let commands = emulator.join().unwrap();
+        assert_eq!(commands[0], [0xe0, 0x01, 0x02, 0x03, 3, 0xab, 0xab, 0xab]);
+        assert_eq!(commands[1][..7], [0xe0, 0x01, 0x02, 0x03, 0x00, 0x01, 0x2c]);
+        assert_eq!(commands[1].len(), 7 + 300);
+    }
+}
@@ -0,0 +1,665 @@
+//! Planning the upgrade of a device to its latest firmware and Bitcoin app, and carrying it out
+//! step by step.
+//!
+//! A firmware update goes through the firmware updater ("OSU"): it's installed from the dashboard,
+//! then the device restarts in updater mode to install the new firmware, and if needed restarts
+//! in bootloader mode to update its MCU. The new firmware removes all the apps, which are then
+//! reinstalled at their latest version for it. This follows what Ledger Live does, see
+//! https://github.com/LedgerHQ/ledger-live/blob/99879eb5bada1ecaea7a02d8886e16b44657af6d/libs/ledger-live-common/src/manager/index.ts
+//!
+//! The device restarts after the firmware steps (see [`UpgradeStep::restarts_device`]), so the
+//! caller must reconnect to it before running the next step.
+
+use crate::{
+    bitcoin_app_installed, ensure_dashboard, get_catalog_for_target, install_bitcoin_app, net,
+    query_via_websocket,
+    snapshot::{self, RestoreOutcome},
+    update_bitcoin_app, ApiConfig, BitcoinAppInfo, DeviceInfo, DeviceVersion, FirmwareInfo,
+    InstallErr, InstalledApp, UpdateErr, LIVE_COMMON_VERSION, PROVIDER,
+};
+
+use form_urlencoded::Serializer as UrlSerializer;
+use ledger_transport_hidapi::TransportNativeHID;
+use serde_derive::{Deserialize, Serialize};
+
+use std::{error, fmt, time};
+
+// Rough figures to estimate how long the steps take. The transfer rate is the pace at which the
+// HSM streams the blocks of an app or a firmware to the device.
+const TRANSFER_BYTES_PER_SEC: u64 = 10 * 1024;
+// Confirming on the device and the round trips with the HSM.
+const STEP_OVERHEAD: time::Duration = time::Duration::from_secs(15);
+// Restarting the device, and reconnecting to it.
+const RESTART_DURATION: time::Duration = time::Duration::from_secs(30);
+// When the API doesn't tell the size of what is installed.
+const UNKNOWN_SIZE_FIRMWARE_DURATION: time::Duration = time::Duration::from_secs(5 * 60);
+const UNKNOWN_SIZE_APP_DURATION: time::Duration = time::Duration::from_secs(30);
+const MCU_DURATION: time::Duration = time::Duration::from_secs(2 * 60);
+
+/// The firmware updater ("OSU") to install to update the firmware of a device, as served by the
+/// Ledger API.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OsuFirmware {
+    pub id: i64,
+    pub name: String,
+    pub perso: String,
+    pub firmware: String,
+    pub firmware_key: String,
+    #[serde(default)]
+    pub hash: String,
+    /// The id of the firmware the updater installs.
+    pub next_se_firmware_final_version: i64,
+    #[serde(default)]
+    pub bytes: Option<u64>,
+}
+
+/// A firmware version, as served by the Ledger API.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FinalFirmware {
+    pub id: i64,
+    pub name: String,
+    pub version: String,
+    pub perso: String,
+    pub firmware: String,
+    pub firmware_key: String,
+    #[serde(default)]
+    pub hash: String,
+    #[serde(default)]
+    pub bytes: Option<u64>,
+    /// The ids of the MCU versions this firmware runs with.
+    #[serde(default)]
+    pub mcu_versions: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct McuVersion {
+    id: i64,
+    name: String,
+}
+
+// The answer to a query for the latest firmware, "null" as a result if already up to date.
+#[derive(Debug, Clone, Deserialize)]
+struct LatestFirmware {
+    #[serde(default)]
+    se_firmware_osu_version: Option<OsuFirmware>,
+}
+
+/// A step of an upgrade, in the order they must be carried out.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum UpgradeStep {
+    /// Install the firmware updater from the dashboard. The device then asks to confirm the
+    /// update and restarts in updater mode.
+    InstallOsu { osu: OsuFirmware },
+    /// Install the new firmware from the updater. This removes all the apps. The device restarts.
+    InstallFirmware { firmware: FinalFirmware },
+    /// Update the MCU to the version the new firmware runs with, from the bootloader. The device
+    /// restarts.
+    UpdateMcu { version: String },
+    /// Reinstall the apps removed by the firmware update, at their latest version for the new
+    /// firmware. The Bitcoin apps are updated by their own step.
+    ReinstallApps { apps: Vec<BitcoinAppInfo> },
+    /// Install the latest Bitcoin app (or Test app) for the firmware, replacing the installed one.
+    UpdateBitcoinApp {
+        is_testnet: bool,
+        app: BitcoinAppInfo,
+    },
+}
+
+impl UpgradeStep {
+    /// The number of bytes to send to the device, if known.
+    pub fn bytes(&self) -> Option<u64> {
+        match self {
+            Self::InstallOsu { osu } => osu.bytes,
+            Self::InstallFirmware { firmware } => firmware.bytes,
+            Self::UpdateMcu { .. } => None,
+            Self::ReinstallApps { apps } => apps.iter().map(|app| app.bytes).sum(),
+            Self::UpdateBitcoinApp { app, .. } => app.bytes,
+        }
+    }
+
+    /// A rough estimate of how long the step takes, including the confirmations on the device.
+    pub fn estimate(&self) -> time::Duration {
+        let transfer = |bytes: Option<u64>, unknown_size: time::Duration| {
+            bytes
+                .map(|b| time::Duration::from_secs(b / TRANSFER_BYTES_PER_SEC))
+                .unwrap_or(unknown_size)
+                + STEP_OVERHEAD
+        };
+        match self {
+            Self::InstallOsu { osu } => {
+                transfer(osu.bytes, UNKNOWN_SIZE_FIRMWARE_DURATION) + RESTART_DURATION
+            }
+            Self::InstallFirmware { firmware } => {
+                transfer(firmware.bytes, UNKNOWN_SIZE_FIRMWARE_DURATION) + RESTART_DURATION
+            }
+            Self::UpdateMcu { .. } => MCU_DURATION + RESTART_DURATION,
+            Self::ReinstallApps { apps } => apps
+                .iter()
+                .map(|app| transfer(app.bytes, UNKNOWN_SIZE_APP_DURATION))
+                .sum(),
+            Self::UpdateBitcoinApp { app, .. } => transfer(app.bytes, UNKNOWN_SIZE_APP_DURATION),
+        }
+    }
+
+    /// Whether the device is in the mode this step runs in: on the dashboard, running the
+    /// firmware updater or in bootloader mode.
+    pub fn is_ready(&self, device_info: &DeviceInfo) -> bool {
+        match self {
+            Self::InstallFirmware { .. } => device_info.is_osu(),
+            Self::UpdateMcu { .. } => device_info.is_bootloader,
+            Self::InstallOsu { .. }
+            | Self::ReinstallApps { .. }
+            | Self::UpdateBitcoinApp { .. } => !device_info.is_bootloader && !device_info.is_osu(),
+        }
+    }
+
+    /// Whether the device restarts at the end of this step. The caller must then reconnect to it.
+    pub fn restarts_device(&self) -> bool {
+        matches!(
+            self,
+            Self::InstallOsu { .. } | Self::InstallFirmware { .. } | Self::UpdateMcu { .. }
+        )
+    }
+}
+
+impl fmt::Display for UpgradeStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InstallOsu { osu } => write!(f, "Install the firmware updater {}", osu.name),
+            Self::InstallFirmware { firmware } => write!(f, "Install firmware {}", firmware.name),
+            Self::UpdateMcu { version } => write!(f, "Update the MCU to version {}", version),
+            Self::ReinstallApps { apps } => {
+                let apps: Vec<_> = apps
+                    .iter()
+                    .map(|a| format!("{} {}", a.version_name, a.version))
+                    .collect();
+                write!(f, "Reinstall {}", apps.join(", "))
+            }
+            Self::UpdateBitcoinApp { app, .. } => {
+                write!(f, "Install {} {}", app.version_name, app.version)
+            }
+        }
+    }
+}
+
+/// The steps to bring a device up to date.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpgradePlan {
+    /// The firmware version the device is updated to, if its firmware is updated.
+    pub firmware_version: Option<String>,
+    pub steps: Vec<UpgradeStep>,
+    /// The names of the installed apps removed by the firmware update that can't be reinstalled,
+    /// as the catalog has no version of them for the new firmware.
+    pub lost_apps: Vec<String>,
+}
+
+impl UpgradePlan {
+    /// Whether the device is already up to date.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// A rough estimate of how long the whole upgrade takes.
+    pub fn estimate(&self) -> time::Duration {
+        self.steps.iter().map(|s| s.estimate()).sum()
+    }
+}
+
+// Get the firmware updater to install to update the firmware of this device, if any.
+fn latest_osu(
+    device_info: &DeviceInfo,
+    config: &ApiConfig,
+) -> Result<Option<OsuFirmware>, Box<dyn error::Error>> {
+    let device_version = DeviceVersion::from_device(device_info, config)?;
+    let firmware = FirmwareInfo::from_device(device_info, config)?;
+    let resp = net::Request::post(format!("{}/get_latest_firmware", config.api_url))
+        .with_param("livecommonversion", LIVE_COMMON_VERSION)
+        .with_json(&serde_json::json!({
+            "current_se_firmware_final_version": firmware.id,
+            "device_version": device_version.id,
+            "provider": PROVIDER,
+        }))?
+        .idempotent()
+        .send(config)?;
+    parse_latest_osu(resp.json()?)
+}
+
+// The firmware updater from the answer to a query for the latest firmware. The result is the
+// string "null" when the firmware is up to date.
+fn parse_latest_osu(
+    latest: serde_json::Value,
+) -> Result<Option<OsuFirmware>, Box<dyn error::Error>> {
+    if latest.get("result").and_then(|r| r.as_str()) == Some("null") {
+        return Ok(None);
+    }
+    Ok(serde_json::from_value::<LatestFirmware>(latest)?.se_firmware_osu_version)
+}
+
+fn final_firmware(id: i64, config: &ApiConfig) -> Result<FinalFirmware, Box<dyn error::Error>> {
+    net::Request::get(format!("{}/firmware_final_versions/{}", config.api_url, id))
+        .with_param("livecommonversion", LIVE_COMMON_VERSION)
+        .send(config)?
+        .json::<FinalFirmware>()
+}
+
+// The MCU version the firmware runs with, if the device doesn't already have one of them.
+fn mcu_update(
+    device_info: &DeviceInfo,
+    firmware: &FinalFirmware,
+    config: &ApiConfig,
+) -> Result<Option<String>, Box<dyn error::Error>> {
+    let mcus: Vec<McuVersion> = net::Request::get(format!("{}/mcu_versions", config.api_url))
+        .with_param("livecommonversion", LIVE_COMMON_VERSION)
+        .send(config)?
+        .json()?;
+    Ok(mcu_to_install(device_info, firmware, mcus))
+}
+
+// The latest of these MCU versions the firmware runs with, unless the device already has one of
+// them.
+fn mcu_to_install(
+    device_info: &DeviceInfo,
+    firmware: &FinalFirmware,
+    mcus: Vec<McuVersion>,
+) -> Option<String> {
+    let compatible: Vec<_> = mcus
+        .into_iter()
+        .filter(|mcu| firmware.mcu_versions.contains(&mcu.id))
+        .collect();
+    if compatible.is_empty()
+        || compatible
+            .iter()
+            .any(|mcu| Some(&mcu.name) == device_info.mcu_version.as_ref())
+    {
+        return None;
+    }
+    compatible
+        .into_iter()
+        .max_by_key(|mcu| mcu.id)
+        .map(|mcu| mcu.name)
+}
+
+fn is_bitcoin_app(name: &str) -> Option<bool> {
+    match name.to_lowercase().as_str() {
+        "bitcoin" => Some(false),
+        "bitcoin test" => Some(true),
+        _ => None,
+    }
+}
+
+// A firmware update: the updater, the firmware it installs and the MCU version to update to if
+// the device doesn't have one the firmware runs with.
+struct FirmwareUpdate {
+    osu: OsuFirmware,
+    firmware: FinalFirmware,
+    mcu: Option<String>,
+}
+
+/// Plan the steps to bring this device up to date: update its firmware if a newer one is
+/// available, reinstall the apps it removes and update the installed Bitcoin apps. The device
+/// must be on its dashboard, not in the middle of an update. `installed` are the apps installed
+/// on the device, as listed by [`list_installed_apps_raw`](crate::list_installed_apps_raw).
+pub fn plan_upgrade(
+    device_info: &DeviceInfo,
+    installed: &[InstalledApp],
+    config: &ApiConfig,
+) -> Result<UpgradePlan, Box<dyn error::Error>> {
+    if device_info.is_bootloader || device_info.is_osu() {
+        return Err("The device is in the middle of an update. Finish it with Ledger Live.".into());
+    }
+    config.status("Querying the Ledger API for a firmware update.");
+    let update = match latest_osu(device_info, config)? {
+        Some(osu) => {
+            let firmware = final_firmware(osu.next_se_firmware_final_version, config)?;
+            let mcu = mcu_update(device_info, &firmware, config)?;
+            Some(FirmwareUpdate { osu, firmware, mcu })
+        }
+        None => None,
+    };
+    // The apps available after the upgrade.
+    let firmware_version = update.as_ref().map_or(device_info.version.as_str(), |u| {
+        u.firmware.version.as_str()
+    });
+    let catalog = get_catalog_for_target(device_info.target_id, firmware_version, config)?;
+    Ok(plan(installed, update, &catalog))
+}
+
+// Plan the upgrade of the device with these apps installed, given the firmware update if any and
+// the catalog for the firmware of the device after the update.
+fn plan(
+    installed: &[InstalledApp],
+    update: Option<FirmwareUpdate>,
+    catalog: &[BitcoinAppInfo],
+) -> UpgradePlan {
+    let mut plan = UpgradePlan {
+        firmware_version: None,
+        steps: Vec::new(),
+        lost_apps: Vec::new(),
+    };
+
+    if let Some(FirmwareUpdate { osu, firmware, mcu }) = update {
+        plan.firmware_version = Some(firmware.name.clone());
+        plan.steps.push(UpgradeStep::InstallOsu { osu });
+        plan.steps.push(UpgradeStep::InstallFirmware { firmware });
+        if let Some(version) = mcu {
+            plan.steps.push(UpgradeStep::UpdateMcu { version });
+        }
+
+        let mut reinstall = Vec::new();
+        for app in installed {
+            if is_bitcoin_app(&app.name).is_some() {
+                continue;
+            }
+            match catalog
+                .iter()
+                .find(|a| a.version_name.eq_ignore_ascii_case(&app.name))
+            {
+                Some(latest) => reinstall.push(latest.clone()),
+                None => plan.lost_apps.push(app.name.clone()),
+            }
+        }
+        if !reinstall.is_empty() {
+            plan.steps
+                .push(UpgradeStep::ReinstallApps { apps: reinstall });
+        }
+    }
+
+    // The Bitcoin apps are reinstalled after a firmware update, or updated if a newer version is
+    // available.
+    for app in installed {
+        let Some(is_testnet) = is_bitcoin_app(&app.name) else {
+            continue;
+        };
+        let latest = catalog
+            .iter()
+            .find(|a| is_bitcoin_app(&a.version_name) == Some(is_testnet));
+        match latest {
+            Some(latest)
+                if plan.firmware_version.is_some() || latest.hash != hex::encode(&app.hash) =>
+            {
+                plan.steps.push(UpgradeStep::UpdateBitcoinApp {
+                    is_testnet,
+                    app: latest.clone(),
+                })
+            }
+            Some(_) => {}
+            None if plan.firmware_version.is_some() => plan.lost_apps.push(app.name.clone()),
+            None => {}
+        }
+    }
+
+    plan
+}
+
+/// Carry out a step of an upgrade plan. The device must be in the mode the step runs in (see
+/// [`UpgradeStep::is_ready`]), which it is after the previous step. If the step restarts the
+/// device, reconnect to it before running the next step.
+pub fn run_upgrade_step(
+    ledger_api: &TransportNativeHID,
+    step: &UpgradeStep,
+    config: &ApiConfig,
+) -> Result<(), Box<dyn error::Error>> {
+    // The firmware and MCU steps run in the updater and the bootloader, the others from the
+    // dashboard: quit any app first so the device info and installed apps can be queried.
+    if !matches!(
+        step,
+        UpgradeStep::InstallFirmware { .. } | UpgradeStep::UpdateMcu { .. }
+    ) {
+        ensure_dashboard(ledger_api)?;
+    }
+    let device_info = DeviceInfo::new(ledger_api)?;
+    if !step.is_ready(&device_info) {
+        return Err(match step {
+            UpgradeStep::InstallFirmware { .. } => {
+                "The device isn't running the firmware updater. Confirm the update on the device."
+            }
+            UpgradeStep::UpdateMcu { .. } => "The device isn't in bootloader mode.",
+            _ => "The device is in the middle of an update.",
+        }
+        .into());
+    }
+    match step {
+        UpgradeStep::InstallOsu { osu } => {
+            let url = UrlSerializer::new(format!("{}/install?", config.socket_url))
+                .append_pair("targetId", &device_info.target_id.to_string())
+                .append_pair("perso", &osu.perso)
+                .append_pair("firmware", &osu.firmware)
+                .append_pair("firmwareKey", &osu.firmware_key)
+                .append_pair("hash", &osu.hash)
+                .finish();
+            config.status(&format!(
+                "Installing the firmware updater {}. Allow the Ledger manager on the device, then \
+                 confirm the update.",
+                osu.name
+            ));
+            query_via_websocket(ledger_api, &url, "installing the firmware updater", config)?;
+        }
+        UpgradeStep::InstallFirmware { firmware } => {
+            let url = UrlSerializer::new(format!("{}/install?", config.socket_url))
+                .append_pair("targetId", &device_info.target_id.to_string())
+                .append_pair("perso", &firmware.perso)
+                .append_pair("firmware", &firmware.firmware)
+                .append_pair("firmwareKey", &firmware.firmware_key)
+                .append_pair("hash", &firmware.hash)
+                .finish();
+            config.status(&format!("Installing firmware {}.", firmware.name));
+            query_via_websocket(ledger_api, &url, "installing the firmware", config)?;
+        }
+        UpgradeStep::UpdateMcu { version } => {
+            let url = UrlSerializer::new(format!("{}/mcu?", config.socket_url))
+                .append_pair("targetId", &device_info.target_id.to_string())
+                .append_pair("version", version)
+                .finish();
+            config.status(&format!("Updating the MCU to version {}.", version));
+            query_via_websocket(ledger_api, &url, "updating the MCU", config)?;
+        }
+        UpgradeStep::ReinstallApps { apps } => {
+            // The apps already reinstalled are skipped, for instance if the step is tried again.
+            let names: Vec<_> = apps.iter().map(|app| app.version_name.as_str()).collect();
+            let outcomes =
+                snapshot::reinstall_apps(ledger_api, &device_info, &names, apps, config)?;
+            let failed: Vec<_> = names
+                .iter()
+                .zip(&outcomes)
+                .filter_map(|(name, outcome)| match outcome {
+                    RestoreOutcome::Failed { error } => Some(format!("{}: {}", name, error)),
+                    _ => None,
+                })
+                .collect();
+            if !failed.is_empty() {
+                return Err(format!("Error reinstalling {}.", failed.join(", ")).into());
+            }
+        }
+        UpgradeStep::UpdateBitcoinApp { is_testnet, .. } => {
+            let is_testnet = *is_testnet;
+            let name = if is_testnet {
+                "Bitcoin Test"
+            } else {
+                "Bitcoin"
+            };
+            if bitcoin_app_installed(ledger_api, is_testnet)?.is_some() {
+                match update_bitcoin_app(ledger_api, is_testnet, config) {
+                    Ok(_) | Err(UpdateErr::AlreadyLatest) => {}
+                    Err(UpdateErr::NotInstalled) | Err(UpdateErr::AppNotFound) => {
+                        return Err(format!("Could not get info about the {} app.", name).into())
+                    }
+                    Err(UpdateErr::Failed(report)) => return Err(report.to_string().into()),
+                    Err(UpdateErr::Verification(e)) => return Err(e.into()),
+                    Err(UpdateErr::Any(e)) => return Err(e),
+                }
+            } else {
+                match install_bitcoin_app(ledger_api, is_testnet, config) {
+                    Ok(_) | Err(InstallErr::AlreadyInstalled) => {}
+                    Err(InstallErr::AppNotFound) => {
+                        return Err(format!("Could not get info about the {} app.", name).into())
+                    }
+                    Err(InstallErr::Verification(e)) => return Err(e.into()),
+                    Err(InstallErr::Any(e)) => return Err(e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    // The answers of the Ledger API and the apps installed on a Nano X, in this set.
+    fn fixture<T: serde::de::DeserializeOwned>(name: &str) -> T {
+        let fixtures: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/data/upgrade.json")).unwrap();
+        serde_json::from_value(fixtures[name].clone()).unwrap()
+    }
+
+    fn device_info(mcu_version: &str) -> DeviceInfo {
+        DeviceInfo {
+            target_id: 0x33000004,
+            version: "2.2.3".to_string(),
+            flags: vec![0x8e, 0, 0, 0],
+            is_bootloader: false,
+            se_version: Some("2.2.3".to_string()),
+            se_target_id: 0x33000004,
+            mcu_version: Some(mcu_version.to_string()),
+        }
+    }
+
+    fn firmware_update(mcu_version: &str) -> FirmwareUpdate {
+        let osu = parse_latest_osu(fixture("latest_firmware"))
+            .unwrap()
+            .unwrap();
+        let firmware: FinalFirmware = fixture("final_firmware");
+        assert_eq!(osu.next_se_firmware_final_version, firmware.id);
+        let mcu = mcu_to_install(
+            &device_info(mcu_version),
+            &firmware,
+            fixture("mcu_versions"),
+        );
+        FirmwareUpdate { osu, firmware, mcu }
+    }
+
+    // The steps of the plan, as displayed.
+    fn steps(plan: &UpgradePlan) -> Vec<String> {
+        plan.steps.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn up_to_date() {
+        assert!(parse_latest_osu(fixture("up_to_date")).unwrap().is_none());
+        let installed: Vec<InstalledApp> = fixture("installed");
+        let installed: Vec<_> = installed
+            .into_iter()
+            .filter(|app| app.name == "Bitcoin" || app.name == "Ethereum")
+            .collect();
+        let plan = plan(&installed, None, &fixture::<Vec<_>>("catalog"));
+        assert!(plan.is_empty());
+        assert!(plan.firmware_version.is_none() && plan.lost_apps.is_empty());
+        assert_eq!(plan.estimate(), Duration::ZERO);
+    }
+
+    #[test]
+    fn bitcoin_app_at_latest_hash() {
+        // The Bitcoin app has the hash of the latest version, not the Test app. The other apps
+        // are only reinstalled along with a firmware update.
+        let plan = plan(
+            &fixture::<Vec<_>>("installed"),
+            None,
+            &fixture::<Vec<_>>("catalog"),
+        );
+        assert_eq!(steps(&plan), ["Install Bitcoin Test 2.2.3"]);
+        assert!(matches!(
+            plan.steps[0],
+            UpgradeStep::UpdateBitcoinApp {
+                is_testnet: true,
+                ..
+            }
+        ));
+        assert!(plan.firmware_version.is_none() && plan.lost_apps.is_empty());
+        // 100KiB at 10KiB/s, and the overhead.
+        assert_eq!(plan.estimate(), Duration::from_secs(10 + 15));
+    }
+
+    #[test]
+    fn firmware_and_mcu() {
+        let plan = plan(
+            &fixture::<Vec<_>>("installed"),
+            Some(firmware_update("2.30")),
+            &fixture::<Vec<_>>("new_catalog"),
+        );
+        assert_eq!(plan.firmware_version.as_deref(), Some("2.4.0"));
+        assert_eq!(
+            steps(&plan),
+            [
+                "Install the firmware updater 2.4.0-osu",
+                "Install firmware 2.4.0",
+                "Update the MCU to version 2.62",
+                "Reinstall Ethereum 1.12.0",
+                "Install Bitcoin 2.3.0",
+            ]
+        );
+        // The apps the new firmware has no version of.
+        assert_eq!(plan.lost_apps, ["Retired", "Bitcoin Test"]);
+
+        let estimates: Vec<_> = plan.steps.iter().map(|s| s.estimate()).collect();
+        assert_eq!(
+            estimates,
+            [
+                // 600KiB, then a restart.
+                Duration::from_secs(60 + 15 + 30),
+                // The size of the firmware isn't known.
+                Duration::from_secs(5 * 60 + 15 + 30),
+                Duration::from_secs(2 * 60 + 30),
+                Duration::from_secs(25 + 15),
+                Duration::from_secs(11 + 15),
+            ]
+        );
+        assert_eq!(plan.estimate(), estimates.iter().sum());
+        assert_eq!(plan.steps[1].bytes(), None);
+        assert_eq!(plan.steps[3].bytes(), Some(256000));
+        assert!(plan.steps[..3].iter().all(|s| s.restarts_device()));
+        assert!(plan.steps[3..].iter().all(|s| !s.restarts_device()));
+    }
+
+    #[test]
+    fn firmware_without_mcu() {
+        // The device already has an MCU version the new firmware runs with.
+        let update = firmware_update("2.61");
+        assert!(update.mcu.is_none());
+        let plan = plan(&[], Some(update), &fixture::<Vec<_>>("new_catalog"));
+        assert_eq!(
+            steps(&plan),
+            [
+                "Install the firmware updater 2.4.0-osu",
+                "Install firmware 2.4.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn lost_app() {
+        // The Bitcoin Test app is lost if the catalog has no version of it for the new firmware,
+        // not when only updating the apps.
+        let installed: Vec<InstalledApp> = fixture("installed");
+        let installed: Vec<_> = installed
+            .into_iter()
+            .filter(|app| app.name == "Bitcoin Test")
+            .collect();
+        let bitcoin_only: Vec<BitcoinAppInfo> = fixture::<Vec<BitcoinAppInfo>>("catalog")
+            .into_iter()
+            .filter(|app| app.version_name == "Bitcoin")
+            .collect();
+        assert!(plan(&installed, None, &bitcoin_only).is_empty());
+        let plan = plan(&installed, Some(firmware_update("2.62")), &bitcoin_only);
+        assert_eq!(plan.lost_apps, ["Bitcoin Test"]);
+        assert_eq!(plan.steps.len(), 2);
+        // An app without size takes a default time.
+        let step = UpgradeStep::ReinstallApps {
+            apps: fixture::<Vec<BitcoinAppInfo>>("catalog")[2..].to_vec(),
+        };
+        assert_eq!(step.estimate(), Duration::from_secs(30 + 15));
+    }
+}
@@ -0,0 +1,243 @@
+//! Tests of the Bitcoin app client against the Speculos emulator.
+//!
+//! They are ignored by default. To run them, set `SPECULOS_APDU_ADDR` to the address of the APDU
+//! port of a Speculos instance running the Bitcoin Test app with the default seed, for instance:
+//! ```text
+//! speculos --model nanosp --apdu-port 9999 bin/app.elf
+//! SPECULOS_APDU_ADDR=127.0.0.1:9999 cargo test -p ledger_manager --test speculos -- --ignored --test-threads 1
+//! ```
+//! Registering a policy and signing a PSBT require approving on the device. This can be done by
+//! hand through the Speculos web interface, or by running Speculos with an automation file.
+
+use ledger_manager::{
+    bitcoin_app::{
+        self,
+        encoding::{hash160, sha256d, write_varint},
+        AddressType, DerivationPath, Psbt, PsbtMap, WalletPolicy,
+    },
+    transport::SpeculosTransport,
+};
+
+use std::env;
+
+// The master key fingerprint of the default Speculos seed.
+const SPECULOS_FINGERPRINT: [u8; 4] = [0xf5, 0xac, 0xc2, 0xfd];
+
+fn speculos() -> SpeculosTransport {
+    let addr = env::var("SPECULOS_APDU_ADDR")
+        .expect("SPECULOS_APDU_ADDR must be set to the address of the APDU port of Speculos");
+    SpeculosTransport::connect(addr.as_str()).expect("Connecting to Speculos")
+}
+
+fn path(s: &str) -> DerivationPath {
+    s.parse().unwrap()
+}
+
+fn pubkey_at(transport: &SpeculosTransport, p: &str) -> [u8; 33] {
+    let xpub = bitcoin_app::get_extended_pubkey(transport, &path(p), false).unwrap();
+    bitcoin_app::xpub_public_key(&xpub).unwrap()
+}
+
+fn p2wpkh_script(pubkey: &[u8; 33]) -> Vec<u8> {
+    let mut script = vec![0x00, 0x14];
+    script.extend_from_slice(&hash160(pubkey));
+    script
+}
+
+// A non-segwit transaction with a single input and the given outputs.
+fn transaction(prevout: ([u8; 32], u32), sequence: u32, outputs: &[(u64, Vec<u8>)]) -> Vec<u8> {
+    let mut tx = 2u32.to_le_bytes().to_vec();
+    tx.push(1);
+    tx.extend_from_slice(&prevout.0);
+    tx.extend_from_slice(&prevout.1.to_le_bytes());
+    tx.push(0);
+    tx.extend_from_slice(&sequence.to_le_bytes());
+    tx.extend(write_varint(outputs.len() as u64));
+    for (amount, script) in outputs {
+        tx.extend_from_slice(&amount.to_le_bytes());
+        tx.extend(write_varint(script.len() as u64));
+        tx.extend_from_slice(script);
+    }
+    tx.extend_from_slice(&0u32.to_le_bytes());
+    tx
+}
+
+// A PSBTv0 spending a coin received at m/84'/1'/0'/0/0 to a change address and a foreign one.
+fn single_sig_psbt(transport: &SpeculosTransport) -> (Psbt, [u8; 33]) {
+    let pubkey = pubkey_at(transport, "m/84'/1'/0'/0/0");
+    let change_pubkey = pubkey_at(transport, "m/84'/1'/0'/1/0");
+    let script = p2wpkh_script(&pubkey);
+
+    let prev_tx = transaction(([0x11; 32], 0), 0xffff_ffff, &[(100_000, script.clone())]);
+    let unsigned_tx = transaction(
+        (sha256d(&prev_tx), 0),
+        0xffff_fffd,
+        &[
+            (60_000, p2wpkh_script(&[0x02; 33])),
+            (39_000, p2wpkh_script(&change_pubkey)),
+        ],
+    );
+
+    let mut global = PsbtMap::new();
+    global.insert(vec![0x00], unsigned_tx);
+
+    let mut input = PsbtMap::new();
+    let mut witness_utxo = 100_000u64.to_le_bytes().to_vec();
+    witness_utxo.extend(write_varint(script.len() as u64));
+    witness_utxo.extend_from_slice(&script);
+    input.insert(vec![0x00], prev_tx);
+    input.insert(vec![0x01], witness_utxo);
+    let mut bip32_key = vec![0x06];
+    bip32_key.extend_from_slice(&pubkey);
+    input.insert(bip32_key, bip32_derivation("m/84'/1'/0'/0/0"));
+
+    let mut change_output = PsbtMap::new();
+    let mut bip32_key = vec![0x02];
+    bip32_key.extend_from_slice(&change_pubkey);
+    change_output.insert(bip32_key, bip32_derivation("m/84'/1'/0'/1/0"));
+
+    let psbt = Psbt {
+        global,
+        inputs: vec![input],
+        outputs: vec![PsbtMap::new(), change_output],
+    };
+    (psbt, pubkey)
+}
+
+fn bip32_derivation(p: &str) -> Vec<u8> {
+    let mut value = SPECULOS_FINGERPRINT.to_vec();
+    for step in path(p).steps() {
+        value.extend_from_slice(&step.to_le_bytes());
+    }
+    value
+}
+
+fn partial_sig(psbt: &Psbt, pubkey: &[u8; 33]) -> Option<Vec<u8>> {
+    let mut key = vec![0x02];
+    key.extend_from_slice(pubkey);
+    psbt.inputs[0].get(&key).cloned()
+}
+
+#[test]
+#[ignore = "requires speculos"]
+fn master_fingerprint() {
+    let transport = speculos();
+    assert_eq!(
+        bitcoin_app::get_master_fingerprint(&transport).unwrap(),
+        SPECULOS_FINGERPRINT
+    );
+}
+
+#[test]
+#[ignore = "requires speculos"]
+fn key_origin() {
+    let transport = speculos();
+    let key = bitcoin_app::get_key_origin(&transport, &path("m/84'/1'/0'"), false).unwrap();
+    assert!(key.xpub.starts_with("tpub"));
+    assert!(key.to_string().starts_with("[f5acc2fd/84'/1'/0']tpub"));
+}
+
+#[test]
+#[ignore = "requires speculos"]
+fn single_sig_address() {
+    let transport = speculos();
+    let key = bitcoin_app::get_key_origin(&transport, &path("m/84'/1'/0'"), false).unwrap();
+    let policy = WalletPolicy::single_sig(&key).unwrap();
+    let address =
+        bitcoin_app::get_wallet_address(&transport, &policy, None, false, 0, false).unwrap();
+    assert!(address.starts_with("tb1q"));
+}
+
+#[test]
+#[ignore = "requires speculos"]
+fn register_multisig_and_get_address() {
+    let transport = speculos();
+    let keys = ["m/48'/1'/0'/2'", "m/48'/1'/1'/2'"]
+        .iter()
+        .map(|p| {
+            bitcoin_app::get_key_origin(&transport, &path(p), false)
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    let policy =
+        WalletPolicy::new("Cold storage", "wsh(sortedmulti(1,@0/**,@1/**))", keys).unwrap();
+
+    let registered = bitcoin_app::register_wallet(&transport, &policy).unwrap();
+    assert_eq!(registered.id, policy.id());
+    let address = bitcoin_app::get_wallet_address(
+        &transport,
+        &policy,
+        Some(&registered.hmac),
+        false,
+        0,
+        false,
+    )
+    .unwrap();
+    assert!(address.starts_with("tb1q"));
+}
+
+#[test]
+#[ignore = "requires speculos"]
+fn sign_psbt_v0() {
+    let transport = speculos();
+    let (psbt, pubkey) = single_sig_psbt(&transport);
+    assert_eq!(psbt.version(), 0);
+
+    let signed =
+        bitcoin_app::sign_psbt_single_sig(&transport, &psbt, &path("m/84'/1'/0'")).unwrap();
+    assert_eq!(signed.version(), 0);
+    let sig = partial_sig(&signed, &pubkey).expect("A signature for our input");
+    // A DER signature with SIGHASH_ALL.
+    assert_eq!(sig[0], 0x30);
+    assert_eq!(*sig.last().unwrap(), 0x01);
+
+    // Roundtrip through base64.
+    assert_eq!(Psbt::from_base64(&signed.to_base64()).unwrap(), signed);
+}
+
+#[test]
+#[ignore = "requires speculos"]
+fn sign_psbt_v2() {
+    let transport = speculos();
+    let (psbt, pubkey) = single_sig_psbt(&transport);
+    let psbt = psbt.to_v2().unwrap();
+    assert_eq!(psbt.version(), 2);
+    assert_eq!(Psbt::deserialize(&psbt.serialize()).unwrap(), psbt);
+
+    let signed =
+        bitcoin_app::sign_psbt_single_sig(&transport, &psbt, &path("m/84'/1'/0'")).unwrap();
+    assert_eq!(signed.version(), 2);
+    assert!(partial_sig(&signed, &pubkey).is_some());
+}
+
+#[test]
+#[ignore = "requires speculos"]
+fn sign_message() {
+    let transport = speculos();
+    let message = b"Proof of ownership of this address, which is longer than a single chunk.";
+
+    for p in ["m/44'/1'/0'/0/0", "m/84'/1'/0'/0/0"] {
+        let pubkey = pubkey_at(&transport, p);
+        let address_type = AddressType::from_path(&path(p)).unwrap();
+        let address = bitcoin_app::address(&pubkey, address_type, true);
+        let signature = bitcoin_app::sign_message(&transport, message, &path(p)).unwrap();
+        assert!(bitcoin_app::verify_message(&address, message, &signature).unwrap());
+        assert!(!bitcoin_app::verify_message(&address, b"Another message", &signature).unwrap());
+    }
+
+    let p = "m/84'/1'/0'/0/0";
+    let address = bitcoin_app::address(&pubkey_at(&transport, p), AddressType::NativeSegwit, true);
+    let signature = bitcoin_app::sign_message_bip322(&transport, message, &path(p)).unwrap();
+    assert!(bitcoin_app::verify_message(&address, message, &signature).unwrap());
+}
+
+#[test]
+#[ignore = "requires speculos"]
+fn running_app() {
+    let transport = speculos();
+    let app = ledger_manager::get_running_app(&transport).unwrap();
+    assert!(app.is_bitcoin_app(true));
+    assert_eq!(app.protocol().unwrap(), bitcoin_app::ClientProtocol::V2);
+    assert!(app.app_version().unwrap() >= bitcoin_app::MIN_CLIENT_VERSION);
+}